@@ -0,0 +1,308 @@
+//! Worst-case time the playback thread spends on each wake-up, and whether it
+//! allocates while doing so.
+//!
+//! A dense sequence plays against a virtual clock that is moved straight on
+//! to each deadline, so the loop runs flat out and every wake-up is measured
+//! from the moment its deadline is handed over to the next wait. It plays once to a
+//! handler that does nothing, for the scheduling alone, and once to the MIDI
+//! step handler on a port that drops every message. Run with
+//!
+//!     cargo bench --bench step_latency
+//!
+//! Exits with an error if either playback thread allocated once warmed up, or,
+//! with `STEP_LATENCY_BUDGET_US` set, if any wake-up took longer than that
+//! many microseconds. The worst case depends on the machine and what else it
+//! runs, so there is no budget by default.
+
+use helloworld_tonic::realtime::Receiver;
+use helloworld_tonic::sequencer::{MidiSink, MidiStepHandler, Sequencer, StepHandler};
+use helloworld_tonic::server::sequence::trig_condition::Condition;
+use helloworld_tonic::server::sequence::{ControlChange, ParameterLocks, TrigCondition};
+use helloworld_tonic::timing::{Clock, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{Note, Subdivision, TrackConfig, Trig, VoiceMode};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::process::ExitCode;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TRACKS: u32 = 16;
+const WARMUP_WAKES: usize = 5_000;
+const MEASURED_WAKES: usize = 200_000;
+
+/// Counts the allocations of each thread, so the clock can tell what the
+/// playback thread allocated between two waits.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count_allocation() {
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Real time and allocations between handing the playback thread its
+/// deadline and it waiting again, for every wake-up after the warmup.
+struct Samples {
+    wakes: usize,
+    latencies: Vec<Duration>,
+    allocations: usize,
+}
+
+/// Virtual clock that times the work the playback thread does between two
+/// waits.
+struct TimedClock {
+    clock: VirtualClock,
+    woke: Mutex<Option<(Instant, usize)>>,
+    samples: Mutex<Samples>,
+}
+
+impl TimedClock {
+    fn new() -> Self {
+        Self {
+            clock: VirtualClock::new(),
+            woke: Mutex::new(None),
+            samples: Mutex::new(Samples {
+                wakes: 0,
+                latencies: Vec::with_capacity(MEASURED_WAKES),
+                allocations: 0,
+            }),
+        }
+    }
+
+    fn measured_enough(&self) -> bool {
+        self.samples.lock().unwrap().latencies.len() == MEASURED_WAKES
+    }
+
+    fn record_wake(&self) {
+        let Some((woke_at, allocations_then)) = self.woke.lock().unwrap().take() else {
+            return;
+        };
+        let elapsed = woke_at.elapsed();
+        let allocations = ALLOCATIONS.with(Cell::get) - allocations_then;
+
+        let mut samples = self.samples.lock().unwrap();
+        samples.wakes += 1;
+        if samples.wakes <= WARMUP_WAKES || samples.latencies.len() == MEASURED_WAKES {
+            return;
+        }
+        samples.latencies.push(elapsed);
+        samples.allocations += allocations;
+    }
+
+    fn wake(&self) {
+        *self.woke.lock().unwrap() = Some((Instant::now(), ALLOCATIONS.with(Cell::get)));
+    }
+}
+
+impl Clock for TimedClock {
+    fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn recv_until<M>(
+        &self,
+        rx: &mut Receiver<M>,
+        deadline: Instant,
+    ) -> Result<M, mpsc::RecvTimeoutError> {
+        self.record_wake();
+        let received = self.clock.recv_until(rx, deadline);
+        self.wake();
+        received
+    }
+}
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+    fn handle_parameters(&self, _track: u32, _parameters: &ParameterLocks) {}
+}
+
+/// Port that takes every message and does nothing with it, so only the work
+/// of the handler sending to it is measured.
+struct NullPort;
+
+impl MidiSink for NullPort {
+    fn send(&mut self, _message: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn note(value: i32, octave: i32) -> Note {
+    Note {
+        octave,
+        value,
+        velocity: 100,
+    }
+}
+
+/// Sixteen tracks of four-note chords on every step, each with locks, a
+/// condition and micro-timing, at lengths that keep the playheads apart.
+fn dense_sequence() -> Sequence {
+    let mut trigs = Vec::new();
+    let mut tracks = Vec::new();
+    for track in 0..TRACKS {
+        let length = 64 - track * 3;
+        for step in 0..length {
+            let offset = match step % 4 {
+                1 => 0.3,
+                3 => -0.3,
+                _ => 0.0,
+            };
+            trigs.push(Trig {
+                note: Some(note(1 + (step % 12) as i32, 3)),
+                chord: vec![note(5, 3 + (track % 3) as i32), note(8, 4), note(12, 5)],
+                track,
+                step,
+                offset,
+                length: 1.0 + (step % 3) as f32 * 0.75,
+                condition: Some(TrigCondition {
+                    condition: Some(Condition::Probability(50 + track * 3)),
+                }),
+                locks: Some(ParameterLocks {
+                    control_changes: vec![
+                        ControlChange {
+                            controller: 74,
+                            value: step % 128,
+                        },
+                        ControlChange {
+                            controller: 71,
+                            value: track * 8,
+                        },
+                    ],
+                    pitch_bend: Some(step as i32 * 64),
+                    ..Default::default()
+                }),
+            });
+        }
+        tracks.push(TrackConfig {
+            track,
+            length,
+            voice_mode: if track % 4 == 3 {
+                VoiceMode::Mono as i32
+            } else {
+                VoiceMode::Poly as i32
+            },
+            defaults: Some(ParameterLocks {
+                control_changes: vec![ControlChange {
+                    controller: 74,
+                    value: 64,
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+    }
+
+    Sequence {
+        sequence_length: 64,
+        trig_subdivision: Some(Subdivision {
+            numerator: 1,
+            denominator: 16,
+        }),
+        bpm: 174,
+        trigs,
+        tracks,
+        ..Default::default()
+    }
+}
+
+fn main() -> ExitCode {
+    let budget = std::env::var("STEP_LATENCY_BUDGET_US")
+        .ok()
+        .and_then(|budget| budget.parse().ok())
+        .map(Duration::from_micros);
+
+    let scheduling = measure("scheduling", NullHandler, budget);
+    let midi = measure(
+        "MIDI output",
+        MidiStepHandler::with_midi_clock(NullPort),
+        budget,
+    );
+    if scheduling && midi {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Play the dense sequence to `handler` and report how the playback thread
+/// did. Returns whether it stayed off the allocator and within `budget`.
+fn measure<T: StepHandler>(name: &str, handler: T, budget: Option<Duration>) -> bool {
+    let clock = Arc::new(TimedClock::new());
+    let sequencer = Sequencer::with_clock(handler, Arc::clone(&clock));
+    sequencer.set_random_seed(1);
+    sequencer.cue_sequence(dense_sequence()).unwrap();
+    sequencer.start_sequence().unwrap();
+    clock.clock.settle();
+    while !clock.measured_enough() {
+        let Some(deadline) = clock.clock.next_deadline() else {
+            eprintln!("step_latency ({}): playback stalled", name);
+            return false;
+        };
+        clock.clock.advance_to(deadline);
+    }
+    drop(sequencer);
+    // Let the playback thread's last log lines out before the report.
+    thread::sleep(Duration::from_millis(50));
+
+    let samples = clock.samples.lock().unwrap();
+    let mut latencies = samples.latencies.clone();
+    latencies.sort_unstable();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    let worst = *latencies.last().unwrap();
+
+    println!();
+    println!(
+        "step_latency ({}): {} wake-ups after {} warmup",
+        name,
+        latencies.len(),
+        WARMUP_WAKES
+    );
+    println!("  median  {:>10.1?}", percentile(0.5));
+    println!("  p99     {:>10.1?}", percentile(0.99));
+    println!("  p99.9   {:>10.1?}", percentile(0.999));
+    match budget {
+        Some(budget) => println!("  worst   {:>10.1?}  (budget {:?})", worst, budget),
+        None => println!("  worst   {:>10.1?}", worst),
+    }
+    println!(
+        "  allocations {} ({:.3} per wake-up)",
+        samples.allocations,
+        samples.allocations as f64 / latencies.len() as f64
+    );
+
+    if samples.allocations > 0 {
+        eprintln!("step_latency ({}): the playback thread allocated", name);
+        return false;
+    }
+    if budget.is_some_and(|budget| worst > budget) {
+        eprintln!("step_latency ({}): over budget", name);
+        return false;
+    }
+    true
+}
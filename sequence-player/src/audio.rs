@@ -0,0 +1,110 @@
+use crate::realtime::{self, Receiver, Sender};
+use crate::rt_println;
+use crate::synth::{Synth, SynthEvent, SynthSink};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, StreamConfig};
+
+// Events waiting for the next audio buffer. Enough for every voice to start
+// and stop several times over within one buffer.
+const EVENT_QUEUE_CAPACITY: usize = 1024;
+// Frames the synth renders at a time inside the audio callback.
+const BLOCK_FRAMES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioError {
+    NoDevice,
+    Config(String),
+    Stream(String),
+}
+
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioError::NoDevice => write!(f, "No audio output device"),
+            AudioError::Config(error) => write!(f, "Audio output can't be configured: {}", error),
+            AudioError::Stream(error) => write!(f, "Audio output can't be started: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+/// The running output stream. Sound stops when it is dropped.
+pub struct AudioStream {
+    _stream: cpal::Stream,
+}
+
+/// Synth sink that hands events to the audio callback. Events apply from the
+/// start of the next buffer the device asks for, so timing is as fine as the
+/// device's buffer size.
+pub struct AudioEvents {
+    events: Sender<SynthEvent>,
+}
+
+impl SynthSink for AudioEvents {
+    fn send(&mut self, event: SynthEvent) {
+        if self.events.send(event).is_err() {
+            rt_println!("   Synth event dropped, the audio queue is full");
+        }
+    }
+}
+
+/// Play a synth on the system's default output device, every channel
+/// carrying the same mono mix.
+pub fn open_default_output() -> Result<(AudioStream, AudioEvents), AudioError> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or(AudioError::NoDevice)?;
+    let supported = device
+        .default_output_config()
+        .map_err(|error| AudioError::Config(error.to_string()))?;
+    let config = supported.config();
+    let (events, receiver) = realtime::channel(EVENT_QUEUE_CAPACITY);
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(&device, &config, receiver),
+        SampleFormat::I16 => build_stream::<i16>(&device, &config, receiver),
+        SampleFormat::U16 => build_stream::<u16>(&device, &config, receiver),
+        format => {
+            return Err(AudioError::Config(format!(
+                "Unsupported sample format {}",
+                format
+            )))
+        }
+    }?;
+    stream
+        .play()
+        .map_err(|error| AudioError::Stream(error.to_string()))?;
+    if let Ok(name) = device.name() {
+        println!("🔊 Playing the synth on {}", name);
+    }
+    Ok((AudioStream { _stream: stream }, AudioEvents { events }))
+}
+
+fn build_stream<T: SizedSample + FromSample<f32>>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut events: Receiver<SynthEvent>,
+) -> Result<cpal::Stream, AudioError> {
+    let channels = config.channels as usize;
+    let mut synth = Synth::new(config.sample_rate.0);
+    let mut block = [0.0f32; BLOCK_FRAMES];
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                while let Ok(event) = events.try_recv() {
+                    synth.handle(event);
+                }
+                for frames in data.chunks_mut(channels * BLOCK_FRAMES) {
+                    let count = frames.len() / channels;
+                    synth.render(&mut block[..count]);
+                    for (frame, sample) in frames.chunks_mut(channels).zip(&block) {
+                        frame.fill(T::from_sample(*sample));
+                    }
+                }
+            },
+            |error| println!("⚠️ Audio output error: {}", error),
+            None,
+        )
+        .map_err(|error| AudioError::Stream(error.to_string()))
+}
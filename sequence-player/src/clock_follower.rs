@@ -0,0 +1,365 @@
+use crate::midi_clock::{ClockMessage, PULSES_PER_QUARTER_NOTE};
+use crate::realtime;
+use crate::sequencer::ExternalTransport;
+use crate::timing::{Clock, StepDuration};
+use midir::{ConnectError, Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Tempo the playback thread counts in while following. Each incoming pulse
+// advances its time by one pulse at this tempo, so the master sets the pace.
+pub const FOLLOWER_BPM: u32 = 120;
+
+// Weight of the newest pulse interval in the tempo estimate. Low enough to
+// ride out USB and scheduler jitter, high enough to track tempo changes
+// within a beat or two.
+const SMOOTHING: f64 = 0.1;
+
+// A gap this many times the expected interval means the master paused, not
+// that the tempo changed.
+const MAX_INTERVAL_RATIO: f64 = 4.0;
+
+// How often to look again while the master has gone quiet mid-pulse.
+const STALL_POLL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy)]
+struct PulseTiming {
+    // Real and follower time of the last pulse.
+    real: Instant,
+    follower: Instant,
+    // Smoothed real time between pulses, once two have arrived.
+    interval: Option<Duration>,
+    pulses: u64,
+}
+
+/// Clock that follows an external MIDI clock master. Its time runs at
+/// `FOLLOWER_BPM` and advances exactly one pulse for every pulse received,
+/// interpolating in between at the smoothed incoming rate. It never runs
+/// more than a pulse ahead of the master, so if the master stalls, so does
+/// playback.
+#[derive(Debug)]
+pub struct ExternalClock {
+    // Where pulses are registered. Only whoever receives the master's
+    // messages takes it; the playback thread reads `published`.
+    timing: Mutex<PulseTiming>,
+    published: PublishedTiming,
+    pulse: Duration,
+}
+
+/// The latest `PulseTiming`, behind a sequence lock: readers never wait, they
+/// just read again if a pulse came in while they were reading.
+#[derive(Debug)]
+struct PublishedTiming {
+    // Odd while a pulse is being written.
+    sequence: AtomicU64,
+    // Times as nanoseconds from `origin`, which can go either way.
+    origin: Instant,
+    real: AtomicI64,
+    follower: AtomicI64,
+    // Zero until two pulses have arrived.
+    interval: AtomicU64,
+    pulses: AtomicU64,
+}
+
+impl PublishedTiming {
+    fn new(timing: &PulseTiming) -> Self {
+        let published = Self {
+            sequence: AtomicU64::new(0),
+            origin: timing.real,
+            real: AtomicI64::new(0),
+            follower: AtomicI64::new(0),
+            interval: AtomicU64::new(0),
+            pulses: AtomicU64::new(0),
+        };
+        published.store(timing);
+        published
+    }
+
+    // Only one writer at a time, which the `ExternalClock::timing` lock sees
+    // to.
+    fn store(&self, timing: &PulseTiming) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.real.store(self.offset(timing.real), Ordering::Relaxed);
+        self.follower
+            .store(self.offset(timing.follower), Ordering::Relaxed);
+        self.interval.store(
+            timing
+                .interval
+                .map_or(0, |interval| interval.as_nanos() as u64),
+            Ordering::Relaxed,
+        );
+        self.pulses.store(timing.pulses, Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+    }
+
+    fn load(&self) -> PulseTiming {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let real = self.real.load(Ordering::Relaxed);
+            let follower = self.follower.load(Ordering::Relaxed);
+            let interval = self.interval.load(Ordering::Relaxed);
+            let pulses = self.pulses.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return PulseTiming {
+                    real: self.instant(real),
+                    follower: self.instant(follower),
+                    interval: (interval > 0).then(|| Duration::from_nanos(interval)),
+                    pulses,
+                };
+            }
+        }
+    }
+
+    fn offset(&self, at: Instant) -> i64 {
+        match at.checked_duration_since(self.origin) {
+            Some(after) => after.as_nanos() as i64,
+            None => -(self.origin.duration_since(at).as_nanos() as i64),
+        }
+    }
+
+    fn instant(&self, offset: i64) -> Instant {
+        let magnitude = Duration::from_nanos(offset.unsigned_abs());
+        if offset >= 0 {
+            self.origin + magnitude
+        } else {
+            self.origin - magnitude
+        }
+    }
+}
+
+impl Default for ExternalClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExternalClock {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        let timing = PulseTiming {
+            real: now,
+            follower: now,
+            interval: None,
+            pulses: 0,
+        };
+        Self {
+            timing: Mutex::new(timing),
+            published: PublishedTiming::new(&timing),
+            pulse: StepDuration::new(FOLLOWER_BPM, 1, 4 * PULSES_PER_QUARTER_NOTE).as_duration(),
+        }
+    }
+
+    /// Register a pulse from the master that arrived at `at`.
+    pub fn pulse(&self, at: Instant) {
+        let mut timing = self.lock();
+        let elapsed = at.saturating_duration_since(timing.real);
+        self.register_pulse(&mut timing, at, elapsed);
+    }
+
+    /// Register a pulse that arrived at `at`, `since_last` after the previous
+    /// one by the driver's timestamps. Only the driver's interval goes into
+    /// the tempo estimate, so however late the callback ran doesn't; `at`
+    /// just anchors where the pulse lands.
+    pub fn timed_pulse(&self, at: Instant, since_last: Duration) {
+        let mut timing = self.lock();
+        self.register_pulse(&mut timing, at, since_last);
+    }
+
+    fn register_pulse(&self, timing: &mut PulseTiming, at: Instant, elapsed: Duration) {
+        if timing.pulses > 0 {
+            timing.interval = match timing.interval {
+                None => Some(elapsed),
+                Some(interval)
+                    if elapsed.as_secs_f64() > interval.as_secs_f64() * MAX_INTERVAL_RATIO =>
+                {
+                    Some(interval)
+                }
+                Some(interval) => {
+                    let smoothed = interval.as_secs_f64()
+                        + (elapsed.as_secs_f64() - interval.as_secs_f64()) * SMOOTHING;
+                    Some(Duration::from_secs_f64(smoothed))
+                }
+            };
+        }
+        timing.follower += self.pulse;
+        timing.real = at;
+        timing.pulses += 1;
+        self.published.store(timing);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PulseTiming> {
+        self.timing.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Tempo of the master in BPM, once it has sent enough pulses to tell.
+    pub fn tempo(&self) -> Option<f64> {
+        self.published
+            .load()
+            .interval
+            .filter(|interval| !interval.is_zero())
+            .map(|interval| 60.0 / (interval.as_secs_f64() * PULSES_PER_QUARTER_NOTE as f64))
+    }
+
+    /// Follower time at real time `at`: the last pulse plus however much of
+    /// the next one has elapsed at the smoothed rate, capped at the next pulse.
+    fn interpolate(timing: &PulseTiming, pulse: Duration, at: Instant) -> Instant {
+        let Some(interval) = timing.interval.filter(|interval| !interval.is_zero()) else {
+            return timing.follower;
+        };
+        let progress =
+            at.saturating_duration_since(timing.real).as_secs_f64() / interval.as_secs_f64();
+        timing.follower + pulse.mul_f64(progress.min(1.0))
+    }
+
+    /// Real time left until follower time reaches `deadline`, assuming the
+    /// master keeps its current pace.
+    fn real_wait(&self, deadline: Instant) -> Duration {
+        let timing = self.published.load();
+        let now = Instant::now();
+        let remaining =
+            deadline.saturating_duration_since(Self::interpolate(&timing, self.pulse, now));
+        match timing.interval {
+            Some(interval) => {
+                let wait = interval.mul_f64(remaining.as_secs_f64() / self.pulse.as_secs_f64());
+                // Look again once the next pulse is due; it moves the estimate.
+                let next_pulse = (timing.real + interval).saturating_duration_since(now);
+                wait.min(next_pulse.max(STALL_POLL))
+            }
+            None => STALL_POLL,
+        }
+    }
+}
+
+impl Clock for ExternalClock {
+    fn now(&self) -> Instant {
+        Self::interpolate(&self.published.load(), self.pulse, Instant::now())
+    }
+
+    fn recv_until<M>(
+        &self,
+        rx: &mut realtime::Receiver<M>,
+        deadline: Instant,
+    ) -> Result<M, mpsc::RecvTimeoutError> {
+        loop {
+            match rx.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(mpsc::RecvTimeoutError::Disconnected)
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+            if self.now() >= deadline {
+                return Err(mpsc::RecvTimeoutError::Timeout);
+            }
+            match rx.recv_timeout(self.real_wait(deadline)) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn fixed_tempo(&self) -> Option<u32> {
+        Some(FOLLOWER_BPM)
+    }
+}
+
+/// Turns incoming MIDI clock and transport messages into pulses for an
+/// `ExternalClock` and transitions of the sequencer's transport. Start and
+/// Continue take effect on the pulse that follows them, as the spec asks, so
+/// the first step lines up with the master's downbeat.
+pub struct ClockFollower<C: AsRef<ExternalClock>> {
+    clock: C,
+    transport: ExternalTransport,
+    pending: Option<ClockMessage>,
+    // Driver timestamp of the last pulse, in microseconds.
+    last_stamp: Option<u64>,
+}
+
+impl<C: AsRef<ExternalClock>> ClockFollower<C> {
+    pub fn new(clock: C, transport: ExternalTransport) -> Self {
+        Self {
+            clock,
+            transport,
+            pending: None,
+            last_stamp: None,
+        }
+    }
+
+    /// Handle one incoming MIDI message that arrived at `at`. Anything that
+    /// isn't clock or transport is ignored.
+    pub fn receive(&mut self, message: &[u8], at: Instant) {
+        self.handle(message, at, None);
+    }
+
+    /// Like `receive`, for a message the driver stamped at `stamp`
+    /// microseconds. Pulse intervals are taken from the stamps.
+    pub fn receive_stamped(&mut self, message: &[u8], at: Instant, stamp: u64) {
+        self.handle(message, at, Some(stamp));
+    }
+
+    fn handle(&mut self, message: &[u8], at: Instant, stamp: Option<u64>) {
+        let Some(message) = ClockMessage::from_bytes(message) else {
+            return;
+        };
+        let result = match message {
+            ClockMessage::Pulse => {
+                let since_last = stamp
+                    .zip(self.last_stamp)
+                    .and_then(|(stamp, last)| stamp.checked_sub(last))
+                    .map(Duration::from_micros);
+                self.last_stamp = stamp;
+                match since_last {
+                    Some(interval) => self.clock.as_ref().timed_pulse(at, interval),
+                    None => self.clock.as_ref().pulse(at),
+                }
+                match self.pending.take() {
+                    Some(ClockMessage::Start) => self.transport.start(),
+                    Some(ClockMessage::Continue) => self.transport.resume(),
+                    _ => Ok(()),
+                }
+            }
+            ClockMessage::Start | ClockMessage::Continue => {
+                println!("⏱️ External clock: {:?}", message);
+                self.pending = Some(message);
+                Ok(())
+            }
+            ClockMessage::Stop => {
+                println!("⏱️ External clock: Stop");
+                self.pending = None;
+                self.transport.stop()
+            }
+            ClockMessage::SongPosition(position) => {
+                self.transport.locate(position);
+                Ok(())
+            }
+        };
+        if let Err(error) = result {
+            println!("❌ Failed to follow external clock: {}", error);
+        }
+    }
+}
+
+/// Listen for clock and transport messages on `port` and hand them to
+/// `follower` for as long as the returned connection is kept.
+pub fn connect_clock_input<C: AsRef<ExternalClock> + Send + 'static>(
+    mut midi_input: MidiInput,
+    port: &MidiInputPort,
+    follower: ClockFollower<C>,
+) -> Result<MidiInputConnection<ClockFollower<C>>, ConnectError<MidiInput>> {
+    // midir drops timing messages unless told otherwise.
+    midi_input.ignore(Ignore::SysexAndActiveSense);
+    midi_input.connect(
+        port,
+        "seq-clock-in",
+        |stamp, message, follower| follower.receive_stamped(message, Instant::now(), stamp),
+        follower,
+    )
+}
@@ -0,0 +1,160 @@
+use crate::server::sequence::trig_condition::Condition;
+use crate::server::sequence::{IterationCondition, Trig};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Longest A:B cycle the hardware offers.
+pub const MAX_ITERATION_CYCLE: u32 = 8;
+
+/// Evaluates trig conditions during playback. Draws probabilities from a
+/// seeded generator so runs can be reproduced; the outcomes PRE and NEI look
+/// back on are kept in `LastResults`.
+#[derive(Debug)]
+pub struct ConditionEvaluator {
+    rng: SplitMix64,
+    fill: bool,
+}
+
+/// Outcome of the last condition on each track of a sequence, for PRE and
+/// NEI. Made with the sequence's playheads, off the playback thread, so
+/// recording an outcome never allocates.
+#[derive(Debug, Clone, Default)]
+pub struct LastResults {
+    // Every track of the sequence in track order, with its last outcome.
+    tracks: Vec<(u32, bool)>,
+}
+
+impl ConditionEvaluator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: SplitMix64(seed),
+            fill: false,
+        }
+    }
+
+    /// Seed from the wall clock, for when reproducibility doesn't matter.
+    pub fn unseeded() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::new(seed)
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = SplitMix64(seed);
+    }
+
+    pub fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+    }
+
+    /// Whether `trig` plays this time round. `iteration` counts the loops its
+    /// track has completed since the sequence started, from zero. PRE and NEI
+    /// only read earlier outcomes from `last_results`; every other condition
+    /// records its own there.
+    pub fn evaluate(
+        &mut self,
+        trig: &Trig,
+        iteration: u32,
+        last_results: &mut LastResults,
+    ) -> bool {
+        let Some(condition) = trig
+            .condition
+            .as_ref()
+            .and_then(|condition| condition.condition.as_ref())
+        else {
+            return true;
+        };
+
+        let result = match condition {
+            Condition::Previous(expected) => {
+                return last_results.get(trig.track) == *expected;
+            }
+            Condition::Neighbor(expected) => {
+                let neighbor = trig.track.checked_sub(1);
+                return neighbor.is_some_and(|track| last_results.get(track)) == *expected;
+            }
+            Condition::Probability(percent) => self.rng.below(100) < *percent as u64,
+            Condition::Iteration(IterationCondition { a, b }) => {
+                *b > 0 && iteration % b == a.saturating_sub(1)
+            }
+            Condition::Fill(expected) => self.fill == *expected,
+            Condition::First(expected) => (iteration == 0) == *expected,
+        };
+        last_results.set(trig.track, result);
+        result
+    }
+}
+
+impl LastResults {
+    /// No outcomes yet for `tracks`, which have to be in order.
+    pub fn new(tracks: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            tracks: tracks.into_iter().map(|track| (track, false)).collect(),
+        }
+    }
+
+    /// Outcome of the last condition on `track`, false if there wasn't one.
+    pub fn get(&self, track: u32) -> bool {
+        self.position(track)
+            .is_some_and(|index| self.tracks[index].1)
+    }
+
+    /// Forget previous outcomes, e.g. when playback restarts.
+    pub fn clear(&mut self) {
+        for (_, result) in &mut self.tracks {
+            *result = false;
+        }
+    }
+
+    /// Pick up the outcomes of `previous`, for a sequence taking over from
+    /// another. Tracks new to this sequence start without one.
+    pub fn carry_over(&mut self, previous: &LastResults) {
+        for (track, result) in &mut self.tracks {
+            *result = previous.get(*track);
+        }
+    }
+
+    // Tracks outside the sequence have no trigs to look back on.
+    fn set(&mut self, track: u32, result: bool) {
+        if let Some(index) = self.position(track) {
+            self.tracks[index].1 = result;
+        }
+    }
+
+    fn position(&self, track: u32) -> Option<usize> {
+        self.tracks
+            .binary_search_by_key(&track, |(track, _)| *track)
+            .ok()
+    }
+}
+
+/// Whether a condition can ever be evaluated: probabilities are 1-100% and
+/// A:B needs 1 <= A <= B <= 8.
+pub fn condition_is_valid(condition: &Condition) -> bool {
+    match condition {
+        Condition::Probability(percent) => (1..=100).contains(percent),
+        Condition::Iteration(IterationCondition { a, b }) => {
+            (1..=MAX_ITERATION_CYCLE).contains(b) && (1..=*b).contains(a)
+        }
+        _ => true,
+    }
+}
+
+// Small, fast generator; plenty for deciding whether a trig plays.
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
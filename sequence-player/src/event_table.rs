@@ -0,0 +1,208 @@
+use crate::groove::apply_groove;
+use crate::midi_clock::pulse_duration;
+use crate::sequencer::note_key;
+use crate::server::sequence::{Note, Sequence, Trig, VoiceMode};
+use crate::timing::StepDuration;
+use crate::tracks::{master_length, tracks, TrackSettings};
+use crate::types::MiddleC;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A sequence compiled for the playback thread when it is cued or swapped
+/// in: swing and groove folded into its trigs, the settings of every track
+/// worked out, and the trigs of each track step sorted by when they play, so
+/// playing a step is a lookup rather than a search of the whole sequence.
+/// Step handlers get it too, for what they need to know about the sequence
+/// while its notes play.
+#[derive(Debug)]
+pub struct EventTable {
+    sequence: Sequence,
+    sequence_id: u64,
+    middle_c: MiddleC,
+    master_length: u32,
+    step_duration: StepDuration,
+    pulse_duration: StepDuration,
+    tracks: Vec<TrackEvents>,
+}
+
+/// The trigs of one track, by step. Only steps with trigs on them are kept,
+/// so long tracks cost no more than short ones.
+#[derive(Debug)]
+pub struct TrackEvents {
+    pub settings: TrackSettings,
+    steps: Vec<(u32, StepEvents)>,
+}
+
+/// Trigs of one track step, in sequence order, split by where their offset
+/// puts them. Each is shared, so queueing it doesn't copy it.
+#[derive(Debug, Default)]
+pub struct StepEvents {
+    pub on_grid: Vec<Arc<TrigEvent>>,
+    // Pushed past the step by a positive offset.
+    pub late: Vec<Arc<TrigEvent>>,
+    // Pulled into the previous step's window by a negative offset.
+    pub early: Vec<Arc<TrigEvent>>,
+}
+
+/// A trig as its track plays it. A chord also carries a copy of the trig for
+/// each of its notes, so part of it can start or end on its own without the
+/// playback thread building a new trig.
+#[derive(Debug)]
+pub struct TrigEvent {
+    trig: Trig,
+    voices: Vec<Trig>,
+}
+
+impl EventTable {
+    pub fn new(sequence: &Sequence, sequence_id: u64) -> Self {
+        let sequence = apply_groove(sequence);
+        let mut tracks: Vec<TrackEvents> = tracks(&sequence)
+            .into_iter()
+            .map(|settings| TrackEvents {
+                settings,
+                steps: Vec::new(),
+            })
+            .collect();
+
+        for trig in &sequence.trigs {
+            let Some(track) = tracks
+                .iter_mut()
+                .find(|track| track.settings.track == trig.track)
+            else {
+                continue;
+            };
+            let index = match track
+                .steps
+                .binary_search_by_key(&trig.step, |(step, _)| *step)
+            {
+                Ok(index) => index,
+                Err(index) => {
+                    track
+                        .steps
+                        .insert(index, (trig.step, StepEvents::default()));
+                    index
+                }
+            };
+            let events = &mut track.steps[index].1;
+            let trig = Arc::new(TrigEvent::new(voiced(trig, track.settings.voice_mode)));
+            if trig.offset > 0.0 {
+                events.late.push(trig);
+            } else if trig.offset < 0.0 {
+                events.early.push(trig);
+            } else {
+                events.on_grid.push(trig);
+            }
+        }
+
+        Self {
+            middle_c: sequence.middle_c(),
+            master_length: master_length(&sequence),
+            step_duration: StepDuration::from_sequence(&sequence),
+            pulse_duration: pulse_duration(&sequence),
+            sequence,
+            sequence_id,
+            tracks,
+        }
+    }
+
+    /// The sequence as it plays, with swing and groove applied.
+    pub fn sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    pub fn sequence_id(&self) -> u64 {
+        self.sequence_id
+    }
+
+    /// How the sequence numbers its octaves.
+    pub fn middle_c(&self) -> MiddleC {
+        self.middle_c
+    }
+
+    pub fn master_length(&self) -> u32 {
+        self.master_length
+    }
+
+    pub fn step_duration(&self) -> StepDuration {
+        self.step_duration
+    }
+
+    pub fn pulse_duration(&self) -> StepDuration {
+        self.pulse_duration
+    }
+
+    /// Every track the sequence configures or has trigs on, in track order.
+    pub fn tracks(&self) -> &[TrackEvents] {
+        &self.tracks
+    }
+
+    /// Channel (0-15) `track` plays on. Tracks the sequence doesn't know
+    /// follow their number, as they would in it.
+    pub fn track_channel(&self, track: u32) -> u8 {
+        self.tracks
+            .binary_search_by_key(&track, |events| events.settings.track)
+            .map_or((track % 16) as u8, |index| {
+                self.tracks[index].settings.midi_channel
+            })
+    }
+}
+
+impl TrackEvents {
+    /// Trigs on `step` of the track, if it has any.
+    pub fn step(&self, step: u32) -> Option<&StepEvents> {
+        self.steps
+            .binary_search_by_key(&step, |(step, _)| *step)
+            .ok()
+            .map(|index| &self.steps[index].1)
+    }
+}
+
+impl TrigEvent {
+    pub fn new(trig: Trig) -> Self {
+        let voices = if trig.notes().nth(1).is_some() {
+            trig.notes()
+                .map(|note| trig.with_notes(vec![note.clone()]))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self { trig, voices }
+    }
+
+    /// The trig playing only the note at `index` of its notes.
+    pub fn voice(&self, index: usize) -> &Trig {
+        self.voices.get(index).unwrap_or(&self.trig)
+    }
+}
+
+impl Deref for TrigEvent {
+    type Target = Trig;
+
+    fn deref(&self) -> &Trig {
+        &self.trig
+    }
+}
+
+/// The trig as its track can play it: a mono track only ever plays the first
+/// note of a chord, and a poly track plays each pitch of a chord once.
+fn voiced(trig: &Trig, voice_mode: VoiceMode) -> Trig {
+    let mut pitches = Vec::new();
+    let notes: Vec<Note> = match voice_mode {
+        VoiceMode::Mono => trig.notes().take(1).cloned().collect(),
+        VoiceMode::Poly => trig
+            .notes()
+            .filter(|note| {
+                let pitch = note_key(note);
+                let first = !pitches.contains(&pitch);
+                pitches.push(pitch);
+                first
+            })
+            .cloned()
+            .collect(),
+    };
+    if notes.len() == trig.notes().count() {
+        trig.clone()
+    } else {
+        trig.with_notes(notes)
+    }
+}
@@ -0,0 +1,90 @@
+use crate::server::sequence::{Sequence, Trig};
+
+// Swing range in percent, as on the hardware. 50% plays straight.
+pub const MIN_SWING: u32 = 50;
+pub const MAX_SWING: u32 = 80;
+
+// Micro-timing range in fractions of a step, matching the hardware's ±23/24.
+pub const MAX_TRIG_OFFSET: f32 = 23.0 / 24.0;
+
+/// Whether `swing` is a usable swing amount. Zero leaves swing unset.
+pub fn swing_is_valid(swing: u32) -> bool {
+    swing == 0 || (MIN_SWING..=MAX_SWING).contains(&swing)
+}
+
+/// Delay of the second step of each pair in fractions of a step. At X% swing
+/// the first step of a pair takes X% of the pair's length.
+pub fn swing_delay(swing: u32) -> f32 {
+    if swing <= MIN_SWING {
+        return 0.0;
+    }
+    (swing.min(MAX_SWING) - MIN_SWING) as f32 / MIN_SWING as f32
+}
+
+/// Micro-timing offset of a trig in fractions of a step, limited to the range
+/// the hardware allows.
+pub fn trig_offset(trig: &Trig) -> f32 {
+    limit_offset(trig.offset)
+}
+
+fn limit_offset(offset: f32) -> f32 {
+    if offset.is_finite() {
+        offset.clamp(-MAX_TRIG_OFFSET, MAX_TRIG_OFFSET)
+    } else {
+        0.0
+    }
+}
+
+/// Total offset of a trig from its step: its own micro-timing, plus the swing
+/// delay on every second step and the groove template's timing for the step.
+/// The sum stays within the micro-timing range, so a trig never plays more
+/// than a step away from its own.
+pub fn trig_timing(sequence: &Sequence, trig: &Trig) -> f32 {
+    let swing = if trig.step % 2 == 1 {
+        swing_delay(sequence.swing)
+    } else {
+        0.0
+    };
+    let groove = sequence
+        .groove
+        .as_ref()
+        .and_then(|groove| cycle(&groove.timing, trig.step))
+        .map_or(0.0, limit_offset);
+    limit_offset(trig_offset(trig) + swing + groove)
+}
+
+/// Velocity of a note on `step` after the groove template's velocity offset
+/// for the step, kept within the MIDI range.
+pub fn note_velocity(sequence: &Sequence, step: u32, velocity: u32) -> u32 {
+    let offset = sequence
+        .groove
+        .as_ref()
+        .and_then(|groove| cycle(&groove.velocity, step))
+        .unwrap_or(0);
+    if offset == 0 {
+        return velocity;
+    }
+    (velocity as i64 + offset as i64).clamp(1, 127) as u32
+}
+
+/// The copy of `sequence` the playback thread plays, with swing and groove
+/// folded into each trig's offset and velocity.
+pub fn apply_groove(sequence: &Sequence) -> Sequence {
+    let mut grooved = sequence.clone();
+    for trig in &mut grooved.trigs {
+        trig.offset = trig_timing(sequence, trig);
+        let step = trig.step;
+        for note in trig.note.iter_mut().chain(&mut trig.chord) {
+            note.velocity = note_velocity(sequence, step, note.velocity);
+        }
+    }
+    grooved
+}
+
+// Groove templates repeat over the steps of a track.
+fn cycle<T: Copy>(values: &[T], step: u32) -> Option<T> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values[step as usize % values.len()])
+}
@@ -0,0 +1,316 @@
+use crate::server::sequence::{MiddleC, Sequence, Subdivision, TrackConfig, Trig};
+use crate::smf::{self, SmfContents, SmfError};
+use crate::timing::{playback_bpm, subdivision_is_valid, MAX_SUBDIVISION_TERM};
+use crate::types::Pitch;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+// Longest sequence an import produces, in steps.
+pub const MAX_IMPORT_STEPS: u32 = 65536;
+
+// Tempo of files that don't set one, as the MIDI file format defines it.
+const DEFAULT_BPM: u32 = 120;
+const MICROS_PER_MINUTE: f64 = 60_000_000.0;
+
+/// How notes of a file are laid out on steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportOptions {
+    /// Step length notes are quantized to.
+    pub subdivision: Subdivision,
+    /// How the imported notes number their octaves.
+    pub middle_c: MiddleC,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            subdivision: Subdivision {
+                numerator: 1,
+                denominator: 16,
+            },
+            middle_c: MiddleC::default(),
+        }
+    }
+}
+
+/// A sequence read from a file, with what it couldn't hold as written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Imported {
+    pub sequence: Sequence,
+    pub unrepresentable: Vec<UnrepresentableNote>,
+    /// Channel messages other than notes, such as controllers and pitch
+    /// bends, which have nowhere to go in a sequence.
+    pub ignored_events: usize,
+    /// Tempo changes after the first tempo, which the sequence can't follow.
+    pub ignored_tempo_changes: usize,
+    /// Whether the file's first tempo was outside what sequences play at, so
+    /// the sequence has the nearest tempo they do instead.
+    pub tempo_clamped: bool,
+}
+
+/// A note of the file that the sequence doesn't hold as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnrepresentableNote {
+    pub channel: u8, // 1-16.
+    pub key: u8,
+    /// Where the note starts in the file.
+    pub tick: u64,
+    pub problem: NoteProblem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteProblem {
+    // Quantized onto a step where its track already plays the same pitch, so
+    // it is left out.
+    SamePitchOnStep { step: u32 },
+    // Never released, so it is held to the end of the file.
+    Unterminated,
+}
+
+impl std::fmt::Display for NoteProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NoteProblem::SamePitchOnStep { step } => write!(
+                f,
+                "Lands on step {} along with the same pitch, so it was left out",
+                step
+            ),
+            NoteProblem::Unterminated => {
+                write!(f, "Never released, so it was held to the end of the file")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImportError {
+    Smf(SmfError),
+    InvalidSubdivision { numerator: i64, denominator: i64 },
+    TooLong { steps: u64 },
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Smf(error) => error.fmt(f),
+            ImportError::InvalidSubdivision {
+                numerator,
+                denominator,
+            } => write!(
+                f,
+                "Subdivision {}/{} must have a numerator and denominator between 1 and {}",
+                numerator, denominator, MAX_SUBDIVISION_TERM
+            ),
+            ImportError::TooLong { steps } => write!(
+                f,
+                "MIDI file spans {} steps, more than the {} a sequence can import",
+                steps, MAX_IMPORT_STEPS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<SmfError> for ImportError {
+    fn from(error: SmfError) -> Self {
+        ImportError::Smf(error)
+    }
+}
+
+// A note of the file from its note-on to its note-off.
+struct FileNote {
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    start: u64,
+    end: u64,
+}
+
+/// Turn a Standard MIDI File into a sequence. Each note goes on the step
+/// nearest its start, with the rest as micro-timing and its duration as the
+/// trig length, and notes starting together become chords. MIDI channels
+/// become tracks, channel 1 track 0 and so on, each still playing on its
+/// channel. The sequence spans the file rounded up to whole steps, at the
+/// file's first tempo, brought into the range sequences play at.
+pub fn import_midi(bytes: &[u8], options: &ImportOptions) -> Result<Imported, ImportError> {
+    let subdivision = &options.subdivision;
+    if !subdivision_is_valid(subdivision) {
+        return Err(ImportError::InvalidSubdivision {
+            numerator: subdivision.numerator,
+            denominator: subdivision.denominator,
+        });
+    }
+    let contents = smf::read(bytes)?;
+    // A step of n/d whole notes is 4n/d quarter notes.
+    let step_ticks = contents.ticks_per_quarter as f64 * 4.0 * subdivision.numerator as f64
+        / subdivision.denominator as f64;
+    let steps = (contents.end_tick as f64 / step_ticks).ceil().max(1.0) as u64;
+    if steps > MAX_IMPORT_STEPS as u64 {
+        return Err(ImportError::TooLong { steps });
+    }
+    let sequence_length = steps as u32;
+
+    let mut imported = Imported {
+        sequence: Sequence::default(),
+        unrepresentable: Vec::new(),
+        ignored_events: 0,
+        ignored_tempo_changes: 0,
+        tempo_clamped: false,
+    };
+    let notes = pair_notes(&contents, &mut imported);
+
+    let mut trigs: Vec<Trig> = Vec::new();
+    // Trig of each track, step, offset and length, to add chord notes to.
+    let mut trig_indices: HashMap<(u32, u32, u32, u32), usize> = HashMap::new();
+    let mut pitches_on_steps = HashSet::new();
+    for note in notes {
+        let track = note.channel as u32;
+        let nearest = (note.start as f64 / step_ticks).round();
+        // Late notes of the last step come round early on the first.
+        let step = (nearest as u64 % steps) as u32;
+        let offset = ((note.start as f64 - nearest * step_ticks) / step_ticks) as f32;
+        let length = ((note.end - note.start) as f64 / step_ticks) as f32;
+
+        if !pitches_on_steps.insert((track, step, note.key)) {
+            imported.unrepresentable.push(UnrepresentableNote {
+                channel: note.channel + 1,
+                key: note.key,
+                tick: note.start,
+                problem: NoteProblem::SamePitchOnStep { step },
+            });
+            continue;
+        }
+        let sequence_note = Pitch::new(note.key)
+            .expect("MIDI data bytes are below 128")
+            .to_note(options.middle_c, note.velocity as u32);
+        let key = (track, step, offset.to_bits(), length.to_bits());
+        match trig_indices.get(&key) {
+            Some(&index) => trigs[index].chord.push(sequence_note),
+            None => {
+                trig_indices.insert(key, trigs.len());
+                trigs.push(Trig {
+                    note: Some(sequence_note),
+                    track,
+                    step,
+                    offset,
+                    length,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    imported
+        .unrepresentable
+        .sort_by_key(|note| (note.tick, note.channel, note.key));
+    trigs.sort_by(|a, b| {
+        (a.track, a.step)
+            .cmp(&(b.track, b.step))
+            .then(a.offset.total_cmp(&b.offset))
+    });
+
+    let file_bpm = first_tempo(&contents);
+    let bpm = playback_bpm(file_bpm);
+    imported.tempo_clamped = bpm != file_bpm;
+    imported.ignored_tempo_changes = tempo_changes(&contents);
+
+    let channels: BTreeSet<u32> = trigs.iter().map(|trig| trig.track).collect();
+    imported.sequence = Sequence {
+        sequence_length,
+        trig_subdivision: Some(subdivision.clone()),
+        bpm,
+        trigs,
+        tracks: channels
+            .into_iter()
+            .map(|track| TrackConfig {
+                track,
+                midi_channel: track + 1,
+                ..Default::default()
+            })
+            .collect(),
+        middle_c: options.middle_c as i32,
+        ..Default::default()
+    };
+    Ok(imported)
+}
+
+/// Every note of the file in order of its start, matching each note-on with
+/// the next note-off of its key and channel. Notes never released last to
+/// the end of the file; other channel messages are only counted.
+fn pair_notes(contents: &SmfContents, imported: &mut Imported) -> Vec<FileNote> {
+    let mut events: Vec<(u64, &[u8])> = contents
+        .tracks
+        .iter()
+        .flat_map(|track| track.events.iter())
+        .map(|event| (event.tick, event.message.as_slice()))
+        .collect();
+    // Stable, so events at the same tick keep the order of their track.
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut notes = Vec::new();
+    let mut sounding: HashMap<(u8, u8), VecDeque<(u64, u8)>> = HashMap::new();
+    for (tick, message) in events {
+        let channel = message[0] & 0x0F;
+        match (message[0] & 0xF0, message) {
+            (0x90, &[_, key, velocity]) if velocity > 0 => sounding
+                .entry((channel, key))
+                .or_default()
+                .push_back((tick, velocity)),
+            (0x80 | 0x90, &[_, key, _]) => {
+                // A note-off for a note that never started has nothing to end.
+                if let Some((start, velocity)) = sounding
+                    .get_mut(&(channel, key))
+                    .and_then(VecDeque::pop_front)
+                {
+                    notes.push(FileNote {
+                        channel,
+                        key,
+                        velocity,
+                        start,
+                        end: tick,
+                    });
+                }
+            }
+            _ => imported.ignored_events += 1,
+        }
+    }
+
+    for ((channel, key), starts) in sounding {
+        for (start, velocity) in starts {
+            imported.unrepresentable.push(UnrepresentableNote {
+                channel: channel + 1,
+                key,
+                tick: start,
+                problem: NoteProblem::Unterminated,
+            });
+            notes.push(FileNote {
+                channel,
+                key,
+                velocity,
+                start,
+                end: contents.end_tick,
+            });
+        }
+    }
+    notes.sort_by_key(|note| (note.start, note.channel, note.key));
+    notes
+}
+
+/// Tempo the file starts at, to the nearest BPM.
+fn first_tempo(contents: &SmfContents) -> u32 {
+    match contents.tempos.first() {
+        Some(&(_, micros_per_quarter)) if micros_per_quarter > 0 => {
+            (MICROS_PER_MINUTE / micros_per_quarter as f64).round() as u32
+        }
+        _ => DEFAULT_BPM,
+    }
+}
+
+/// Tempo events after the first that set a different tempo from the one
+/// before them.
+fn tempo_changes(contents: &SmfContents) -> usize {
+    contents
+        .tempos
+        .windows(2)
+        .filter(|pair| pair[0].1 != pair[1].1)
+        .count()
+}
@@ -1,6 +1,27 @@
+#[cfg(feature = "audio-device")]
+pub mod audio;
+pub mod clock_follower;
+pub mod conditions;
+pub mod event_table;
+pub mod groove;
+pub mod import;
+pub mod locks;
+pub mod logging;
+pub mod midi_clock;
+pub mod ports;
+pub mod realtime;
+pub mod recording;
+pub mod render;
+pub mod routing;
+pub mod sampler;
 pub mod sequencer;
 pub mod server;
+pub mod smf;
+pub mod synth;
+pub mod timing;
+pub mod tracks;
 pub mod types;
+pub mod validation;
 
 pub use sequencer::Sequencer;
-pub use types::{Note, NoteValue, Subdivision, Trig};
+pub use types::{MiddleC, Note, NoteValue, Pitch, Subdivision, TrackConfig, Trig, VoiceMode};
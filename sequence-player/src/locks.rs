@@ -0,0 +1,114 @@
+use crate::server::sequence::{ParameterLocks, Trig};
+
+// Controllers 120-127 are channel mode messages, not parameters.
+pub const MAX_LOCKABLE_CONTROLLER: u32 = 119;
+// Most controllers a set of restored values can hold, one of each.
+pub const LOCKABLE_CONTROLLERS: usize = MAX_LOCKABLE_CONTROLLER as usize + 1;
+pub const MIN_PITCH_BEND: i32 = -8192;
+pub const MAX_PITCH_BEND: i32 = 8191;
+pub const MAX_PROGRAM: u32 = 127;
+
+/// Parameter locks of a trig, if it locks anything.
+pub fn trig_locks(trig: &Trig) -> Option<&ParameterLocks> {
+    trig.locks.as_ref().filter(|locks| !locks_are_empty(locks))
+}
+
+pub fn locks_are_empty(locks: &ParameterLocks) -> bool {
+    locks.control_changes.is_empty()
+        && locks.pitch_bend.is_none()
+        && locks.channel_pressure.is_none()
+        && locks.program.is_none()
+}
+
+/// Values that undo `locked` on a track: the track default for every locked
+/// controller that has one, the track's program if a lock changed it, and the
+/// resting position of pitch bend and channel pressure unless the track sets
+/// its own.
+pub fn restored_values(
+    locked: &ParameterLocks,
+    defaults: Option<&ParameterLocks>,
+) -> ParameterLocks {
+    let mut restored = ParameterLocks::default();
+    restore_values_into(&mut restored, locked, defaults);
+    restored
+}
+
+/// `restored_values`, written over `restored` so its controller list can be
+/// reused instead of allocated. Each controller is restored once however
+/// often it is locked, so a list with room for `LOCKABLE_CONTROLLERS` never
+/// has to grow.
+pub fn restore_values_into(
+    restored: &mut ParameterLocks,
+    locked: &ParameterLocks,
+    defaults: Option<&ParameterLocks>,
+) {
+    restored.control_changes.clear();
+    for locked in &locked.control_changes {
+        let already_restored = restored
+            .control_changes
+            .iter()
+            .any(|restored| restored.controller == locked.controller);
+        let default = defaults.and_then(|defaults| {
+            defaults
+                .control_changes
+                .iter()
+                .rev()
+                .find(|default| default.controller == locked.controller)
+        });
+        if let Some(default) = default.filter(|_| !already_restored) {
+            restored.control_changes.push(default.clone());
+        }
+    }
+    restored.pitch_bend = locked.pitch_bend.map(|_| {
+        defaults
+            .and_then(|defaults| defaults.pitch_bend)
+            .unwrap_or(0)
+    });
+    restored.channel_pressure = locked.channel_pressure.map(|_| {
+        defaults
+            .and_then(|defaults| defaults.channel_pressure)
+            .unwrap_or(0)
+    });
+    restored.program = locked
+        .program
+        .and(defaults.and_then(|defaults| defaults.program));
+}
+
+/// Describe the first value in `locks` that can't be sent as MIDI.
+pub fn invalid_lock(locks: &ParameterLocks) -> Option<String> {
+    let invalid_control_change = locks.control_changes.iter().find(|control_change| {
+        control_change.controller > MAX_LOCKABLE_CONTROLLER || control_change.value > 127
+    });
+    if let Some(control_change) = invalid_control_change {
+        return Some(format!(
+            "CC {} = {} must have a controller of at most {} and a value of at most 127",
+            control_change.controller, control_change.value, MAX_LOCKABLE_CONTROLLER
+        ));
+    }
+
+    if let Some(pitch_bend) = locks
+        .pitch_bend
+        .filter(|pitch_bend| !(MIN_PITCH_BEND..=MAX_PITCH_BEND).contains(pitch_bend))
+    {
+        return Some(format!(
+            "pitch bend {} must be between {} and {}",
+            pitch_bend, MIN_PITCH_BEND, MAX_PITCH_BEND
+        ));
+    }
+
+    if let Some(channel_pressure) = locks.channel_pressure.filter(|pressure| *pressure > 127) {
+        return Some(format!(
+            "channel pressure {} must be at most 127",
+            channel_pressure
+        ));
+    }
+
+    if let Some(program) = locks.program.filter(|program| *program > MAX_PROGRAM) {
+        return Some(format!(
+            "program {} must be at most {}",
+            program, MAX_PROGRAM
+        ));
+    }
+
+    None
+}
@@ -0,0 +1,137 @@
+use rtrb::{Consumer, Producer, RingBuffer};
+use std::cell::RefCell;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+// Longest line kept; anything longer is cut short.
+const LINE_CAPACITY: usize = 192;
+// Lines a thread can have waiting before new ones are dropped.
+const QUEUE_LINES: usize = 512;
+// How often the printer thread looks for new lines.
+const PRINT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// `println!` for threads that must not block or allocate, such as the
+/// playback thread. The line is formatted into a fixed buffer on the calling
+/// thread and printed later by a printer thread.
+#[macro_export]
+macro_rules! rt_println {
+    ($($arg:tt)*) => {
+        $crate::logging::log(format_args!($($arg)*))
+    };
+}
+
+/// Queue one line for printing. Each thread gets its own queue the first time
+/// it logs, which is the only time logging allocates or locks; a thread with
+/// latency to keep can log once up front to get that out of the way. Lines
+/// that don't fit in a full queue are dropped and counted.
+pub fn log(args: fmt::Arguments) {
+    let mut line = Line::default();
+    let _ = line.write_fmt(args);
+
+    let queued = QUEUE.try_with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let producer = queue.get_or_insert_with(register);
+        if producer.push(line).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    // The thread is on its way out and its queue is gone.
+    if queued.is_err() {
+        println!("{}", line.as_str());
+    }
+}
+
+thread_local! {
+    static QUEUE: RefCell<Option<Producer<Line>>> = const { RefCell::new(None) };
+}
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// Queues of every thread that has logged, drained by the printer thread.
+static QUEUES: OnceLock<Mutex<Vec<Consumer<Line>>>> = OnceLock::new();
+
+fn register() -> Producer<Line> {
+    let (producer, consumer) = RingBuffer::new(QUEUE_LINES);
+    let mut spawned = false;
+    let queues = QUEUES.get_or_init(|| {
+        spawned = true;
+        Mutex::new(Vec::new())
+    });
+    queues
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(consumer);
+    if spawned {
+        thread::Builder::new()
+            .name("log-printer".to_string())
+            .spawn(|| print_queued_lines(queues))
+            .expect("Failed to spawn log printer thread");
+    }
+    producer
+}
+
+/// Print whatever the logging threads have queued, a thread's lines at a
+/// time, and forget the queues of threads that have finished.
+fn print_queued_lines(queues: &Mutex<Vec<Consumer<Line>>>) {
+    loop {
+        {
+            let mut queues = queues.lock().unwrap_or_else(PoisonError::into_inner);
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            for queue in queues.iter_mut() {
+                while let Ok(line) = queue.pop() {
+                    let _ = writeln!(out, "{}", line.as_str());
+                }
+            }
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                let _ = writeln!(out, "… {} log lines dropped", dropped);
+            }
+            queues.retain(|queue| !(queue.is_abandoned() && queue.is_empty()));
+        }
+        thread::sleep(PRINT_INTERVAL);
+    }
+}
+
+/// One formatted line, held inline so queueing it doesn't allocate.
+#[derive(Clone, Copy)]
+struct Line {
+    length: usize,
+    bytes: [u8; LINE_CAPACITY],
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Self {
+            length: 0,
+            bytes: [0; LINE_CAPACITY],
+        }
+    }
+}
+
+impl Line {
+    fn as_str(&self) -> &str {
+        // Only ever filled with whole characters.
+        std::str::from_utf8(&self.bytes[..self.length]).unwrap_or_default()
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let space = LINE_CAPACITY - self.length;
+        let mut end = s.len().min(space);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.length..self.length + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.length += end;
+        if end < s.len() {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
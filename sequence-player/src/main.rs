@@ -1,23 +1,245 @@
-use helloworld_tonic::sequencer::MidiStepHandler;
+#[cfg(feature = "audio-device")]
+use helloworld_tonic::audio::{open_default_output, AudioStream};
+use helloworld_tonic::clock_follower::{connect_clock_input, ClockFollower, ExternalClock};
+use helloworld_tonic::ports::{
+    create_virtual_output, select_input, PortSelector, SelectedOutput, SystemPorts,
+    VIRTUAL_PORT_NAME,
+};
+use helloworld_tonic::render::{render_midi, render_wav_with_samples, RenderError, RenderOptions};
+use helloworld_tonic::routing::{MidiRouter, DEFAULT_ROUTES_FILE};
+use helloworld_tonic::sampler::{Sample, SampleBank, SampleSettings, TrackSample};
+use helloworld_tonic::sequencer::{MidiSink, MidiStepHandler, StepHandler};
+use helloworld_tonic::server::sequence::Sequence;
 use helloworld_tonic::server::{SequencerServiceImpl, SequencerServiceServer, FILE_DESCRIPTOR_SET};
+#[cfg(feature = "audio-device")]
+use helloworld_tonic::synth::SynthStepHandler;
 use helloworld_tonic::Sequencer;
-use midir::MidiOutput;
+use midir::MidiInput;
+use prost::Message;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tonic::transport::Server;
 use tonic_reflection::server::Builder;
 
+/// Value of `--name value` or `--name=value` on the command line, falling
+/// back to the environment variable `env`.
+fn option(name: &str, env: &str) -> Option<String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+        if let Some(value) = arg
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_string());
+        }
+    }
+    std::env::var(env).ok()
+}
+
+/// Samples for tracks of the built-in synth, each given as
+/// `--sample TRACK=FILE` and played from its start, once through, at root
+/// key 60.
+fn sample_options() -> Result<SampleBank, Box<dyn std::error::Error>> {
+    let samples = SampleBank::default();
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--sample") {
+            Some("") => args.next(),
+            Some(rest) => rest.strip_prefix('=').map(str::to_string),
+            None => None,
+        };
+        let Some(value) = value else {
+            continue;
+        };
+        let (track, path) = value.split_once('=').ok_or("--sample takes TRACK=FILE")?;
+        let sample = Sample::from_wav(&std::fs::read(path)?)?;
+        samples.assign(
+            track.parse()?,
+            TrackSample::new(sample, SampleSettings::default())?,
+        );
+        println!("🥁 Track {} plays {}", track, path);
+    }
+    Ok(samples)
+}
+
+/// `export-midi SEQUENCE [--loops N] [--seed N] [--output FILE]`: render a
+/// binary `Sequence` message, as `protoc --encode=sequence.Sequence` writes
+/// one, to a Standard MIDI File. `render-wav` takes the same arguments, plus
+/// a `--sample` for each track playing a sample, and plays the sequence on
+/// the built-in synth into a WAV file instead. The file goes next to the
+/// input with a `.mid` or `.wav` extension unless `--output` says otherwise.
+fn render_to_file(
+    usage: &str,
+    extension: &str,
+    render: impl FnOnce(Sequence, &RenderOptions) -> Result<Vec<u8>, RenderError>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input = std::env::args()
+        .nth(2)
+        .filter(|arg| !arg.starts_with("--"))
+        .ok_or_else(|| format!("Usage: {}", usage))?;
+    let sequence = Sequence::decode(std::fs::read(&input)?.as_slice())?;
+    let defaults = RenderOptions::default();
+    let options = RenderOptions {
+        loops: match option("--loops", "SEQUENCER_EXPORT_LOOPS") {
+            Some(loops) => loops.parse()?,
+            None => defaults.loops,
+        },
+        seed: match option("--seed", "SEQUENCER_EXPORT_SEED") {
+            Some(seed) => seed.parse()?,
+            None => defaults.seed,
+        },
+    };
+    let output = option("--output", "SEQUENCER_EXPORT_OUTPUT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(&input).with_extension(extension));
+
+    let file = render(sequence, &options)?;
+    std::fs::write(&output, file)?;
+    println!(
+        "🎼 Rendered {} loop(s) of {} to {}",
+        options.loops,
+        input,
+        output.display()
+    );
+    Ok(())
+}
+
+// Where notes go, and the router in front of the system ports if any.
+type MidiOutput = (Box<dyn MidiSink>, Option<MidiRouter>);
+
+/// MIDI output, either a virtual port or the selected system port with the
+/// track router in front of it.
+fn open_midi_output() -> Result<MidiOutput, Box<dyn std::error::Error>> {
+    let virtual_port = std::env::args()
+        .find_map(|arg| match arg.strip_prefix("--virtual-port") {
+            Some("") => Some(VIRTUAL_PORT_NAME.to_string()),
+            Some(rest) => rest.strip_prefix('=').map(str::to_string),
+            None => None,
+        })
+        .or_else(|| std::env::var("SEQUENCER_VIRTUAL_PORT").ok());
+    Ok(match virtual_port {
+        Some(name) => {
+            let conn = create_virtual_output(&name)?;
+            println!("🎹 Publishing virtual MIDI output {}", name);
+            (Box::new(conn), None)
+        }
+        None => {
+            let output_selector =
+                PortSelector::new(&option("--midi-out", "SEQUENCER_MIDI_OUT").unwrap_or_default());
+            let (conn, connected) = SelectedOutput::open(SystemPorts, output_selector);
+            if let Err(error) = connected {
+                println!("⚠️ {}, waiting for a MIDI output", error);
+            }
+            let routes_file = option("--routes", "SEQUENCER_ROUTES")
+                .unwrap_or_else(|| DEFAULT_ROUTES_FILE.to_string());
+            let router = MidiRouter::new(SystemPorts, conn, Some(routes_file.into()))?;
+            router.watch();
+            (Box::new(router.clone()), Some(router))
+        }
+    })
+}
+
+/// Sequencer on our own clock, or following the external one.
+fn sequencer<T: StepHandler>(
+    step_handler: T,
+    external_clock: &Option<Arc<ExternalClock>>,
+) -> Sequencer {
+    match external_clock {
+        Some(clock) => Sequencer::with_clock(step_handler, Arc::clone(clock)),
+        None => Sequencer::new(step_handler),
+    }
+}
+
+#[cfg(feature = "audio-device")]
+fn synth_sequencer(
+    external_clock: &Option<Arc<ExternalClock>>,
+    samples: SampleBank,
+) -> Result<(Sequencer, AudioStream), Box<dyn std::error::Error>> {
+    let (stream, events) = open_default_output()?;
+    let step_handler = SynthStepHandler::with_samples(events, samples);
+    Ok((sequencer(step_handler, external_clock), stream))
+}
+
+#[cfg(not(feature = "audio-device"))]
+fn synth_sequencer(
+    _external_clock: &Option<Arc<ExternalClock>>,
+    _samples: SampleBank,
+) -> Result<(Sequencer, ()), Box<dyn std::error::Error>> {
+    Err("--synth needs a build with the audio-device feature".into())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Wiring up MIDI
-    let midi_out = MidiOutput::new("Sequencer").unwrap();
-    let ports = midi_out.ports();
-    let last_port = ports.last().unwrap();
-    let conn = midi_out.connect(last_port, "seq").unwrap();
+    // Rendering to a file needs neither MIDI nor the server.
+    match std::env::args().nth(1).as_deref() {
+        Some("export-midi") => {
+            let usage = "export-midi SEQUENCE [--loops N] [--seed N] [--output FILE]";
+            return render_to_file(usage, "mid", render_midi);
+        }
+        Some("render-wav") => {
+            let usage = "render-wav SEQUENCE [--loops N] [--seed N] [--output FILE] \
+                         [--sample TRACK=FILE]...";
+            let samples = sample_options()?;
+            return render_to_file(usage, "wav", |sequence, options| {
+                render_wav_with_samples(sequence, options, samples)
+            });
+        }
+        _ => {}
+    }
 
-    let step_handler = MidiStepHandler::new(conn);
+    // Wiring up sequencer, on our own clock or following a master on the
+    // chosen MIDI input.
+    let external_clock = std::env::args()
+        .any(|arg| arg == "--follow-clock")
+        .then(|| Arc::new(ExternalClock::new()));
 
-    // Wiring up sequencer
-    let sequencer = Sequencer::new(step_handler);
-    let sequencer_service = SequencerServiceImpl::new(sequencer);
+    // Wiring up sound: the built-in synth on the default audio output, with
+    // samples for tracks given on the command line or loaded later, or
+    // MIDI, either on a virtual port of our own for other software to
+    // subscribe to, or on the port picked by name or regex, the last one by
+    // default. Until that one is there, and whenever it goes away, notes are
+    // dropped and the port is looked for again. Tracks can be routed to
+    // further ports, and the routes are kept in a file between runs.
+    let synth = std::env::args().any(|arg| arg == "--synth");
+    let (sequencer, midi_router, sample_bank, _audio_stream) = if synth {
+        let samples = sample_options()?;
+        let (sequencer, stream) = synth_sequencer(&external_clock, samples.clone())?;
+        (sequencer, None, Some(samples), Some(stream))
+    } else {
+        let (conn, midi_router) = open_midi_output()?;
+        // Chained devices follow our tempo when asked to.
+        let step_handler = if std::env::args().any(|arg| arg == "--midi-clock") {
+            MidiStepHandler::with_midi_clock(conn)
+        } else {
+            MidiStepHandler::new(conn)
+        };
+        (
+            sequencer(step_handler, &external_clock),
+            midi_router,
+            None,
+            None,
+        )
+    };
+    let _clock_input = match external_clock {
+        Some(clock) => {
+            let input_selector =
+                PortSelector::new(&option("--clock-in", "SEQUENCER_CLOCK_IN").unwrap_or_default());
+            let midi_in = MidiInput::new("Sequencer clock")?;
+            let (name, port) = select_input(&midi_in, &input_selector)?;
+            let follower = ClockFollower::new(clock, sequencer.external_transport());
+            println!("⏱️ Following clock on {}", name);
+            Some(connect_clock_input(midi_in, &port, follower)?)
+        }
+        None => None,
+    };
+    let sequencer_service = match (midi_router, sample_bank) {
+        (Some(midi_router), _) => SequencerServiceImpl::with_midi_router(sequencer, midi_router),
+        (None, Some(sample_bank)) => SequencerServiceImpl::with_sample_bank(sequencer, sample_bank),
+        (None, None) => SequencerServiceImpl::new(sequencer),
+    };
 
     // Wiring up server
     let addr = "[::1]:50051".parse()?;
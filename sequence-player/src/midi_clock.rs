@@ -0,0 +1,87 @@
+use crate::server::sequence::{Sequence, Subdivision};
+use crate::timing::{subdivision_is_valid, StepDuration};
+use crate::tracks::master_length;
+use smallvec::{smallvec, SmallVec};
+
+// MIDI clock resolution.
+pub const PULSES_PER_QUARTER_NOTE: u64 = 24;
+
+// Song Position Pointer counts sixteenth notes in 14 bits.
+pub const MAX_SONG_POSITION: u16 = 0x3fff;
+
+/// System real-time and common messages a clock master sends to its
+/// followers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockMessage {
+    Pulse,
+    Start,
+    Continue,
+    Stop,
+    // Sixteenth notes since the start of the sequence.
+    SongPosition(u16),
+}
+
+impl ClockMessage {
+    /// Parse an incoming message. Anything that isn't clock or transport is
+    /// `None`.
+    pub fn from_bytes(message: &[u8]) -> Option<Self> {
+        match message {
+            [0xF8, ..] => Some(ClockMessage::Pulse),
+            [0xFA, ..] => Some(ClockMessage::Start),
+            [0xFB, ..] => Some(ClockMessage::Continue),
+            [0xFC, ..] => Some(ClockMessage::Stop),
+            [0xF2, lsb, msb, ..] => Some(ClockMessage::SongPosition(
+                (*lsb as u16 & 0x7f) | ((*msb as u16 & 0x7f) << 7),
+            )),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(self) -> SmallVec<[u8; 3]> {
+        match self {
+            ClockMessage::Pulse => smallvec![0xF8],
+            ClockMessage::Start => smallvec![0xFA],
+            ClockMessage::Continue => smallvec![0xFB],
+            ClockMessage::Stop => smallvec![0xFC],
+            ClockMessage::SongPosition(position) => {
+                let position = position.min(MAX_SONG_POSITION);
+                smallvec![0xF2, (position & 0x7f) as u8, (position >> 7) as u8]
+            }
+        }
+    }
+}
+
+/// Time between clock pulses at the tempo of `sequence`, clamped the same way
+/// as its steps so the two grids line up.
+pub fn pulse_duration(sequence: &Sequence) -> StepDuration {
+    let pulse = Subdivision {
+        numerator: 1,
+        denominator: 4 * PULSES_PER_QUARTER_NOTE as i64,
+    };
+    StepDuration::at_tempo(sequence.bpm, Some(&pulse))
+}
+
+fn step_length(sequence: &Sequence) -> (u64, u64) {
+    match &sequence.trig_subdivision {
+        Some(subdivision) if subdivision_is_valid(subdivision) => {
+            (subdivision.numerator as u64, subdivision.denominator as u64)
+        }
+        _ => (1, 16),
+    }
+}
+
+/// Song position of master step `step` of `sequence`. Steps that don't start
+/// on a sixteenth note round down to the one before.
+pub fn song_position(sequence: &Sequence, step: u32) -> u16 {
+    let (numerator, denominator) = step_length(sequence);
+    let sixteenths = step as u64 * numerator * 16 / denominator;
+    sixteenths.min(MAX_SONG_POSITION as u64) as u16
+}
+
+/// Master step of `sequence` at song position `position`, wrapped to the
+/// master length. Positions between steps round down to the step before.
+pub fn step_at_song_position(sequence: &Sequence, position: u16) -> u32 {
+    let (numerator, denominator) = step_length(sequence);
+    let step = position as u64 * denominator / (16 * numerator);
+    (step % master_length(sequence) as u64) as u32
+}
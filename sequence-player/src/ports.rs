@@ -0,0 +1,379 @@
+use crate::rt_println;
+use crate::sequencer::MidiSink;
+use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputConnection};
+use regex::Regex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError, Weak};
+use std::thread;
+use std::time::Duration;
+
+// Name the sequencer shows up as to other MIDI software.
+pub const CLIENT_NAME: &str = "Sequencer";
+
+// Name of the port published in virtual mode unless another is given.
+pub const VIRTUAL_PORT_NAME: &str = "vtakt";
+
+// How often a watched output looks for its device coming and going.
+pub const RECONNECT_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortError {
+    // The MIDI system itself couldn't be opened.
+    Unavailable(String),
+    NoPorts,
+    NoMatch {
+        pattern: String,
+        available: Vec<String>,
+    },
+    ConnectFailed {
+        port: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for PortError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortError::Unavailable(reason) => write!(f, "MIDI unavailable: {}", reason),
+            PortError::NoPorts => write!(f, "No MIDI ports available"),
+            PortError::NoMatch { pattern, available } => write!(
+                f,
+                "No MIDI port matches \"{}\" (available: {})",
+                pattern,
+                available.join(", ")
+            ),
+            PortError::ConnectFailed { port, reason } => {
+                write!(f, "Failed to connect to MIDI port {}: {}", port, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PortError {}
+
+/// Which port to use, given as its exact name, part of it or a regex. An
+/// empty selector takes the last port, as the sequencer always has.
+#[derive(Debug, Clone, Default)]
+pub struct PortSelector {
+    pattern: Option<String>,
+    // Patterns that aren't valid regexes, such as names with unbalanced
+    // brackets, still match by name.
+    regex: Option<Regex>,
+}
+
+impl PortSelector {
+    pub fn new(pattern: &str) -> Self {
+        if pattern.is_empty() {
+            return Self::default();
+        }
+        Self {
+            pattern: Some(pattern.to_string()),
+            regex: Regex::new(pattern).ok(),
+        }
+    }
+
+    pub fn pattern(&self) -> Option<&str> {
+        self.pattern.as_deref()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match &self.pattern {
+            None => true,
+            Some(pattern) => {
+                name.contains(pattern.as_str())
+                    || self
+                        .regex
+                        .as_ref()
+                        .is_some_and(|regex| regex.is_match(name))
+            }
+        }
+    }
+
+    /// Pick a port from `names`: the one named exactly by the pattern if
+    /// there is one, otherwise the last that matches.
+    pub fn choose<'a>(&self, names: &'a [String]) -> Result<&'a str, PortError> {
+        if names.is_empty() {
+            return Err(PortError::NoPorts);
+        }
+        let exact = self
+            .pattern
+            .as_ref()
+            .and_then(|pattern| names.iter().find(|name| *name == pattern));
+        exact
+            .or_else(|| names.iter().rev().find(|name| self.matches(name)))
+            .map(String::as_str)
+            .ok_or_else(|| PortError::NoMatch {
+                pattern: self.pattern.clone().unwrap_or_default(),
+                available: names.to_vec(),
+            })
+    }
+}
+
+/// Where output ports come from: the system's MIDI ports, or anything else
+/// that can stand in for them.
+pub trait OutputPorts: Send + Sync + 'static {
+    type Connection: MidiSink;
+
+    fn names(&self) -> Result<Vec<String>, PortError>;
+    fn connect(&self, name: &str) -> Result<Self::Connection, PortError>;
+}
+
+/// The MIDI output ports of this machine, through midir.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemPorts;
+
+impl OutputPorts for SystemPorts {
+    type Connection = MidiOutputConnection;
+
+    fn names(&self) -> Result<Vec<String>, PortError> {
+        let midi_out = MidiOutput::new(CLIENT_NAME)
+            .map_err(|error| PortError::Unavailable(error.to_string()))?;
+        Ok(midi_out
+            .ports()
+            .iter()
+            .filter_map(|port| midi_out.port_name(port).ok())
+            .collect())
+    }
+
+    fn connect(&self, name: &str) -> Result<MidiOutputConnection, PortError> {
+        let midi_out = MidiOutput::new(CLIENT_NAME)
+            .map_err(|error| PortError::Unavailable(error.to_string()))?;
+        let port = midi_out
+            .ports()
+            .into_iter()
+            .find(|port| midi_out.port_name(port).as_deref() == Ok(name))
+            .ok_or_else(|| PortError::ConnectFailed {
+                port: name.to_string(),
+                reason: "port went away".to_string(),
+            })?;
+        midi_out
+            .connect(&port, "seq")
+            .map_err(|error| PortError::ConnectFailed {
+                port: name.to_string(),
+                reason: error.to_string(),
+            })
+    }
+}
+
+/// Publish an output port of our own named `name`, under a client of the same
+/// name, for DAWs and soft synths to subscribe to. Only ALSA and CoreMIDI can
+/// do this.
+#[cfg(unix)]
+pub fn create_virtual_output(name: &str) -> Result<MidiOutputConnection, PortError> {
+    use midir::os::unix::VirtualOutput;
+
+    let midi_out =
+        MidiOutput::new(name).map_err(|error| PortError::Unavailable(error.to_string()))?;
+    midi_out
+        .create_virtual(name)
+        .map_err(|error| PortError::ConnectFailed {
+            port: name.to_string(),
+            reason: error.to_string(),
+        })
+}
+
+#[cfg(not(unix))]
+pub fn create_virtual_output(_name: &str) -> Result<MidiOutputConnection, PortError> {
+    Err(PortError::Unavailable(
+        "virtual ports aren't supported on this platform".to_string(),
+    ))
+}
+
+/// Names of the MIDI input ports of this machine.
+pub fn input_port_names() -> Result<Vec<String>, PortError> {
+    let midi_in =
+        MidiInput::new(CLIENT_NAME).map_err(|error| PortError::Unavailable(error.to_string()))?;
+    Ok(input_ports(&midi_in)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// The input port of `midi_in` that `selector` picks, with its name.
+pub fn select_input(
+    midi_in: &MidiInput,
+    selector: &PortSelector,
+) -> Result<(String, MidiInputPort), PortError> {
+    let ports = input_ports(midi_in);
+    let names: Vec<String> = ports.iter().map(|(name, _)| name.clone()).collect();
+    let chosen = selector.choose(&names)?;
+    Ok(ports
+        .into_iter()
+        .find(|(name, _)| name == chosen)
+        .expect("chosen from these ports"))
+}
+
+fn input_ports(midi_in: &MidiInput) -> Vec<(String, MidiInputPort)> {
+    midi_in
+        .ports()
+        .into_iter()
+        .filter_map(|port| Some((midi_in.port_name(&port).ok()?, port)))
+        .collect()
+}
+
+struct OutputState<C> {
+    selector: PortSelector,
+    // Connection and the name of the port it goes to.
+    connection: Option<(String, C)>,
+}
+
+/// Output that follows a `PortSelector` rather than a fixed port. It can be
+/// pointed at another port while playing, and when its device disappears it
+/// waits for a matching one to reappear and reconnects. Messages sent while
+/// nothing is connected, or while the port is being changed, are dropped.
+/// Clones share the connection, so one can drive the step handler while
+/// another is kept to select ports.
+pub struct SelectedOutput<P: OutputPorts = SystemPorts> {
+    ports: Arc<P>,
+    state: Arc<Mutex<OutputState<P::Connection>>>,
+    // Messages dropped because the connection was busy.
+    dropped: Arc<AtomicU64>,
+}
+
+impl<P: OutputPorts> Clone for SelectedOutput<P> {
+    fn clone(&self) -> Self {
+        Self {
+            ports: Arc::clone(&self.ports),
+            state: Arc::clone(&self.state),
+            dropped: Arc::clone(&self.dropped),
+        }
+    }
+}
+
+impl<P: OutputPorts> std::fmt::Debug for SelectedOutput<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SelectedOutput")
+            .field("port", &self.port_name())
+            .finish()
+    }
+}
+
+impl<P: OutputPorts> SelectedOutput<P> {
+    /// Connect to the port `selector` picks from `ports`. If there isn't one
+    /// yet, the output starts out disconnected and `reconnect` picks the port
+    /// up once it shows up.
+    pub fn open(ports: P, selector: PortSelector) -> (Self, Result<String, PortError>) {
+        let output = Self {
+            ports: Arc::new(ports),
+            state: Arc::new(Mutex::new(OutputState {
+                selector,
+                connection: None,
+            })),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        let result = output.reconnect();
+        (output, result)
+    }
+
+    /// Switch to the port `selector` picks. If it can't be connected, the
+    /// current port and selector stay as they were.
+    pub fn select(&self, selector: PortSelector) -> Result<String, PortError> {
+        let names = self.ports.names()?;
+        let name = selector.choose(&names)?.to_string();
+        let connection = self.ports.connect(&name)?;
+
+        let mut state = self.lock();
+        state.selector = selector;
+        state.connection = Some((name.clone(), connection));
+        println!("🎹 MIDI output: {}", name);
+        Ok(name)
+    }
+
+    /// Send `message` to the connected port, or drop it while there is none.
+    /// Clones share the connection, so any of them can send. Never waits: if
+    /// another clone is using the connection, the message is dropped and
+    /// counted instead.
+    pub fn send_message(&self, message: &[u8]) -> Result<(), String> {
+        let mut state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                rt_println!("   MIDI output busy, dropping a message");
+                return Ok(());
+            }
+        };
+        match &mut state.connection {
+            Some((_, connection)) => connection.send(message),
+            None => Ok(()),
+        }
+    }
+
+    /// Messages dropped so far because the connection was busy.
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Name of the connected port, if any.
+    pub fn port_name(&self) -> Option<String> {
+        let state = self.lock();
+        state.connection.as_ref().map(|(name, _)| name.clone())
+    }
+
+    /// Look for changes in the available ports: drop the connection if its
+    /// port has gone, connect if a matching port is there while disconnected.
+    /// Returns the connected port.
+    pub fn reconnect(&self) -> Result<String, PortError> {
+        let names = self.ports.names()?;
+        let (selector, gone) = {
+            let mut state = self.lock();
+            if let Some((name, _)) = &state.connection {
+                if names.contains(name) {
+                    return Ok(name.clone());
+                }
+                println!(
+                    "⚠️ MIDI output {} disappeared, waiting for it to return",
+                    name
+                );
+            }
+            (state.selector.clone(), state.connection.take())
+        };
+        drop(gone);
+
+        // Connecting can take a while; notes keep flowing, or being dropped,
+        // in the meantime, as with `select`.
+        let name = selector.choose(&names)?.to_string();
+        let connection = self.ports.connect(&name)?;
+        let mut state = self.lock();
+        // A port selected meanwhile wins.
+        if let Some((selected, _)) = &state.connection {
+            return Ok(selected.clone());
+        }
+        println!("🎹 MIDI output: {}", name);
+        state.connection = Some((name.clone(), connection));
+        Ok(name)
+    }
+
+    /// Call `reconnect` every `RECONNECT_POLL` for as long as any clone of
+    /// this output is around.
+    pub fn watch(&self) -> thread::JoinHandle<()> {
+        let ports = Arc::clone(&self.ports);
+        let state: Weak<Mutex<OutputState<P::Connection>>> = Arc::downgrade(&self.state);
+        let dropped = Arc::clone(&self.dropped);
+        thread::spawn(move || loop {
+            thread::sleep(RECONNECT_POLL);
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let output = SelectedOutput {
+                ports: Arc::clone(&ports),
+                state,
+                dropped: Arc::clone(&dropped),
+            };
+            // Not finding the port is the normal state while it's unplugged.
+            if let Err(error @ PortError::ConnectFailed { .. }) = output.reconnect() {
+                println!("❌ {}", error);
+            }
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OutputState<P::Connection>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<P: OutputPorts> MidiSink for SelectedOutput<P> {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        self.send_message(message)
+    }
+}
@@ -0,0 +1,200 @@
+use rtrb::{Consumer, Producer, PushError, RingBuffer};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, Thread, ThreadId};
+use std::time::{Duration, Instant};
+
+/// Queue from any number of control threads to one real-time thread. Both
+/// ends sit on a lock-free ring buffer allocated up front. Senders take turns
+/// on a mutex among themselves, but the receiving thread never touches it
+/// while it runs: it only parks, and senders unpark it after each message.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let (producer, consumer) = RingBuffer::new(capacity);
+    let waiter = Arc::new(Mutex::new(None));
+    let sender = Sender {
+        producer: Arc::new(Mutex::new(producer)),
+        waiter: Arc::clone(&waiter),
+    };
+    let receiver = Receiver {
+        consumer,
+        waiter,
+        attached: None,
+    };
+    (sender, receiver)
+}
+
+pub struct Sender<T> {
+    producer: Arc<Mutex<Producer<T>>>,
+    // Thread blocked on the receiving end, if any has waited yet.
+    waiter: Arc<Mutex<Option<Thread>>>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            producer: Arc::clone(&self.producer),
+            waiter: Arc::clone(&self.waiter),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender").finish_non_exhaustive()
+    }
+}
+
+impl<T> Sender<T> {
+    /// Queue `value` without waiting. Fails when the queue is full or the
+    /// receiver is gone, handing the value back.
+    pub fn send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut producer = self.producer.lock().unwrap_or_else(PoisonError::into_inner);
+        if producer.is_abandoned() {
+            return Err(TrySendError::Disconnected(value));
+        }
+        producer
+            .push(value)
+            .map_err(|PushError::Full(value)| TrySendError::Full(value))?;
+        drop(producer);
+
+        let waiter = self.waiter.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(thread) = waiter.as_ref() {
+            thread.unpark();
+        }
+        Ok(())
+    }
+}
+
+/// Receiving end of `channel`, with the blocking calls of an mpsc receiver.
+/// Whichever thread waits on it is the one senders wake; it registers the
+/// first time it waits, so a receiver can move to a new thread.
+pub struct Receiver<T> {
+    consumer: Consumer<T>,
+    waiter: Arc<Mutex<Option<Thread>>>,
+    attached: Option<ThreadId>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.consumer.pop() {
+            Ok(value) => Ok(value),
+            // Senders may have queued a last message before leaving.
+            Err(_) if self.consumer.is_abandoned() => {
+                self.consumer.pop().map_err(|_| TryRecvError::Disconnected)
+            }
+            Err(_) => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        self.attach();
+        loop {
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Disconnected) => return Err(RecvTimeoutError::Disconnected),
+                Err(TryRecvError::Empty) => {}
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
+
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        self.attach();
+        loop {
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => thread::park(),
+            }
+        }
+    }
+
+    /// Wait until a message arrives or something else unparks the thread,
+    /// then take whatever is there. `Empty` means the thread was woken
+    /// without a message, which can also happen spuriously.
+    pub fn recv_or_wake(&mut self) -> Result<T, TryRecvError> {
+        self.attach();
+        match self.try_recv() {
+            Err(TryRecvError::Empty) => {
+                thread::park();
+                self.try_recv()
+            }
+            received => received,
+        }
+    }
+
+    // Make the current thread the one senders wake. Only takes the lock when
+    // the receiver has moved to a thread it hasn't waited on before.
+    fn attach(&mut self) {
+        let current = thread::current();
+        if self.attached == Some(current.id()) {
+            return;
+        }
+        self.attached = Some(current.id());
+        *self.waiter.lock().unwrap_or_else(PoisonError::into_inner) = Some(current);
+    }
+}
+
+/// Queue from a real-time thread to a thread of its own that hands each item
+/// to `deliver`, so whatever the item sets off, and dropping it, happens off
+/// the real-time thread. The delivery thread stops once the outbox is dropped
+/// and everything in it has been delivered.
+pub fn outbox<T: Send + 'static>(
+    name: &str,
+    capacity: usize,
+    mut deliver: impl FnMut(T) + Send + 'static,
+) -> Outbox<T> {
+    let (producer, mut consumer) = RingBuffer::new(capacity);
+    let delivery = thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || loop {
+            match consumer.pop() {
+                Ok(item) => deliver(item),
+                Err(_) if consumer.is_abandoned() => {
+                    while let Ok(item) = consumer.pop() {
+                        deliver(item);
+                    }
+                    return;
+                }
+                Err(_) => thread::park(),
+            }
+        })
+        .expect("Failed to spawn delivery thread");
+
+    Outbox {
+        producer: Some(producer),
+        reader: delivery.thread().clone(),
+    }
+}
+
+pub struct Outbox<T> {
+    // Only taken on drop, so the delivery thread can be woken to find it
+    // gone.
+    producer: Option<Producer<T>>,
+    reader: Thread,
+}
+
+impl<T> Outbox<T> {
+    /// Queue `item` for delivery without blocking. A full outbox hands the
+    /// item back; the caller decides whether losing it matters.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        let Some(producer) = self.producer.as_mut() else {
+            return Err(item);
+        };
+        let pushed = producer.push(item).map_err(|PushError::Full(item)| item);
+        self.reader.unpark();
+        pushed
+    }
+}
+
+impl<T> Drop for Outbox<T> {
+    fn drop(&mut self) {
+        drop(self.producer.take());
+        self.reader.unpark();
+    }
+}
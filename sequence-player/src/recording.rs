@@ -0,0 +1,131 @@
+use crate::midi_clock::ClockMessage;
+use crate::sequencer::StepHandler;
+use crate::server::sequence::{Note, ParameterLocks, Trig};
+use crate::timing::Clock;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+/// Something the sequencer asked a step handler to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recorded {
+    NoteOn {
+        track: u32,
+        step: u32,
+        note: Note,
+    },
+    NoteOff {
+        track: u32,
+        step: u32,
+        note: Note,
+    },
+    Parameters {
+        track: u32,
+        parameters: ParameterLocks,
+    },
+    Clock(ClockMessage),
+    AllNotesOff,
+}
+
+/// A recorded call, stamped with the clock's time when it was made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub time: Instant,
+    pub event: Recorded,
+}
+
+/// Step handler that keeps a timestamped log of everything it is asked to
+/// play instead of playing it, one entry per note. Clones share the log, so
+/// keep one to read it after handing the other to a sequencer.
+#[derive(Debug, Clone)]
+pub struct RecordingStepHandler<C> {
+    clock: C,
+    sends_clock: bool,
+    events: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl<C: Clock + Clone + Sync> RecordingStepHandler<C> {
+    /// Record with times read from `clock`, normally the clock the sequencer
+    /// runs on.
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            sends_clock: false,
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Like `new`, but also asks for and records MIDI clock.
+    pub fn with_midi_clock(clock: C) -> Self {
+        Self {
+            sends_clock: true,
+            ..Self::new(clock)
+        }
+    }
+
+    /// Everything recorded so far, oldest first.
+    pub fn events(&self) -> Vec<RecordedEvent> {
+        self.lock().clone()
+    }
+
+    /// Like `events`, emptying the log.
+    pub fn take_events(&self) -> Vec<RecordedEvent> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn record(&self, event: Recorded) {
+        let time = self.clock.now();
+        self.lock().push(RecordedEvent { time, event });
+    }
+
+    fn record_notes(&self, trigs: &[&Trig], event: fn(u32, u32, Note) -> Recorded) {
+        let time = self.clock.now();
+        let mut events = self.lock();
+        for trig in trigs {
+            events.extend(trig.notes().map(|note| RecordedEvent {
+                time,
+                event: event(trig.track, trig.step, note.clone()),
+            }));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<RecordedEvent>> {
+        self.events.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<C: Clock + Clone + Sync> StepHandler for RecordingStepHandler<C> {
+    fn handle_notes_on(&self, trigs: &[&Trig]) {
+        self.record_notes(trigs, |track, step, note| Recorded::NoteOn {
+            track,
+            step,
+            note,
+        });
+    }
+
+    fn handle_notes_off(&self, trigs: &[&Trig]) {
+        self.record_notes(trigs, |track, step, note| Recorded::NoteOff {
+            track,
+            step,
+            note,
+        });
+    }
+
+    fn handle_parameters(&self, track: u32, parameters: &ParameterLocks) {
+        self.record(Recorded::Parameters {
+            track,
+            parameters: parameters.clone(),
+        });
+    }
+
+    fn sends_clock(&self) -> bool {
+        self.sends_clock
+    }
+
+    fn handle_clock(&self, message: ClockMessage) {
+        self.record(Recorded::Clock(message));
+    }
+
+    fn handle_all_notes_off(&self) {
+        self.record(Recorded::AllNotesOff);
+    }
+}
@@ -0,0 +1,281 @@
+use crate::sampler::SampleBank;
+use crate::sequencer::{MidiSink, MidiStepHandler, Sequencer, SequencerError, StepHandler};
+use crate::server::sequence::Sequence;
+use crate::smf::{self, SmfTrack, TrackEvent};
+use crate::synth::{Synth, SynthEvent, SynthSink, SynthStepHandler, SAMPLE_RATE};
+use crate::timing::{playback_bpm, Clock, StepDuration, VirtualClock};
+use crate::tracks::{master_length, tracks};
+use crate::validation::{check_sequence, check_synth_sequence, InvalidSequence};
+use std::collections::BTreeMap;
+use std::io::{Cursor, Seek, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Longest stretch of a sequence rendered at once.
+pub const MAX_RENDER_LENGTH: Duration = Duration::from_secs(60 * 60);
+
+/// How much of a sequence to render.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Times through the sequence's master length.
+    pub loops: u32,
+    /// Seed probability conditions draw from, so renders can be compared.
+    pub seed: u64,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self { loops: 1, seed: 0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    InvalidSequence(InvalidSequence),
+    NoLoops,
+    TooLong(Duration),
+    TooManyTracks(usize),
+    Sequencer(SequencerError),
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RenderError::InvalidSequence(InvalidSequence(violations)) => {
+                write!(f, "Sequence has {} invalid fields", violations.len())?;
+                for violation in violations {
+                    write!(f, "\n  {}", violation)?;
+                }
+                Ok(())
+            }
+            RenderError::NoLoops => write!(f, "At least one loop must be rendered"),
+            RenderError::TooLong(length) => write!(
+                f,
+                "Render would last {:?}, longer than the limit of {:?}",
+                length, MAX_RENDER_LENGTH
+            ),
+            RenderError::TooManyTracks(count) => write!(
+                f,
+                "Sequence has {} tracks, more than a MIDI file can hold",
+                count
+            ),
+            RenderError::Sequencer(error) => write!(f, "Sequencer error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+impl From<InvalidSequence> for RenderError {
+    fn from(error: InvalidSequence) -> Self {
+        RenderError::InvalidSequence(error)
+    }
+}
+
+impl From<SequencerError> for RenderError {
+    fn from(error: SequencerError) -> Self {
+        RenderError::Sequencer(error)
+    }
+}
+
+/// Play `sequence` from the top through `step_handler`, as fast as the
+/// handler keeps up, with the sequencer's own playback thread on `clock`.
+/// Playback starts at the clock's current time and stops just before the
+/// first step after the last loop, cutting off whatever still sounds.
+/// Returns the length played.
+pub fn play_offline<T: StepHandler>(
+    sequence: Sequence,
+    options: &RenderOptions,
+    clock: Arc<VirtualClock>,
+    step_handler: T,
+) -> Result<Duration, RenderError> {
+    if options.loops == 0 {
+        return Err(RenderError::NoLoops);
+    }
+    check_sequence(&sequence)?;
+    let steps = master_length(&sequence) as u64 * options.loops as u64;
+    let length = StepDuration::from_sequence(&sequence).steps(steps);
+    if length > MAX_RENDER_LENGTH {
+        return Err(RenderError::TooLong(length));
+    }
+
+    let sequencer = Sequencer::with_clock(step_handler, Arc::clone(&clock));
+    sequencer.set_random_seed(options.seed);
+    sequencer.cue_sequence(sequence)?;
+    clock.settle();
+    let start = clock.now();
+    sequencer.start_sequence()?;
+    clock.advance_to(start + length - Duration::from_nanos(1));
+    sequencer.stop_sequence()?;
+    clock.settle();
+    Ok(length)
+}
+
+/// Render `sequence` to a Type-1 Standard MIDI File: a tempo track, then one
+/// track per sequence track with the notes and parameters it would send,
+/// on its MIDI channel.
+pub fn render_midi(sequence: Sequence, options: &RenderOptions) -> Result<Vec<u8>, RenderError> {
+    let bpm = playback_bpm(sequence.bpm);
+    let track_numbers: Vec<u32> = tracks(&sequence)
+        .iter()
+        .map(|settings| settings.track)
+        .collect();
+    if track_numbers.len() >= u16::MAX as usize {
+        return Err(RenderError::TooManyTracks(track_numbers.len()));
+    }
+
+    let clock = Arc::new(VirtualClock::new());
+    let sink = TrackMessages::new(Arc::clone(&clock));
+    let messages = Arc::clone(&sink.messages);
+    let start = clock.now();
+    let length = play_offline(sequence, options, clock, MidiStepHandler::new(sink))?;
+
+    let end_tick = smf::ticks(length, bpm);
+    let mut events: BTreeMap<u32, Vec<TrackEvent>> = track_numbers
+        .into_iter()
+        .map(|track| (track, Vec::new()))
+        .collect();
+    let messages = std::mem::take(&mut *messages.lock().unwrap_or_else(PoisonError::into_inner));
+    for (track, time, message) in messages {
+        events.entry(track).or_default().push(TrackEvent {
+            tick: smf::ticks(time.saturating_duration_since(start), bpm).min(end_tick),
+            message,
+        });
+    }
+    let tracks: Vec<SmfTrack> = events
+        .into_iter()
+        .map(|(track, events)| SmfTrack {
+            name: format!("Track {}", track),
+            events,
+        })
+        .collect();
+    Ok(smf::write_type1(bpm, &tracks, end_tick))
+}
+
+/// Render `sequence` on the built-in synth to a 16-bit mono WAV file at
+/// `SAMPLE_RATE`. The file is exactly as long as the loops played, so it
+/// loops cleanly, and notes still sounding at the end are cut off there.
+/// Sequences on tracks the synth doesn't play are turned away.
+pub fn render_wav(sequence: Sequence, options: &RenderOptions) -> Result<Vec<u8>, RenderError> {
+    render_wav_with_samples(sequence, options, SampleBank::default())
+}
+
+/// Like `render_wav`, but tracks play the samples `samples` assigns them.
+pub fn render_wav_with_samples(
+    sequence: Sequence,
+    options: &RenderOptions,
+    samples: SampleBank,
+) -> Result<Vec<u8>, RenderError> {
+    check_synth_sequence(&sequence)?;
+    let clock = Arc::new(VirtualClock::new());
+    let sink = SynthEvents::new(Arc::clone(&clock));
+    let events = Arc::clone(&sink.events);
+    let start = clock.now();
+    let step_handler = SynthStepHandler::with_samples(sink, samples);
+    let length = play_offline(sequence, options, clock, step_handler)?;
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut bytes = Cursor::new(Vec::new());
+    let mut file =
+        hound::WavWriter::new(&mut bytes, spec).expect("Writing a WAV header to memory can't fail");
+    let mut synth = Synth::new(SAMPLE_RATE);
+    let mut rendered = 0;
+    let end = sample_index(length);
+    let events = std::mem::take(&mut *events.lock().unwrap_or_else(PoisonError::into_inner));
+    for (time, event) in events {
+        let at = sample_index(time.saturating_duration_since(start)).min(end);
+        write_samples(&mut synth, at - rendered, &mut file);
+        rendered = at;
+        synth.handle(event);
+    }
+    write_samples(&mut synth, end - rendered, &mut file);
+    file.finalize()
+        .expect("Writing a WAV file to memory can't fail");
+    Ok(bytes.into_inner())
+}
+
+// Sample `elapsed` falls on, to the nearest.
+fn sample_index(elapsed: Duration) -> u64 {
+    let rate = SAMPLE_RATE as u128;
+    ((elapsed.as_nanos() * rate + 500_000_000) / 1_000_000_000) as u64
+}
+
+// Render `count` samples into `file` a block at a time.
+fn write_samples<W: Write + Seek>(synth: &mut Synth, count: u64, file: &mut hound::WavWriter<W>) {
+    let mut block = [0.0; 1024];
+    let mut left = count;
+    while left > 0 {
+        let size = left.min(block.len() as u64) as usize;
+        synth.render(&mut block[..size]);
+        for sample in &block[..size] {
+            file.write_sample((sample * i16::MAX as f32) as i16)
+                .expect("Writing a WAV file to memory can't fail");
+        }
+        left -= size as u64;
+    }
+}
+
+// A message of a track, with the time it was sent.
+type TrackMessage = (u32, Instant, Vec<u8>);
+
+/// MIDI sink that keeps each track's messages with the time they were sent.
+/// Messages that belong to no track, such as the All Notes Off sweep, have
+/// no place in a rendered file and are dropped.
+struct TrackMessages {
+    clock: Arc<VirtualClock>,
+    messages: Arc<Mutex<Vec<TrackMessage>>>,
+}
+
+impl TrackMessages {
+    fn new(clock: Arc<VirtualClock>) -> Self {
+        Self {
+            clock,
+            messages: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl MidiSink for TrackMessages {
+    fn send(&mut self, _message: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn send_for_track(&mut self, track: u32, message: &[u8]) -> Result<(), String> {
+        let time = self.clock.now();
+        self.messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((track, time, message.to_vec()));
+        Ok(())
+    }
+}
+
+/// Synth sink that keeps each event with the time it was sent.
+struct SynthEvents {
+    clock: Arc<VirtualClock>,
+    events: Arc<Mutex<Vec<(Instant, SynthEvent)>>>,
+}
+
+impl SynthEvents {
+    fn new(clock: Arc<VirtualClock>) -> Self {
+        Self {
+            clock,
+            events: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl SynthSink for SynthEvents {
+    fn send(&mut self, event: SynthEvent) {
+        let time = self.clock.now();
+        self.events
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((time, event));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::ports::{OutputPorts, PortSelector, SelectedOutput, SystemPorts};
+use crate::ports::{PortError, RECONNECT_POLL};
+use crate::sequencer::MidiSink;
+use crate::server::sequence::MidiRoute;
+use crate::tracks::MAX_MIDI_CHANNEL;
+use arc_swap::ArcSwap;
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Where the routing table is kept between runs unless told otherwise.
+pub const DEFAULT_ROUTES_FILE: &str = "midi-routes.conf";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoutingError {
+    InvalidChannel { track: u32, channel: u32 },
+    DuplicateTrack(u32),
+    // A line of the routes file that doesn't read as a route.
+    Malformed { line: usize, text: String },
+    Io(String),
+}
+
+impl std::fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingError::InvalidChannel { track, channel } => write!(
+                f,
+                "Track {} is routed to channel {}, which must be between 1 and 16",
+                track, channel
+            ),
+            RoutingError::DuplicateTrack(track) => {
+                write!(f, "Track {} is routed more than once", track)
+            }
+            RoutingError::Malformed { line, text } => {
+                write!(f, "Can't read route on line {}: {}", line, text)
+            }
+            RoutingError::Io(reason) => write!(f, "Can't access routes file: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+pub fn validate_routes(routes: &[MidiRoute]) -> Result<(), RoutingError> {
+    let mut tracks = HashSet::new();
+    for route in routes {
+        if route.channel > MAX_MIDI_CHANNEL {
+            return Err(RoutingError::InvalidChannel {
+                track: route.track,
+                channel: route.channel,
+            });
+        }
+        if !tracks.insert(route.track) {
+            return Err(RoutingError::DuplicateTrack(route.track));
+        }
+    }
+    Ok(())
+}
+
+/// Read routes written by `format_routes`: one per line as track, channel
+/// and port, with the port taking the rest of the line so names can contain
+/// spaces. Blank lines and lines starting with `#` are skipped.
+pub fn parse_routes(text: &str) -> Result<Vec<MidiRoute>, RoutingError> {
+    let mut routes = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let malformed = || RoutingError::Malformed {
+            line: index + 1,
+            text: line.to_string(),
+        };
+        let (track, rest) = line.split_once(char::is_whitespace).ok_or_else(malformed)?;
+        let rest = rest.trim_start();
+        let (channel, port) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        routes.push(MidiRoute {
+            track: track.parse().map_err(|_| malformed())?,
+            channel: channel.parse().map_err(|_| malformed())?,
+            port: port.trim().to_string(),
+        });
+    }
+    validate_routes(&routes)?;
+    Ok(routes)
+}
+
+pub fn format_routes(routes: &[MidiRoute]) -> String {
+    let mut text = String::from(
+        "# track  channel (0 keeps the sequence's)  port (empty for the main output)\n",
+    );
+    for route in routes {
+        text.push_str(&format!(
+            "{} {} {}\n",
+            route.track, route.channel, route.port
+        ));
+    }
+    text
+}
+
+// What the playback thread sends through. It is never changed in place, only
+// swapped for a new one, so sending takes no lock but the port's own.
+struct RouterOutputs<P: OutputPorts> {
+    routes: BTreeMap<u32, MidiRoute>,
+    // One output per port pattern in use, shared by the tracks routed to it.
+    by_pattern: BTreeMap<String, SelectedOutput<P>>,
+    // The main output, then one output per further port, for messages that
+    // belong to no track. Outputs still waiting for their port are kept in
+    // until they connect to one already there.
+    broadcast: Vec<SelectedOutput<P>>,
+    // Port each output had when `broadcast` was worked out, main first.
+    connected: Vec<Option<String>>,
+}
+
+impl<P: OutputPorts> RouterOutputs<P> {
+    fn new(
+        main: &SelectedOutput<P>,
+        routes: BTreeMap<u32, MidiRoute>,
+        by_pattern: BTreeMap<String, SelectedOutput<P>>,
+    ) -> Self {
+        let all: Vec<&SelectedOutput<P>> =
+            std::iter::once(main).chain(by_pattern.values()).collect();
+        let connected: Vec<Option<String>> = all.iter().map(|output| output.port_name()).collect();
+        // Patterns that find the same port share its messages.
+        let mut ports = HashSet::new();
+        let broadcast = all
+            .into_iter()
+            .zip(&connected)
+            .filter(|(_, port)| port.as_ref().is_none_or(|port| ports.insert(port)))
+            .map(|(output, _)| output.clone())
+            .collect();
+        Self {
+            routes,
+            by_pattern,
+            broadcast,
+            connected,
+        }
+    }
+}
+
+/// Sends each track to the output port and channel its route names, and
+/// tracks without a route to the main output on their own channel. Clock
+/// and other messages that belong to no track go to every port once.
+pub struct MidiRouter<P: OutputPorts + Clone = SystemPorts> {
+    ports: P,
+    main: SelectedOutput<P>,
+    outputs: Arc<ArcSwap<RouterOutputs<P>>>,
+    // Held while the outputs are being replaced, so changes don't race.
+    changing: Arc<Mutex<()>>,
+    // File the routes are saved to whenever they change.
+    config: Option<PathBuf>,
+}
+
+impl<P: OutputPorts + Clone> Clone for MidiRouter<P> {
+    fn clone(&self) -> Self {
+        Self {
+            ports: self.ports.clone(),
+            main: self.main.clone(),
+            outputs: Arc::clone(&self.outputs),
+            changing: Arc::clone(&self.changing),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<P: OutputPorts + Clone> std::fmt::Debug for MidiRouter<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MidiRouter")
+            .field("main", &self.main)
+            .field("routes", &self.routes().len())
+            .finish()
+    }
+}
+
+impl<P: OutputPorts + Clone> MidiRouter<P> {
+    /// Route through `main` and any further outputs `ports` has, starting
+    /// from the routes saved in `config` if it exists.
+    pub fn new(
+        ports: P,
+        main: SelectedOutput<P>,
+        config: Option<PathBuf>,
+    ) -> Result<Self, RoutingError> {
+        let outputs = RouterOutputs::new(&main, BTreeMap::new(), BTreeMap::new());
+        let router = Self {
+            ports,
+            main,
+            outputs: Arc::new(ArcSwap::from_pointee(outputs)),
+            changing: Arc::new(Mutex::new(())),
+            config,
+        };
+        if let Some(path) = router.config.as_ref().filter(|path| path.exists()) {
+            let text = std::fs::read_to_string(path)
+                .map_err(|error| RoutingError::Io(error.to_string()))?;
+            let routes = parse_routes(&text)?;
+            println!(
+                "🔀 Loaded {} MIDI routes from {}",
+                routes.len(),
+                path.display()
+            );
+            router.apply(routes);
+        }
+        Ok(router)
+    }
+
+    pub fn main_output(&self) -> &SelectedOutput<P> {
+        &self.main
+    }
+
+    /// Point the main output at the port `selector` picks.
+    pub fn select_main(&self, selector: PortSelector) -> Result<String, PortError> {
+        let _changing = self.changing.lock().unwrap();
+        let name = self.main.select(selector)?;
+        self.refresh();
+        Ok(name)
+    }
+
+    pub fn routes(&self) -> Vec<MidiRoute> {
+        self.outputs.load().routes.values().cloned().collect()
+    }
+
+    /// Replace the whole routing table and save it. Ports that aren't there
+    /// yet are connected as soon as they appear.
+    pub fn set_routes(&self, routes: Vec<MidiRoute>) -> Result<Vec<MidiRoute>, RoutingError> {
+        validate_routes(&routes)?;
+        if let Some(path) = &self.config {
+            std::fs::write(path, format_routes(&routes))
+                .map_err(|error| RoutingError::Io(error.to_string()))?;
+        }
+        self.apply(routes);
+        Ok(self.routes())
+    }
+
+    fn apply(&self, routes: Vec<MidiRoute>) {
+        let _changing = self.changing.lock().unwrap();
+        let current = self.outputs.load();
+        let mut by_pattern = BTreeMap::new();
+        for route in &routes {
+            if route.port.is_empty() || by_pattern.contains_key(&route.port) {
+                continue;
+            }
+            let output = current
+                .by_pattern
+                .get(&route.port)
+                .cloned()
+                .unwrap_or_else(|| {
+                    let (output, connected) =
+                        SelectedOutput::open(self.ports.clone(), PortSelector::new(&route.port));
+                    if let Err(error) = connected {
+                        println!("⚠️ {}, waiting for it", error);
+                    }
+                    output
+                });
+            by_pattern.insert(route.port.clone(), output);
+        }
+        let routes: BTreeMap<u32, MidiRoute> = routes
+            .into_iter()
+            .map(|route| (route.track, route))
+            .collect();
+        println!("🔀 MIDI routes: {}", routes.len());
+        self.outputs
+            .store(Arc::new(RouterOutputs::new(&self.main, routes, by_pattern)));
+    }
+
+    /// Reconnect every output whose device came or went, then work out
+    /// again which of them share a port.
+    pub fn reconnect(&self) {
+        let _changing = self.changing.lock().unwrap();
+        let current = self.outputs.load();
+        for output in std::iter::once(&self.main).chain(current.by_pattern.values()) {
+            // Not finding the port is the normal state while it's unplugged.
+            if let Err(error @ PortError::ConnectFailed { .. }) = output.reconnect() {
+                println!("❌ {}", error);
+            }
+        }
+        self.refresh();
+    }
+
+    // Swap in outputs for the ports connected now, unless nothing changed.
+    fn refresh(&self) {
+        let current = self.outputs.load();
+        let outputs = RouterOutputs::new(
+            &self.main,
+            current.routes.clone(),
+            current.by_pattern.clone(),
+        );
+        if outputs.connected != current.connected {
+            self.outputs.store(Arc::new(outputs));
+        }
+    }
+
+    /// Call `reconnect` every `RECONNECT_POLL` for as long as any clone of
+    /// this router is around.
+    pub fn watch(&self) -> thread::JoinHandle<()> {
+        let outputs = Arc::downgrade(&self.outputs);
+        let (ports, main, changing) = (
+            self.ports.clone(),
+            self.main.clone(),
+            Arc::clone(&self.changing),
+        );
+        thread::spawn(move || loop {
+            thread::sleep(RECONNECT_POLL);
+            let Some(outputs) = outputs.upgrade() else {
+                return;
+            };
+            let router = MidiRouter {
+                ports: ports.clone(),
+                main: main.clone(),
+                outputs,
+                changing: Arc::clone(&changing),
+                config: None,
+            };
+            router.reconnect();
+        })
+    }
+}
+
+impl<P: OutputPorts + Clone> MidiSink for MidiRouter<P> {
+    /// Send `message` to every port. A port that fails doesn't keep it from
+    /// the others; the first failure is returned once all were tried.
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        let outputs = self.outputs.load();
+        let mut result = Ok(());
+        for output in &outputs.broadcast {
+            let sent = output.send_message(message);
+            if result.is_ok() {
+                result = sent;
+            }
+        }
+        result
+    }
+
+    fn send_for_track(&mut self, track: u32, message: &[u8]) -> Result<(), String> {
+        let outputs = self.outputs.load();
+        let output = outputs
+            .routes
+            .get(&track)
+            .and_then(|route| outputs.by_pattern.get(&route.port))
+            .unwrap_or(&self.main);
+        output.send_message(message)
+    }
+
+    fn track_channel(&self, track: u32) -> Option<u8> {
+        self.outputs
+            .load()
+            .routes
+            .get(&track)
+            .filter(|route| route.channel > 0)
+            .map(|route| (route.channel - 1) as u8)
+    }
+}
@@ -0,0 +1,182 @@
+use crate::synth::SYNTH_TRACKS;
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+// Key a sample plays at its own pitch unless told otherwise, middle C.
+pub const DEFAULT_ROOT_KEY: u8 = 60;
+
+/// A sound as it was recorded, mixed down to mono.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub sample_rate: u32,
+    pub frames: Vec<f32>,
+}
+
+impl Sample {
+    /// Read a WAV file of integer or float samples at any bit depth hound
+    /// understands. Channels are averaged.
+    pub fn from_wav(bytes: &[u8]) -> Result<Sample, SampleError> {
+        let reader = hound::WavReader::new(bytes).map_err(wav_error)?;
+        let spec = reader.spec();
+        let values: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .into_samples::<f32>()
+                .collect::<Result<_, _>>()
+                .map_err(wav_error)?,
+            hound::SampleFormat::Int => {
+                let full_scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .map(|value| value.map(|value| value as f32 / full_scale))
+                    .collect::<Result<_, _>>()
+                    .map_err(wav_error)?
+            }
+        };
+        let channels = spec.channels.max(1) as usize;
+        let frames: Vec<f32> = values
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        if frames.is_empty() {
+            return Err(SampleError::Empty);
+        }
+        Ok(Sample {
+            sample_rate: spec.sample_rate,
+            frames,
+        })
+    }
+}
+
+fn wav_error(error: hound::Error) -> SampleError {
+    SampleError::Wav(error.to_string())
+}
+
+/// How a track plays its sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleSettings {
+    /// MIDI note that plays the sample at its own pitch; notes above and
+    /// below play it faster and slower.
+    pub root_key: u8,
+    /// Where playback starts, as a fraction of the sample from 0 to 1.
+    pub start: f32,
+    /// Fraction of the sample played from the start, up to its end.
+    pub length: f32,
+    /// Repeat the played part for as long as the note is held, instead of
+    /// playing it through once whatever the note length.
+    pub looping: bool,
+}
+
+impl Default for SampleSettings {
+    fn default() -> Self {
+        Self {
+            root_key: DEFAULT_ROOT_KEY,
+            start: 0.0,
+            length: 1.0,
+            looping: false,
+        }
+    }
+}
+
+/// A sample with the settings of the track it is assigned to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackSample {
+    pub sample: Sample,
+    pub settings: SampleSettings,
+}
+
+impl TrackSample {
+    pub fn new(sample: Sample, settings: SampleSettings) -> Result<TrackSample, SampleError> {
+        if settings.root_key > 127 {
+            return Err(SampleError::InvalidRootKey(settings.root_key as u32));
+        }
+        let SampleSettings { start, length, .. } = settings;
+        let region_is_valid = (0.0..1.0).contains(&start) && length > 0.0 && length <= 1.0;
+        if !region_is_valid {
+            return Err(SampleError::InvalidRegion { start, length });
+        }
+        Ok(TrackSample { sample, settings })
+    }
+
+    /// First and one past the last frame played, never empty.
+    pub fn region(&self) -> (usize, usize) {
+        let frames = self.sample.frames.len();
+        let start = ((self.settings.start * frames as f32) as usize).min(frames - 1);
+        let length = (self.settings.length * frames as f32).round() as usize;
+        (start, (start + length.max(1)).min(frames))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SampleError {
+    Wav(String),
+    Empty,
+    InvalidRootKey(u32),
+    InvalidRegion { start: f32, length: f32 },
+    NoSynthTrack(u32),
+}
+
+impl std::fmt::Display for SampleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SampleError::Wav(error) => write!(f, "Not a readable WAV file: {}", error),
+            SampleError::Empty => write!(f, "WAV file holds no samples"),
+            SampleError::InvalidRootKey(key) => {
+                write!(f, "Root key {} must be a MIDI note from 0 to 127", key)
+            }
+            SampleError::InvalidRegion { start, length } => write!(
+                f,
+                "Sample start {} must be from 0 up to 1, and length {} above 0 up to 1",
+                start, length
+            ),
+            SampleError::NoSynthTrack(track) => write!(
+                f,
+                "Track {} must be below {} to play a sample",
+                track, SYNTH_TRACKS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SampleError {}
+
+/// Samples assigned to tracks, shared between whoever loads them and the
+/// step handler playing them. Clones share the same assignments. Every change
+/// publishes a new set of assignments whole, so looking a track up from the
+/// playback thread never waits for a sample being loaded.
+#[derive(Debug, Clone, Default)]
+pub struct SampleBank {
+    samples: Arc<ArcSwap<HashMap<u32, Arc<TrackSample>>>>,
+    // Held while the assignments are being replaced, so changes don't race.
+    changing: Arc<Mutex<()>>,
+}
+
+impl SampleBank {
+    /// Play `sample` on `track` from its next note, in place of whatever it
+    /// played before.
+    pub fn assign(&self, track: u32, sample: TrackSample) {
+        let sample = Arc::new(sample);
+        self.change(|samples| {
+            samples.insert(track, sample);
+        });
+    }
+
+    /// Put `track` back on the synth. Returns whether it had a sample.
+    pub fn clear(&self, track: u32) -> bool {
+        let mut cleared = false;
+        self.change(|samples| cleared = samples.remove(&track).is_some());
+        cleared
+    }
+
+    pub fn get(&self, track: u32) -> Option<Arc<TrackSample>> {
+        self.samples.load().get(&track).cloned()
+    }
+
+    // Apply `change` to a copy of the assignments and publish it.
+    fn change(&self, change: impl FnOnce(&mut HashMap<u32, Arc<TrackSample>>)) {
+        let _changing = self.changing.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut samples = HashMap::clone(&self.samples.load());
+        change(&mut samples);
+        self.samples.store(Arc::new(samples));
+    }
+}
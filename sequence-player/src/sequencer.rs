@@ -1,39 +1,551 @@
+use crate::conditions::{ConditionEvaluator, LastResults};
+use crate::event_table::{EventTable, TrigEvent};
+use crate::locks::{locks_are_empty, restore_values_into, trig_locks, LOCKABLE_CONTROLLERS};
+use crate::midi_clock::{song_position, step_at_song_position, ClockMessage};
+use crate::realtime::{self, Outbox};
+use crate::rt_println;
 use crate::server::sequence::Note as SequenceNote;
-use crate::server::sequence::{Sequence, Trig};
+use crate::server::sequence::{
+    ParameterLocks, Sequence, TransportEvent, TransportEventKind, Trig, VoiceMode,
+};
+use crate::timing::{Clock, StepDuration, StepTimeline, SystemClock};
+use crate::tracks::{track_config, voice_mode, TrackSettings};
+use crate::types::{MiddleC, Pitch};
+use crate::validation::validate_sequence;
 use midir::MidiOutputConnection;
-use spin_sleep::LoopHelper;
-use std::collections::HashMap;
-use std::sync::{mpsc, Arc, Mutex};
+use smallvec::SmallVec;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError, TryLockError};
 use std::thread;
-use std::time::{Duration, Instant};
-use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// Transport events buffered per subscriber before slow watchers start lagging.
+const TRANSPORT_EVENT_CAPACITY: usize = 256;
+
+// Commands waiting for the playback thread before callers are turned away.
+const COMMAND_CAPACITY: usize = 64;
+
+// Events the playback thread can get ahead of their delivery.
+const PLAYBACK_EVENT_CAPACITY: usize = 1024;
+
+// Notes and off-grid trigs the playback thread can have queued before its
+// queues have to grow.
+const SCHEDULE_CAPACITY: usize = 256;
+
+// Trigs starting or ending together that are listed on the stack.
+const INLINE_TRIGS: usize = 32;
+
+// Trigs handed to the step handler at once that are listed on the stack. A
+// chord played in part is handed over a note at a time.
+const INLINE_NOTES: usize = 128;
+
+type TrigList = SmallVec<[Sounding; INLINE_TRIGS]>;
+
+// Trigs starting together, each with the step length of its track.
+type FiringTrigs = SmallVec<[(Sounding, StepDuration); INLINE_TRIGS]>;
+
+// Trigs handed to the step handler together.
+type TrigRefs<'a> = SmallVec<[&'a Trig; INLINE_NOTES]>;
 
 // General sequencer data structure definition.
 pub trait StepHandler: Send + Sync + 'static {
-    fn handle_notes_on(&self, trigs: Vec<&Trig>);
-    fn handle_notes_off(&self, trigs: Vec<&Trig>);
+    /// Trigs arrive as a borrowed list, so the playback thread can hand them
+    /// over without allocating.
+    fn handle_notes_on(&self, trigs: &[&Trig]);
+    fn handle_notes_off(&self, trigs: &[&Trig]);
+
+    /// Called on the playback thread whenever a different sequence starts
+    /// playing, before any of its notes. The sequence comes compiled, and a
+    /// handler can hold on to it for what it needs to know while it plays.
+    fn handle_sequence_change(&self, _table: &Arc<EventTable>) {}
+
+    /// Send parameter values for a track: a trig's locks just before its
+    /// note-on, or the track defaults when a lock ends.
+    fn handle_parameters(&self, _track: u32, _parameters: &ParameterLocks) {}
+
+    /// Whether the handler drives clock followers. Clock pulses are only
+    /// scheduled for handlers that do.
+    fn sends_clock(&self) -> bool {
+        false
+    }
+
+    /// Send a clock pulse or transport message to clock followers. Pulses fall
+    /// on the same timeline as the steps, 24 to the quarter note.
+    fn handle_clock(&self, _message: ClockMessage) {}
+
+    /// Silence everything the handler may have left sounding, whether or not
+    /// the sequencer still knows about it. Called on the playback thread after
+    /// the pending note-offs on stop, swap and shutdown.
+    fn handle_all_notes_off(&self) {}
+
+    /// `handle_all_notes_off` from outside the playback thread, on panic and
+    /// after a playback crash. Handlers that never wait on the playback
+    /// thread can wait here, so the silence isn't skipped.
+    fn handle_panic(&self) {
+        self.handle_all_notes_off();
+    }
 }
 
-#[derive(Debug)]
 pub struct Sequencer {
     state: Arc<Mutex<SequencerState>>,
-    playback_control: mpsc::Sender<PlaybackCommand>,
+    status: Arc<PlaybackStatus>,
+    playback_control: realtime::Sender<PlaybackCommand>,
+    transport_events: broadcast::Sender<TransportEvent>,
+    // Tempo every sequence plays at when the clock follows an external master.
+    fixed_tempo: Option<u32>,
+    // Shared with the playback thread, so a panic can silence it directly.
+    step_handler: Arc<dyn StepHandler>,
 }
 
+impl std::fmt::Debug for Sequencer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sequencer")
+            .field("state", &self.state)
+            .field("status", &self.status)
+            .field("fixed_tempo", &self.fixed_tempo)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The sequences as the control side knows them. API calls and the event
+/// delivery thread share it; the playback thread never touches it.
 #[derive(Debug, Default)]
 struct SequencerState {
-    current_sequence: Option<Sequence>,
-    cued_sequence: Option<Sequence>,
-    playing: bool,
-    current_step: u32,
+    current_sequence: Option<Arc<EventTable>>,
+    cued_sequence: Option<Arc<EventTable>>,
+    // Identifiers handed out to cued/swapped sequences so transport watchers
+    // can tell which sequence a step belongs to.
+    last_sequence_id: u64,
+    // Seed for probability conditions, applied on every start. Unset draws a
+    // fresh seed per run.
+    random_seed: Option<u64>,
+}
+
+impl SequencerState {
+    fn next_sequence_id(&mut self) -> u64 {
+        self.last_sequence_id += 1;
+        self.last_sequence_id
+    }
+}
+
+/// Where the transport is. Written by the playback thread and read from
+/// anywhere without holding it up.
+#[derive(Debug, Default)]
+struct PlaybackStatus {
+    playing: AtomicBool,
+    current_step: AtomicU32,
+    sequence_id: AtomicU64,
+}
+
+impl PlaybackStatus {
+    fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    fn current_step(&self) -> u32 {
+        self.current_step.load(Ordering::Relaxed)
+    }
+
+    fn sequence_id(&self) -> u64 {
+        self.sequence_id.load(Ordering::Relaxed)
+    }
+}
+
+/// What the playback thread reports back. Delivered on a thread of its own,
+/// which also frees whatever the playback thread lets go of.
+enum PlaybackEvent {
+    Transport(TransportEvent),
+    // A different sequence is current, after a start, swap or cue promotion.
+    Current(Arc<EventTable>),
+    Retired(ActivePattern),
+}
+
+/// The playback thread's ends of its queues, and its clock. The mutex around
+/// them is only there so a replacement thread can take them over after a
+/// crash; the running thread holds it throughout.
+struct PlaybackIo<C> {
+    commands: realtime::Receiver<PlaybackCommand>,
+    events: Outbox<PlaybackEvent>,
+    clock: C,
+}
+
+/// Items waiting for their time, earliest first. Items due at the same
+/// instant keep the order they were queued in. The buffer is reserved up
+/// front, so queueing only allocates once it is outgrown.
+#[derive(Debug)]
+struct TimedQueue<T> {
+    items: VecDeque<(Instant, T)>,
+}
+
+impl<T> TimedQueue<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        Self {
+            items: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn push(&mut self, due: Instant, item: T) {
+        let index = self.items.partition_point(|(queued, _)| *queued <= due);
+        self.items.insert(index, (due, item));
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.items.front().map(|(due, _)| *due)
+    }
+
+    /// Remove and return the earliest item if it is due at or before `now`.
+    fn pop_due(&mut self, now: Instant) -> Option<(Instant, T)> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.items.pop_front()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter().map(|(_, item)| item)
+    }
+
+    fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.items.retain(|(_, item)| keep(item));
+    }
+
+    fn retain_mut(&mut self, mut keep: impl FnMut(&mut T) -> bool) {
+        self.items.retain_mut(|(_, item)| keep(item));
+    }
+
+    fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.items.drain(..).map(|(_, item)| item)
+    }
+}
+
+/// The notes of a trig that play: all of them, less the ones in `silent`,
+/// which another trig took over or released early. Silencing notes shares
+/// the trig rather than copying it.
+#[derive(Debug, Clone)]
+struct Sounding {
+    event: Arc<TrigEvent>,
+    // Bits by index into the trig's notes. Voiced trigs have at most one note
+    // per MIDI pitch, so 128 bits are enough.
+    silent: u128,
+}
+
+impl Sounding {
+    fn new(event: &Arc<TrigEvent>) -> Self {
+        Self {
+            event: Arc::clone(event),
+            silent: 0,
+        }
+    }
+
+    fn playing(&self) -> impl Iterator<Item = (usize, &SequenceNote)> {
+        self.event
+            .notes()
+            .enumerate()
+            .filter(|(index, _)| note_bit(*index) & self.silent == 0)
+    }
+
+    fn is_rest(&self) -> bool {
+        self.playing().next().is_none()
+    }
+
+    /// Bits of the playing notes that `matches` picks out.
+    fn notes_where(&self, matches: impl Fn(&SequenceNote) -> bool) -> u128 {
+        self.playing()
+            .filter(|(_, note)| matches(note))
+            .fold(0, |bits, (index, _)| bits | note_bit(index))
+    }
+
+    fn plays_pitch(&self, note: &SequenceNote) -> bool {
+        self.playing()
+            .any(|(_, playing)| note_key(playing) == note_key(note))
+    }
+
+    /// The trigs to hand the step handler for the playing notes: the trig
+    /// itself when all of them play, otherwise one per note.
+    fn trigs<'a>(&'a self, trigs: &mut TrigRefs<'a>) {
+        if self.silent == 0 {
+            trigs.push(&self.event);
+        } else {
+            trigs.extend(self.playing().map(|(index, _)| self.event.voice(index)));
+        }
+    }
+}
+
+fn note_bit(index: usize) -> u128 {
+    1u128.checked_shl(index as u32).unwrap_or(0)
+}
+
+/// Note-offs waiting to be sent, kept in the order they fall due. Tied notes
+/// have no due time; they are released by the next trig on their track.
+#[derive(Debug)]
+struct NoteOffQueue {
+    timed: TimedQueue<Sounding>,
+    tied: Vec<Sounding>,
+    // Where the values undoing a lock are put together when its note ends.
+    restored: ParameterLocks,
+}
+
+impl Default for NoteOffQueue {
+    fn default() -> Self {
+        Self {
+            timed: TimedQueue::with_capacity(SCHEDULE_CAPACITY),
+            tied: Vec::with_capacity(SCHEDULE_CAPACITY),
+            restored: ParameterLocks {
+                control_changes: Vec::with_capacity(LOCKABLE_CONTROLLERS),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl NoteOffQueue {
+    fn schedule(&mut self, trig: &Sounding, note_on_time: Instant, step_duration: StepDuration) {
+        if trig.is_rest() {
+            return;
+        }
+
+        if trig_is_tied(&trig.event) {
+            self.tied.push(trig.clone());
+        } else {
+            let note_off_time = note_on_time + step_duration.span(trig.event.length as f64);
+            self.timed.push(note_off_time, trig.clone());
+        }
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.timed.next_due()
+    }
+
+    /// Move every timed note-off due at or before `current_time` to
+    /// `released`, earliest first.
+    fn take_due(&mut self, current_time: Instant, released: &mut TrigList) {
+        while let Some((_, trig)) = self.timed.pop_due(current_time) {
+            released.push(trig);
+        }
+    }
+
+    /// Whether a note with parameter locks is still sounding on `track`.
+    fn has_locked_note(&self, track: u32) -> bool {
+        self.timed
+            .iter()
+            .chain(&self.tied)
+            .any(|sounding| sounding.event.track == track && trig_locks(&sounding.event).is_some())
+    }
+
+    fn take_tied(&mut self, track: u32, released: &mut TrigList) {
+        self.tied
+            .retain_mut(|sounding| !take_notes(sounding, track, |_| true, released));
+    }
+
+    /// Move everything still sounding on `track` to `released`.
+    fn take_track(&mut self, track: u32, released: &mut TrigList) {
+        self.take_tied(track, released);
+        self.timed
+            .retain_mut(|sounding| !take_notes(sounding, track, |_| true, released));
+    }
+
+    /// Move the notes sounding on `trig`'s track at a pitch `trig` is about
+    /// to play to `released`. A sounding trig retriggered in part keeps the
+    /// rest of its notes, and its own note-off for them.
+    fn take_pitches(&mut self, trig: &Sounding, released: &mut TrigList) {
+        let track = trig.event.track;
+        let retriggered = |note: &SequenceNote| trig.plays_pitch(note);
+        self.timed
+            .retain_mut(|sounding| !take_notes(sounding, track, retriggered, released));
+        self.tied
+            .retain_mut(|sounding| !take_notes(sounding, track, retriggered, released));
+    }
+
+    /// Move every note still waiting for its note-off to `released`.
+    fn take_all(&mut self, released: &mut TrigList) {
+        released.extend(self.timed.drain());
+        released.extend(self.tied.drain(..));
+    }
+}
+
+// Move the notes of `sounding` on `track` that `matches` picks out to
+// `released`, for use in `retain_mut`. Returns whether none are left.
+fn take_notes(
+    sounding: &mut Sounding,
+    track: u32,
+    matches: impl Fn(&SequenceNote) -> bool,
+    released: &mut TrigList,
+) -> bool {
+    if sounding.event.track != track {
+        return false;
+    }
+    let ending = sounding.notes_where(matches);
+    if ending == 0 {
+        return false;
+    }
+    let remaining = sounding.notes_where(|_| true) & !ending;
+    released.push(Sounding {
+        event: Arc::clone(&sounding.event),
+        silent: sounding.silent | remaining,
+    });
+    sounding.silent |= ending;
+    remaining == 0
+}
+
+/// Position of one track within the sequence on the transport.
+#[derive(Debug)]
+struct TrackPlayhead {
+    settings: TrackSettings,
+    step: u32,
+    // Loops completed since the sequence started, for trig conditions.
+    iteration: u32,
+    timeline: StepTimeline,
+    // Whether the track has a step on the boundary being played.
+    due: bool,
+}
+
+impl TrackPlayhead {
+    fn plays(&self, trig: &Trig) -> bool {
+        trig.track == self.settings.track && trig.step == self.step
+    }
+
+    fn advance(&mut self) {
+        self.step = (self.step + 1) % self.settings.length;
+        if self.step == 0 {
+            self.iteration = self.iteration.wrapping_add(1);
+        }
+        self.timeline.advance();
+    }
+}
+
+/// A compiled sequence on the transport together with its playheads. The
+/// master playhead counts steps of the sequence subdivision up to the master
+/// length and decides when a cued sequence takes over, while every track
+/// loops over its own length at its own speed. Patterns are built off the
+/// playback thread, which only positions them.
+#[derive(Debug)]
+struct ActivePattern {
+    table: Arc<EventTable>,
+    master: StepTimeline,
+    master_step: u32,
+    tracks: Vec<TrackPlayhead>,
+    last_results: LastResults,
+}
+
+impl ActivePattern {
+    /// Playheads for every track of `table`, to be put on the transport by
+    /// `start_at` or `follow`.
+    fn new(table: Arc<EventTable>) -> Self {
+        let unplaced = Instant::now();
+        let tracks = table
+            .tracks()
+            .iter()
+            .map(|events| TrackPlayhead {
+                settings: events.settings,
+                step: 0,
+                iteration: 0,
+                timeline: StepTimeline::new(unplaced, events.settings.step_duration),
+                due: false,
+            })
+            .collect();
+
+        Self {
+            master: StepTimeline::new(unplaced, table.step_duration()),
+            master_step: 0,
+            tracks,
+            last_results: LastResults::new(
+                table.tracks().iter().map(|events| events.settings.track),
+            ),
+            table,
+        }
+    }
+
+    fn sequence(&self) -> &Sequence {
+        self.table.sequence()
+    }
+
+    fn sequence_id(&self) -> u64 {
+        self.table.sequence_id()
+    }
+
+    fn master_length(&self) -> u32 {
+        self.table.master_length()
+    }
+
+    /// Every playhead at master step `master_step`, with that step due at
+    /// `start`. Tracks pick up at the same step, wrapped to their own length.
+    fn start_at(&mut self, start: Instant, master_step: u32) {
+        self.master = StepTimeline::new(start, self.table.step_duration());
+        self.master_step = master_step % self.master_length();
+        for playhead in &mut self.tracks {
+            playhead.step = self.master_step % playhead.settings.length;
+            playhead.iteration = 0;
+            playhead.timeline = StepTimeline::new(start, playhead.settings.step_duration);
+        }
+    }
+
+    /// Take over the position of `current` without restarting it. Playheads
+    /// keep their position where the new lengths allow; tracks new to the
+    /// sequence join on the next master step, lined up with the master.
+    /// Condition outcomes carry over as well.
+    fn follow(&mut self, current: &ActivePattern) {
+        let mut master = current.master;
+        master.retime(self.table.step_duration());
+        let master_step = if current.master_step >= self.master_length() {
+            0
+        } else {
+            current.master_step
+        };
+
+        for playhead in &mut self.tracks {
+            let existing = current
+                .tracks
+                .iter()
+                .find(|existing| existing.settings.track == playhead.settings.track);
+            let (step, iteration, timeline) = match existing {
+                Some(existing) => {
+                    let mut timeline = existing.timeline;
+                    timeline.retime(playhead.settings.step_duration);
+                    (existing.step, existing.iteration, timeline)
+                }
+                None => (
+                    master_step,
+                    0,
+                    StepTimeline::new(master.next_deadline(), playhead.settings.step_duration),
+                ),
+            };
+            playhead.step = step % playhead.settings.length;
+            playhead.iteration = iteration;
+            playhead.timeline = timeline;
+        }
+        self.master = master;
+        self.master_step = master_step;
+        self.last_results.carry_over(&current.last_results);
+    }
+
+    /// The next instant at which any playhead reaches a step.
+    fn next_deadline(&self) -> Instant {
+        self.tracks
+            .iter()
+            .map(|playhead| playhead.timeline.next_deadline())
+            .fold(self.master.next_deadline(), Instant::min)
+    }
 }
 
-// Public interface for performing actions upon the sequencer.
+// Public interface for performing actions upon the sequencer. Sequences
+// arrive compiled, with their playheads allocated.
 #[derive(Debug)]
 enum PlaybackCommand {
-    Start(Sequence),
+    // Play from the top, drawing probabilities from the seed if one is set.
+    Start(ActivePattern, Option<u64>),
+    // Resume a stopped sequence from a master step.
+    Continue(ActivePattern, u32),
+    // Take over from the playing sequence once its master length runs out.
+    Cue(ActivePattern),
     Stop,
-    Swap(Sequence),
+    Swap(ActivePattern),
+    SetFill(bool),
     Shutdown,
 }
 
@@ -43,6 +555,18 @@ pub enum SequencerError {
     PlaybackNotInitialized,
     CommandSendFailed,
     NoSequenceCued,
+    NothingToResume,
+    InvalidSubdivision { numerator: i64, denominator: i64 },
+    InvalidSwing(u32),
+    InvalidTrigCondition { track: u32, step: u32, condition: String },
+    InvalidParameterLock { track: u32, step: Option<u32>, reason: String },
+    InvalidMidiChannel { track: u32, channel: u32 },
+    DuplicateTrack(u32),
+    InvalidNote { track: u32, step: u32, note: String },
+    EmptySequence,
+    StepOutOfRange { track: u32, step: u32, length: u32 },
+    InvalidVelocity { track: u32, step: u32, velocity: u32 },
+    NoSynthTrack(u32),
     Other(String),
 }
 
@@ -54,6 +578,62 @@ impl std::fmt::Display for SequencerError {
                 write!(f, "Failed to send command to playback thread")
             }
             SequencerError::NoSequenceCued => write!(f, "No sequence cued"),
+            SequencerError::NothingToResume => write!(f, "No stopped sequence to resume"),
+            SequencerError::InvalidSubdivision {
+                numerator,
+                denominator,
+            } => write!(f, "Invalid subdivision {}/{}", numerator, denominator),
+            SequencerError::InvalidSwing(swing) => write!(f, "Invalid swing {}%", swing),
+            SequencerError::InvalidTrigCondition {
+                track,
+                step,
+                condition,
+            } => write!(
+                f,
+                "Invalid condition {} on track {}, step {}",
+                condition, track, step
+            ),
+            SequencerError::InvalidParameterLock {
+                track,
+                step: Some(step),
+                reason,
+            } => write!(f, "Invalid lock on track {}, step {}: {}", track, step, reason),
+            SequencerError::InvalidParameterLock {
+                track,
+                step: None,
+                reason,
+            } => write!(f, "Invalid default on track {}: {}", track, reason),
+            SequencerError::InvalidMidiChannel { track, channel } => {
+                write!(f, "Invalid MIDI channel {} on track {}", channel, track)
+            }
+            SequencerError::DuplicateTrack(track) => {
+                write!(f, "Track {} is configured more than once", track)
+            }
+            SequencerError::InvalidNote { track, step, note } => {
+                write!(f, "Invalid note {} on track {}, step {}", note, track, step)
+            }
+            SequencerError::EmptySequence => write!(f, "Sequence has no steps"),
+            SequencerError::StepOutOfRange {
+                track,
+                step,
+                length,
+            } => write!(
+                f,
+                "Step {} on track {} is past the track's {} steps",
+                step, track, length
+            ),
+            SequencerError::InvalidVelocity {
+                track,
+                step,
+                velocity,
+            } => write!(
+                f,
+                "Invalid velocity {} on track {}, step {}",
+                velocity, track, step
+            ),
+            SequencerError::NoSynthTrack(track) => {
+                write!(f, "Track {} is not one the synth plays", track)
+            }
             SequencerError::Other(msg) => write!(f, "{}", msg),
         }
     }
@@ -66,6 +646,14 @@ impl std::error::Error for SequencerError {}
 pub struct CueMetadata {
     pub replaced_existing: bool,
     pub remaining_steps: u32,
+    pub sequence_id: u64,
+    pub step_duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct StartMetadata {
+    pub sequence_id: u64,
+    pub step_duration: Duration,
 }
 
 #[derive(Debug, Clone)]
@@ -79,173 +667,532 @@ pub struct StopMetadata {
 }
 
 pub type CueResult = Result<CueMetadata, SequencerError>;
-pub type StartResult = Result<(), SequencerError>;
+pub type StartResult = Result<StartMetadata, SequencerError>;
 pub type StopResult = Result<StopMetadata, SequencerError>;
 pub type SwapResult = Result<SwapMetadata, SequencerError>;
 
 // Core sequencer implementation.
 impl Sequencer {
     pub fn new<T: StepHandler>(step_handler: T) -> Self {
-        let (tx, rx) = mpsc::channel();
+        Self::with_clock(step_handler, SystemClock)
+    }
+
+    /// Build a sequencer whose playback thread takes its time from `clock`.
+    pub fn with_clock<T: StepHandler, C: Clock>(step_handler: T, clock: C) -> Self {
+        let (tx, rx) = realtime::channel(COMMAND_CAPACITY);
+        let (transport_tx, _) = broadcast::channel(TRANSPORT_EVENT_CAPACITY);
         let state = Arc::new(Mutex::new(SequencerState::default()));
+        let status = Arc::new(PlaybackStatus::default());
+        let fixed_tempo = clock.fixed_tempo();
+        let step_handler = Arc::new(step_handler);
+        let io = PlaybackIo {
+            commands: rx,
+            events: Self::playback_events(Arc::clone(&state), transport_tx.clone()),
+            clock,
+        };
 
         // Cloning all of these references to our playback loop.
-        let state_clone = Arc::clone(&state);
+        let status_clone = Arc::clone(&status);
+        let handler_clone = Arc::clone(&step_handler);
         let _handle = thread::Builder::new()
-            .name("sequencer-playback".to_string())
+            .name("sequencer-supervisor".to_string())
             .spawn(move || {
-                println!("🎵 Sequencer thread started!");
-                Self::playback_loop(state_clone, rx, step_handler);
+                Self::supervise(status_clone, Arc::new(Mutex::new(io)), handler_clone);
             })
-            .expect("Failed to spawn playback thread");
+            .expect("Failed to spawn supervisor thread");
 
         Self {
             state,
-            playback_control: tx
+            status,
+            playback_control: tx,
+            transport_events: transport_tx,
+            fixed_tempo,
+            step_handler,
         }
     }
 
-    /// High-precision playback loop running on dedicated thread
-    fn playback_loop<T: StepHandler>(
+    /// Outbox for the playback thread's events. Transport events go out to
+    /// watchers and sequence changes reach the control state, on the delivery
+    /// thread rather than the playback thread.
+    fn playback_events(
         state: Arc<Mutex<SequencerState>>,
-        command_rx: mpsc::Receiver<PlaybackCommand>,
-        step_handler: T,
-    ) {
-        let mut current_sequence: Option<Sequence> = None;
-        let mut current_step = 0u32;
-        let mut playing = false;
-        let mut active_note_off_events = HashMap::new();
-        let mut last_step_time = Instant::now();
-        let mut loop_helper = LoopHelper::builder().build_with_target_rate(60.0);
-        let step_handler = Rc::new(step_handler);
+        transport_tx: broadcast::Sender<TransportEvent>,
+    ) -> Outbox<PlaybackEvent> {
+        realtime::outbox(
+            "sequencer-events",
+            PLAYBACK_EVENT_CAPACITY,
+            move |event| match event {
+                PlaybackEvent::Transport(event) => {
+                    // Having no watchers is not an error.
+                    let _ = transport_tx.send(event);
+                }
+                PlaybackEvent::Current(table) => {
+                    let mut state = lock(&state);
+                    let promoted = state
+                        .cued_sequence
+                        .as_ref()
+                        .is_some_and(|cued| cued.sequence_id() == table.sequence_id());
+                    if promoted {
+                        state.cued_sequence = None;
+                    }
+                    state.current_sequence = Some(table);
+                }
+                PlaybackEvent::Retired(pattern) => drop(pattern),
+            },
+        )
+    }
 
+    /// Run the playback loop on a thread of its own and start a new one each
+    /// time it panics, so commands keep reaching a playback thread. The
+    /// playback thread's queues and clock outlive the crashed thread behind
+    /// their mutex. Whatever it left sounding is silenced and the transport
+    /// comes back stopped, ready to start again.
+    fn supervise<T: StepHandler, C: Clock>(
+        status: Arc<PlaybackStatus>,
+        io: Arc<Mutex<PlaybackIo<C>>>,
+        step_handler: Arc<T>,
+    ) {
         loop {
-            let loop_start = Instant::now();
-
-            // Handle any incoming commands
-            if Self::handle_playback_commands(
-                &command_rx,
-                &state,
-                &mut current_sequence,
-                &mut current_step,
-                &mut playing,
-                &mut active_note_off_events,
-                &mut last_step_time,
-            ) {
-                // Command handler returned true, indicating shutdown
+            let status_clone = Arc::clone(&status);
+            let io_clone = Arc::clone(&io);
+            let handler_clone = Arc::clone(&step_handler);
+            let playback = thread::Builder::new()
+                .name("sequencer-playback".to_string())
+                .spawn(move || {
+                    let mut io = lock(&io_clone);
+                    // Also sets up this thread's log queue before there is
+                    // any timing to keep.
+                    rt_println!("🎵 Sequencer thread started!");
+                    Self::playback_loop(&mut io, &status_clone, &handler_clone);
+                })
+                .expect("Failed to spawn playback thread");
+
+            if playback.join().is_ok() {
                 return;
             }
+            println!("💥 Playback thread crashed, silencing and restarting it");
+            step_handler.handle_panic();
+
+            status.playing.store(false, Ordering::Relaxed);
+            Self::publish_transport_event(
+                &mut lock(&io).events,
+                TransportEventKind::Stopped,
+                status.current_step(),
+                status.sequence_id(),
+                None,
+            );
+        }
+    }
 
-            if playing {
-                if let Some(ref sequence) = current_sequence {
-                    let elapsed = last_step_time.elapsed();
-                    let actual_step_duration = Self::calculate_step_duration(sequence);
+    /// High-precision playback loop running on dedicated thread. Sleeps until
+    /// the next step deadline on the absolute timeline, waking early to fire
+    /// micro-timed trigs and to handle commands. Everything it needs is
+    /// allocated before it gets here, and it only talks to other threads
+    /// through its queues and the transport status.
+    fn playback_loop<T: StepHandler, C: Clock>(
+        io: &mut PlaybackIo<C>,
+        status: &PlaybackStatus,
+        step_handler: &Arc<T>,
+    ) {
+        let PlaybackIo {
+            commands,
+            events,
+            clock,
+        } = io;
+        let mut active: Option<ActivePattern> = None;
+        let mut incoming: Option<ActivePattern> = None;
+        let mut cued: Option<ActivePattern> = None;
+        let mut pending_note_on_events = TimedQueue::with_capacity(SCHEDULE_CAPACITY);
+        let mut note_off_queue = NoteOffQueue::default();
+        let mut conditions = ConditionEvaluator::unseeded();
+        // Clock pulse grid while playing, for handlers that send clock.
+        let mut clock_pulses: Option<StepTimeline> = None;
 
-                    if elapsed >= actual_step_duration {
-                        Self::process_note_off_events(
-                            &mut active_note_off_events,
-                            &step_handler,
-                            loop_start,
-                        );
-                        Self::process_note_on_events(
-                            sequence,
-                            current_step,
-                            &step_handler,
-                            &mut active_note_off_events,
-                        );
+        loop {
+            // Block on the command queue until the next step, off-grid trig,
+            // note-off or clock pulse is due. While stopped there is nothing to
+            // schedule, so wait indefinitely.
+            let command = match &active {
+                Some(pattern) => {
+                    let wake_time = [
+                        pending_note_on_events.next_due(),
+                        note_off_queue.next_due(),
+                        clock_pulses.as_ref().map(StepTimeline::next_deadline),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .fold(pattern.next_deadline(), Instant::min);
+                    clock.recv_until(commands, wake_time)
+                }
+                None => clock
+                    .recv(commands)
+                    .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            };
+
+            match command {
+                Ok(command) => {
+                    if Self::handle_playback_command(
+                        command,
+                        clock,
+                        status,
+                        events,
+                        step_handler,
+                        &mut active,
+                        &mut incoming,
+                        &mut cued,
+                        &mut pending_note_on_events,
+                        &mut note_off_queue,
+                        &mut conditions,
+                        &mut clock_pulses,
+                    ) {
+                        // Command handler returned true, indicating shutdown
+                        return;
+                    }
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    // Step deadline, off-grid trig or note-off reached
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    rt_println!("Command channel disconnected");
+                    Self::silence(&mut note_off_queue, step_handler);
+                    return;
+                }
+            }
 
-                        // Advance to next step
-                        current_step = (current_step + 1) % sequence.sequence_length;
-                        last_step_time = Instant::now();
-
-                        // Update shared state with new step
-                        if let Ok(mut state_guard) = state.lock() {
-                            state_guard.current_step = current_step;
-                            if current_step == 0 {
-                                if let Some(cued_seq) = state_guard.cued_sequence.take() {
-                                    println!("Swap registered!");
-                                    // TODO Massively hacky
-                                    state_guard.current_sequence = Some(cued_seq.clone());
-                                    current_sequence = Some(cued_seq);
-                                    state_guard.cued_sequence = None;
-                                }
-                            }
-                        }
+            let Some(pattern) = &mut active else {
+                continue;
+            };
+            let step_time = pattern.next_deadline();
+            let now = clock.now();
+
+            // Everything due up to the step boundary goes out before the step
+            // itself, note-offs first so legato notes don't overlap.
+            Self::process_note_off_events(
+                pattern.sequence(),
+                &mut note_off_queue,
+                step_handler,
+                now.min(step_time),
+            );
+            Self::process_pending_note_on_events(
+                pattern.sequence(),
+                &mut pending_note_on_events,
+                &mut note_off_queue,
+                step_handler,
+                now.min(step_time),
+            );
+            // Pulses on the step boundary wait until a promoted cue has set
+            // the tempo they continue at.
+            Self::send_clock_pulses(&mut clock_pulses, step_handler, |deadline| {
+                deadline <= now && deadline < step_time
+            });
+            if now < step_time {
+                continue;
+            }
+
+            // A promoted cue takes over on the master step boundary, before
+            // anything of the outgoing sequence plays there.
+            if incoming
+                .as_ref()
+                .is_some_and(|next| next.next_deadline() <= step_time)
+            {
+                let outgoing = std::mem::replace(pattern, incoming.take().unwrap());
+                // The outgoing pattern still shares the table the handler lets
+                // go of, so freeing it is left to the delivery thread.
+                step_handler.handle_sequence_change(&pattern.table);
+                Self::retire(events, outgoing);
+                Self::send_track_defaults(pattern.sequence(), step_handler);
+                if let Some(pulses) = &mut clock_pulses {
+                    pulses.retime(pattern.table.pulse_duration());
+                }
+            }
+            Self::send_clock_pulses(&mut clock_pulses, step_handler, |deadline| {
+                deadline <= step_time
+            });
+
+            Self::play_step(
+                pattern,
+                &mut incoming,
+                &mut cued,
+                status,
+                events,
+                step_handler,
+                &mut pending_note_on_events,
+                &mut note_off_queue,
+                &mut conditions,
+            );
+        }
+    }
+
+    /// Play everything that falls on the next step boundary: the steps of the
+    /// tracks due there and, if the master playhead is due too, the master
+    /// step with its transport event and cue promotion.
+    #[allow(clippy::too_many_arguments)]
+    fn play_step<T: StepHandler>(
+        pattern: &mut ActivePattern,
+        incoming: &mut Option<ActivePattern>,
+        cued: &mut Option<ActivePattern>,
+        status: &PlaybackStatus,
+        events: &mut Outbox<PlaybackEvent>,
+        step_handler: &Arc<T>,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+        note_off_queue: &mut NoteOffQueue,
+        conditions: &mut ConditionEvaluator,
+    ) {
+        let step_time = pattern.next_deadline();
+        let master_due = pattern.master.next_deadline() == step_time;
+        for playhead in &mut pattern.tracks {
+            playhead.due = playhead.timeline.next_deadline() == step_time;
+        }
+
+        if master_due {
+            rt_println!(
+                "🎵 Step {} of {}:",
+                pattern.master_step,
+                pattern.master_length()
+            );
+        }
+        Self::process_note_on_events(pattern, step_time, step_handler, note_off_queue, conditions);
+        for index in 0..pattern.tracks.len() {
+            if !pattern.tracks[index].due {
+                continue;
+            }
+            Self::schedule_late_trigs(
+                pattern,
+                index,
+                step_time,
+                pending_note_on_events,
+                conditions,
+            );
+            pattern.tracks[index].advance();
+        }
+
+        if master_due {
+            let played_step = pattern.master_step;
+            pattern.master_step = (played_step + 1) % pattern.master_length();
+            pattern.master.advance();
+            status
+                .current_step
+                .store(pattern.master_step, Ordering::Relaxed);
+
+            let cued_remaining_steps = cued
+                .as_ref()
+                .map(|_| pattern.master_length() - played_step - 1);
+            Self::publish_transport_event(
+                events,
+                TransportEventKind::Step,
+                played_step,
+                pattern.sequence_id(),
+                cued_remaining_steps,
+            );
+
+            if pattern.master_step == 0 {
+                if let Some(mut next) = cued.take() {
+                    rt_println!("Swap registered!");
+                    status
+                        .sequence_id
+                        .store(next.sequence_id(), Ordering::Relaxed);
+                    let _ = events.push(PlaybackEvent::Current(Arc::clone(&next.table)));
+
+                    // The cued sequence starts from the top on the next
+                    // master step. Tracks of the outgoing sequence keep
+                    // playing until then but don't reach past it.
+                    next.start_at(pattern.master.next_deadline(), 0);
+                    next.last_results.carry_over(&pattern.last_results);
+                    Self::cancel_early_trigs(pattern, next.next_deadline(), pending_note_on_events);
+                    for index in 0..next.tracks.len() {
+                        Self::schedule_early_trigs(
+                            &mut next,
+                            index,
+                            step_time,
+                            pending_note_on_events,
+                            conditions,
+                        );
+                    }
+                    Self::publish_transport_event(
+                        events,
+                        TransportEventKind::CuePromoted,
+                        0,
+                        next.sequence_id(),
+                        None,
+                    );
+                    if let Some(replaced) = incoming.replace(next) {
+                        Self::retire(events, replaced);
                     }
                 }
             }
+        }
 
-            loop_helper.loop_sleep();
+        // Trigs nudged ahead of a track's next step play inside this step's
+        // window, unless the next step belongs to the incoming sequence.
+        let handover = incoming.as_ref().map(ActivePattern::next_deadline);
+        for index in 0..pattern.tracks.len() {
+            let playhead = &pattern.tracks[index];
+            if !playhead.due
+                || handover.is_some_and(|handover| playhead.timeline.next_deadline() >= handover)
+            {
+                continue;
+            }
+            Self::schedule_early_trigs(
+                pattern,
+                index,
+                step_time,
+                pending_note_on_events,
+                conditions,
+            );
         }
     }
 
-    /// Handle incoming playback commands with minimal latency
+    /// Apply a single playback command.
     /// Returns true if shutdown was requested, false otherwise
-    fn handle_playback_commands(
-        command_rx: &mpsc::Receiver<PlaybackCommand>,
-        state: &Arc<Mutex<SequencerState>>,
-        current_sequence: &mut Option<Sequence>,
-        current_step: &mut u32,
-        playing: &mut bool,
-        active_note_off_events: &mut HashMap<Instant, Vec<Trig>>,
-        last_step_time: &mut Instant,
+    #[allow(clippy::too_many_arguments)]
+    fn handle_playback_command<T: StepHandler, C: Clock>(
+        command: PlaybackCommand,
+        clock: &C,
+        status: &PlaybackStatus,
+        events: &mut Outbox<PlaybackEvent>,
+        step_handler: &Arc<T>,
+        active: &mut Option<ActivePattern>,
+        incoming: &mut Option<ActivePattern>,
+        cued: &mut Option<ActivePattern>,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+        note_off_queue: &mut NoteOffQueue,
+        conditions: &mut ConditionEvaluator,
+        clock_pulses: &mut Option<StepTimeline>,
     ) -> bool {
-        match command_rx.try_recv() {
-            Ok(command) => {
-                match command {
-                    PlaybackCommand::Start(sequence) => {
-                        println!("Starting playback");
-                        *current_sequence = Some(sequence);
-                        *current_step = 0;
-                        // TODO - This playing variable is redundant.
-                        *playing = true;
-                        *last_step_time = Instant::now(); // Reset timing
-
-                        // Update shared state
-                        if let Ok(mut state_guard) = state.lock() {
-                            state_guard.playing = true;
-                            state_guard.current_step = 0;
-                            state_guard.current_sequence = current_sequence.clone();
+        match command {
+            PlaybackCommand::Start(pattern, random_seed) => {
+                rt_println!("Starting playback");
+                // Conditions start over with the transport, as the pattern
+                // comes without outcomes; a fixed seed makes every run draw
+                // the same probabilities.
+                if let Some(seed) = random_seed {
+                    conditions.reseed(seed);
+                }
+
+                Self::begin_playback(
+                    pattern,
+                    None,
+                    clock,
+                    status,
+                    events,
+                    step_handler,
+                    active,
+                    incoming,
+                    cued,
+                    pending_note_on_events,
+                    conditions,
+                    clock_pulses,
+                );
+            }
+            PlaybackCommand::Continue(pattern, step) => {
+                rt_println!("Continuing playback from step {}", step);
+                Self::begin_playback(
+                    pattern,
+                    Some(step),
+                    clock,
+                    status,
+                    events,
+                    step_handler,
+                    active,
+                    incoming,
+                    cued,
+                    pending_note_on_events,
+                    conditions,
+                    clock_pulses,
+                );
+            }
+            PlaybackCommand::Cue(pattern) => {
+                if let Some(replaced) = cued.replace(pattern) {
+                    Self::retire(events, replaced);
+                }
+            }
+            PlaybackCommand::Stop => {
+                rt_println!("Stopping playback");
+                for pattern in [active.take(), incoming.take()].into_iter().flatten() {
+                    Self::retire(events, pattern);
+                }
+                pending_note_on_events.clear();
+                Self::silence(note_off_queue, step_handler);
+                if clock_pulses.take().is_some() {
+                    step_handler.handle_clock(ClockMessage::Stop);
+                }
+
+                status.playing.store(false, Ordering::Relaxed);
+                Self::publish_transport_event(
+                    events,
+                    TransportEventKind::Stopped,
+                    status.current_step(),
+                    status.sequence_id(),
+                    None,
+                );
+            }
+            PlaybackCommand::Swap(mut swapped) => {
+                rt_println!("Swapping sequence");
+                // Off-grid trigs queued from the old sequence no longer apply,
+                // and the swapped-in sequence replaces anything promoted. Its
+                // notes end here rather than linger into the new sequence.
+                pending_note_on_events.clear();
+                if let Some(promoted) = incoming.take() {
+                    Self::retire(events, promoted);
+                }
+                Self::silence(note_off_queue, step_handler);
+
+                let sequence_id = swapped.sequence_id();
+                let _ = events.push(PlaybackEvent::Current(Arc::clone(&swapped.table)));
+                let current_step = match active {
+                    Some(pattern) => {
+                        // Every playhead keeps its position, clamped to the
+                        // new lengths.
+                        swapped.follow(pattern);
+                        let now = clock.now();
+                        for index in 0..swapped.tracks.len() {
+                            Self::schedule_early_trigs(
+                                &mut swapped,
+                                index,
+                                now,
+                                pending_note_on_events,
+                                conditions,
+                            );
                         }
-                    }
-                    PlaybackCommand::Stop => {
-                        println!("Stopping playback");
-                        *playing = false;
-                        active_note_off_events.clear();
-
-                        // Update shared state
-                        if let Ok(mut state_guard) = state.lock() {
-                            state_guard.playing = false;
+                        step_handler.handle_sequence_change(&swapped.table);
+                        Self::send_track_defaults(swapped.sequence(), step_handler);
+                        if let Some(pulses) = clock_pulses {
+                            pulses.retime(swapped.table.pulse_duration());
                         }
+                        let swapped_out = std::mem::replace(pattern, swapped);
+                        Self::retire(events, swapped_out);
+                        pattern.master_step
                     }
-                    PlaybackCommand::Swap(sequence) => {
-                        println!("Swapping sequence");
-                        *current_sequence = Some(sequence);
-                        // Keep current step position, but clamp to new sequence length
-                        if let Some(ref seq) = current_sequence {
-                            if *current_step >= seq.sequence_length {
-                                *current_step = 0;
-                                *last_step_time = Instant::now(); // Reset timing on wrap
-                            }
-                        }
-
-                        // Update shared state
-                        if let Ok(mut state_guard) = state.lock() {
-                            state_guard.current_sequence = current_sequence.clone();
-                            state_guard.current_step = *current_step;
+                    None => {
+                        let current_step = status.current_step();
+                        let master_length = swapped.master_length();
+                        Self::retire(events, swapped);
+                        if current_step >= master_length {
+                            0
+                        } else {
+                            current_step
                         }
                     }
-                    PlaybackCommand::Shutdown => {
-                        println!("Shutting down playback thread");
-                        return true; // Signal shutdown
-                    }
-                }
+                };
+
+                status.current_step.store(current_step, Ordering::Relaxed);
+                status.sequence_id.store(sequence_id, Ordering::Relaxed);
+                Self::publish_transport_event(
+                    events,
+                    TransportEventKind::Swapped,
+                    current_step,
+                    sequence_id,
+                    None,
+                );
             }
-            Err(mpsc::TryRecvError::Empty) => {
-                // No command, continue with timing loop
+            PlaybackCommand::SetFill(fill) => {
+                rt_println!("Fill {}", if fill { "on" } else { "off" });
+                conditions.set_fill(fill);
             }
-            Err(mpsc::TryRecvError::Disconnected) => {
-                println!("Command channel disconnected");
+            PlaybackCommand::Shutdown => {
+                rt_println!("Shutting down playback thread");
+                Self::silence(note_off_queue, step_handler);
                 return true; // Signal shutdown
             }
         }
@@ -253,103 +1200,432 @@ impl Sequencer {
         false // Continue running
     }
 
-    /// Calculate step duration based on BPM and subdivision
-    fn calculate_step_duration(sequence: &Sequence) -> Duration {
-        let bpm = sequence.bpm.max(60).min(300); // Clamp BPM to reasonable range
+    /// Put `pattern` on the transport with its first step, or master step
+    /// `resume_step` when resuming, due right away. Clock followers get a
+    /// Start, or the song position followed by a Continue.
+    #[allow(clippy::too_many_arguments)]
+    fn begin_playback<T: StepHandler, C: Clock>(
+        mut pattern: ActivePattern,
+        resume_step: Option<u32>,
+        clock: &C,
+        status: &PlaybackStatus,
+        events: &mut Outbox<PlaybackEvent>,
+        step_handler: &Arc<T>,
+        active: &mut Option<ActivePattern>,
+        incoming: &mut Option<ActivePattern>,
+        cued: &mut Option<ActivePattern>,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+        conditions: &mut ConditionEvaluator,
+        clock_pulses: &mut Option<StepTimeline>,
+    ) {
+        // The first step is due immediately; every later deadline is measured
+        // from this instant.
+        let start_time = clock.now();
+        pattern.start_at(start_time, resume_step.unwrap_or(0));
+        let step = pattern.master_step;
+        let sequence_id = pattern.sequence_id();
+        step_handler.handle_sequence_change(&pattern.table);
+        Self::send_track_defaults(pattern.sequence(), step_handler);
+
+        // Nothing came before the first step, so its early trigs play on the
+        // downbeat.
+        pending_note_on_events.clear();
+        for index in 0..pattern.tracks.len() {
+            Self::schedule_early_trigs(
+                &mut pattern,
+                index,
+                start_time,
+                pending_note_on_events,
+                conditions,
+            );
+        }
+
+        // Followers start counting from the first pulse, which goes out with
+        // the first step.
+        if step_handler.sends_clock() {
+            if resume_step.is_some() {
+                let position = song_position(pattern.sequence(), step);
+                step_handler.handle_clock(ClockMessage::SongPosition(position));
+                step_handler.handle_clock(ClockMessage::Continue);
+            } else {
+                step_handler.handle_clock(ClockMessage::Start);
+            }
+            *clock_pulses = Some(StepTimeline::new(
+                start_time,
+                pattern.table.pulse_duration(),
+            ));
+        }
+
+        status.playing.store(true, Ordering::Relaxed);
+        status.current_step.store(step, Ordering::Relaxed);
+        status.sequence_id.store(sequence_id, Ordering::Relaxed);
+        let _ = events.push(PlaybackEvent::Current(Arc::clone(&pattern.table)));
 
-        // Default to 16th notes if no subdivision specified
-        let subdivision = sequence
-            .trig_subdivision
+        // A cue of the sequence being started has been taken up.
+        if cued
             .as_ref()
-            .map(|s| s.denominator as f64)
-            .unwrap_or(16.0);
+            .is_some_and(|cued| cued.sequence_id() == sequence_id)
+        {
+            Self::retire(events, cued.take().unwrap());
+        }
+        for replaced in [active.replace(pattern), incoming.take()]
+            .into_iter()
+            .flatten()
+        {
+            Self::retire(events, replaced);
+        }
 
-        // Calculate milliseconds per step
-        let beats_per_minute = bpm as f64;
-        let beats_per_second = beats_per_minute / 60.0;
-        let steps_per_beat = subdivision / 4.0; // Assuming quarter note = 1 beat
-        let steps_per_second = beats_per_second * steps_per_beat;
-        let milliseconds_per_step = 1000.0 / steps_per_second;
+        Self::publish_transport_event(events, TransportEventKind::Started, step, sequence_id, None);
+    }
 
-        Duration::from_millis(milliseconds_per_step as u64)
+    /// Hand a pattern the playback thread is done with to the delivery thread
+    /// to free. Should the outbox be full, it is freed here after all.
+    fn retire(events: &mut Outbox<PlaybackEvent>, pattern: ActivePattern) {
+        let _ = events.push(PlaybackEvent::Retired(pattern));
     }
 
+    /// Send every clock pulse whose deadline `is_due`.
+    fn send_clock_pulses<T: StepHandler>(
+        clock_pulses: &mut Option<StepTimeline>,
+        step_handler: &Arc<T>,
+        is_due: impl Fn(Instant) -> bool,
+    ) {
+        let Some(pulses) = clock_pulses else {
+            return;
+        };
+        while is_due(pulses.next_deadline()) {
+            step_handler.handle_clock(ClockMessage::Pulse);
+            pulses.advance();
+        }
+    }
+
+    /// Queue a transport event for every watcher. If the delivery thread has
+    /// fallen that far behind, the event is dropped rather than waited for.
+    fn publish_transport_event(
+        events: &mut Outbox<PlaybackEvent>,
+        kind: TransportEventKind,
+        step: u32,
+        sequence_id: u64,
+        cued_remaining_steps: Option<u32>,
+    ) {
+        let _ = events.push(PlaybackEvent::Transport(TransportEvent {
+            kind: kind as i32,
+            step,
+            sequence_id,
+            timestamp_micros: wall_clock_micros(),
+            cued_remaining_steps,
+        }));
+    }
+
+    /// Fire the on-grid trigs of every track due on this boundary. The
+    /// handler is called once per boundary, even when nothing plays, so
+    /// master steps without trigs still reach it.
     fn process_note_on_events<T: StepHandler>(
+        pattern: &mut ActivePattern,
+        step_time: Instant,
+        step_handler: &Arc<T>,
+        note_off_queue: &mut NoteOffQueue,
+        conditions: &mut ConditionEvaluator,
+    ) {
+        // Find all trigs for the due steps that sit on the grid and whose
+        // conditions pass. Micro-timed trigs are queued separately and fire at
+        // their own time.
+        let mut step_trigs = FiringTrigs::new();
+        let last_results = &mut pattern.last_results;
+        for (index, playhead) in pattern.tracks.iter().enumerate() {
+            let Some(events) = pattern.table.tracks()[index]
+                .step(playhead.step)
+                .filter(|_| playhead.due)
+            else {
+                continue;
+            };
+            step_trigs.extend(
+                events
+                    .on_grid
+                    .iter()
+                    .filter(|trig| conditions.evaluate(trig, playhead.iteration, last_results))
+                    .map(|trig| (Sounding::new(trig), playhead.settings.step_duration)),
+            );
+        }
+
+        Self::fire_trigs(
+            pattern.sequence(),
+            step_trigs,
+            step_time,
+            step_handler,
+            note_off_queue,
+        );
+    }
+
+    /// Queue the trigs of a track's current step that are pushed late by a
+    /// positive offset. Their conditions are settled now, with the step.
+    fn schedule_late_trigs(
+        pattern: &mut ActivePattern,
+        index: usize,
+        step_time: Instant,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+        conditions: &mut ConditionEvaluator,
+    ) {
+        let playhead = &pattern.tracks[index];
+        let Some(events) = pattern.table.tracks()[index].step(playhead.step) else {
+            return;
+        };
+        let step_duration = playhead.settings.step_duration;
+        for trig in &events.late {
+            if conditions.evaluate(trig, playhead.iteration, &mut pattern.last_results) {
+                let trig_time = step_time + step_duration.span(trig.offset as f64);
+                pending_note_on_events.push(trig_time, (Arc::clone(trig), step_duration));
+            }
+        }
+    }
+
+    /// Queue the trigs of a track's current step that are pulled early by a
+    /// negative offset. They play in the previous step's window, but never
+    /// before `not_before`, and their conditions are settled when queued.
+    fn schedule_early_trigs(
+        pattern: &mut ActivePattern,
+        index: usize,
+        not_before: Instant,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+        conditions: &mut ConditionEvaluator,
+    ) {
+        let playhead = &pattern.tracks[index];
+        let Some(events) = pattern.table.tracks()[index].step(playhead.step) else {
+            return;
+        };
+        let step_time = playhead.timeline.next_deadline();
+        let step_duration = playhead.settings.step_duration;
+        for trig in &events.early {
+            if conditions.evaluate(trig, playhead.iteration, &mut pattern.last_results) {
+                let trig_time = step_time
+                    .checked_sub(step_duration.span(-trig.offset as f64))
+                    .map_or(not_before, |trig_time| trig_time.max(not_before));
+                pending_note_on_events.push(trig_time, (Arc::clone(trig), step_duration));
+            }
+        }
+    }
+
+    /// Drop the early trigs already queued for steps of `pattern` that fall
+    /// at or after `handover`, where another sequence takes over.
+    fn cancel_early_trigs(
+        pattern: &ActivePattern,
+        handover: Instant,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+    ) {
+        let cancelled = |trig: &Trig| {
+            trig.offset < 0.0
+                && pattern.tracks.iter().any(|playhead| {
+                    playhead.timeline.next_deadline() >= handover && playhead.plays(trig)
+                })
+        };
+        pending_note_on_events.retain(|(trig, _)| !cancelled(trig));
+    }
+
+    /// Fire every queued off-grid trig due at or before `current_time`.
+    fn process_pending_note_on_events<T: StepHandler>(
         sequence: &Sequence,
-        step: u32,
-        step_handler: &Rc<T>,
-        active_note_off_events: &mut HashMap<Instant, Vec<Trig>>,
+        pending_note_on_events: &mut TimedQueue<(Arc<TrigEvent>, StepDuration)>,
+        note_off_queue: &mut NoteOffQueue,
+        step_handler: &Arc<T>,
+        current_time: Instant,
     ) {
-        println!("🎵 Step {} of {}:", step, sequence.sequence_length);
+        while let Some(trig_time) = pending_note_on_events
+            .next_due()
+            .filter(|trig_time| *trig_time <= current_time)
+        {
+            let mut trigs = FiringTrigs::new();
+            while let Some((_, (trig, step_duration))) = pending_note_on_events.pop_due(trig_time) {
+                trigs.push((Sounding::new(&trig), step_duration));
+            }
 
-        // Find all trigs for this step
-        let step_trigs: Vec<&Trig> = sequence
-            .trigs
-            .iter()
-            .filter(|trig| trig.step == step)
-            .collect();
+            Self::fire_trigs(sequence, trigs, trig_time, step_handler, note_off_queue);
+        }
+    }
 
-        let now = Instant::now();
-        // TODO - We should really statically calculate this once.
-        let step_duration = Self::calculate_step_duration(sequence);
+    /// Send note-ons for `trigs` and queue their note-offs, each measured in
+    /// steps of its own track. Notes tied over on the same tracks are released
+    /// first, as is everything a mono track still plays and any pitch a poly
+    /// track is about to retrigger, so each pitch gets exactly one note-off.
+    /// Parameter locks go out just ahead of the note-ons. Note-offs are
+    /// measured from when the note actually started, so micro-timed trigs keep
+    /// their full length.
+    fn fire_trigs<T: StepHandler>(
+        sequence: &Sequence,
+        mut trigs: FiringTrigs,
+        note_on_time: Instant,
+        step_handler: &Arc<T>,
+        note_off_queue: &mut NoteOffQueue,
+    ) {
+        Self::allocate_voices(sequence, &mut trigs);
 
-        for trig in &step_trigs {
-            if let Some(_note) = &trig.note {
-                let note_off_time = now + (step_duration * trig.length.ceil() as u32);
+        let mut released = TrigList::new();
+        for (trig, _) in &trigs {
+            note_off_queue.take_tied(trig.event.track, &mut released);
+        }
+        for (trig, _) in trigs.iter().filter(|(trig, _)| !trig.is_rest()) {
+            match voice_mode(sequence, trig.event.track) {
+                VoiceMode::Mono => note_off_queue.take_track(trig.event.track, &mut released),
+                VoiceMode::Poly => note_off_queue.take_pitches(trig, &mut released),
+            }
+        }
+        Self::release_notes(sequence, &released, step_handler, note_off_queue);
 
-                active_note_off_events
-                    .entry(note_off_time)
-                    .or_insert_with(Vec::new)
-                    .push((*trig).clone());
+        for (trig, _) in &trigs {
+            if let Some(locks) = trig_locks(&trig.event) {
+                step_handler.handle_parameters(trig.event.track, locks);
             }
         }
+        for (trig, step_duration) in &trigs {
+            note_off_queue.schedule(trig, note_on_time, *step_duration);
+        }
+        let mut notes_on = TrigRefs::new();
+        for (trig, _) in &trigs {
+            trig.trigs(&mut notes_on);
+        }
+        step_handler.handle_notes_on(&notes_on);
+    }
 
-        step_handler.handle_notes_on(step_trigs);
+    /// Apply each track's voice mode to trigs starting together. A mono track
+    /// plays only the last trig on it that has notes; a poly track plays each
+    /// pitch once, from the last trig that has it. Event tables have already
+    /// voiced each trig on its own, so only clashes between trigs are left.
+    fn allocate_voices(sequence: &Sequence, trigs: &mut FiringTrigs) {
+        for index in (0..trigs.len()).rev() {
+            let (earlier, later) = trigs.split_at_mut(index + 1);
+            let trig = &mut earlier[index].0;
+            if trig.is_rest() {
+                continue;
+            }
+            let track = trig.event.track;
+            let on_track = || {
+                later
+                    .iter()
+                    .map(|(other, _)| other)
+                    .filter(|other| other.event.track == track)
+            };
+            let taken = match voice_mode(sequence, track) {
+                VoiceMode::Mono if on_track().any(|other| !other.is_rest()) => {
+                    trig.notes_where(|_| true)
+                }
+                VoiceMode::Mono => 0,
+                VoiceMode::Poly => {
+                    trig.notes_where(|note| on_track().any(|other| other.plays_pitch(note)))
+                }
+            };
+            trig.silent |= taken;
+            if trig.is_rest() {
+                trigs.remove(index);
+            }
+        }
     }
 
-    /// New helper function to process note off events
+    /// Send every note-off due at or before `current_time`.
     fn process_note_off_events<T: StepHandler>(
-        active_note_off_events: &mut HashMap<Instant, Vec<Trig>>,
-        step_handler: &Rc<T>,
+        sequence: &Sequence,
+        note_off_queue: &mut NoteOffQueue,
+        step_handler: &Arc<T>,
         current_time: Instant,
     ) {
-        // Collect all note-off events that should trigger now or earlier
-        let mut events_to_remove = Vec::new();
-        let mut trigs_to_turn_off = Vec::new();
+        let mut trigs_to_turn_off = TrigList::new();
+        note_off_queue.take_due(current_time, &mut trigs_to_turn_off);
+        Self::release_notes(sequence, &trigs_to_turn_off, step_handler, note_off_queue);
+    }
 
-        for (event_time, trigs) in active_note_off_events.iter() {
-            if *event_time <= current_time {
-                // TODO Weird. Just use a data structure.
-                events_to_remove.push(*event_time);
-                trigs_to_turn_off.extend(trigs.iter().cloned());
+    /// Send note-offs for `trigs`, then put back the track defaults for
+    /// whatever they locked, unless another locked note on the same track is
+    /// still sounding and will restore them itself. Locked rests have no
+    /// note to end, so their values stay until something else changes them.
+    fn release_notes<T: StepHandler>(
+        sequence: &Sequence,
+        trigs: &[Sounding],
+        step_handler: &Arc<T>,
+        note_off_queue: &mut NoteOffQueue,
+    ) {
+        if trigs.is_empty() {
+            return;
+        }
+        let mut notes_off = TrigRefs::new();
+        for trig in trigs {
+            trig.trigs(&mut notes_off);
+        }
+        step_handler.handle_notes_off(&notes_off);
+
+        for trig in trigs.iter().map(|sounding| &sounding.event) {
+            let Some(locks) = trig_locks(trig) else {
+                continue;
+            };
+            if note_off_queue.has_locked_note(trig.track) {
+                continue;
+            }
+            let defaults =
+                track_config(sequence, trig.track).and_then(|config| config.defaults.as_ref());
+            let restored = &mut note_off_queue.restored;
+            restore_values_into(restored, locks, defaults);
+            if !locks_are_empty(restored) {
+                step_handler.handle_parameters(trig.track, restored);
             }
         }
+    }
 
-        // Remove processed events
-        for time in events_to_remove {
-            active_note_off_events.remove(&time);
+    /// End everything still sounding: a note-off for every queued note, timed
+    /// or tied, then the handler's all-notes-off for anything it missed.
+    fn silence<T: StepHandler>(note_off_queue: &mut NoteOffQueue, step_handler: &Arc<T>) {
+        let mut sounding = TrigList::new();
+        note_off_queue.take_all(&mut sounding);
+        if !sounding.is_empty() {
+            let mut notes_off = TrigRefs::new();
+            for trig in &sounding {
+                trig.trigs(&mut notes_off);
+            }
+            step_handler.handle_notes_off(&notes_off);
         }
+        step_handler.handle_all_notes_off();
+    }
 
-        step_handler.handle_notes_off(trigs_to_turn_off.iter().collect());
+    /// Send the default parameter values of every track that sets them, so a
+    /// sequence starts from a known state.
+    fn send_track_defaults<T: StepHandler>(sequence: &Sequence, step_handler: &Arc<T>) {
+        for config in &sequence.tracks {
+            if let Some(defaults) = config
+                .defaults
+                .as_ref()
+                .filter(|defaults| !locks_are_empty(defaults))
+            {
+                step_handler.handle_parameters(config.track, defaults);
+            }
+        }
     }
 
     pub fn cue_sequence(&self, sequence: Sequence) -> CueResult {
         println!("Cueing sequence: {}", sequence);
+        validate_sequence(&sequence)?;
+        let sequence = self.at_playback_tempo(sequence);
+        let step_duration = StepDuration::from_sequence(&sequence).as_duration();
 
         let mut state = self.state.lock().unwrap();
         let replaced_existing = state.cued_sequence.is_some();
+        let sequence_id = state.next_sequence_id();
+        let table = Arc::new(EventTable::new(&sequence, sequence_id));
 
-        let remaining_steps = if state.playing {
+        let remaining_steps = if self.status.is_playing() {
             if let Some(current_seq) = &state.current_sequence {
-                current_seq.sequence_length - state.current_step
+                current_seq
+                    .master_length()
+                    .saturating_sub(self.status.current_step())
             } else {
                 0
             }
         } else {
-            sequence.sequence_length
+            table.master_length()
         };
 
-        state.cued_sequence = Some(sequence);
+        // Sent under the lock, so the playback thread sees cues in the order
+        // they were made.
+        self.send(
+            PlaybackCommand::Cue(ActivePattern::new(Arc::clone(&table))),
+            "cue",
+        )?;
+        state.cued_sequence = Some(table);
 
         if replaced_existing {
             println!("Replaced existing cued sequence");
@@ -360,9 +1636,12 @@ impl Sequencer {
         Ok(CueMetadata {
             replaced_existing,
             remaining_steps,
+            sequence_id,
+            step_duration,
         })
     }
 
+    /// Play the cued sequence, or else the stopped one, from the top.
     pub fn start_sequence(&self) -> StartResult {
         let mut state = self.state.lock().unwrap();
         // TODO - One thing we can do to remove an entire conditional check:
@@ -373,32 +1652,77 @@ impl Sequencer {
         // No constant evaluation of command state while the sequencer is running,
         // and it will be more performant!
 
-        if let Some(cued_sequence) = state.cued_sequence.take() {
-            drop(state); // Release lock before sending command
-
-            self.playback_control.send(PlaybackCommand::Start(cued_sequence))
-                .map_err(|_| {
-                    println!("❌ Failed to send start command");
-                    SequencerError::CommandSendFailed
-                })?;
-
-            Ok(())
+        let (command, table) = if let Some(cued_sequence) = state.cued_sequence.take() {
+            (
+                PlaybackCommand::Start(
+                    ActivePattern::new(Arc::clone(&cued_sequence)),
+                    state.random_seed,
+                ),
+                cued_sequence,
+            )
+        } else if let Some(stopped_sequence) = state
+            .current_sequence
+            .clone()
+            .filter(|_| !self.status.is_playing())
+        {
+            // Nothing new cued: play the stopped sequence again from the top.
+            (
+                PlaybackCommand::Start(
+                    ActivePattern::new(Arc::clone(&stopped_sequence)),
+                    state.random_seed,
+                ),
+                stopped_sequence,
+            )
         } else {
             println!("❌ No sequence cued - cannot start");
-            Err(SequencerError::NoSequenceCued)
-        }
+            return Err(SequencerError::NoSequenceCued);
+        };
+        drop(state); // Release lock before sending command
+
+        self.send(command, "start")?;
+
+        Ok(StartMetadata {
+            sequence_id: table.sequence_id(),
+            step_duration: table.step_duration().as_duration(),
+        })
+    }
+
+    /// Pick the stopped sequence up where it stopped. Clock followers get
+    /// the song position and a Continue rather than a Start.
+    pub fn resume_sequence(&self) -> StartResult {
+        let state = self.state.lock().unwrap();
+        let Some(stopped_sequence) = state
+            .current_sequence
+            .clone()
+            .filter(|_| !self.status.is_playing())
+        else {
+            println!("❌ No stopped sequence - cannot resume");
+            return Err(SequencerError::NothingToResume);
+        };
+        drop(state); // Release lock before sending command
+
+        let command = PlaybackCommand::Continue(
+            ActivePattern::new(Arc::clone(&stopped_sequence)),
+            self.status.current_step(),
+        );
+        self.send(command, "resume")?;
+
+        Ok(StartMetadata {
+            sequence_id: stopped_sequence.sequence_id(),
+            step_duration: stopped_sequence.step_duration().as_duration(),
+        })
     }
 
     pub fn stop_sequence(&self) -> StopResult {
         let trig_count = {
             let state = self.state.lock().unwrap();
-            state.current_sequence.as_ref().map(|seq| seq.trigs.len())
+            state
+                .current_sequence
+                .as_ref()
+                .map(|table| table.sequence().trigs.len())
         };
 
-        self.playback_control.send(PlaybackCommand::Stop).map_err(|_| {
-            println!("❌ Failed to send stop command");
-            SequencerError::CommandSendFailed
-        })?;
+        self.send(PlaybackCommand::Stop, "stop")?;
 
         if let Some(count) = trig_count {
             println!("Stopped sequence had {} trigs", count);
@@ -409,36 +1733,87 @@ impl Sequencer {
 
     pub fn swap_sequence(&self, sequence: Sequence) -> SwapResult {
         println!("Swapping sequence: {}", sequence);
+        validate_sequence(&sequence)?;
+        let sequence = self.at_playback_tempo(sequence);
 
-        let replaced_existing = {
-            let state = self.state.lock().unwrap();
-            state.current_sequence.is_some()
+        let (replaced_existing, sequence_id) = {
+            let mut state = self.state.lock().unwrap();
+            (state.current_sequence.is_some(), state.next_sequence_id())
         };
+        let table = Arc::new(EventTable::new(&sequence, sequence_id));
 
-        self.playback_control.send(PlaybackCommand::Swap(sequence)).map_err(|_| {
-            println!("❌ Failed to send swap command");
-            SequencerError::CommandSendFailed
-        })?;
+        self.send(PlaybackCommand::Swap(ActivePattern::new(table)), "swap")?;
 
         Ok(SwapMetadata { replaced_existing })
     }
 
+    /// The sequence as it will play: at the clock's tempo when following an
+    /// external master, otherwise unchanged.
+    fn at_playback_tempo(&self, mut sequence: Sequence) -> Sequence {
+        if let Some(bpm) = self.fixed_tempo {
+            sequence.bpm = bpm;
+        }
+        sequence
+    }
+
+    fn send(&self, command: PlaybackCommand, action: &str) -> Result<(), SequencerError> {
+        self.playback_control.send(command).map_err(|_| {
+            println!("❌ Failed to send {} command", action);
+            SequencerError::CommandSendFailed
+        })
+    }
+
+    /// Kill all sound at once: stop the transport and have the handler
+    /// silence everything. The handler is called from this thread, so sound
+    /// stops even while the playback thread is busy or restarting.
+    pub fn panic(&self) -> Result<(), SequencerError> {
+        println!("🚨 Panic: silencing everything");
+        let stopped = self.send(PlaybackCommand::Stop, "stop");
+        self.step_handler.handle_panic();
+        stopped
+    }
+
+    /// Switch fill mode, which FILL and !FILL conditions follow.
+    pub fn set_fill(&self, active: bool) -> Result<(), SequencerError> {
+        self.send(PlaybackCommand::SetFill(active), "fill")
+    }
+
+    /// Fix the seed probability conditions draw from, from the next start on,
+    /// so playback can be reproduced.
+    pub fn set_random_seed(&self, seed: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.random_seed = Some(seed);
+    }
+
     pub fn is_playing(&self) -> bool {
-        let state = self.state.lock().unwrap();
-        state.playing
+        self.status.is_playing()
     }
 
     pub fn current_step(&self) -> u32 {
-        let state = self.state.lock().unwrap();
-        state.current_step
+        self.status.current_step()
+    }
+
+    /// Subscribe to step, start/stop, swap and cue-promotion events emitted by
+    /// the playback thread.
+    pub fn subscribe_transport(&self) -> broadcast::Receiver<TransportEvent> {
+        self.transport_events.subscribe()
+    }
+
+    /// Handle for driving the transport from an external clock master.
+    pub fn external_transport(&self) -> ExternalTransport {
+        ExternalTransport {
+            state: Arc::clone(&self.state),
+            status: Arc::clone(&self.status),
+            playback_control: self.playback_control.clone(),
+        }
     }
 
     pub fn current_sequence_info(&self) -> Option<(u32, usize)> {
         let state = self.state.lock().unwrap();
-        state
-            .current_sequence
-            .as_ref()
-            .map(|seq| (seq.sequence_length, seq.trigs.len()))
+        state.current_sequence.as_ref().map(|table| {
+            let sequence = table.sequence();
+            (sequence.sequence_length, sequence.trigs.len())
+        })
     }
 
     pub fn shutdown(&mut self) {
@@ -455,111 +1830,451 @@ impl Drop for Sequencer {
     }
 }
 
-pub struct MidiStepHandler {
-    midi_connection: Mutex<MidiOutputConnection>,
+/// Transport controls for an external clock master. Its Start, Continue, Stop
+/// and song position messages make the same transitions `start_sequence`,
+/// `resume_sequence` and `stop_sequence` do, but Start always plays from the
+/// top and Continue resumes wherever the master says.
+#[derive(Debug, Clone)]
+pub struct ExternalTransport {
+    state: Arc<Mutex<SequencerState>>,
+    status: Arc<PlaybackStatus>,
+    playback_control: realtime::Sender<PlaybackCommand>,
 }
 
-impl MidiStepHandler {
-    pub fn new(midi_connection: MidiOutputConnection) -> Self {
+impl ExternalTransport {
+    /// Play from the top: the cued sequence if there is one, otherwise the
+    /// current sequence again.
+    pub fn start(&self) -> Result<(), SequencerError> {
+        let mut state = self.state.lock().unwrap();
+        let table = if let Some(cued_sequence) = state.cued_sequence.take() {
+            cued_sequence
+        } else if let Some(current_sequence) = state.current_sequence.clone() {
+            current_sequence
+        } else {
+            println!("❌ No sequence cued - cannot follow clock start");
+            return Err(SequencerError::NoSequenceCued);
+        };
+        let command = PlaybackCommand::Start(ActivePattern::new(table), state.random_seed);
+        drop(state);
+        self.send(command)
+    }
+
+    /// Resume from the last song position, or from where playback stopped.
+    /// A sequence that was only cued starts at that position too.
+    pub fn resume(&self) -> Result<(), SequencerError> {
+        let mut state = self.state.lock().unwrap();
+        if self.status.is_playing() {
+            return Ok(());
+        }
+        let table = if let Some(current_sequence) = state.current_sequence.clone() {
+            current_sequence
+        } else if let Some(cued_sequence) = state.cued_sequence.take() {
+            cued_sequence
+        } else {
+            println!("❌ No sequence cued - cannot follow clock continue");
+            return Err(SequencerError::NoSequenceCued);
+        };
+        drop(state);
+        let step = self.status.current_step();
+        self.send(PlaybackCommand::Continue(ActivePattern::new(table), step))
+    }
+
+    pub fn stop(&self) -> Result<(), SequencerError> {
+        self.send(PlaybackCommand::Stop)
+    }
+
+    /// Move a stopped transport to `position` sixteenths into the sequence,
+    /// for the next `resume`. Ignored while playing.
+    pub fn locate(&self, position: u16) {
+        let state = self.state.lock().unwrap();
+        if self.status.is_playing() {
+            return;
+        }
+        let step = state
+            .current_sequence
+            .as_ref()
+            .or(state.cued_sequence.as_ref())
+            .map_or(0, |table| step_at_song_position(table.sequence(), position));
+        self.status.current_step.store(step, Ordering::Relaxed);
+    }
+
+    fn send(&self, command: PlaybackCommand) -> Result<(), SequencerError> {
+        self.playback_control.send(command).map_err(|_| {
+            println!("❌ Failed to send external transport command");
+            SequencerError::CommandSendFailed
+        })
+    }
+}
+
+/// Destination for raw MIDI messages. Implemented for midir connections;
+/// anything else, such as a test double, can stand in for a port.
+pub trait MidiSink: Send + 'static {
+    fn send(&mut self, message: &[u8]) -> Result<(), String>;
+
+    /// Send a message that belongs to `track`. Sinks that route tracks to
+    /// different ports pick the port here; the rest send it like any other.
+    fn send_for_track(&mut self, _track: u32, message: &[u8]) -> Result<(), String> {
+        self.send(message)
+    }
+
+    /// Channel (0-15) `track` is routed to, if the sink overrides the one
+    /// the sequence gives it.
+    fn track_channel(&self, _track: u32) -> Option<u8> {
+        None
+    }
+}
+
+impl MidiSink for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        MidiOutputConnection::send(self, message).map_err(|error| error.to_string())
+    }
+}
+
+impl MidiSink for Box<dyn MidiSink> {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        (**self).send(message)
+    }
+
+    fn send_for_track(&mut self, track: u32, message: &[u8]) -> Result<(), String> {
+        (**self).send_for_track(track, message)
+    }
+
+    fn track_channel(&self, track: u32) -> Option<u8> {
+        (**self).track_channel(track)
+    }
+}
+
+// Channel mode messages that silence a whole channel.
+const ALL_SOUND_OFF: u8 = 120;
+const ALL_NOTES_OFF: u8 = 123;
+
+pub struct MidiStepHandler<O: MidiSink = MidiOutputConnection> {
+    // The playback thread never waits for it; only silencing the port after a
+    // panic, from another thread, can hold it.
+    output: Mutex<MidiOutput<O>>,
+    // Whether this port drives clock followers.
+    sends_clock: bool,
+}
+
+/// The port of a `MidiStepHandler` and what the playback thread keeps about
+/// the notes it sends there.
+struct MidiOutput<O> {
+    connection: O,
+    // The playing sequence, for the channels of its tracks and how it numbers
+    // its octaves.
+    table: Option<Arc<EventTable>>,
+    sounding_notes: SoundingNotes,
+}
+
+/// Notes left sounding, by channel and MIDI note, each with the track and
+/// note of the trig that struck it, so its note-off follows it even if the
+/// sequence changes in between. Fixed in size, so keeping it never allocates.
+struct SoundingNotes(Box<[[Option<SoundingTrig>; 128]; 16]>);
+
+// Track of a trig and the key of one of its notes.
+type SoundingTrig = (u32, i64);
+
+impl SoundingNotes {
+    fn new() -> Self {
+        Self(Box::new([[None; 128]; 16]))
+    }
+
+    /// A note struck again belongs to the trig that struck it last, whose
+    /// note-off ends it.
+    fn strike(&mut self, channel: u8, midi_note: u8, trig: SoundingTrig) {
+        self.0[channel as usize][midi_note as usize] = Some(trig);
+    }
+
+    /// Channel and MIDI note the note of `trig` sounds on, cleared for its
+    /// note-off. Where the playing sequence would put it is looked at first,
+    /// then everywhere else.
+    fn release(&mut self, trig: SoundingTrig, expected: Option<(u8, u8)>) -> Option<(u8, u8)> {
+        let found = expected
+            .filter(|(channel, midi_note)| {
+                self.0[*channel as usize][*midi_note as usize] == Some(trig)
+            })
+            .or_else(|| {
+                (0..16u8)
+                    .flat_map(|channel| (0..128u8).map(move |midi_note| (channel, midi_note)))
+                    .find(|(channel, midi_note)| {
+                        self.0[*channel as usize][*midi_note as usize] == Some(trig)
+                    })
+            })?;
+        self.0[found.0 as usize][found.1 as usize] = None;
+        Some(found)
+    }
+
+    /// Clear every sounding note, handing each to `release` as its track,
+    /// channel and MIDI note.
+    fn release_all(&mut self, mut release: impl FnMut(u32, u8, u8)) {
+        for (channel, notes) in self.0.iter_mut().enumerate() {
+            for (midi_note, sounding) in notes.iter_mut().enumerate() {
+                if let Some((track, _)) = sounding.take() {
+                    release(track, channel as u8, midi_note as u8);
+                }
+            }
+        }
+    }
+}
+
+impl<O: MidiSink> MidiOutput<O> {
+    fn track_channel(&self, track: u32) -> u8 {
+        if let Some(channel) = self.connection.track_channel(track) {
+            return channel & 0x0F;
+        }
+        self.table
+            .as_ref()
+            .map_or((track % 16) as u8, |table| table.track_channel(track))
+    }
+
+    fn middle_c(&self) -> MiddleC {
+        self.table
+            .as_ref()
+            .map_or(MiddleC::default(), |table| table.middle_c())
+    }
+
+    // Note-offs for everything sounding, then All Notes Off and All Sound
+    // Off on every channel.
+    fn all_notes_off(&mut self) {
+        let MidiOutput {
+            connection,
+            sounding_notes,
+            ..
+        } = self;
+        sounding_notes.release_all(|track, channel, midi_note| {
+            if let Err(e) = connection.send_for_track(track, &[0x80 | channel, midi_note, 0]) {
+                rt_println!("   Track {}: Failed to send note off: {}", track, e);
+            }
+        });
+        for channel in 0..16 {
+            for controller in [ALL_NOTES_OFF, ALL_SOUND_OFF] {
+                if let Err(e) = connection.send(&[0xB0 | channel, controller, 0]) {
+                    rt_println!("   Failed to silence channel {}: {}", channel + 1, e);
+                }
+            }
+        }
+        rt_println!("   All notes off");
+    }
+}
+
+impl<O: MidiSink> MidiStepHandler<O> {
+    pub fn new(midi_connection: O) -> Self {
         Self {
-            midi_connection: Mutex::new(midi_connection),
+            output: Mutex::new(MidiOutput {
+                connection: midi_connection,
+                table: None,
+                sounding_notes: SoundingNotes::new(),
+            }),
+            sends_clock: false,
+        }
+    }
+
+    /// Like `new`, but also sends MIDI clock, Start, Stop, Continue and song
+    /// position on this port so chained devices follow the sequencer.
+    pub fn with_midi_clock(midi_connection: O) -> Self {
+        Self {
+            sends_clock: true,
+            ..Self::new(midi_connection)
+        }
+    }
+
+    /// The port, unless something is silencing it after a panic, in which
+    /// case the playback thread drops what it was about to send rather than
+    /// wait.
+    fn output(&self) -> Option<MutexGuard<'_, MidiOutput<O>>> {
+        match self.output.try_lock() {
+            Ok(output) => Some(output),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                rt_println!("   MIDI output busy, dropping messages");
+                None
+            }
         }
     }
 }
 
-impl StepHandler for MidiStepHandler {
-    fn handle_notes_on(&self, trigs: Vec<&Trig>) {
-        let mut connection = self.midi_connection.lock().unwrap();
+impl<O: MidiSink> StepHandler for MidiStepHandler<O> {
+    fn handle_notes_on(&self, trigs: &[&Trig]) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        let output = &mut *output;
         if trigs.is_empty() {
-            println!("   (silence)");
+            rt_println!("   (silence)");
         } else {
+            let middle_c = output.middle_c();
             for trig in trigs {
-                match &trig.note {
-                    Some(note) => {
-                        let midi_note = parse_note_to_midi(note);
-                        let channel = (trig.track % 16) as u8;
-                        let note_on_msg = [0x90 | channel, midi_note, note.velocity as u8];
-                        match connection.send(&note_on_msg) {
-                            Ok(_) => {
-                                let note_name = note_value_to_string(note.value);
-
-                                println!(
-                                    "   Track {}: Play {}{} (MIDI: {}, Velocity: {})",
-                                    trig.track, note_name, note.octave, midi_note, note.velocity
-                                );
-                            }
-
-                            Err(e) => {
-                                let note_name = note_value_to_string(note.value);
-                                println!(
-                                    "   Track {}: Failed to send note on for {}{}: {}",
-                                    trig.track, note_name, note.octave, e
-                                );
-                            }
+                if trig.is_rest() {
+                    rt_println!("   Track {}: REST", trig.track);
+                    continue;
+                }
+                let channel = output.track_channel(trig.track);
+                for note in trig.notes() {
+                    // Validated on cue, but a handler can be driven directly.
+                    let Some(pitch) = Pitch::from_note(note, middle_c) else {
+                        rt_println!("   Track {}: Skipping invalid note {}", trig.track, note);
+                        continue;
+                    };
+                    let midi_note = pitch.midi_note();
+                    output
+                        .sounding_notes
+                        .strike(channel, midi_note, (trig.track, note_key(note)));
+                    let note_on_msg = [0x90 | channel, midi_note, note.velocity as u8];
+                    match output.connection.send_for_track(trig.track, &note_on_msg) {
+                        Ok(_) => {
+                            rt_println!(
+                                "   Track {}: Play {} (MIDI: {})",
+                                trig.track,
+                                note,
+                                midi_note
+                            );
+                        }
+
+                        Err(e) => {
+                            rt_println!(
+                                "   Track {}: Failed to send note on for {}: {}",
+                                trig.track,
+                                note,
+                                e
+                            );
                         }
-                    }
-                    None => {
-                        println!("   Track {}: REST", trig.track);
                     }
                 }
             }
         }
     }
 
-    fn handle_notes_off(&self, trigs: Vec<&Trig>) {
-        let mut connection = self.midi_connection.lock().unwrap();
-        if !trigs.is_empty() {
-            for trig in trigs {
-                match &trig.note {
-                    Some(note) => {
-                        let midi_note = parse_note_to_midi(note);
-                        let channel = (trig.track % 16) as u8;
-                        let note_off_msg = [0x80 | channel, midi_note, 0];
-
-                        match connection.send(&note_off_msg) {
-                            Ok(_) => {
-                                let note_name = note_value_to_string(note.value);
-                                println!(
-                                    "   Track {}: Off {}{} (MIDI: {})",
-                                    trig.track, note_name, note.octave, midi_note
-                                );
-                            }
-                            Err(e) => {
-                                let note_name = note_value_to_string(note.value);
-                                println!(
-                                    "   Track {}: Failed to send note off for {}{}: {}",
-                                    trig.track, note_name, note.octave, e
-                                );
-                            }
-                        }
+    fn handle_notes_off(&self, trigs: &[&Trig]) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        let output = &mut *output;
+        let middle_c = output.middle_c();
+        // Rests have no note to turn off, and skipped notes never sounded.
+        for trig in trigs {
+            let channel = output.track_channel(trig.track);
+            for note in trig.notes() {
+                let expected =
+                    Pitch::from_note(note, middle_c).map(|pitch| (channel, pitch.midi_note()));
+                let sounding = output
+                    .sounding_notes
+                    .release((trig.track, note_key(note)), expected);
+                let Some((channel, midi_note)) = sounding else {
+                    continue;
+                };
+                let note_off_msg = [0x80 | channel, midi_note, 0];
+
+                match output.connection.send_for_track(trig.track, &note_off_msg) {
+                    Ok(_) => {
+                        rt_println!(
+                            "   Track {}: Off {} (MIDI: {})",
+                            trig.track,
+                            note,
+                            midi_note
+                        );
                     }
-                    None => {
-                        // No note to turn off for rests
+                    Err(e) => {
+                        rt_println!(
+                            "   Track {}: Failed to send note off for {}: {}",
+                            trig.track,
+                            note,
+                            e
+                        );
                     }
                 }
             }
         }
     }
+
+    fn handle_parameters(&self, track: u32, parameters: &ParameterLocks) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        let channel = output.track_channel(track);
+        let mut send = |message: &[u8]| {
+            if let Err(e) = output.connection.send_for_track(track, message) {
+                rt_println!("   Track {}: Failed to send parameter: {}", track, e);
+            }
+        };
+        // A new program goes first so the values that follow apply to it.
+        if let Some(program) = parameters.program {
+            send(&[0xC0 | channel, program as u8]);
+        }
+        for control_change in &parameters.control_changes {
+            send(&[
+                0xB0 | channel,
+                control_change.controller as u8,
+                control_change.value as u8,
+            ]);
+        }
+        if let Some(pitch_bend) = parameters.pitch_bend {
+            // 14-bit value centred on 8192, least significant seven bits first.
+            let value = (pitch_bend + 8192).clamp(0, 0x3FFF) as u16;
+            send(&[0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8]);
+        }
+        if let Some(pressure) = parameters.channel_pressure {
+            send(&[0xD0 | channel, pressure as u8]);
+        }
+        rt_println!("   Track {}: Parameters {}", track, parameters);
+    }
+
+    fn handle_sequence_change(&self, table: &Arc<EventTable>) {
+        if let Some(mut output) = self.output() {
+            output.table = Some(Arc::clone(table));
+        }
+    }
+
+    fn sends_clock(&self) -> bool {
+        self.sends_clock
+    }
+
+    fn handle_clock(&self, message: ClockMessage) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        if let Err(e) = output.connection.send(&message.to_bytes()) {
+            rt_println!("   Failed to send clock message {:?}: {}", message, e);
+        } else if message != ClockMessage::Pulse {
+            // Pulses are too frequent to log.
+            rt_println!("   Clock: {:?}", message);
+        }
+    }
+
+    /// Note-offs for every note still sounding, then All Notes Off and All
+    /// Sound Off on every channel of every port, for notes nothing here knew
+    /// about and for synths that ignore one of the two. Left to the panic if
+    /// one is silencing the port at the same time.
+    fn handle_all_notes_off(&self) {
+        if let Some(mut output) = self.output() {
+            output.all_notes_off();
+        }
+    }
+
+    fn handle_panic(&self) {
+        lock(&self.output).all_notes_off();
+    }
 }
 
-fn parse_note_to_midi(note: &SequenceNote) -> u8 {
-    ((note.octave * 12) + note.value as i32).try_into().unwrap()
+/// Tied trigs hold their note until the next trig on the same track instead
+/// of for a fixed length.
+fn trig_is_tied(trig: &Trig) -> bool {
+    trig.length == f32::INFINITY
 }
 
-fn note_value_to_string(value: i32) -> &'static str {
-    match value {
-        0 => "C",
-        1 => "C#",
-        2 => "D",
-        3 => "D#",
-        4 => "E",
-        5 => "F",
-        6 => "F#",
-        7 => "G",
-        8 => "G#",
-        9 => "A",
-        10 => "A#",
-        11 => "B",
-        _ => "?",
-    }
+fn wall_clock_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+// A handler that panicked mid-send leaves its locks poisoned; the playback
+// thread that replaces it still has to get through to the port.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Identifies a note regardless of how its name is spelled.
+pub(crate) fn note_key(note: &SequenceNote) -> i64 {
+    note.octave as i64 * 12 + note.value as i64
 }
@@ -1,23 +1,134 @@
+use crate::groove::{MAX_SWING, MIN_SWING};
+use crate::import::{import_midi, ImportError, ImportOptions};
+use crate::ports::{input_port_names, OutputPorts, PortError, PortSelector, SystemPorts};
+use crate::render::{render_midi, render_wav_with_samples, RenderError, RenderOptions};
+use crate::routing::{MidiRouter, RoutingError};
+use crate::sampler::{
+    Sample, SampleBank, SampleError, SampleSettings, TrackSample, DEFAULT_ROOT_KEY,
+};
 use crate::sequencer::{Sequencer, SequencerError};
+use crate::synth::{synth_track_is_valid, SYNTH_TRACKS};
+use crate::timing::MAX_SUBDIVISION_TERM;
+use crate::tracks::MAX_MIDI_CHANNEL;
+use crate::types::Pitch;
+use crate::validation::{
+    check_sequence, check_synth_sequence, InvalidSequence, Violation, MAX_VELOCITY,
+};
+use bytes::Bytes;
+use prost::Message;
 use sequence::sequencer_service_server::SequencerService;
-use sequence::{CueResponse, Empty, Sequence};
-use tonic::{Request, Response, Status};
+use sequence::{
+    CueResponse, Empty, ExportMidiRequest, FillRequest, ImportMidiRequest, ImportMidiResponse,
+    LoadSampleRequest, LoadedSample, MidiFile, MidiOutputSelection, MidiPorts, MidiRoutes,
+    RenderWavRequest, Sequence, StartResponse, TransportEvent, UnrepresentableNote, WavFile,
+};
+use std::pin::Pin;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Code, Request, Response, Status};
 
 pub mod sequence {
     tonic::include_proto!("sequence");
 }
 
+/// The `google.rpc` messages gRPC clients decode from the details of an
+/// error status.
+pub mod rpc {
+    pub const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: String,
+        #[prost(message, repeated, tag = "3")]
+        pub details: Vec<Any>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Any {
+        #[prost(string, tag = "1")]
+        pub type_url: String,
+        #[prost(bytes = "vec", tag = "2")]
+        pub value: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct BadRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub field_violations: Vec<FieldViolation>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct FieldViolation {
+        #[prost(string, tag = "1")]
+        pub field: String,
+        #[prost(string, tag = "2")]
+        pub description: String,
+    }
+}
+
 pub use sequence::sequencer_service_server::SequencerServiceServer;
 pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("sequence_descriptor");
 
 #[derive(Debug)]
 pub struct SequencerServiceImpl {
     sequencer: Sequencer,
+    // Outputs the step handler plays through, if they can be switched.
+    midi_router: Option<MidiRouter>,
+    // Samples of the synth the step handler plays on, if it does.
+    sample_bank: Option<SampleBank>,
 }
 
 impl SequencerServiceImpl {
     pub fn new(sequencer: Sequencer) -> Self {
-        Self { sequencer }
+        Self {
+            sequencer,
+            midi_router: None,
+            sample_bank: None,
+        }
+    }
+
+    /// Like `new`, but lets clients switch the step handler's MIDI outputs
+    /// and route tracks between them.
+    pub fn with_midi_router(sequencer: Sequencer, midi_router: MidiRouter) -> Self {
+        Self {
+            midi_router: Some(midi_router),
+            ..Self::new(sequencer)
+        }
+    }
+
+    /// Like `new`, for a sequencer playing on the built-in synth: clients
+    /// load samples for its tracks into `sample_bank`, and WAV renders play
+    /// them too.
+    pub fn with_sample_bank(sequencer: Sequencer, sample_bank: SampleBank) -> Self {
+        Self {
+            sample_bank: Some(sample_bank),
+            ..Self::new(sequencer)
+        }
+    }
+
+    /// Check a sequence sent to play, against the synth's tracks too when it
+    /// plays on the synth.
+    fn check_playable(&self, sequence: &Sequence) -> Result<(), InvalidSequence> {
+        match self.sample_bank {
+            Some(_) => check_synth_sequence(sequence),
+            None => check_sequence(sequence),
+        }
+    }
+
+    fn midi_ports(&self) -> Result<MidiPorts, PortError> {
+        Ok(MidiPorts {
+            outputs: SystemPorts.names()?,
+            inputs: input_port_names()?,
+            connected_output: self
+                .midi_router
+                .as_ref()
+                .and_then(|router| router.main_output().port_name())
+                .unwrap_or_default(),
+        })
     }
 }
 
@@ -33,18 +144,185 @@ impl From<SequencerError> for Status {
             SequencerError::NoSequenceCued => {
                 Status::failed_precondition("No sequence cued for playback")
             }
+            SequencerError::NothingToResume => {
+                Status::failed_precondition("No stopped sequence to resume")
+            }
             SequencerError::Other(msg) => Status::internal(format!("Sequencer error: {}", msg)),
+            invalid => Status::invalid_argument(describe_invalid(&invalid)),
+        }
+    }
+}
+
+/// What a client has to change for a sequence the sequencer rejected.
+fn describe_invalid(error: &SequencerError) -> String {
+    match error {
+        SequencerError::InvalidSubdivision {
+            numerator,
+            denominator,
+        } => format!(
+            "Subdivision {}/{} must have a numerator and denominator between 1 and {}",
+            numerator, denominator, MAX_SUBDIVISION_TERM
+        ),
+        SequencerError::InvalidSwing(swing) => format!(
+            "Swing {}% must be between {}% and {}%",
+            swing, MIN_SWING, MAX_SWING
+        ),
+        SequencerError::InvalidNote { track, step, note } => format!(
+            "Note {} on track {}, step {} must have a note value and lie within MIDI \
+             notes 0-{}",
+            note,
+            track,
+            step,
+            Pitch::MAX
+        ),
+        SequencerError::InvalidMidiChannel { track, channel } => format!(
+            "MIDI channel {} on track {} must be between 1 and {}, or 0 to follow the track number",
+            channel, track, MAX_MIDI_CHANNEL
+        ),
+        SequencerError::DuplicateTrack(track) => {
+            format!("Track {} must have only one track config", track)
+        }
+        SequencerError::EmptySequence => "Sequence length must be at least 1".to_string(),
+        SequencerError::StepOutOfRange {
+            track,
+            step,
+            length,
+        } => format!(
+            "Step {} on track {} must be below the track length of {}",
+            step, track, length
+        ),
+        SequencerError::InvalidVelocity {
+            track,
+            step,
+            velocity,
+        } => format!(
+            "Velocity {} on track {}, step {} must be at most {}",
+            velocity, track, step, MAX_VELOCITY
+        ),
+        SequencerError::NoSynthTrack(track) => format!(
+            "Track {} must be below {} to play on the built-in synth",
+            track, SYNTH_TRACKS
+        ),
+        _ => error.to_string(),
+    }
+}
+
+/// Reject a sequence with every violation it has, each as a field violation
+/// of a `google.rpc.BadRequest` in the status details, so clients can point
+/// at the fields to fix rather than parse the message.
+impl From<InvalidSequence> for Status {
+    fn from(InvalidSequence(violations): InvalidSequence) -> Self {
+        let message = match violations.as_slice() {
+            [violation] => describe_invalid(&violation.error),
+            _ => format!("Sequence has {} invalid fields", violations.len()),
+        };
+        let bad_request = rpc::BadRequest {
+            field_violations: violations
+                .iter()
+                .map(|violation| rpc::FieldViolation {
+                    field: violation.field.clone(),
+                    description: describe_invalid(&violation.error),
+                })
+                .collect(),
+        };
+        let details = rpc::Status {
+            code: Code::InvalidArgument as i32,
+            message: message.clone(),
+            details: vec![rpc::Any {
+                type_url: rpc::BAD_REQUEST_TYPE_URL.to_string(),
+                value: bad_request.encode_to_vec(),
+            }],
+        };
+        Status::with_details(
+            Code::InvalidArgument,
+            message,
+            Bytes::from(details.encode_to_vec()),
+        )
+    }
+}
+
+impl From<PortError> for Status {
+    fn from(error: PortError) -> Self {
+        match error {
+            PortError::Unavailable(_) | PortError::ConnectFailed { .. } => {
+                Status::unavailable(error.to_string())
+            }
+            PortError::NoPorts | PortError::NoMatch { .. } => Status::not_found(error.to_string()),
+        }
+    }
+}
+
+fn no_midi_router() -> Status {
+    Status::failed_precondition("MIDI outputs can't be switched")
+}
+
+fn no_sample_bank() -> Status {
+    Status::failed_precondition("Samples only play on the built-in synth")
+}
+
+impl From<SampleError> for Status {
+    fn from(error: SampleError) -> Self {
+        Status::invalid_argument(error.to_string())
+    }
+}
+
+impl From<RoutingError> for Status {
+    fn from(error: RoutingError) -> Self {
+        match error {
+            RoutingError::InvalidChannel { .. } | RoutingError::DuplicateTrack(_) => {
+                Status::invalid_argument(error.to_string())
+            }
+            RoutingError::Malformed { .. } | RoutingError::Io(_) => {
+                Status::internal(error.to_string())
+            }
+        }
+    }
+}
+
+impl From<RenderError> for Status {
+    fn from(error: RenderError) -> Self {
+        match error {
+            RenderError::InvalidSequence(invalid) => invalid.into(),
+            RenderError::Sequencer(error) => error.into(),
+            RenderError::NoLoops | RenderError::TooLong(_) | RenderError::TooManyTracks(_) => {
+                Status::invalid_argument(error.to_string())
+            }
         }
     }
 }
 
+/// Violations of a sequence sent to render, pointing at fields of the
+/// request.
+fn in_render_request(InvalidSequence(violations): InvalidSequence) -> InvalidSequence {
+    InvalidSequence(
+        violations
+            .into_iter()
+            .map(|violation| Violation {
+                field: format!("sequence.{}", violation.field),
+                ..violation
+            })
+            .collect(),
+    )
+}
+
+impl From<ImportError> for Status {
+    fn from(error: ImportError) -> Self {
+        Status::invalid_argument(error.to_string())
+    }
+}
+
+type TransportEventStream = Pin<Box<dyn Stream<Item = Result<TransportEvent, Status>> + Send>>;
+
 #[tonic::async_trait]
 impl SequencerService for SequencerServiceImpl {
+    type WatchTransportStream = TransportEventStream;
+
     async fn swap_sequence(&self, request: Request<Sequence>) -> Result<Response<Empty>, Status> {
         println!("Received a SwapSequence message");
 
-        // Convert the result directly using ? operator
-        self.sequencer.swap_sequence(request.into_inner())?;
+        let sequence = request.into_inner();
+        self.check_playable(&sequence)?;
+        self.sequencer.swap_sequence(sequence)?;
 
         Ok(Response::new(Empty {}))
     }
@@ -55,22 +333,45 @@ impl SequencerService for SequencerServiceImpl {
     ) -> Result<Response<CueResponse>, Status> {
         println!("Received a CueSequence message");
 
-        // Use the ? operator and pattern matching on the success case
-        let metadata = self.sequencer.cue_sequence(request.into_inner())?;
+        let sequence = request.into_inner();
+        self.check_playable(&sequence)?;
+        let metadata = self.sequencer.cue_sequence(sequence)?;
 
         Ok(Response::new(CueResponse {
             success: true, // Always true if we get here (no error)
             remaining_steps: metadata.remaining_steps,
+            sequence_id: metadata.sequence_id,
+            step_duration_nanos: metadata.step_duration.as_nanos() as u64,
         }))
     }
 
-    async fn start_sequence(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
+    async fn start_sequence(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<StartResponse>, Status> {
         println!("Got a StartSequence message");
 
         // Simple conversion using ? operator
-        self.sequencer.start_sequence()?;
+        let metadata = self.sequencer.start_sequence()?;
 
-        Ok(Response::new(Empty {}))
+        Ok(Response::new(StartResponse {
+            sequence_id: metadata.sequence_id,
+            step_duration_nanos: metadata.step_duration.as_nanos() as u64,
+        }))
+    }
+
+    async fn resume_sequence(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<StartResponse>, Status> {
+        println!("Got a ResumeSequence message");
+
+        let metadata = self.sequencer.resume_sequence()?;
+
+        Ok(Response::new(StartResponse {
+            sequence_id: metadata.sequence_id,
+            step_duration_nanos: metadata.step_duration.as_nanos() as u64,
+        }))
     }
 
     async fn stop_sequence(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
@@ -81,4 +382,219 @@ impl SequencerService for SequencerServiceImpl {
 
         Ok(Response::new(Empty {}))
     }
+
+    async fn panic(&self, _request: Request<Empty>) -> Result<Response<Empty>, Status> {
+        println!("Got a Panic request");
+
+        self.sequencer.panic()?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn set_fill(&self, request: Request<FillRequest>) -> Result<Response<Empty>, Status> {
+        println!("Got a SetFill request");
+
+        self.sequencer.set_fill(request.into_inner().active)?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn watch_transport(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::WatchTransportStream>, Status> {
+        println!("Got a WatchTransport request");
+
+        // A watcher that falls behind skips the events it missed rather than
+        // being disconnected; the next step event resynchronises it.
+        let events =
+            BroadcastStream::new(self.sequencer.subscribe_transport()).filter_map(|event| {
+                match event {
+                    Ok(event) => Some(Ok(event)),
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        println!("Transport watcher lagged, skipped {} events", skipped);
+                        None
+                    }
+                }
+            });
+
+        Ok(Response::new(Box::pin(events) as Self::WatchTransportStream))
+    }
+
+    async fn list_midi_ports(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<MidiPorts>, Status> {
+        println!("Got a ListMidiPorts request");
+
+        Ok(Response::new(self.midi_ports()?))
+    }
+
+    async fn select_midi_output(
+        &self,
+        request: Request<MidiOutputSelection>,
+    ) -> Result<Response<MidiPorts>, Status> {
+        println!("Got a SelectMidiOutput request");
+
+        self.midi_router
+            .as_ref()
+            .ok_or_else(no_midi_router)?
+            .select_main(PortSelector::new(&request.into_inner().port))?;
+
+        Ok(Response::new(self.midi_ports()?))
+    }
+
+    async fn get_midi_routes(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<MidiRoutes>, Status> {
+        println!("Got a GetMidiRoutes request");
+
+        let routes = self
+            .midi_router
+            .as_ref()
+            .ok_or_else(no_midi_router)?
+            .routes();
+        Ok(Response::new(MidiRoutes { routes }))
+    }
+
+    async fn set_midi_routes(
+        &self,
+        request: Request<MidiRoutes>,
+    ) -> Result<Response<MidiRoutes>, Status> {
+        println!("Got a SetMidiRoutes request");
+
+        let routes = self
+            .midi_router
+            .as_ref()
+            .ok_or_else(no_midi_router)?
+            .set_routes(request.into_inner().routes)?;
+        Ok(Response::new(MidiRoutes { routes }))
+    }
+
+    async fn export_midi(
+        &self,
+        request: Request<ExportMidiRequest>,
+    ) -> Result<Response<MidiFile>, Status> {
+        println!("Got an ExportMidi request");
+
+        let request = request.into_inner();
+        let sequence = request
+            .sequence
+            .ok_or_else(|| Status::invalid_argument("A sequence is needed to export"))?;
+        check_sequence(&sequence).map_err(in_render_request)?;
+        let options = RenderOptions {
+            loops: request.loops.max(1),
+            seed: request.seed,
+        };
+        // Rendering runs a sequencer of its own and takes a while for long
+        // sequences, so it stays off the async workers.
+        let data = tokio::task::spawn_blocking(move || render_midi(sequence, &options))
+            .await
+            .map_err(|error| Status::internal(format!("Render failed: {}", error)))??;
+
+        Ok(Response::new(MidiFile { data }))
+    }
+
+    async fn import_midi(
+        &self,
+        request: Request<ImportMidiRequest>,
+    ) -> Result<Response<ImportMidiResponse>, Status> {
+        println!("Got an ImportMidi request");
+
+        let request = request.into_inner();
+        let defaults = ImportOptions::default();
+        let options = ImportOptions {
+            middle_c: request.middle_c(),
+            subdivision: request.subdivision.unwrap_or(defaults.subdivision),
+        };
+        let imported = import_midi(&request.data, &options)?;
+
+        Ok(Response::new(ImportMidiResponse {
+            sequence: Some(imported.sequence),
+            unrepresentable_notes: imported
+                .unrepresentable
+                .into_iter()
+                .map(|note| UnrepresentableNote {
+                    channel: note.channel as u32,
+                    key: note.key as u32,
+                    tick: note.tick,
+                    reason: note.problem.to_string(),
+                })
+                .collect(),
+            ignored_events: imported.ignored_events as u32,
+            ignored_tempo_changes: imported.ignored_tempo_changes as u32,
+            tempo_clamped: imported.tempo_clamped,
+        }))
+    }
+
+    async fn render_wav(
+        &self,
+        request: Request<RenderWavRequest>,
+    ) -> Result<Response<WavFile>, Status> {
+        println!("Got a RenderWav request");
+
+        let request = request.into_inner();
+        let sequence = request
+            .sequence
+            .ok_or_else(|| Status::invalid_argument("A sequence is needed to render"))?;
+        check_synth_sequence(&sequence).map_err(in_render_request)?;
+        let options = RenderOptions {
+            loops: request.loops.max(1),
+            seed: request.seed,
+        };
+        let samples = self.sample_bank.clone().unwrap_or_default();
+        let data = tokio::task::spawn_blocking(move || {
+            render_wav_with_samples(sequence, &options, samples)
+        })
+        .await
+        .map_err(|error| Status::internal(format!("Render failed: {}", error)))??;
+
+        Ok(Response::new(WavFile { data }))
+    }
+
+    async fn load_sample(
+        &self,
+        request: Request<LoadSampleRequest>,
+    ) -> Result<Response<LoadedSample>, Status> {
+        println!("Got a LoadSample request");
+
+        let sample_bank = self.sample_bank.as_ref().ok_or_else(no_sample_bank)?;
+        let request = request.into_inner();
+        if !synth_track_is_valid(request.track) {
+            return Err(SampleError::NoSynthTrack(request.track).into());
+        }
+        if request.data.is_empty() {
+            sample_bank.clear(request.track);
+            println!("🎹 Track {} is back on the synth", request.track);
+            return Ok(Response::new(LoadedSample::default()));
+        }
+
+        let root_key = request.root_key.unwrap_or(DEFAULT_ROOT_KEY as u32);
+        let root_key = u8::try_from(root_key)
+            .ok()
+            .filter(|key| *key <= 127)
+            .ok_or(SampleError::InvalidRootKey(root_key))?;
+        let settings = SampleSettings {
+            root_key,
+            start: request.start,
+            length: if request.length == 0.0 {
+                1.0
+            } else {
+                request.length
+            },
+            looping: request.looping,
+        };
+        let sample = TrackSample::new(Sample::from_wav(&request.data)?, settings)?;
+        let loaded = LoadedSample {
+            frames: sample.sample.frames.len() as u32,
+            sample_rate: sample.sample.sample_rate,
+        };
+        sample_bank.assign(request.track, sample);
+        println!(
+            "🥁 Track {} plays a sample of {} frames at {} Hz",
+            request.track, loaded.frames, loaded.sample_rate
+        );
+        Ok(Response::new(loaded))
+    }
 }
@@ -0,0 +1,317 @@
+use std::time::Duration;
+
+/// Resolution of the files written here, in ticks per quarter note.
+pub const TICKS_PER_QUARTER: u16 = 480;
+
+const MICROS_PER_MINUTE: u64 = 60_000_000;
+const NANOS_PER_MINUTE: u128 = 60_000_000_000;
+
+// Meta event types.
+const TRACK_NAME: u8 = 0x03;
+const END_OF_TRACK: u8 = 0x2F;
+const SET_TEMPO: u8 = 0x51;
+
+/// A MIDI message at an absolute tick of its track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackEvent {
+    pub tick: u64,
+    pub message: Vec<u8>,
+}
+
+/// One track of a file: its name and its channel messages in time order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SmfTrack {
+    pub name: String,
+    pub events: Vec<TrackEvent>,
+}
+
+/// Ticks `elapsed` spans at a constant `bpm`, to the nearest tick.
+pub fn ticks(elapsed: Duration, bpm: u32) -> u64 {
+    let ticks_per_minute = TICKS_PER_QUARTER as u128 * bpm as u128;
+    ((elapsed.as_nanos() * ticks_per_minute + NANOS_PER_MINUTE / 2) / NANOS_PER_MINUTE) as u64
+}
+
+/// A Type-1 Standard MIDI File at a constant `bpm`: a tempo track, then
+/// `tracks` in order. Every track runs to `end_tick`, or to its last event if
+/// that comes later, so the file keeps the full length even where it ends in
+/// silence.
+///
+/// Panics with more tracks than a file can hold, 65534 besides the tempo
+/// track.
+pub fn write_type1(bpm: u32, tracks: &[SmfTrack], end_tick: u64) -> Vec<u8> {
+    let track_count = u16::try_from(tracks.len() + 1).expect("Too many tracks for a MIDI file");
+
+    let mut file = Vec::new();
+    file.extend_from_slice(b"MThd");
+    file.extend_from_slice(&6u32.to_be_bytes());
+    file.extend_from_slice(&1u16.to_be_bytes());
+    file.extend_from_slice(&track_count.to_be_bytes());
+    file.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+
+    // Microseconds per quarter note, in three bytes.
+    let tempo = MICROS_PER_MINUTE / bpm.max(1) as u64;
+    let mut tempo_track = TrackChunk::default();
+    tempo_track.meta(0, SET_TEMPO, &tempo.min(0xFF_FFFF).to_be_bytes()[5..]);
+    tempo_track.finish(end_tick, &mut file);
+
+    for track in tracks {
+        let mut chunk = TrackChunk::default();
+        chunk.meta(0, TRACK_NAME, track.name.as_bytes());
+        for event in &track.events {
+            chunk.event(event.tick, &event.message);
+        }
+        chunk.finish(end_tick, &mut file);
+    }
+    file
+}
+
+// Events of one MTrk chunk as they are written, with delta times.
+#[derive(Default)]
+struct TrackChunk {
+    data: Vec<u8>,
+    tick: u64,
+}
+
+impl TrackChunk {
+    // Events are expected in time order; one that isn't is written at the
+    // tick of the one before it.
+    fn event(&mut self, tick: u64, message: &[u8]) {
+        write_variable_length(&mut self.data, tick.saturating_sub(self.tick));
+        self.tick = self.tick.max(tick);
+        self.data.extend_from_slice(message);
+    }
+
+    fn meta(&mut self, tick: u64, kind: u8, data: &[u8]) {
+        let mut header = vec![0xFF, kind];
+        write_variable_length(&mut header, data.len() as u64);
+        header.extend_from_slice(data);
+        self.event(tick, &header);
+    }
+
+    fn finish(mut self, end_tick: u64, file: &mut Vec<u8>) {
+        self.meta(end_tick.max(self.tick), END_OF_TRACK, &[]);
+        file.extend_from_slice(b"MTrk");
+        file.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        file.extend_from_slice(&self.data);
+    }
+}
+
+// Seven bits a byte, most significant first, with the top bit set on all but
+// the last.
+fn write_variable_length(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0u8; 10];
+    let mut start = bytes.len() - 1;
+    bytes[start] = (value & 0x7F) as u8;
+    let mut rest = value >> 7;
+    while rest > 0 {
+        start -= 1;
+        bytes[start] = 0x80 | (rest & 0x7F) as u8;
+        rest >>= 7;
+    }
+    out.extend_from_slice(&bytes[start..]);
+}
+
+/// What a file holds that the sequencer can use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmfContents {
+    pub ticks_per_quarter: u16,
+    /// Every track with its channel messages, in the order of the file.
+    pub tracks: Vec<SmfTrack>,
+    /// Tempo changes as the tick and microseconds per quarter note, in time
+    /// order.
+    pub tempos: Vec<(u64, u32)>,
+    /// Tick the longest track ends at.
+    pub end_tick: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmfError {
+    NotMidi,
+    Truncated,
+    // Format 2 files hold independent patterns rather than one piece.
+    UnsupportedFormat(u16),
+    // Time counted in SMPTE frames rather than ticks per quarter note.
+    SmpteTiming,
+    Malformed { offset: usize, reason: &'static str },
+}
+
+impl std::fmt::Display for SmfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SmfError::NotMidi => write!(f, "Not a Standard MIDI File"),
+            SmfError::Truncated => write!(f, "MIDI file ends early"),
+            SmfError::UnsupportedFormat(format) => write!(
+                f,
+                "MIDI file format {} isn't supported, only formats 0 and 1",
+                format
+            ),
+            SmfError::SmpteTiming => write!(f, "MIDI files timed in SMPTE frames aren't supported"),
+            SmfError::Malformed { offset, reason } => {
+                write!(f, "Malformed MIDI file at byte {}: {}", offset, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SmfError {}
+
+/// Read a format 0 or 1 file. Channel messages keep their status byte even
+/// where the file relies on running status; system exclusive and meta
+/// events other than track names and tempos are dropped.
+pub fn read(bytes: &[u8]) -> Result<SmfContents, SmfError> {
+    let mut reader = Reader {
+        bytes,
+        at: 0,
+        base: 0,
+    };
+    if reader.take(4).map_err(|_| SmfError::NotMidi)? != b"MThd" {
+        return Err(SmfError::NotMidi);
+    }
+    let header_length = reader.u32()? as usize;
+    if header_length < 6 {
+        return Err(reader.malformed("header too short"));
+    }
+    let format = reader.u16()?;
+    let track_count = reader.u16()?;
+    let division = reader.u16()?;
+    reader.take(header_length - 6)?;
+    if format > 1 {
+        return Err(SmfError::UnsupportedFormat(format));
+    }
+    if division & 0x8000 != 0 {
+        return Err(SmfError::SmpteTiming);
+    }
+    if division == 0 {
+        return Err(reader.malformed("zero ticks per quarter note"));
+    }
+
+    let mut contents = SmfContents {
+        ticks_per_quarter: division,
+        tracks: Vec::new(),
+        tempos: Vec::new(),
+        end_tick: 0,
+    };
+    while contents.tracks.len() < track_count as usize {
+        let kind = reader.take(4)?;
+        let length = reader.u32()? as usize;
+        let offset = reader.at;
+        let data = reader.take(length)?;
+        // Chunks of other kinds are for other software to read.
+        if kind == b"MTrk" {
+            read_track(data, offset, &mut contents)?;
+        }
+    }
+    contents.tempos.sort_by_key(|(tick, _)| *tick);
+    Ok(contents)
+}
+
+fn read_track(data: &[u8], offset: usize, contents: &mut SmfContents) -> Result<(), SmfError> {
+    let mut reader = Reader {
+        bytes: data,
+        at: 0,
+        base: offset,
+    };
+    let mut track = SmfTrack::default();
+    let mut tick = 0u64;
+    let mut running_status = None;
+    while reader.at < data.len() {
+        tick = tick.saturating_add(reader.variable_length()?);
+        let status = match reader.peek()? {
+            byte if byte & 0x80 != 0 => {
+                reader.at += 1;
+                byte
+            }
+            _ => running_status.ok_or_else(|| reader.malformed("data byte without a status"))?,
+        };
+        match status {
+            0xFF => {
+                let kind = reader.take(1)?[0];
+                let length = reader.variable_length()? as usize;
+                let meta = reader.take(length)?;
+                match kind {
+                    TRACK_NAME => track.name = String::from_utf8_lossy(meta).into_owned(),
+                    SET_TEMPO if length == 3 => contents
+                        .tempos
+                        .push((tick, u32::from_be_bytes([0, meta[0], meta[1], meta[2]]))),
+                    END_OF_TRACK => break,
+                    _ => {}
+                }
+                running_status = None;
+            }
+            0xF0 | 0xF7 => {
+                let length = reader.variable_length()? as usize;
+                reader.take(length)?;
+                running_status = None;
+            }
+            0x80..=0xEF => {
+                let data_length = if matches!(status & 0xF0, 0xC0 | 0xD0) {
+                    1
+                } else {
+                    2
+                };
+                let values = reader.take(data_length)?;
+                if values.iter().any(|value| value & 0x80 != 0) {
+                    return Err(reader.malformed("status byte inside a message"));
+                }
+                let mut message = vec![status];
+                message.extend_from_slice(values);
+                track.events.push(TrackEvent { tick, message });
+                running_status = Some(status);
+            }
+            _ => return Err(reader.malformed("system message in a track")),
+        }
+    }
+    contents.end_tick = contents.end_tick.max(tick);
+    contents.tracks.push(track);
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    // Where `bytes` start in the file, for error offsets.
+    base: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SmfError> {
+        let end = self.at.checked_add(count).ok_or(SmfError::Truncated)?;
+        let taken = self.bytes.get(self.at..end).ok_or(SmfError::Truncated)?;
+        self.at = end;
+        Ok(taken)
+    }
+
+    fn peek(&self) -> Result<u8, SmfError> {
+        self.bytes.get(self.at).copied().ok_or(SmfError::Truncated)
+    }
+
+    fn u16(&mut self) -> Result<u16, SmfError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, SmfError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // At most four bytes, as the format allows.
+    fn variable_length(&mut self) -> Result<u64, SmfError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.take(1)?[0];
+            value = (value << 7) | (byte & 0x7F) as u64;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.malformed("variable-length quantity over four bytes"))
+    }
+
+    fn malformed(&self, reason: &'static str) -> SmfError {
+        SmfError::Malformed {
+            offset: self.base + self.at,
+            reason,
+        }
+    }
+}
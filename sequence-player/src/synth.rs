@@ -0,0 +1,702 @@
+use crate::event_table::EventTable;
+use crate::rt_println;
+use crate::sampler::{SampleBank, TrackSample};
+use crate::sequencer::{note_key, StepHandler};
+use crate::server::sequence::{ParameterLocks, Trig};
+use crate::types::{MiddleC, Pitch};
+use std::f32::consts::TAU;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+
+/// Rate the synth renders at unless told otherwise.
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// Voices sounding at once; past that the oldest gives way.
+pub const MAX_VOICES: usize = 32;
+/// Tracks the synth plays, numbered from 0. Sequences and samples for any
+/// other track are turned away when they arrive.
+pub const SYNTH_TRACKS: usize = 16;
+
+// General MIDI puts percussion on channel 10.
+const DRUM_CHANNEL: u8 = 9;
+const VOLUME_CONTROLLER: u32 = 7;
+// Headroom so a handful of voices together stay clear of clipping.
+const MASTER_GAIN: f32 = 0.25;
+// How fast All Notes Off fades voices out, in seconds, short enough to be
+// silence and long enough not to click.
+const CUT_OFF_TIME: f32 = 0.005;
+// A drum voice is done after this many of its decay times.
+const DRUM_DECAYS: f32 = 8.0;
+
+/// Oscillator shape of a tone. Programs pick one in this order, wrapping
+/// round, so the program of a track's channel changes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+}
+
+const WAVEFORMS: [Waveform; 4] = [
+    Waveform::Saw,
+    Waveform::Square,
+    Waveform::Triangle,
+    Waveform::Sine,
+];
+
+impl Waveform {
+    pub fn for_program(program: u32) -> Waveform {
+        WAVEFORMS[program as usize % WAVEFORMS.len()]
+    }
+
+    // One cycle, with `phase` from 0 to 1.
+    fn sample(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 4.0 * (phase - 0.5).abs() - 1.0,
+            Waveform::Sine => (TAU * phase).sin(),
+        }
+    }
+}
+
+/// ADSR envelope: attack, decay and release in seconds, sustain as a level
+/// from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self {
+            attack: 0.005,
+            decay: 0.15,
+            sustain: 0.6,
+            release: 0.2,
+        }
+    }
+}
+
+impl Envelope {
+    // Level `time` seconds into a note that is still held.
+    fn held_level(&self, time: f32) -> f32 {
+        if time < self.attack {
+            return time / self.attack;
+        }
+        let decaying = time - self.attack;
+        if decaying < self.decay {
+            1.0 - (1.0 - self.sustain) * decaying / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
+
+/// How a track sounds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Patch {
+    /// An oscillator at the note's pitch, shaped by an envelope.
+    Tone {
+        waveform: Waveform,
+        envelope: Envelope,
+    },
+    /// General MIDI percussion: each key plays a drum, which rings out on
+    /// its own whatever the note length.
+    Drums,
+    /// A recorded sound, played faster for notes above its root key and
+    /// slower for those below.
+    Sample(Arc<TrackSample>),
+}
+
+impl Patch {
+    /// Drums for tracks on MIDI channel 10, otherwise a tone whose waveform
+    /// follows the track number.
+    pub fn for_track(track: u32, midi_channel: u8) -> Patch {
+        if midi_channel == DRUM_CHANNEL {
+            Patch::Drums
+        } else {
+            Patch::Tone {
+                waveform: Waveform::for_program(track),
+                envelope: Envelope::default(),
+            }
+        }
+    }
+}
+
+/// Drum voices, each standing in for a group of General MIDI percussion
+/// keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drum {
+    Kick,
+    Snare,
+    Tom,
+    ClosedHat,
+    OpenHat,
+    Cymbal,
+}
+
+impl Drum {
+    pub fn for_key(key: u8) -> Drum {
+        match key {
+            0..=36 => Drum::Kick,
+            37..=40 => Drum::Snare,
+            42 | 44 => Drum::ClosedHat,
+            46 => Drum::OpenHat,
+            49 | 51 | 52 | 53 | 55 | 57 | 59 => Drum::Cymbal,
+            _ => Drum::Tom,
+        }
+    }
+
+    // Seconds for the drum to fall to a third of its level.
+    fn decay(self) -> f32 {
+        match self {
+            Drum::Kick => 0.15,
+            Drum::Snare => 0.08,
+            Drum::Tom => 0.2,
+            Drum::ClosedHat => 0.02,
+            Drum::OpenHat => 0.15,
+            Drum::Cymbal => 0.5,
+        }
+    }
+}
+
+/// What the synth is asked to do, in the order it happens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SynthEvent {
+    NoteOn {
+        track: u32,
+        key: u8,
+        velocity: u8,
+        patch: Patch,
+    },
+    NoteOff {
+        track: u32,
+        key: u8,
+    },
+    /// Level from 0 to 1 for everything on the track, as controller 7 sets
+    /// it.
+    Volume {
+        track: u32,
+        level: f32,
+    },
+    /// Fade every voice out at once.
+    AllNotesOff,
+}
+
+// Where a voice let go, and how long it takes to fade from there.
+#[derive(Debug, Clone, Copy)]
+struct Release {
+    time: f32,
+    level: f32,
+    length: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Voice {
+    track: u32,
+    key: u8,
+    patch: Patch,
+    gain: f32,
+    frequency: f32,
+    // Position in the oscillator cycle, from 0 to 1.
+    phase: f32,
+    // Seconds since the note started.
+    time: f32,
+    release: Option<Release>,
+    // Previous noise sample, to high-pass noise for cymbals.
+    last_noise: f32,
+    // Frame of a sample playing, and frames it moves on by per output frame.
+    position: f64,
+    speed: f64,
+}
+
+impl Voice {
+    // Level of the envelope, or of the cut-off fade for drums and samples.
+    fn level(&self) -> f32 {
+        let held = |time| match &self.patch {
+            Patch::Tone { envelope, .. } => envelope.held_level(time),
+            Patch::Drums | Patch::Sample(_) => 1.0,
+        };
+        match self.release {
+            None => held(self.time),
+            Some(release) if release.length <= 0.0 => 0.0,
+            Some(release) => {
+                let fading = (self.time - release.time) / release.length;
+                release.level * (1.0 - fading).max(0.0)
+            }
+        }
+    }
+
+    fn finished(&self) -> bool {
+        let faded = self.release.is_some() && self.level() <= 0.0;
+        match &self.patch {
+            Patch::Tone { .. } => faded,
+            Patch::Drums => faded || self.time > Drum::for_key(self.key).decay() * DRUM_DECAYS,
+            Patch::Sample(sample) => {
+                faded || (!sample.settings.looping && self.position >= sample.region().1 as f64)
+            }
+        }
+    }
+
+    fn let_go(&mut self, length: f32) {
+        self.release = Some(Release {
+            time: self.time,
+            level: self.level(),
+            length,
+        });
+    }
+
+    fn is_held(&self, track: u32, key: u8) -> bool {
+        self.track == track && self.key == key && self.release.is_none()
+    }
+
+    fn sample(&mut self, noise: f32, sample_period: f32) -> f32 {
+        let sample = match &self.patch {
+            Patch::Tone { waveform, .. } => {
+                let sample = waveform.sample(self.phase);
+                self.advance(self.frequency, sample_period);
+                sample
+            }
+            Patch::Drums => self.drum_sample(noise, sample_period),
+            Patch::Sample(sample) => sample_frame(sample, &mut self.position, self.speed),
+        };
+        self.time += sample_period;
+        sample * self.level() * self.gain
+    }
+
+    fn drum_sample(&mut self, noise: f32, sample_period: f32) -> f32 {
+        let drum = Drum::for_key(self.key);
+        let time = self.time;
+        let decay = (-time / drum.decay()).exp();
+        let high_passed = (noise - self.last_noise) * 0.5;
+        self.last_noise = noise;
+        match drum {
+            Drum::Kick => {
+                let tone = (TAU * self.phase).sin();
+                // Pitch falls fast from the click to the body.
+                self.advance(50.0 + 100.0 * (-time * 30.0).exp(), sample_period);
+                tone * decay
+            }
+            Drum::Snare => {
+                let tone = (TAU * self.phase).sin() * (-time / 0.04).exp();
+                self.advance(185.0, sample_period);
+                0.5 * tone + 0.6 * noise * decay
+            }
+            Drum::Tom => {
+                let tone = (TAU * self.phase).sin();
+                self.advance(
+                    self.frequency * (1.0 + 0.5 * (-time * 20.0).exp()),
+                    sample_period,
+                );
+                tone * decay
+            }
+            Drum::ClosedHat | Drum::OpenHat | Drum::Cymbal => high_passed * decay,
+        }
+    }
+
+    fn advance(&mut self, frequency: f32, sample_period: f32) {
+        self.phase = (self.phase + frequency * sample_period).fract();
+    }
+}
+
+// The frame of `sample` at `position`, between two frames interpolated,
+// then on to the next. Looping samples wrap round to the region start.
+fn sample_frame(sample: &TrackSample, position: &mut f64, speed: f64) -> f32 {
+    let (start, end) = sample.region();
+    if *position >= end as f64 {
+        if !sample.settings.looping {
+            return 0.0;
+        }
+        let looped = (*position - start as f64) % (end - start) as f64;
+        *position = start as f64 + looped;
+    }
+    let frames = &sample.sample.frames;
+    let index = *position as usize;
+    let fraction = (*position - index as f64) as f32;
+    let next = if index + 1 < end {
+        frames[index + 1]
+    } else if sample.settings.looping {
+        frames[start]
+    } else {
+        0.0
+    };
+    *position += speed;
+    frames[index] + (next - frames[index]) * fraction
+}
+
+/// Polyphonic synth: tones, drums and samples mixed down to mono samples.
+/// It keeps no clock of its own; events apply from the next sample rendered.
+pub struct Synth {
+    sample_rate: u32,
+    sample_period: f32,
+    voices: Vec<Voice>,
+    // Level of each track, as controller 7 last set it.
+    volumes: [f32; SYNTH_TRACKS],
+    // Xorshift state, so the same events always render the same samples.
+    noise: u32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            sample_period: 1.0 / sample_rate as f32,
+            voices: Vec::with_capacity(MAX_VOICES),
+            volumes: [1.0; SYNTH_TRACKS],
+            noise: 0x9E37_79B9,
+        }
+    }
+
+    pub fn handle(&mut self, event: SynthEvent) {
+        match event {
+            SynthEvent::NoteOn {
+                track,
+                key,
+                velocity,
+                patch,
+            } => {
+                if !synth_track_is_valid(track) {
+                    return;
+                }
+                // A key struck again lets go of the note it was holding.
+                for voice in &mut self.voices {
+                    if voice.is_held(track, key) && matches!(voice.patch, Patch::Tone { .. }) {
+                        voice.let_go(CUT_OFF_TIME);
+                    }
+                }
+                if self.voices.len() >= MAX_VOICES {
+                    let oldest = (0..self.voices.len())
+                        .max_by(|&a, &b| self.voices[a].time.total_cmp(&self.voices[b].time));
+                    if let Some(oldest) = oldest {
+                        self.voices.swap_remove(oldest);
+                    }
+                }
+                let (position, speed) = match &patch {
+                    Patch::Sample(sample) => {
+                        let transpose = (key as f64 - sample.settings.root_key as f64) / 12.0;
+                        let rate = sample.sample.sample_rate as f64 / self.sample_rate as f64;
+                        (sample.region().0 as f64, transpose.exp2() * rate)
+                    }
+                    _ => (0.0, 0.0),
+                };
+                self.voices.push(Voice {
+                    track,
+                    key,
+                    patch,
+                    gain: velocity.min(127) as f32 / 127.0,
+                    frequency: 440.0 * ((key as f32 - 69.0) / 12.0).exp2(),
+                    phase: 0.0,
+                    time: 0.0,
+                    release: None,
+                    last_noise: 0.0,
+                    position,
+                    speed,
+                });
+            }
+            SynthEvent::NoteOff { track, key } => {
+                for voice in &mut self.voices {
+                    if !voice.is_held(track, key) {
+                        continue;
+                    }
+                    // Drums and samples played once through ignore it.
+                    match &voice.patch {
+                        Patch::Tone { envelope, .. } => voice.let_go(envelope.release),
+                        Patch::Sample(sample) if sample.settings.looping => {
+                            voice.let_go(CUT_OFF_TIME)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            SynthEvent::Volume { track, level } => {
+                if let Some(volume) = self.volumes.get_mut(track as usize) {
+                    *volume = level.clamp(0.0, 1.0);
+                }
+            }
+            SynthEvent::AllNotesOff => {
+                for voice in &mut self.voices {
+                    if !matches!(voice.release, Some(release) if release.length <= CUT_OFF_TIME) {
+                        voice.let_go(CUT_OFF_TIME);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mix the sounding voices into `output`, one mono sample a frame, and
+    /// move on by as many frames.
+    pub fn render(&mut self, output: &mut [f32]) {
+        for frame in output.iter_mut() {
+            let noise = self.next_noise();
+            let mut mix = 0.0;
+            for voice in &mut self.voices {
+                let volume = self.volumes[voice.track as usize];
+                mix += voice.sample(noise, self.sample_period) * volume;
+            }
+            *frame = (mix * MASTER_GAIN).clamp(-1.0, 1.0);
+        }
+        self.voices.retain(|voice| !voice.finished());
+    }
+
+    /// Voices still sounding, including those fading out.
+    pub fn voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    fn next_noise(&mut self) -> f32 {
+        self.noise ^= self.noise << 13;
+        self.noise ^= self.noise >> 17;
+        self.noise ^= self.noise << 5;
+        self.noise as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// Where a `SynthStepHandler` sends its events: a recorder for offline
+/// renders or the queue to an audio device.
+pub trait SynthSink: Send + 'static {
+    fn send(&mut self, event: SynthEvent);
+}
+
+/// Step handler that plays on the built-in synth rather than over MIDI.
+/// Tracks with a sample assigned play it; of the rest, those on MIDI channel
+/// 10 play drums and the others tones, with the waveform set by the program
+/// of the track's channel.
+pub struct SynthStepHandler<S: SynthSink> {
+    // The playback thread never waits for it; only silencing the synth after
+    // a panic, from another thread, can hold it.
+    output: Mutex<SynthOutput<S>>,
+    samples: SampleBank,
+}
+
+/// The sink of a `SynthStepHandler` and what the playback thread keeps about
+/// the notes it plays there.
+struct SynthOutput<S> {
+    sink: S,
+    // The playing sequence, for the channels of its tracks and how it numbers
+    // its octaves.
+    table: Option<Arc<EventTable>>,
+    // Program each channel is on, as the playing sequence's locks leave it.
+    // Tracks sharing a channel share it, as they would on a MIDI synth.
+    programs: [Option<u32>; 16],
+    sounding_notes: SoundingNotes,
+}
+
+/// Notes left sounding, by track and key, each with the note of the trig
+/// that struck it, so its note-off follows it even if the sequence changes
+/// in between. Fixed in size, so keeping it never allocates.
+struct SoundingNotes(Box<[[Option<i64>; 128]; SYNTH_TRACKS]>);
+
+impl SoundingNotes {
+    fn new() -> Self {
+        Self(Box::new([[None; 128]; SYNTH_TRACKS]))
+    }
+
+    /// A key struck again belongs to the note that struck it last, whose
+    /// note-off ends it.
+    fn strike(&mut self, track: u32, key: u8, note: i64) {
+        if let Some(keys) = self.0.get_mut(track as usize) {
+            keys[key as usize] = Some(note);
+        }
+    }
+
+    /// Key `note` of `track` sounds on, cleared for its note-off.
+    fn release(&mut self, track: u32, note: i64) -> Option<u8> {
+        let keys = self.0.get_mut(track as usize)?;
+        let key = keys.iter().position(|sounding| *sounding == Some(note))?;
+        keys[key] = None;
+        Some(key as u8)
+    }
+
+    fn clear(&mut self) {
+        for keys in self.0.iter_mut() {
+            keys.fill(None);
+        }
+    }
+}
+
+impl<S: SynthSink> SynthOutput<S> {
+    fn track_channel(&self, track: u32) -> u8 {
+        self.table
+            .as_ref()
+            .map_or((track % 16) as u8, |table| table.track_channel(track))
+    }
+
+    fn middle_c(&self) -> MiddleC {
+        self.table
+            .as_ref()
+            .map_or(MiddleC::default(), |table| table.middle_c())
+    }
+
+    fn patch(&self, samples: &SampleBank, track: u32) -> Patch {
+        if let Some(sample) = samples.get(track) {
+            return Patch::Sample(sample);
+        }
+        let channel = self.track_channel(track);
+        match (
+            Patch::for_track(track, channel),
+            self.programs[channel as usize],
+        ) {
+            (Patch::Tone { envelope, .. }, Some(program)) => Patch::Tone {
+                waveform: Waveform::for_program(program),
+                envelope,
+            },
+            (patch, _) => patch,
+        }
+    }
+
+    fn all_notes_off(&mut self) {
+        self.sounding_notes.clear();
+        self.sink.send(SynthEvent::AllNotesOff);
+        rt_println!("   All notes off");
+    }
+}
+
+impl<S: SynthSink> SynthStepHandler<S> {
+    pub fn new(sink: S) -> Self {
+        Self::with_samples(sink, SampleBank::default())
+    }
+
+    /// Like `new`, but tracks play the samples `samples` assigns them, as
+    /// they stand at each note.
+    pub fn with_samples(sink: S, samples: SampleBank) -> Self {
+        Self {
+            output: Mutex::new(SynthOutput {
+                sink,
+                table: None,
+                programs: [None; 16],
+                sounding_notes: SoundingNotes::new(),
+            }),
+            samples,
+        }
+    }
+
+    /// The synth, unless something is silencing it after a panic, in which
+    /// case the playback thread drops what it was about to play rather than
+    /// wait.
+    fn output(&self) -> Option<MutexGuard<'_, SynthOutput<S>>> {
+        match self.output.try_lock() {
+            Ok(output) => Some(output),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => {
+                rt_println!("   Synth busy, dropping events");
+                None
+            }
+        }
+    }
+}
+
+impl<S: SynthSink> StepHandler for SynthStepHandler<S> {
+    fn handle_notes_on(&self, trigs: &[&Trig]) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        let output = &mut *output;
+        let middle_c = output.middle_c();
+        for trig in trigs {
+            // Turned away on cue, but a handler can be driven directly.
+            if !synth_track_is_valid(trig.track) {
+                rt_println!(
+                    "   Track {}: Skipping, the synth has no such track",
+                    trig.track
+                );
+                continue;
+            }
+            let patch = output.patch(&self.samples, trig.track);
+            let played_on = match patch {
+                Patch::Sample(_) => "Sample",
+                _ => "Synth",
+            };
+            for note in trig.notes() {
+                // Validated on cue, but a handler can be driven directly.
+                let Some(pitch) = Pitch::from_note(note, middle_c) else {
+                    rt_println!("   Track {}: Skipping invalid note {}", trig.track, note);
+                    continue;
+                };
+                let key = pitch.midi_note();
+                output
+                    .sounding_notes
+                    .strike(trig.track, key, note_key(note));
+                output.sink.send(SynthEvent::NoteOn {
+                    track: trig.track,
+                    key,
+                    velocity: note.velocity.min(127) as u8,
+                    patch: patch.clone(),
+                });
+                rt_println!("   Track {}: {} {}", trig.track, played_on, note);
+            }
+        }
+    }
+
+    fn handle_notes_off(&self, trigs: &[&Trig]) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        let output = &mut *output;
+        for trig in trigs {
+            for note in trig.notes() {
+                let sounding = output.sounding_notes.release(trig.track, note_key(note));
+                if let Some(key) = sounding {
+                    output.sink.send(SynthEvent::NoteOff {
+                        track: trig.track,
+                        key,
+                    });
+                }
+            }
+        }
+    }
+
+    fn handle_parameters(&self, track: u32, parameters: &ParameterLocks) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        if let Some(program) = parameters.program {
+            let channel = output.track_channel(track);
+            output.programs[channel as usize] = Some(program);
+        }
+        for control_change in &parameters.control_changes {
+            if control_change.controller == VOLUME_CONTROLLER {
+                output.sink.send(SynthEvent::Volume {
+                    track,
+                    level: control_change.value as f32 / 127.0,
+                });
+            }
+        }
+    }
+
+    fn handle_sequence_change(&self, table: &Arc<EventTable>) {
+        let Some(mut output) = self.output() else {
+            return;
+        };
+        output.table = Some(Arc::clone(table));
+        output.programs = [None; 16];
+    }
+
+    fn handle_all_notes_off(&self) {
+        if let Some(mut output) = self.output() {
+            output.all_notes_off();
+        }
+    }
+
+    fn handle_panic(&self) {
+        lock(&self.output).all_notes_off();
+    }
+}
+
+/// Whether the synth plays `track`.
+pub fn synth_track_is_valid(track: u32) -> bool {
+    (track as usize) < SYNTH_TRACKS
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
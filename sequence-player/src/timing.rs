@@ -0,0 +1,437 @@
+use crate::realtime;
+use crate::server::sequence::{Sequence, Subdivision};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+// How long a virtual clock waits for the playback thread to catch up before
+// giving up on it.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Native sleeps are only trusted to wake up within this margin of a deadline;
+// the remainder is spun by spin_sleep.
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+const NANOS_PER_MINUTE: u128 = 60_000_000_000;
+
+// Largest numerator or denominator accepted in a subdivision. Anything beyond
+// this is far outside musical use and only risks overflowing the timeline.
+pub const MAX_SUBDIVISION_TERM: i64 = 1024;
+
+// Tempos sequences play at. Anything outside is clamped into the range.
+pub const MIN_BPM: u32 = 60;
+pub const MAX_BPM: u32 = 300;
+
+/// Source of time for the playback thread. Swapping the clock lets tests drive
+/// the scheduler without waiting on the wall clock.
+pub trait Clock: Send + 'static {
+    fn now(&self) -> Instant;
+
+    /// Wait for a message on `rx` until `deadline`. Returns the message as soon
+    /// as one arrives, or `RecvTimeoutError::Timeout` once the deadline is
+    /// reached.
+    fn recv_until<M>(
+        &self,
+        rx: &mut realtime::Receiver<M>,
+        deadline: Instant,
+    ) -> Result<M, mpsc::RecvTimeoutError>;
+
+    /// Wait for a message on `rx` with nothing scheduled, as the playback
+    /// thread does while stopped.
+    fn recv<M>(&self, rx: &mut realtime::Receiver<M>) -> Result<M, mpsc::RecvError> {
+        rx.recv()
+    }
+
+    /// Tempo the clock's time is measured in, for clocks that stretch time to
+    /// follow an external master. Sequences then play at this tempo instead
+    /// of their own.
+    fn fixed_tempo(&self) -> Option<u32> {
+        None
+    }
+}
+
+impl<C: Clock + Sync> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+
+    fn recv_until<M>(
+        &self,
+        rx: &mut realtime::Receiver<M>,
+        deadline: Instant,
+    ) -> Result<M, mpsc::RecvTimeoutError> {
+        (**self).recv_until(rx, deadline)
+    }
+
+    fn recv<M>(&self, rx: &mut realtime::Receiver<M>) -> Result<M, mpsc::RecvError> {
+        (**self).recv(rx)
+    }
+
+    fn fixed_tempo(&self) -> Option<u32> {
+        (**self).fixed_tempo()
+    }
+}
+
+/// The real monotonic clock. Blocks on the channel for the bulk of the wait and
+/// spin sleeps the final stretch so steps land on their deadline.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn recv_until<M>(
+        &self,
+        rx: &mut realtime::Receiver<M>,
+        deadline: Instant,
+    ) -> Result<M, mpsc::RecvTimeoutError> {
+        let coarse_wait = deadline
+            .saturating_duration_since(Instant::now())
+            .saturating_sub(SPIN_MARGIN);
+        if !coarse_wait.is_zero() {
+            match rx.recv_timeout(coarse_wait) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                result => return result,
+            }
+        }
+
+        match rx.try_recv() {
+            Ok(message) => return Ok(message),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                return Err(mpsc::RecvTimeoutError::Disconnected)
+            }
+            Err(mpsc::TryRecvError::Empty) => {}
+        }
+
+        spin_sleep::sleep(deadline.saturating_duration_since(Instant::now()));
+        Err(mpsc::RecvTimeoutError::Timeout)
+    }
+}
+
+/// Clock that only moves when told to, for driving the sequencer in tests.
+/// Share it with the sequencer through an `Arc` and move time along with
+/// `advance`: the playback thread wakes at every deadline on the way, in
+/// order, and sees the clock read exactly that deadline, so timing comes out
+/// the same on every run. Calls return once the playback thread is waiting
+/// again, so whatever it did is there to check.
+#[derive(Debug)]
+pub struct VirtualClock {
+    time: Mutex<VirtualTime>,
+    settled: Condvar,
+    // Most the playback thread wakes up late by.
+    max_jitter: Duration,
+}
+
+#[derive(Debug)]
+struct VirtualTime {
+    now: Instant,
+    // Bumped whenever the playback thread has something new to look at.
+    epoch: u64,
+    // The playback thread, once it has waited on the clock.
+    waiter: Option<Thread>,
+    // Epoch the playback thread last went idle in, and the deadline it is
+    // waiting for then. No deadline means it waits for commands only.
+    idle: Option<(u64, Option<Instant>)>,
+    // State of the generator lateness is drawn from.
+    rng: u64,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::with_jitter(Duration::ZERO)
+    }
+
+    /// Like `new`, but the playback thread wakes up late for every deadline,
+    /// by anything up to `max_jitter`, as it would on a busy machine. How
+    /// late is pseudo-random, and the same on every run.
+    pub fn with_jitter(max_jitter: Duration) -> Self {
+        Self {
+            time: Mutex::new(VirtualTime {
+                now: Instant::now(),
+                epoch: 0,
+                waiter: None,
+                idle: None,
+                rng: 0x2545_f491_4f6c_dd1d,
+            }),
+            settled: Condvar::new(),
+            max_jitter,
+        }
+    }
+
+    /// Move time forward by `duration`. See `advance_to`.
+    pub fn advance(&self, duration: Duration) {
+        let target = self.now() + duration;
+        self.advance_to(target);
+    }
+
+    /// Move time forward to `target`, stopping at every deadline on the way
+    /// for the playback thread to handle. With jitter, time can end up past
+    /// `target` when the playback thread wakes late for the last deadline.
+    pub fn advance_to(&self, target: Instant) {
+        loop {
+            let mut time = self.settled();
+            match time.idle.and_then(|(_, deadline)| deadline) {
+                Some(deadline) if deadline <= target => {
+                    time.now = deadline + self.jitter(&mut time.rng);
+                }
+                _ => {
+                    time.now = time.now.max(target);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Wait until the playback thread has handled every command sent so far
+    /// and everything due by now, without moving time.
+    ///
+    /// Panics if the playback thread doesn't get there, for instance because
+    /// the sequencer has been dropped.
+    pub fn settle(&self) {
+        drop(self.settled());
+    }
+
+    /// The next deadline the playback thread is waiting for, if it is waiting
+    /// for one.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.settled().idle.and_then(|(_, deadline)| deadline)
+    }
+
+    fn settled(&self) -> MutexGuard<'_, VirtualTime> {
+        let mut time = self.lock();
+        time.epoch += 1;
+        let epoch = time.epoch;
+        if let Some(waiter) = &time.waiter {
+            waiter.unpark();
+        }
+        let (time, timeout) = self
+            .settled
+            .wait_timeout_while(time, SETTLE_TIMEOUT, |time| {
+                time.idle.map(|(idle, _)| idle) != Some(epoch)
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        assert!(!timeout.timed_out(), "playback thread did not settle");
+        time
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VirtualTime> {
+        self.time.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // How late the playback thread wakes for the next deadline.
+    fn jitter(&self, rng: &mut u64) -> Duration {
+        if self.max_jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+        Duration::from_nanos(*rng % self.max_jitter.as_nanos() as u64)
+    }
+
+    // Register the calling thread as the one time wakes, and return the epoch
+    // it is looking at.
+    fn watch(&self) -> u64 {
+        let mut time = self.lock();
+        time.waiter = Some(thread::current());
+        time.epoch
+    }
+
+    // Record that the playback thread has nothing left to do in `epoch` until
+    // `deadline`. Anything newer means it has to look again first.
+    fn go_idle(&self, epoch: u64, deadline: Option<Instant>) {
+        let mut time = self.lock();
+        if time.epoch == epoch {
+            time.idle = Some((epoch, deadline));
+            self.settled.notify_all();
+        }
+    }
+
+    // Wait for a message until `deadline`, or for good without one.
+    fn wait<M>(
+        &self,
+        rx: &mut realtime::Receiver<M>,
+        deadline: Option<Instant>,
+    ) -> Result<M, mpsc::RecvTimeoutError> {
+        loop {
+            let epoch = self.watch();
+            match rx.try_recv() {
+                Ok(message) => return Ok(message),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(mpsc::RecvTimeoutError::Disconnected)
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+            if deadline.is_some_and(|deadline| self.now() >= deadline) {
+                return Err(mpsc::RecvTimeoutError::Timeout);
+            }
+
+            self.go_idle(epoch, deadline);
+            match rx.recv_or_wake() {
+                Ok(message) => return Ok(message),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err(mpsc::RecvTimeoutError::Disconnected)
+                }
+                Err(mpsc::TryRecvError::Empty) => {}
+            }
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.lock().now
+    }
+
+    fn recv_until<M>(
+        &self,
+        rx: &mut realtime::Receiver<M>,
+        deadline: Instant,
+    ) -> Result<M, mpsc::RecvTimeoutError> {
+        self.wait(rx, Some(deadline))
+    }
+
+    fn recv<M>(&self, rx: &mut realtime::Receiver<M>) -> Result<M, mpsc::RecvError> {
+        self.wait(rx, None).map_err(|_| mpsc::RecvError)
+    }
+}
+
+/// Exact length of one step, kept as a rational number of nanoseconds so that
+/// tempos like 128 BPM sixteenths (117.1875 ms) don't get rounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepDuration {
+    nanos_numerator: u128,
+    nanos_denominator: u128,
+}
+
+impl StepDuration {
+    /// Length of a step of `numerator/denominator` whole notes at `bpm`, with
+    /// a quarter note as the beat. 1/16 is a sixteenth, 1/12 an eighth-note
+    /// triplet and 3/32 a dotted sixteenth.
+    pub fn new(bpm: u32, numerator: u64, denominator: u64) -> Self {
+        Self {
+            nanos_numerator: NANOS_PER_MINUTE * 4 * numerator as u128,
+            nanos_denominator: bpm as u128 * denominator as u128,
+        }
+    }
+
+    /// Step length of a sequence. Sequences are validated before they reach
+    /// playback, so a malformed subdivision here falls back to sixteenths.
+    pub fn from_sequence(sequence: &Sequence) -> Self {
+        Self::at_tempo(sequence.bpm, sequence.trig_subdivision.as_ref())
+    }
+
+    /// Step length of `subdivision` at `bpm`, the same way `from_sequence`
+    /// derives it. Used for tracks running at their own speed.
+    pub fn at_tempo(bpm: u32, subdivision: Option<&Subdivision>) -> Self {
+        let bpm = playback_bpm(bpm);
+
+        // Default to 16th notes if no subdivision specified
+        match subdivision {
+            Some(subdivision) if subdivision_is_valid(subdivision) => Self::new(
+                bpm,
+                subdivision.numerator as u64,
+                subdivision.denominator as u64,
+            ),
+            _ => Self::new(bpm, 1, 16),
+        }
+    }
+
+    /// Duration of `steps` consecutive steps. Rounding happens once on the
+    /// total, so long spans never accumulate per-step error.
+    pub fn steps(&self, steps: u64) -> Duration {
+        let nanos = (steps as u128).saturating_mul(self.nanos_numerator) / self.nanos_denominator;
+        Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        )
+    }
+
+    pub fn as_duration(&self) -> Duration {
+        self.steps(1)
+    }
+
+    /// Duration of a fractional number of steps, e.g. a quarter-step gate.
+    /// Negative and non-finite spans are treated as zero.
+    pub fn span(&self, steps: f64) -> Duration {
+        if !steps.is_finite() || steps <= 0.0 {
+            return Duration::ZERO;
+        }
+        let nanos = self.nanos_numerator as f64 * steps / self.nanos_denominator as f64;
+        Duration::from_nanos(nanos.round() as u64)
+    }
+}
+
+/// Tempo a sequence with `bpm` actually plays at.
+pub fn playback_bpm(bpm: u32) -> u32 {
+    bpm.clamp(MIN_BPM, MAX_BPM)
+}
+
+/// A subdivision describes a step length, so both terms must be positive.
+pub fn subdivision_is_valid(subdivision: &Subdivision) -> bool {
+    let valid_term = 1..=MAX_SUBDIVISION_TERM;
+    valid_term.contains(&subdivision.numerator) && valid_term.contains(&subdivision.denominator)
+}
+
+/// Absolute step grid anchored at the moment the transport started. Every
+/// deadline is computed from the anchor rather than from the previous step, so
+/// late wake-ups don't push later steps back.
+#[derive(Debug, Clone, Copy)]
+pub struct StepTimeline {
+    anchor: Instant,
+    anchor_step: u64,
+    next_step: u64,
+    step_duration: StepDuration,
+}
+
+impl StepTimeline {
+    pub fn new(start: Instant, step_duration: StepDuration) -> Self {
+        Self {
+            anchor: start,
+            anchor_step: 0,
+            next_step: 0,
+            step_duration,
+        }
+    }
+
+    /// Number of steps fired since the transport started.
+    pub fn steps_elapsed(&self) -> u64 {
+        self.next_step
+    }
+
+    pub fn step_duration(&self) -> StepDuration {
+        self.step_duration
+    }
+
+    pub fn deadline(&self, step: u64) -> Instant {
+        self.anchor + self.step_duration.steps(step - self.anchor_step)
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        self.deadline(self.next_step)
+    }
+
+    pub fn advance(&mut self) {
+        self.next_step += 1;
+    }
+
+    /// Change the step length from the next step onwards, e.g. when a sequence
+    /// with a different tempo takes over. Steps already scheduled keep their
+    /// deadlines.
+    pub fn retime(&mut self, step_duration: StepDuration) {
+        if step_duration == self.step_duration {
+            return;
+        }
+        self.anchor = self.next_deadline();
+        self.anchor_step = self.next_step;
+        self.step_duration = step_duration;
+    }
+}
@@ -0,0 +1,77 @@
+use crate::server::sequence::{Sequence, TrackConfig, VoiceMode};
+use crate::timing::StepDuration;
+use std::collections::BTreeSet;
+
+pub const MAX_MIDI_CHANNEL: u32 = 16;
+
+/// Playback settings of one track, with anything its `TrackConfig` leaves
+/// unset filled in from the sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackSettings {
+    pub track: u32,
+    pub length: u32,
+    pub step_duration: StepDuration,
+    // Zero-based, as it goes out in the status byte.
+    pub midi_channel: u8,
+    pub voice_mode: VoiceMode,
+}
+
+/// Number of steps, at the sequence subdivision, before a cued sequence takes
+/// over. Tracks may loop over shorter or longer lengths underneath it.
+pub fn master_length(sequence: &Sequence) -> u32 {
+    let length = if sequence.master_length > 0 {
+        sequence.master_length
+    } else {
+        sequence.sequence_length
+    };
+    length.max(1)
+}
+
+pub fn track_config(sequence: &Sequence, track: u32) -> Option<&TrackConfig> {
+    sequence.tracks.iter().find(|config| config.track == track)
+}
+
+pub fn voice_mode(sequence: &Sequence, track: u32) -> VoiceMode {
+    track_config(sequence, track).map_or(VoiceMode::Poly, |config| config.voice_mode())
+}
+
+pub fn track_settings(sequence: &Sequence, track: u32) -> TrackSettings {
+    let config = track_config(sequence, track);
+
+    let length = config
+        .map(|config| config.length)
+        .filter(|length| *length > 0)
+        .unwrap_or(sequence.sequence_length)
+        .max(1);
+    let subdivision = config
+        .and_then(|config| config.subdivision.as_ref())
+        .or(sequence.trig_subdivision.as_ref());
+    let midi_channel = config
+        .map(|config| config.midi_channel)
+        .filter(|channel| (1..=MAX_MIDI_CHANNEL).contains(channel))
+        .map_or((track % 16) as u8, |channel| (channel - 1) as u8);
+
+    TrackSettings {
+        track,
+        length,
+        step_duration: StepDuration::at_tempo(sequence.bpm, subdivision),
+        midi_channel,
+        voice_mode: voice_mode(sequence, track),
+    }
+}
+
+/// Settings of every track the sequence configures or has trigs on, in track
+/// order.
+pub fn tracks(sequence: &Sequence) -> Vec<TrackSettings> {
+    let track_numbers: BTreeSet<u32> = sequence
+        .tracks
+        .iter()
+        .map(|config| config.track)
+        .chain(sequence.trigs.iter().map(|trig| trig.track))
+        .collect();
+
+    track_numbers
+        .into_iter()
+        .map(|track| track_settings(sequence, track))
+        .collect()
+}
@@ -1,6 +1,118 @@
 use std::fmt;
 
-pub use crate::server::sequence::{self, Note, NoteValue, Sequence, Subdivision, Trig};
+use crate::server::sequence::trig_condition::Condition;
+use crate::server::sequence::{IterationCondition, ParameterLocks};
+
+pub use crate::server::sequence::{
+    self, MiddleC, Note, NoteValue, Sequence, Subdivision, TrackConfig, Trig, VoiceMode,
+};
+
+impl Subdivision {
+    /// Step length for a speed multiplier applied to a sixteenth-note grid,
+    /// the way Elektron machines express track speed: 2x gives 1/32, 3/4x
+    /// gives 1/12 and 1/8x gives 1/2.
+    pub fn from_speed(numerator: i64, denominator: i64) -> Self {
+        let (numerator, denominator) = (denominator, 16 * numerator);
+        let divisor = gcd(numerator, denominator).max(1);
+        Self {
+            numerator: numerator / divisor,
+            denominator: denominator / divisor,
+        }
+    }
+}
+
+impl Trig {
+    /// Every note the trig plays: `note` followed by the rest of its chord.
+    pub fn notes(&self) -> impl Iterator<Item = &Note> {
+        self.note.iter().chain(&self.chord)
+    }
+
+    /// Whether the trig plays no notes at all, e.g. a trig that only locks
+    /// parameters.
+    pub fn is_rest(&self) -> bool {
+        self.notes().next().is_none()
+    }
+
+    /// Copy of the trig that plays `notes` instead of its own.
+    pub fn with_notes(&self, notes: Vec<Note>) -> Trig {
+        let mut notes = notes.into_iter();
+        Trig {
+            note: notes.next(),
+            chord: notes.collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// A MIDI note number, 0-127. Sequences name notes by octave and note
+/// value; this is where they turn into note numbers and back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pitch(u8);
+
+impl Pitch {
+    pub const MAX: u8 = 127;
+
+    pub fn new(midi_note: u8) -> Option<Self> {
+        (midi_note <= Self::MAX).then_some(Self(midi_note))
+    }
+
+    /// Pitch of `note` with octaves numbered the `middle_c` way, or `None`
+    /// if it has no note value or lies outside the MIDI range.
+    pub fn from_note(note: &Note, middle_c: MiddleC) -> Option<Self> {
+        let semitone = note.value().semitone()?;
+        let octave_of_zero = middle_c.octave() - 5;
+        let midi_note = (note.octave as i64 - octave_of_zero as i64) * 12 + semitone as i64;
+        u8::try_from(midi_note).ok().and_then(Self::new)
+    }
+
+    /// The note at this pitch, spelled with sharps.
+    pub fn to_note(self, middle_c: MiddleC, velocity: u32) -> Note {
+        let octave_of_zero = middle_c.octave() - 5;
+        Note {
+            octave: (self.0 / 12) as i32 + octave_of_zero,
+            value: NoteValue::from_semitone(self.0 % 12) as i32,
+            velocity,
+        }
+    }
+
+    pub fn midi_note(self) -> u8 {
+        self.0
+    }
+}
+
+impl MiddleC {
+    /// Octave number middle C is written with.
+    pub fn octave(self) -> i32 {
+        match self {
+            MiddleC::C5 => 5,
+            MiddleC::C4 => 4,
+            MiddleC::C3 => 3,
+        }
+    }
+}
+
+impl NoteValue {
+    /// Semitones above C, or `None` for `UnknownNote`.
+    pub fn semitone(self) -> Option<u8> {
+        match self {
+            NoteValue::UnknownNote => None,
+            value => Some(value as u8 - 1),
+        }
+    }
+
+    /// Note value `semitone` semitones above C, wrapping at the octave.
+    pub fn from_semitone(semitone: u8) -> Self {
+        NoteValue::try_from(semitone as i32 % 12 + 1).unwrap_or(NoteValue::UnknownNote)
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs()
+    } else {
+        gcd(b, a % b)
+    }
+}
 
 impl fmt::Display for Subdivision {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -31,22 +143,100 @@ impl fmt::Display for NoteValue {
 
 impl fmt::Display for Note {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let note_value = NoteValue::try_from(self.value).unwrap_or(NoteValue::UnknownNote);
-        write!(f, "{}{} (vel: {})", note_value, self.octave, self.velocity)
+        write!(
+            f,
+            "{}{} (vel: {})",
+            self.value(),
+            self.octave,
+            self.velocity
+        )
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let negation = |expected: &bool| if *expected { "" } else { "!" };
+        match self {
+            Condition::Probability(percent) => write!(f, "{}%", percent),
+            Condition::Iteration(IterationCondition { a, b }) => write!(f, "{}:{}", a, b),
+            Condition::Fill(expected) => write!(f, "{}FILL", negation(expected)),
+            Condition::Previous(expected) => write!(f, "{}PRE", negation(expected)),
+            Condition::Neighbor(expected) => write!(f, "{}NEI", negation(expected)),
+            Condition::First(expected) => write!(f, "{}1ST", negation(expected)),
+        }
+    }
+}
+
+impl fmt::Display for ParameterLocks {
+    // Written piece by piece so the playback thread can log locks without
+    // allocating.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        let mut value = |f: &mut fmt::Formatter<'_>, args: fmt::Arguments| {
+            let written = write!(f, "{}{}", separator, args);
+            separator = ", ";
+            written
+        };
+        write!(f, "[")?;
+        if let Some(program) = self.program {
+            value(f, format_args!("program={}", program))?;
+        }
+        for control_change in &self.control_changes {
+            value(
+                f,
+                format_args!("CC{}={}", control_change.controller, control_change.value),
+            )?;
+        }
+        if let Some(pitch_bend) = self.pitch_bend {
+            value(f, format_args!("bend={}", pitch_bend))?;
+        }
+        if let Some(pressure) = self.channel_pressure {
+            value(f, format_args!("pressure={}", pressure))?;
+        }
+        write!(f, "]")
     }
 }
 
 impl fmt::Display for Trig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let note_str = match &self.note {
-            Some(note) => format!("{}", note),
-            None => "REST".to_string(),
+        let note_str = if self.is_rest() {
+            "REST".to_string()
+        } else {
+            self.notes()
+                .map(|note| note.to_string())
+                .collect::<Vec<_>>()
+                .join(" + ")
         };
         write!(
             f,
             "Track {}, Step {}: {} [offset: {:.2}, length: {:.2}]",
             self.track, self.step, note_str, self.offset, self.length
-        )
+        )?;
+        if let Some(condition) = self
+            .condition
+            .as_ref()
+            .and_then(|condition| condition.condition.as_ref())
+        {
+            write!(f, " if {}", condition)?;
+        }
+        match &self.locks {
+            Some(locks) => write!(f, " locks {}", locks),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for TrackConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Track {}: length {}", self.track, self.length)?;
+        if let Some(subdivision) = &self.subdivision {
+            write!(f, ", subdivision {}", subdivision)?;
+        }
+        write!(f, ", channel {}", self.midi_channel)?;
+        if self.voice_mode() == VoiceMode::Mono {
+            write!(f, ", mono")?;
+        }
+        Ok(())
     }
 }
 
@@ -54,10 +244,22 @@ impl fmt::Display for Sequence {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Sequence (length: {})", self.sequence_length)?;
 
+        if self.master_length > 0 {
+            writeln!(f, "  Master length: {}", self.master_length)?;
+        }
+
+        if self.swing > 0 {
+            writeln!(f, "  Swing: {}%", self.swing)?;
+        }
+
         if let Some(subdivision) = &self.trig_subdivision {
             writeln!(f, "  Subdivision: {}", subdivision)?;
         }
 
+        for config in &self.tracks {
+            writeln!(f, "  {}", config)?;
+        }
+
         writeln!(f, "  Trigs ({}):", self.trigs.len())?;
         for (i, trig) in self.trigs.iter().enumerate() {
             writeln!(f, "    {}: {}", i + 1, trig)?;
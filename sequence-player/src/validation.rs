@@ -0,0 +1,298 @@
+use crate::conditions::condition_is_valid;
+use crate::groove::swing_is_valid;
+use crate::locks::invalid_lock;
+use crate::sequencer::SequencerError;
+use crate::server::sequence::{Note, Sequence, Trig};
+use crate::synth::synth_track_is_valid;
+use crate::timing::subdivision_is_valid;
+use crate::tracks::{track_settings, MAX_MIDI_CHANNEL};
+use crate::types::Pitch;
+use std::collections::HashSet;
+
+pub const MAX_VELOCITY: u32 = 127;
+
+/// Something wrong with one field of a sequence, located by its path in the
+/// message, e.g. `trigs[3].note.velocity`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub field: String,
+    pub error: SequencerError,
+}
+
+impl Violation {
+    fn new(field: impl Into<String>, error: SequencerError) -> Self {
+        Self {
+            field: field.into(),
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.error)
+    }
+}
+
+/// Every violation of a rejected sequence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSequence(pub Vec<Violation>);
+
+/// Everything in `sequence` that would keep it from playing as written, in
+/// the order the checks run. Empty when the sequence can be cued.
+pub fn violations(sequence: &Sequence) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    check_length(sequence, &mut violations);
+    check_tracks(sequence, &mut violations);
+    check_step_rates(sequence, &mut violations);
+    check_swing(sequence, &mut violations);
+    check_trig_conditions(sequence, &mut violations);
+    check_parameter_locks(sequence, &mut violations);
+    check_midi_channels(sequence, &mut violations);
+    check_notes(sequence, &mut violations);
+    check_steps(sequence, &mut violations);
+    check_velocities(sequence, &mut violations);
+    violations
+}
+
+/// Every violation at once, for callers that report them all.
+pub fn check_sequence(sequence: &Sequence) -> Result<(), InvalidSequence> {
+    let violations = violations(sequence);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidSequence(violations))
+    }
+}
+
+/// Like `check_sequence`, for a sequence played on the built-in synth, which
+/// also has to keep to the tracks the synth plays.
+pub fn check_synth_sequence(sequence: &Sequence) -> Result<(), InvalidSequence> {
+    let mut violations = violations(sequence);
+    check_synth_tracks(sequence, &mut violations);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(InvalidSequence(violations))
+    }
+}
+
+/// The first violation, for callers that stop at one.
+pub fn validate_sequence(sequence: &Sequence) -> Result<(), SequencerError> {
+    match violations(sequence).into_iter().next() {
+        Some(violation) => Err(violation.error),
+        None => Ok(()),
+    }
+}
+
+/// Every note a trig plays with its path below the trig: `note` followed by
+/// `chord[i]`.
+fn trig_notes(trig: &Trig) -> impl Iterator<Item = (String, &Note)> {
+    let note = trig.note.iter().map(|note| ("note".to_string(), note));
+    let chord = trig
+        .chord
+        .iter()
+        .enumerate()
+        .map(|(index, note)| (format!("chord[{}]", index), note));
+    note.chain(chord)
+}
+
+fn check_length(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    if sequence.sequence_length == 0 {
+        violations.push(Violation::new(
+            "sequence_length",
+            SequencerError::EmptySequence,
+        ));
+    }
+}
+
+/// Each track is configured once at most; any further config for it would
+/// be ignored.
+fn check_tracks(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    let mut configured = HashSet::new();
+    for (index, config) in sequence.tracks.iter().enumerate() {
+        if !configured.insert(config.track) {
+            violations.push(Violation::new(
+                format!("tracks[{}].track", index),
+                SequencerError::DuplicateTrack(config.track),
+            ));
+        }
+    }
+}
+
+/// Subdivisions, sequence-wide or per track, have to describe a positive
+/// step length. A missing subdivision is fine: the sequence plays as
+/// sixteenths and tracks follow the sequence.
+fn check_step_rates(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    let track_subdivisions = sequence
+        .tracks
+        .iter()
+        .enumerate()
+        .filter_map(|(index, config)| {
+            let field = format!("tracks[{}].subdivision", index);
+            config
+                .subdivision
+                .as_ref()
+                .map(|subdivision| (field, subdivision))
+        });
+    let subdivisions = sequence
+        .trig_subdivision
+        .as_ref()
+        .map(|subdivision| ("trig_subdivision".to_string(), subdivision))
+        .into_iter()
+        .chain(track_subdivisions);
+    for (field, subdivision) in subdivisions {
+        if !subdivision_is_valid(subdivision) {
+            violations.push(Violation::new(
+                field,
+                SequencerError::InvalidSubdivision {
+                    numerator: subdivision.numerator,
+                    denominator: subdivision.denominator,
+                },
+            ));
+        }
+    }
+}
+
+fn check_swing(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    if !swing_is_valid(sequence.swing) {
+        violations.push(Violation::new(
+            "swing",
+            SequencerError::InvalidSwing(sequence.swing),
+        ));
+    }
+}
+
+fn check_trig_conditions(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    for (index, trig) in sequence.trigs.iter().enumerate() {
+        let condition = trig
+            .condition
+            .as_ref()
+            .and_then(|condition| condition.condition.as_ref());
+        if let Some(condition) = condition.filter(|condition| !condition_is_valid(condition)) {
+            violations.push(Violation::new(
+                format!("trigs[{}].condition", index),
+                SequencerError::InvalidTrigCondition {
+                    track: trig.track,
+                    step: trig.step,
+                    condition: condition.to_string(),
+                },
+            ));
+        }
+    }
+}
+
+/// Locks and track defaults have to hold values MIDI can carry.
+fn check_parameter_locks(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    for (index, config) in sequence.tracks.iter().enumerate() {
+        if let Some(reason) = config.defaults.as_ref().and_then(invalid_lock) {
+            violations.push(Violation::new(
+                format!("tracks[{}].defaults", index),
+                SequencerError::InvalidParameterLock {
+                    track: config.track,
+                    step: None,
+                    reason,
+                },
+            ));
+        }
+    }
+    for (index, trig) in sequence.trigs.iter().enumerate() {
+        if let Some(reason) = trig.locks.as_ref().and_then(invalid_lock) {
+            violations.push(Violation::new(
+                format!("trigs[{}].locks", index),
+                SequencerError::InvalidParameterLock {
+                    track: trig.track,
+                    step: Some(trig.step),
+                    reason,
+                },
+            ));
+        }
+    }
+}
+
+/// Track channels go from 1 to 16. Zero leaves the channel to the track
+/// number.
+fn check_midi_channels(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    for (index, config) in sequence.tracks.iter().enumerate() {
+        if config.midi_channel > MAX_MIDI_CHANNEL {
+            violations.push(Violation::new(
+                format!("tracks[{}].midi_channel", index),
+                SequencerError::InvalidMidiChannel {
+                    track: config.track,
+                    channel: config.midi_channel,
+                },
+            ));
+        }
+    }
+}
+
+/// Notes need a note value and have to land within the MIDI note range.
+fn check_notes(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    for (index, trig) in sequence.trigs.iter().enumerate() {
+        for (path, note) in trig_notes(trig) {
+            if Pitch::from_note(note, sequence.middle_c()).is_none() {
+                violations.push(Violation::new(
+                    format!("trigs[{}].{}", index, path),
+                    SequencerError::InvalidNote {
+                        track: trig.track,
+                        step: trig.step,
+                        note: format!("{}{}", note.value(), note.octave),
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// Trigs past the end of their track would never play.
+fn check_steps(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    for (index, trig) in sequence.trigs.iter().enumerate() {
+        let length = track_settings(sequence, trig.track).length;
+        if trig.step >= length {
+            violations.push(Violation::new(
+                format!("trigs[{}].step", index),
+                SequencerError::StepOutOfRange {
+                    track: trig.track,
+                    step: trig.step,
+                    length,
+                },
+            ));
+        }
+    }
+}
+
+fn check_velocities(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    for (index, trig) in sequence.trigs.iter().enumerate() {
+        for (path, note) in trig_notes(trig) {
+            if note.velocity > MAX_VELOCITY {
+                violations.push(Violation::new(
+                    format!("trigs[{}].{}.velocity", index, path),
+                    SequencerError::InvalidVelocity {
+                        track: trig.track,
+                        step: trig.step,
+                        velocity: note.velocity,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// Track configs and trigs have to be on tracks the synth plays.
+fn check_synth_tracks(sequence: &Sequence, violations: &mut Vec<Violation>) {
+    let configs = sequence
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(index, config)| (format!("tracks[{}].track", index), config.track));
+    let trigs = sequence
+        .trigs
+        .iter()
+        .enumerate()
+        .map(|(index, trig)| (format!("trigs[{}].track", index), trig.track));
+    for (field, track) in configs.chain(trigs) {
+        if !synth_track_is_valid(track) {
+            violations.push(Violation::new(field, SequencerError::NoSynthTrack(track)));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use common::{sequence, trig};
+use helloworld_tonic::clock_follower::{ClockFollower, ExternalClock, FOLLOWER_BPM};
+use helloworld_tonic::sequencer::StepHandler;
+use helloworld_tonic::timing::{Clock, StepDuration};
+use helloworld_tonic::{Sequencer, Trig};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PULSE: [u8; 1] = [0xF8];
+const START: [u8; 1] = [0xFA];
+const CONTINUE: [u8; 1] = [0xFB];
+const STOP: [u8; 1] = [0xFC];
+
+/// Records the step of every trig that plays.
+struct StepRecorder {
+    steps: Arc<Mutex<Vec<u32>>>,
+}
+
+impl StepHandler for StepRecorder {
+    fn handle_notes_on(&self, trigs: &[&Trig]) {
+        self.steps
+            .lock()
+            .unwrap()
+            .extend(trigs.iter().map(|trig| trig.step));
+    }
+
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn follower_pulse() -> Duration {
+    StepDuration::new(FOLLOWER_BPM, 1, 96).as_duration()
+}
+
+/// Pulse times of a master at `bpm`, each nudged by up to `jitter` either way.
+fn pulses_at(start: Instant, bpm: f64, count: u32, jitter: Duration) -> Vec<Instant> {
+    let interval = Duration::from_secs_f64(60.0 / bpm / 24.0);
+    (0..count)
+        .map(|index| {
+            let grid = start + interval * index;
+            // Alternate early and late so the jitter has no trend.
+            let nudge = jitter.mul_f64(((index * 7) % 5) as f64 / 4.0);
+            if index % 2 == 0 {
+                grid + nudge
+            } else {
+                grid - nudge.min(interval / 2)
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn tempo_is_smoothed_over_jittery_pulses() {
+    let clock = ExternalClock::new();
+    assert_eq!(clock.tempo(), None);
+
+    let start = Instant::now();
+    for pulse in pulses_at(start, 125.0, 192, Duration::from_millis(2)) {
+        clock.pulse(pulse);
+    }
+    let tempo = clock.tempo().unwrap();
+    assert!((tempo - 125.0).abs() < 1.5, "estimated {} BPM", tempo);
+}
+
+#[test]
+fn a_pause_in_the_pulses_is_not_a_tempo_change() {
+    let clock = ExternalClock::new();
+    let start = Instant::now();
+    for pulse in pulses_at(start, 100.0, 48, Duration::ZERO) {
+        clock.pulse(pulse);
+    }
+    let resumed = start + Duration::from_secs(5);
+    for pulse in pulses_at(resumed, 100.0, 3, Duration::ZERO) {
+        clock.pulse(pulse);
+    }
+    let tempo = clock.tempo().unwrap();
+    assert!((tempo - 100.0).abs() < 0.5, "estimated {} BPM", tempo);
+}
+
+#[test]
+fn driver_timestamps_keep_callback_jitter_out_of_the_tempo() {
+    let clock = Arc::new(ExternalClock::new());
+    let sequencer = Sequencer::with_clock(
+        StepRecorder {
+            steps: Arc::default(),
+        },
+        Arc::clone(&clock),
+    );
+    let mut follower = ClockFollower::new(Arc::clone(&clock), sequencer.external_transport());
+
+    // The callbacks run up to 5ms late, but the driver stamped every pulse
+    // exactly 20ms apart.
+    let start = Instant::now();
+    let late = pulses_at(start, 125.0, 48, Duration::from_millis(5));
+    for (index, at) in late.into_iter().enumerate() {
+        follower.receive_stamped(&PULSE, at, index as u64 * 20_000);
+    }
+    let tempo = clock.tempo().unwrap();
+    assert!((tempo - 125.0).abs() < 0.001, "estimated {} BPM", tempo);
+}
+
+#[test]
+fn time_advances_one_pulse_per_pulse_and_never_runs_ahead() {
+    let clock = ExternalClock::new();
+    let origin = clock.now();
+
+    // Pulses from well in the past: however long it has been since the last
+    // one, time only runs on to where the next pulse is due.
+    let start = Instant::now() - Duration::from_secs(10);
+    for pulse in pulses_at(start, 90.0, 10, Duration::ZERO) {
+        clock.pulse(pulse);
+    }
+    assert_eq!(clock.now(), origin + follower_pulse() * 11);
+    assert_eq!(clock.now(), origin + follower_pulse() * 11);
+}
+
+#[test]
+fn transport_messages_drive_playback() {
+    let clock = Arc::new(ExternalClock::new());
+    let steps = Arc::new(Mutex::new(Vec::new()));
+    let recorder = StepRecorder {
+        steps: Arc::clone(&steps),
+    };
+    let sequencer = Sequencer::with_clock(recorder, Arc::clone(&clock));
+    let mut follower = ClockFollower::new(Arc::clone(&clock), sequencer.external_transport());
+    sequencer
+        .cue_sequence(sequence(
+            8,
+            120,
+            (0..8).map(|step| trig(step, 0.0, 0.5)).collect(),
+        ))
+        .unwrap();
+
+    let send = |follower: &mut ClockFollower<Arc<ExternalClock>>, message: &[u8]| {
+        follower.receive(message, Instant::now());
+        thread::sleep(Duration::from_millis(1));
+    };
+    let settle = || thread::sleep(Duration::from_millis(50));
+
+    // Start waits for the next pulse.
+    send(&mut follower, &START);
+    settle();
+    assert!(!sequencer.is_playing());
+
+    // Every sixth pulse is a sixteenth step; playback never gets ahead of the
+    // pulses it has been given.
+    for _ in 0..1 + 6 * 3 {
+        send(&mut follower, &PULSE);
+    }
+    settle();
+    assert!(sequencer.is_playing());
+    assert_eq!(*steps.lock().unwrap(), vec![0, 1, 2, 3]);
+
+    send(&mut follower, &STOP);
+    for _ in 0..12 {
+        send(&mut follower, &PULSE);
+    }
+    settle();
+    assert!(!sequencer.is_playing());
+    assert_eq!(steps.lock().unwrap().len(), 4);
+
+    // Song position 6 is the seventh sixteenth; Continue plays from there on
+    // the next pulse.
+    send(&mut follower, &[0xF2, 6, 0]);
+    send(&mut follower, &CONTINUE);
+    send(&mut follower, &PULSE);
+    settle();
+    assert!(sequencer.is_playing());
+    assert_eq!(steps.lock().unwrap()[4..], [6]);
+}
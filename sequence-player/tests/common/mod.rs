@@ -0,0 +1,230 @@
+#![allow(dead_code)]
+
+use helloworld_tonic::event_table::EventTable;
+use helloworld_tonic::ports::{OutputPorts, PortError};
+use helloworld_tonic::recording::{Recorded, RecordedEvent, RecordingStepHandler};
+use helloworld_tonic::sequencer::{MidiSink, Sequencer};
+use helloworld_tonic::timing::{Clock, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{Note, Subdivision, Trig};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Move `clock` on from one deadline of the playback thread to the next,
+/// letting it play everything due there, until `done` holds.
+pub fn advance_until(clock: &VirtualClock, mut done: impl FnMut() -> bool) {
+    let give_up = Instant::now() + Duration::from_secs(60);
+    while !done() {
+        assert!(Instant::now() < give_up, "sequencer stalled");
+        let deadline = clock
+            .next_deadline()
+            .expect("playback stopped before it got there");
+        clock.advance_to(deadline);
+    }
+}
+
+/// Play `sequence` against `clock` until `done` holds for the recorded events,
+/// then return the transport start time and everything recorded so far.
+pub fn play_until(
+    clock: Arc<VirtualClock>,
+    sequence: Sequence,
+    done: impl Fn(&[RecordedEvent]) -> bool,
+) -> (Instant, Vec<RecordedEvent>) {
+    play_with(clock, sequence, |_| {}, done)
+}
+
+/// Like `play_until`, with probability conditions drawn from `seed`.
+pub fn play_seeded(
+    seed: u64,
+    clock: Arc<VirtualClock>,
+    sequence: Sequence,
+    done: impl Fn(&[RecordedEvent]) -> bool,
+) -> (Instant, Vec<RecordedEvent>) {
+    play_prepared(
+        clock,
+        sequence,
+        |sequencer| sequencer.set_random_seed(seed),
+        |_| {},
+        done,
+    )
+}
+
+/// Like `play_until`, but runs `on_first_step` against the sequencer once
+/// the first step has played, before time moves on.
+pub fn play_with(
+    clock: Arc<VirtualClock>,
+    sequence: Sequence,
+    on_first_step: impl FnOnce(&Sequencer),
+    done: impl Fn(&[RecordedEvent]) -> bool,
+) -> (Instant, Vec<RecordedEvent>) {
+    play_prepared(clock, sequence, |_| {}, on_first_step, done)
+}
+
+fn play_prepared(
+    clock: Arc<VirtualClock>,
+    sequence: Sequence,
+    before_start: impl FnOnce(&Sequencer),
+    on_first_step: impl FnOnce(&Sequencer),
+    done: impl Fn(&[RecordedEvent]) -> bool,
+) -> (Instant, Vec<RecordedEvent>) {
+    let recorder = RecordingStepHandler::new(Arc::clone(&clock));
+    let sequencer = Sequencer::with_clock(recorder.clone(), Arc::clone(&clock));
+    let start = clock.now();
+    before_start(&sequencer);
+    sequencer.cue_sequence(sequence).unwrap();
+    sequencer.start_sequence().unwrap();
+    clock.settle();
+    on_first_step(&sequencer);
+
+    let mut events = Vec::new();
+    advance_until(&clock, || {
+        events.extend(recorder.take_events());
+        done(&events)
+    });
+    drop(sequencer);
+    (start, events)
+}
+
+fn event_times(
+    events: &[RecordedEvent],
+    step: u32,
+    wanted: impl Fn(&Recorded) -> bool,
+) -> Vec<Instant> {
+    let mut times: Vec<Instant> = events
+        .iter()
+        .filter(|recorded| wanted(&recorded.event))
+        .filter(|recorded| match &recorded.event {
+            Recorded::NoteOn { step: played, .. } | Recorded::NoteOff { step: played, .. } => {
+                *played == step
+            }
+            _ => false,
+        })
+        .map(|recorded| recorded.time)
+        .collect();
+    // The notes of a chord start and end together.
+    times.dedup();
+    times
+}
+
+/// Times at which trigs on `step` started playing.
+pub fn note_on_times(events: &[RecordedEvent], step: u32) -> Vec<Instant> {
+    event_times(events, step, |event| {
+        matches!(event, Recorded::NoteOn { .. })
+    })
+}
+
+/// Times at which trigs on `step` were released.
+pub fn note_off_times(events: &[RecordedEvent], step: u32) -> Vec<Instant> {
+    event_times(events, step, |event| {
+        matches!(event, Recorded::NoteOff { .. })
+    })
+}
+
+pub fn sequence(sequence_length: u32, bpm: u32, trigs: Vec<Trig>) -> Sequence {
+    Sequence {
+        sequence_length,
+        trig_subdivision: Some(Subdivision {
+            numerator: 1,
+            denominator: 16,
+        }),
+        bpm,
+        trigs,
+        ..Default::default()
+    }
+}
+
+/// `sequence` as the playback thread hands it to step handlers.
+pub fn compiled(sequence: &Sequence) -> Arc<EventTable> {
+    Arc::new(EventTable::new(sequence, 0))
+}
+
+pub fn trig(step: u32, offset: f32, length: f32) -> Trig {
+    Trig {
+        note: Some(Note {
+            octave: 4,
+            value: 1,
+            velocity: 100,
+        }),
+        track: 0,
+        step,
+        offset,
+        length,
+        ..Default::default()
+    }
+}
+
+pub fn millis(millis: f64) -> Duration {
+    Duration::from_secs_f64(millis / 1000.0)
+}
+
+pub type Sent = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+/// Ports that come and go as the test says, recording what each connection
+/// sends along with the name of its port.
+#[derive(Default, Clone)]
+pub struct FakePorts {
+    pub names: Arc<Mutex<Vec<String>>>,
+    pub sent: Sent,
+    // Held by a test to keep connecting from finishing.
+    pub connecting: Arc<Mutex<()>>,
+    // Ports whose connections fail every send, as an unplugged device would.
+    pub failing: Arc<Mutex<Vec<String>>>,
+    // Held by a test to keep sends from finishing.
+    pub sending: Arc<Mutex<()>>,
+}
+
+impl FakePorts {
+    pub fn with(names: &[&str]) -> Self {
+        let ports = Self::default();
+        ports.set(names);
+        ports
+    }
+
+    pub fn set(&self, names: &[&str]) {
+        *self.names.lock().unwrap() = names.iter().map(|name| name.to_string()).collect();
+    }
+
+    pub fn fail(&self, names: &[&str]) {
+        *self.failing.lock().unwrap() = names.iter().map(|name| name.to_string()).collect();
+    }
+}
+
+#[derive(Default)]
+pub struct FakeConnection {
+    pub port: String,
+    pub sent: Sent,
+    pub failing: Arc<Mutex<Vec<String>>>,
+    pub sending: Arc<Mutex<()>>,
+}
+
+impl MidiSink for FakeConnection {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        drop(self.sending.lock().unwrap());
+        if self.failing.lock().unwrap().contains(&self.port) {
+            return Err(format!("{} is gone", self.port));
+        }
+        self.sent
+            .lock()
+            .unwrap()
+            .push((self.port.clone(), message.to_vec()));
+        Ok(())
+    }
+}
+
+impl OutputPorts for FakePorts {
+    type Connection = FakeConnection;
+
+    fn names(&self) -> Result<Vec<String>, PortError> {
+        Ok(self.names.lock().unwrap().clone())
+    }
+
+    fn connect(&self, name: &str) -> Result<FakeConnection, PortError> {
+        drop(self.connecting.lock().unwrap());
+        Ok(FakeConnection {
+            port: name.to_string(),
+            sent: Arc::clone(&self.sent),
+            failing: Arc::clone(&self.failing),
+            sending: Arc::clone(&self.sending),
+        })
+    }
+}
@@ -0,0 +1,76 @@
+mod common;
+
+use common::{millis, note_off_times, note_on_times, play_until, sequence, trig};
+use helloworld_tonic::timing::VirtualClock;
+use std::sync::Arc;
+use std::time::Duration;
+
+// 120 BPM sixteenths: every step is 125 ms.
+
+#[test]
+fn positive_offset_delays_the_note_within_its_step() {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, sequence(4, 120, vec![trig(1, 0.5, 1.0)]), |events| {
+        note_on_times(events, 1).len() >= 2
+    });
+
+    assert_eq!(
+        note_on_times(&events, 1)[..2],
+        [start + millis(187.5), start + millis(687.5)]
+    );
+}
+
+#[test]
+fn negative_offset_plays_in_the_previous_step_window() {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(
+        clock,
+        sequence(4, 120, vec![trig(2, -0.25, 1.0)]),
+        |events| note_on_times(events, 2).len() >= 2,
+    );
+
+    assert_eq!(
+        note_on_times(&events, 2)[..2],
+        [start + millis(218.75), start + millis(718.75)]
+    );
+}
+
+#[test]
+fn negative_offset_on_the_first_step_wraps_into_the_previous_loop() {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(
+        clock,
+        sequence(4, 120, vec![trig(0, -0.5, 1.0)]),
+        |events| note_on_times(events, 0).len() >= 3,
+    );
+
+    // Nothing precedes the very first downbeat, so it can't be pulled early.
+    assert_eq!(
+        note_on_times(&events, 0)[..3],
+        [start, start + millis(437.5), start + millis(937.5)]
+    );
+}
+
+#[test]
+fn offsets_are_limited_to_the_hardware_range() {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, sequence(4, 120, vec![trig(1, 3.0, 1.0)]), |events| {
+        !note_on_times(events, 1).is_empty()
+    });
+
+    // 23/24 of a step after step 1, give or take float rounding.
+    let expected = start + millis(125.0 + 125.0 * 23.0 / 24.0);
+    let actual = note_on_times(&events, 1)[0];
+    assert!(actual.max(expected) - actual.min(expected) < Duration::from_micros(1));
+}
+
+#[test]
+fn note_off_is_measured_from_the_shifted_start() {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, sequence(4, 120, vec![trig(1, 0.5, 2.0)]), |events| {
+        !note_off_times(events, 1).is_empty()
+    });
+
+    // The note starts at 187.5 ms and lasts two steps.
+    assert_eq!(note_off_times(&events, 1)[0], start + millis(437.5));
+}
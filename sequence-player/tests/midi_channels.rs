@@ -0,0 +1,113 @@
+mod common;
+
+use common::{compiled, sequence, trig, FakeConnection, Sent};
+use helloworld_tonic::locks::restored_values;
+use helloworld_tonic::sequencer::{MidiStepHandler, Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::server::sequence::{ControlChange, ParameterLocks};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{TrackConfig, Trig};
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn recording_handler() -> (MidiStepHandler<FakeConnection>, Sent) {
+    let sent = Sent::default();
+    let connection = FakeConnection {
+        port: String::new(),
+        sent: sent.clone(),
+        ..Default::default()
+    };
+    (MidiStepHandler::new(connection), sent)
+}
+
+fn messages(sent: &Sent) -> Vec<Vec<u8>> {
+    let sent = sent.lock().unwrap();
+    sent.iter().map(|(_, message)| message.clone()).collect()
+}
+
+fn on_track(track: u32) -> Trig {
+    Trig {
+        track,
+        ..trig(0, 0.0, 0.5)
+    }
+}
+
+fn with_channel(track: u32, midi_channel: u32) -> Sequence {
+    let mut sequence = sequence(4, 120, vec![on_track(track)]);
+    sequence.tracks = vec![TrackConfig {
+        track,
+        midi_channel,
+        ..Default::default()
+    }];
+    sequence
+}
+
+#[test]
+fn a_track_plays_on_its_assigned_channel_whatever_its_number() {
+    let (handler, sent) = recording_handler();
+    handler.handle_sequence_change(&compiled(&with_channel(3, 10)));
+
+    let drums = on_track(3);
+    handler.handle_parameters(
+        3,
+        &ParameterLocks {
+            control_changes: vec![ControlChange {
+                controller: 74,
+                value: 20,
+            }],
+            program: Some(5),
+            ..Default::default()
+        },
+    );
+    handler.handle_notes_on(&[&drums]);
+    handler.handle_notes_off(&[&drums]);
+
+    let statuses: Vec<u8> = messages(&sent).iter().map(|message| message[0]).collect();
+    assert_eq!(statuses, vec![0xC9, 0xB9, 0x99, 0x89]);
+    assert_eq!(messages(&sent)[0], vec![0xC9, 5]);
+}
+
+#[test]
+fn unassigned_tracks_follow_their_number() {
+    let (handler, sent) = recording_handler();
+    handler.handle_sequence_change(&compiled(&with_channel(3, 10)));
+
+    handler.handle_notes_on(&[&on_track(1), &on_track(18)]);
+
+    let statuses: Vec<u8> = messages(&sent).iter().map(|message| message[0]).collect();
+    assert_eq!(statuses, vec![0x91, 0x92]);
+}
+
+#[test]
+fn channels_outside_1_to_16_are_rejected() {
+    let sequencer = Sequencer::new(NullHandler);
+    assert_eq!(
+        sequencer.cue_sequence(with_channel(2, 17)).err(),
+        Some(SequencerError::InvalidMidiChannel {
+            track: 2,
+            channel: 17
+        })
+    );
+    assert!(sequencer.cue_sequence(with_channel(2, 16)).is_ok());
+    assert!(sequencer.cue_sequence(with_channel(2, 0)).is_ok());
+}
+
+#[test]
+fn a_locked_program_returns_to_the_track_program() {
+    let locked = ParameterLocks {
+        program: Some(12),
+        ..Default::default()
+    };
+    let defaults = ParameterLocks {
+        program: Some(3),
+        ..Default::default()
+    };
+
+    assert_eq!(restored_values(&locked, Some(&defaults)).program, Some(3));
+    // Without a track program there is nothing to go back to.
+    assert_eq!(restored_values(&locked, None).program, None);
+}
@@ -0,0 +1,224 @@
+mod common;
+
+use common::{advance_until, sequence, trig};
+use helloworld_tonic::sequencer::{MidiSink, MidiStepHandler, SequencerError};
+use helloworld_tonic::timing::{Clock, StepDuration, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::Sequencer;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const PULSE: u8 = 0xF8;
+const START: u8 = 0xFA;
+const CONTINUE: u8 = 0xFB;
+const STOP: u8 = 0xFC;
+const SONG_POSITION: u8 = 0xF2;
+
+type SentMessages = Arc<Mutex<Vec<(Instant, Vec<u8>)>>>;
+
+/// Output port that records every message with the time it went out.
+struct MockOutput {
+    clock: Arc<VirtualClock>,
+    sent: SentMessages,
+}
+
+impl MidiSink for MockOutput {
+    fn send(&mut self, message: &[u8]) -> Result<(), String> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((self.clock.now(), message.to_vec()));
+        Ok(())
+    }
+}
+
+fn clocked_sequencer(sends_clock: bool) -> (Sequencer, Arc<VirtualClock>, SentMessages) {
+    let clock = Arc::new(VirtualClock::new());
+    let sent = SentMessages::default();
+    let output = MockOutput {
+        clock: Arc::clone(&clock),
+        sent: Arc::clone(&sent),
+    };
+    let handler = if sends_clock {
+        MidiStepHandler::with_midi_clock(output)
+    } else {
+        MidiStepHandler::new(output)
+    };
+    (
+        Sequencer::with_clock(handler, Arc::clone(&clock)),
+        clock,
+        sent,
+    )
+}
+
+fn every_step(bpm: u32) -> Sequence {
+    sequence(16, bpm, (0..16).map(|step| trig(step, 0.0, 0.5)).collect())
+}
+
+/// Play on from wherever the sequencer is until `done` holds for what was sent.
+fn play_until(
+    clock: &VirtualClock,
+    sent: &SentMessages,
+    done: impl Fn(&[(Instant, Vec<u8>)]) -> bool,
+) {
+    clock.settle();
+    advance_until(clock, || done(&sent.lock().unwrap()));
+}
+
+fn pulse_times(sent: &[(Instant, Vec<u8>)]) -> Vec<Instant> {
+    sent.iter()
+        .filter(|(_, message)| message == &[PULSE])
+        .map(|(time, _)| *time)
+        .collect()
+}
+
+fn note_on_times(sent: &[(Instant, Vec<u8>)]) -> Vec<Instant> {
+    sent.iter()
+        .filter(|(_, message)| message[0] & 0xF0 == 0x90)
+        .map(|(time, _)| *time)
+        .collect()
+}
+
+#[test]
+fn pulses_tick_24_to_the_quarter_note_on_the_step_grid() {
+    let (sequencer, clock, sent) = clocked_sequencer(true);
+    sequencer.cue_sequence(every_step(120)).unwrap();
+    sequencer.start_sequence().unwrap();
+    play_until(&clock, &sent, |sent| pulse_times(sent).len() > 96);
+    drop(sequencer);
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent[0].1, vec![START]);
+    let pulses = pulse_times(&sent);
+    let pulse = StepDuration::new(120, 1, 96);
+    for (index, time) in pulses.iter().enumerate().take(96) {
+        assert_eq!(
+            *time,
+            pulses[0] + pulse.steps(index as u64),
+            "pulse {}",
+            index
+        );
+    }
+
+    // Sixteenth-note steps land on every sixth pulse.
+    let note_ons = note_on_times(&sent);
+    for (step, time) in note_ons.iter().enumerate().take(16) {
+        assert_eq!(*time, pulses[step * 6]);
+    }
+}
+
+#[test]
+fn pulses_follow_the_tempo_of_a_promoted_cue() {
+    let (sequencer, clock, sent) = clocked_sequencer(true);
+    sequencer.cue_sequence(every_step(120)).unwrap();
+    sequencer.start_sequence().unwrap();
+    sequencer.cue_sequence(every_step(150)).unwrap();
+    // One loop of 16 sixteenths is 96 pulses.
+    play_until(&clock, &sent, |sent| pulse_times(sent).len() > 96 + 48);
+    drop(sequencer);
+
+    let pulses = pulse_times(&sent.lock().unwrap());
+    let slow = StepDuration::new(120, 1, 96);
+    let fast = StepDuration::new(150, 1, 96);
+    let handover = pulses[0] + slow.steps(96);
+    assert_eq!(pulses[96], handover);
+    for index in 0..48 {
+        assert_eq!(pulses[96 + index], handover + fast.steps(index as u64));
+    }
+}
+
+#[test]
+fn resuming_continues_from_the_song_position() {
+    let (sequencer, clock, sent) = clocked_sequencer(true);
+    sequencer.cue_sequence(every_step(120)).unwrap();
+    sequencer.start_sequence().unwrap();
+    play_until(&clock, &sent, |sent| pulse_times(sent).len() > 30);
+    sequencer.stop_sequence().unwrap();
+    clock.settle();
+    assert!(!sequencer.is_playing());
+    let stopped_at = sequencer.current_step();
+    let stopped_count = sent.lock().unwrap().len();
+    assert_eq!(sent.lock().unwrap().last().unwrap().1, vec![STOP]);
+
+    sequencer.resume_sequence().unwrap();
+    play_until(&clock, &sent, |sent| {
+        pulse_times(&sent[stopped_count..]).len() > 12
+    });
+    drop(sequencer);
+
+    let sent = sent.lock().unwrap();
+    let resumed = &sent[stopped_count..];
+    assert_eq!(
+        resumed[0].1,
+        vec![
+            SONG_POSITION,
+            (stopped_at & 0x7f) as u8,
+            (stopped_at >> 7) as u8
+        ]
+    );
+    assert_eq!(resumed[1].1, vec![CONTINUE]);
+    assert!(!resumed.iter().any(|(_, message)| message == &[START]));
+    let pulses = pulse_times(resumed);
+    assert_eq!(note_on_times(resumed)[0], pulses[0]);
+}
+
+#[test]
+fn starting_again_after_a_stop_plays_from_the_top() {
+    let (sequencer, clock, sent) = clocked_sequencer(true);
+    sequencer.cue_sequence(every_step(120)).unwrap();
+    sequencer.start_sequence().unwrap();
+    play_until(&clock, &sent, |sent| pulse_times(sent).len() > 30);
+    sequencer.stop_sequence().unwrap();
+    clock.settle();
+    assert_ne!(sequencer.current_step(), 0);
+    let stopped_count = sent.lock().unwrap().len();
+
+    // Nothing cued, so the stopped sequence plays again.
+    sequencer.start_sequence().unwrap();
+    play_until(&clock, &sent, |sent| {
+        pulse_times(&sent[stopped_count..]).len() > 12
+    });
+    drop(sequencer);
+
+    let sent = sent.lock().unwrap();
+    let restarted = &sent[stopped_count..];
+    assert_eq!(restarted[0].1, vec![START]);
+    assert!(!restarted
+        .iter()
+        .any(|(_, message)| message == &[CONTINUE] || message[0] == SONG_POSITION));
+    let pulses = pulse_times(restarted);
+    assert_eq!(note_on_times(restarted)[0], pulses[0]);
+}
+
+#[test]
+fn nothing_resumes_without_a_stopped_sequence() {
+    let (sequencer, clock, _sent) = clocked_sequencer(true);
+    assert_eq!(
+        sequencer.resume_sequence().err(),
+        Some(SequencerError::NothingToResume)
+    );
+    sequencer.cue_sequence(every_step(120)).unwrap();
+    assert_eq!(
+        sequencer.resume_sequence().err(),
+        Some(SequencerError::NothingToResume)
+    );
+    sequencer.start_sequence().unwrap();
+    clock.settle();
+    assert_eq!(
+        sequencer.resume_sequence().err(),
+        Some(SequencerError::NothingToResume)
+    );
+}
+
+#[test]
+fn ports_without_clock_send_only_notes() {
+    let (sequencer, clock, sent) = clocked_sequencer(false);
+    sequencer.cue_sequence(every_step(120)).unwrap();
+    sequencer.start_sequence().unwrap();
+    play_until(&clock, &sent, |sent| note_on_times(sent).len() > 8);
+    sequencer.stop_sequence().unwrap();
+    drop(sequencer);
+
+    let sent = sent.lock().unwrap();
+    assert!(sent.iter().all(|(_, message)| message[0] < 0xF0));
+}
@@ -0,0 +1,301 @@
+mod common;
+
+use common::{sequence, trig};
+use helloworld_tonic::render::{render_midi, RenderError, RenderOptions};
+use helloworld_tonic::server::sequence::trig_condition::Condition;
+use helloworld_tonic::server::sequence::{ControlChange, ParameterLocks, TrigCondition};
+use helloworld_tonic::smf::TICKS_PER_QUARTER;
+use helloworld_tonic::{MiddleC, Pitch, TrackConfig, Trig};
+
+// Ticks in a sixteenth.
+const STEP: u64 = TICKS_PER_QUARTER as u64 / 4;
+
+struct MidiFile {
+    format: u16,
+    division: u16,
+    // Events of each track at absolute ticks, meta events starting 0xFF.
+    tracks: Vec<Vec<(u64, Vec<u8>)>>,
+}
+
+impl MidiFile {
+    fn parse(bytes: &[u8]) -> Self {
+        assert_eq!(&bytes[0..4], b"MThd");
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let format = u16_at(8);
+        let track_count = u16_at(10) as usize;
+        let division = u16_at(12);
+
+        let mut at = 14;
+        let mut tracks = Vec::new();
+        for _ in 0..track_count {
+            assert_eq!(&bytes[at..at + 4], b"MTrk");
+            let length = u32::from_be_bytes(bytes[at + 4..at + 8].try_into().unwrap()) as usize;
+            tracks.push(parse_track(&bytes[at + 8..at + 8 + length]));
+            at += 8 + length;
+        }
+        assert_eq!(at, bytes.len());
+        Self {
+            format,
+            division,
+            tracks,
+        }
+    }
+
+    fn track_name(&self, index: usize) -> String {
+        let name = self.tracks[index]
+            .iter()
+            .find(|(_, event)| event[..2] == [0xFF, 0x03])
+            .unwrap();
+        String::from_utf8(name.1[3..].to_vec()).unwrap()
+    }
+
+    fn end_of_track(&self, index: usize) -> u64 {
+        let (tick, event) = self.tracks[index].last().unwrap();
+        assert_eq!(event, &[0xFF, 0x2F, 0x00]);
+        *tick
+    }
+
+    // Note-ons and note-offs of a track as (tick, status, key).
+    fn notes(&self, index: usize) -> Vec<(u64, u8, u8)> {
+        self.tracks[index]
+            .iter()
+            .filter(|(_, event)| matches!(event[0] & 0xF0, 0x80 | 0x90))
+            .map(|(tick, event)| (*tick, event[0], event[1]))
+            .collect()
+    }
+}
+
+fn parse_track(mut data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+    let mut events = Vec::new();
+    let mut tick = 0;
+    while !data.is_empty() {
+        let (delta, rest) = variable_length(data);
+        tick += delta;
+        let length = match rest[0] {
+            0xFF => {
+                let (length, after) = variable_length(&rest[2..]);
+                rest.len() - after.len() + length as usize
+            }
+            0xC0..=0xDF => 2,
+            _ => 3,
+        };
+        events.push((tick, rest[..length].to_vec()));
+        data = &rest[length..];
+    }
+    events
+}
+
+fn variable_length(data: &[u8]) -> (u64, &[u8]) {
+    let mut value = 0;
+    for (index, byte) in data.iter().enumerate() {
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return (value, &data[index + 1..]);
+        }
+    }
+    panic!("Unterminated variable-length quantity");
+}
+
+fn render(sequence: helloworld_tonic::types::Sequence, loops: u32) -> MidiFile {
+    let options = RenderOptions {
+        loops,
+        ..Default::default()
+    };
+    MidiFile::parse(&render_midi(sequence, &options).unwrap())
+}
+
+fn key() -> u8 {
+    Pitch::from_note(trig(0, 0.0, 1.0).note.as_ref().unwrap(), MiddleC::default())
+        .unwrap()
+        .midi_note()
+}
+
+fn on_track(track: u32, trig: Trig) -> Trig {
+    Trig { track, ..trig }
+}
+
+#[test]
+fn a_tempo_track_comes_first_then_one_track_per_sequence_track() {
+    let file = render(
+        sequence(
+            16,
+            120,
+            vec![trig(0, 0.0, 1.0), on_track(2, trig(4, 0.0, 1.0))],
+        ),
+        1,
+    );
+
+    assert_eq!(file.format, 1);
+    assert_eq!(file.division, TICKS_PER_QUARTER);
+    assert_eq!(file.tracks.len(), 3);
+    assert_eq!(
+        file.tracks[0][0],
+        (0, vec![0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20])
+    );
+    assert_eq!(file.track_name(1), "Track 0");
+    assert_eq!(file.track_name(2), "Track 2");
+    assert_eq!(
+        file.notes(2),
+        vec![(4 * STEP, 0x92, key()), (5 * STEP, 0x82, key())]
+    );
+}
+
+#[test]
+fn the_tempo_is_the_one_the_sequence_plays_at() {
+    // Below the slowest tempo the sequencer plays, 60 BPM.
+    let file = render(sequence(16, 20, vec![trig(0, 0.0, 1.0)]), 1);
+
+    assert_eq!(
+        file.tracks[0][0],
+        (0, vec![0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40])
+    );
+    assert_eq!(file.notes(1)[1].0, STEP);
+}
+
+#[test]
+fn notes_land_on_their_ticks_with_their_lengths_and_micro_timing() {
+    let file = render(
+        sequence(
+            16,
+            120,
+            vec![
+                trig(0, 0.0, 0.5),
+                trig(4, 0.5, 2.0),
+                on_track(1, trig(10, -0.25, 1.25)),
+            ],
+        ),
+        1,
+    );
+
+    assert_eq!(
+        file.notes(1),
+        vec![
+            (0, 0x90, key()),
+            (STEP / 2, 0x80, key()),
+            (4 * STEP + STEP / 2, 0x90, key()),
+            (6 * STEP + STEP / 2, 0x80, key()),
+        ]
+    );
+    assert_eq!(
+        file.notes(2),
+        vec![
+            (10 * STEP - STEP / 4, 0x91, key()),
+            (11 * STEP, 0x81, key()),
+        ]
+    );
+}
+
+#[test]
+fn each_loop_plays_the_sequence_again_and_the_file_ends_with_the_last() {
+    let file = render(sequence(16, 120, vec![trig(0, 0.0, 1.0)]), 3);
+
+    let note_ons: Vec<u64> = file
+        .notes(1)
+        .into_iter()
+        .filter(|(_, status, _)| status & 0xF0 == 0x90)
+        .map(|(tick, _, _)| tick)
+        .collect();
+    assert_eq!(note_ons, vec![0, 16 * STEP, 32 * STEP]);
+    assert_eq!(file.end_of_track(0), 48 * STEP);
+    assert_eq!(file.end_of_track(1), 48 * STEP);
+}
+
+#[test]
+fn notes_still_sounding_at_the_end_are_cut_off_there() {
+    let file = render(sequence(16, 120, vec![trig(12, 0.0, 8.0)]), 1);
+
+    assert_eq!(
+        file.notes(1),
+        vec![(12 * STEP, 0x90, key()), (16 * STEP, 0x80, key())]
+    );
+    // No All Notes Off or All Sound Off sweep.
+    assert_eq!(file.tracks[1].len(), 4);
+}
+
+#[test]
+fn parameters_go_out_on_the_track_channel() {
+    let mut locked = sequence(
+        16,
+        120,
+        vec![on_track(
+            1,
+            Trig {
+                locks: Some(ParameterLocks {
+                    control_changes: vec![ControlChange {
+                        controller: 74,
+                        value: 20,
+                    }],
+                    ..Default::default()
+                }),
+                ..trig(4, 0.0, 1.0)
+            },
+        )],
+    );
+    locked.tracks = vec![TrackConfig {
+        track: 1,
+        midi_channel: 10,
+        defaults: Some(ParameterLocks {
+            control_changes: vec![ControlChange {
+                controller: 74,
+                value: 64,
+            }],
+            ..Default::default()
+        }),
+        ..Default::default()
+    }];
+    let file = render(locked, 1);
+
+    let controls: Vec<(u64, Vec<u8>)> = file.tracks[1]
+        .iter()
+        .filter(|(_, event)| event[0] & 0xF0 == 0xB0)
+        .cloned()
+        .collect();
+    assert_eq!(
+        controls,
+        vec![
+            (0, vec![0xB9, 74, 64]),
+            (4 * STEP, vec![0xB9, 74, 20]),
+            (5 * STEP, vec![0xB9, 74, 64]),
+        ]
+    );
+    assert_eq!(file.notes(1)[0], (4 * STEP, 0x99, key()));
+}
+
+#[test]
+fn renders_with_the_same_seed_are_identical() {
+    let coin_flips = sequence(
+        16,
+        120,
+        (0..16)
+            .map(|step| Trig {
+                condition: Some(TrigCondition {
+                    condition: Some(Condition::Probability(50)),
+                }),
+                ..trig(step, 0.0, 0.5)
+            })
+            .collect(),
+    );
+    let seeded = |seed| render_midi(coin_flips.clone(), &RenderOptions { loops: 4, seed }).unwrap();
+
+    assert_eq!(seeded(3), seeded(3));
+    assert_ne!(seeded(3), seeded(4));
+}
+
+#[test]
+fn nothing_renders_without_loops_or_from_an_invalid_sequence() {
+    let options = RenderOptions {
+        loops: 0,
+        ..Default::default()
+    };
+    assert_eq!(
+        render_midi(sequence(16, 120, vec![trig(0, 0.0, 1.0)]), &options),
+        Err(RenderError::NoLoops)
+    );
+
+    let error = render_midi(
+        sequence(16, 120, vec![trig(16, 0.0, 1.0)]),
+        &RenderOptions::default(),
+    )
+    .unwrap_err();
+    assert!(matches!(error, RenderError::InvalidSequence(_)));
+}
@@ -0,0 +1,391 @@
+mod common;
+
+use common::{sequence, trig};
+use helloworld_tonic::import::{
+    import_midi, ImportError, ImportOptions, Imported, NoteProblem, UnrepresentableNote,
+};
+use helloworld_tonic::render::{render_midi, RenderOptions};
+use helloworld_tonic::smf::{self, SmfError, SmfTrack, TrackEvent};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::validation::check_sequence;
+use helloworld_tonic::{MiddleC, Note, Subdivision, TrackConfig, Trig};
+
+// Ticks in a sixteenth at the resolution files are written with.
+const STEP: u64 = smf::TICKS_PER_QUARTER as u64 / 4;
+
+fn note(octave: i32, value: i32, velocity: u32) -> Note {
+    Note {
+        octave,
+        value,
+        velocity,
+    }
+}
+
+fn on_track(track: u32, trig: Trig) -> Trig {
+    Trig { track, ..trig }
+}
+
+fn event(tick: u64, message: &[u8]) -> TrackEvent {
+    TrackEvent {
+        tick,
+        message: message.to_vec(),
+    }
+}
+
+/// A file with one track of `events`, 16 sixteenths long at 120 BPM.
+fn file(events: Vec<TrackEvent>) -> Vec<u8> {
+    let track = SmfTrack {
+        name: "Track".to_string(),
+        events,
+    };
+    smf::write_type1(120, &[track], 16 * STEP)
+}
+
+fn import(bytes: &[u8]) -> Imported {
+    let imported = import_midi(bytes, &ImportOptions::default()).unwrap();
+    check_sequence(&imported.sequence).unwrap();
+    imported
+}
+
+fn sorted(mut trigs: Vec<Trig>) -> Vec<Trig> {
+    trigs.sort_by(|a, b| {
+        (a.track, a.step)
+            .cmp(&(b.track, b.step))
+            .then(a.offset.total_cmp(&b.offset))
+    });
+    trigs
+}
+
+#[test]
+fn an_exported_sequence_comes_back_as_it_was() {
+    let mut original = sequence(
+        16,
+        132,
+        vec![
+            Trig {
+                chord: vec![note(4, 5, 90), note(4, 8, 80)],
+                ..trig(0, 0.0, 2.0)
+            },
+            trig(4, 0.25, 0.5),
+            on_track(1, trig(6, -0.125, 1.5)),
+            on_track(3, trig(15, 0.0, 1.0)),
+        ],
+    );
+    original.tracks = [0, 1, 3]
+        .into_iter()
+        .map(|track| TrackConfig {
+            track,
+            midi_channel: track + 1,
+            ..Default::default()
+        })
+        .collect();
+
+    let exported = render_midi(original.clone(), &RenderOptions::default()).unwrap();
+    let imported = import(&exported);
+
+    assert_eq!(imported.sequence.sequence_length, 16);
+    assert_eq!(imported.sequence.bpm, 132);
+    assert_eq!(
+        imported.sequence.trig_subdivision,
+        original.trig_subdivision
+    );
+    assert_eq!(imported.sequence.tracks, original.tracks);
+    assert_eq!(imported.sequence.trigs, sorted(original.trigs));
+    assert!(imported.unrepresentable.is_empty());
+    assert_eq!(imported.ignored_events, 0);
+    assert_eq!(imported.ignored_tempo_changes, 0);
+    assert!(!imported.tempo_clamped);
+}
+
+#[test]
+fn notes_go_on_the_nearest_step_with_the_rest_as_offset() {
+    let imported = import(&file(vec![
+        event(STEP + 10, &[0x90, 60, 100]),
+        event(2 * STEP + 10, &[0x80, 60, 0]),
+        event(5 * STEP - 30, &[0x90, 62, 100]),
+        event(5 * STEP + 60, &[0x80, 62, 0]),
+    ]));
+
+    let placed: Vec<(u32, f32, f32)> = imported
+        .sequence
+        .trigs
+        .iter()
+        .map(|trig| (trig.step, trig.offset, trig.length))
+        .collect();
+    assert_eq!(
+        placed,
+        vec![(1, 10.0 / 120.0, 1.0), (5, -0.25, 90.0 / 120.0)]
+    );
+}
+
+#[test]
+fn coarser_subdivisions_put_more_on_each_step() {
+    let bytes = file(vec![
+        event(0, &[0x90, 60, 100]),
+        event(STEP, &[0x80, 60, 0]),
+        event(3 * STEP, &[0x90, 62, 100]),
+        event(4 * STEP, &[0x80, 62, 0]),
+    ]);
+    let eighths = ImportOptions {
+        subdivision: Subdivision {
+            numerator: 1,
+            denominator: 8,
+        },
+        ..Default::default()
+    };
+    let imported = import_midi(&bytes, &eighths).unwrap();
+
+    assert_eq!(imported.sequence.sequence_length, 8);
+    let placed: Vec<(u32, f32, f32)> = imported
+        .sequence
+        .trigs
+        .iter()
+        .map(|trig| (trig.step, trig.offset, trig.length))
+        .collect();
+    assert_eq!(placed, vec![(0, 0.0, 0.5), (2, -0.5, 0.5)]);
+}
+
+#[test]
+fn channels_become_tracks_that_keep_their_channel() {
+    let imported = import(&file(vec![
+        event(0, &[0x92, 60, 100]),
+        event(0, &[0x9A, 36, 127]),
+        event(STEP, &[0x82, 60, 0]),
+        event(STEP, &[0x8A, 36, 0]),
+    ]));
+
+    let tracks: Vec<(u32, u32)> = imported
+        .sequence
+        .tracks
+        .iter()
+        .map(|config| (config.track, config.midi_channel))
+        .collect();
+    assert_eq!(tracks, vec![(2, 3), (10, 11)]);
+    let trig_tracks: Vec<u32> = imported
+        .sequence
+        .trigs
+        .iter()
+        .map(|trig| trig.track)
+        .collect();
+    assert_eq!(trig_tracks, vec![2, 10]);
+}
+
+#[test]
+fn notes_starting_together_become_chords_and_keep_their_velocities() {
+    let imported = import(&file(vec![
+        event(0, &[0x90, 60, 100]),
+        event(0, &[0x90, 64, 80]),
+        event(0, &[0x90, 67, 60]),
+        event(STEP, &[0x80, 60, 0]),
+        event(STEP, &[0x80, 64, 0]),
+        // Released later, so it can't share the trig's length.
+        event(2 * STEP, &[0x80, 67, 0]),
+    ]));
+
+    let trigs = &imported.sequence.trigs;
+    assert_eq!(trigs.len(), 2);
+    assert_eq!(trigs[0].note, Some(note(5, 1, 100)));
+    assert_eq!(trigs[0].chord, vec![note(5, 5, 80)]);
+    assert_eq!(trigs[1].note, Some(note(5, 8, 60)));
+    assert_eq!(trigs[1].length, 2.0);
+}
+
+#[test]
+fn octaves_are_numbered_the_chosen_way() {
+    let bytes = file(vec![
+        event(0, &[0x90, 60, 100]),
+        event(STEP, &[0x80, 60, 0]),
+    ]);
+    let options = ImportOptions {
+        middle_c: MiddleC::C3,
+        ..Default::default()
+    };
+    let imported = import_midi(&bytes, &options).unwrap();
+
+    assert_eq!(imported.sequence.middle_c(), MiddleC::C3);
+    assert_eq!(imported.sequence.trigs[0].note, Some(note(3, 1, 100)));
+}
+
+#[test]
+fn running_status_and_zero_velocity_note_offs_are_understood() {
+    // Format 0, 96 ticks to the quarter: a note-on, its release as a
+    // note-on at velocity 0, then a second note, all under one status byte.
+    let track = [
+        0x00, 0x90, 60, 100, //
+        0x18, 60, 0, //
+        0x18, 62, 90, //
+        0x18, 62, 0, //
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+    let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\0\x60MTrk".to_vec();
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&track);
+
+    let imported = import(&bytes);
+    let placed: Vec<(u32, f32)> = imported
+        .sequence
+        .trigs
+        .iter()
+        .map(|trig| (trig.step, trig.length))
+        .collect();
+    assert_eq!(placed, vec![(0, 1.0), (2, 1.0)]);
+    assert_eq!(imported.sequence.sequence_length, 3);
+}
+
+#[test]
+fn notes_that_dont_fit_are_reported() {
+    let imported = import(&file(vec![
+        event(0, &[0x90, 60, 100]),
+        event(20, &[0x80, 60, 0]),
+        // Quantizes onto step 0 as well, where 60 already plays.
+        event(30, &[0x90, 60, 100]),
+        event(50, &[0x80, 60, 0]),
+        event(4 * STEP, &[0x91, 48, 100]),
+    ]));
+
+    assert_eq!(
+        imported.unrepresentable,
+        vec![
+            UnrepresentableNote {
+                channel: 1,
+                key: 60,
+                tick: 30,
+                problem: NoteProblem::SamePitchOnStep { step: 0 },
+            },
+            UnrepresentableNote {
+                channel: 2,
+                key: 48,
+                tick: 4 * STEP,
+                problem: NoteProblem::Unterminated,
+            },
+        ]
+    );
+    // The held note is kept, up to the end of the file.
+    let held = imported.sequence.trigs.last().unwrap();
+    assert_eq!((held.track, held.step, held.length), (1, 4, 12.0));
+}
+
+#[test]
+fn late_notes_on_the_last_step_come_round_on_the_first() {
+    let imported = import(&file(vec![
+        event(16 * STEP - 20, &[0x90, 60, 100]),
+        event(16 * STEP, &[0x80, 60, 0]),
+    ]));
+
+    let trig = &imported.sequence.trigs[0];
+    assert_eq!((trig.step, trig.offset), (0, -20.0 / 120.0));
+    assert_eq!(imported.sequence.sequence_length, 16);
+}
+
+#[test]
+fn messages_other_than_notes_are_counted() {
+    let imported = import(&file(vec![
+        event(0, &[0xB0, 74, 20]),
+        event(0, &[0xC0, 5]),
+        event(0, &[0x90, 60, 100]),
+        event(STEP, &[0xE0, 0, 64]),
+        event(STEP, &[0x80, 60, 0]),
+    ]));
+
+    assert_eq!(imported.ignored_events, 3);
+    assert_eq!(imported.sequence.trigs.len(), 1);
+}
+
+#[test]
+fn files_it_cant_read_are_rejected() {
+    let options = ImportOptions::default();
+    assert_eq!(
+        import_midi(b"RIFF....WAVE", &options),
+        Err(ImportError::Smf(SmfError::NotMidi))
+    );
+
+    let mut truncated = file(vec![event(0, &[0x90, 60, 100])]);
+    truncated.truncate(truncated.len() - 3);
+    assert_eq!(
+        import_midi(&truncated, &options),
+        Err(ImportError::Smf(SmfError::Truncated))
+    );
+
+    let mut format_2 = file(Vec::new());
+    format_2[9] = 2;
+    assert_eq!(
+        import_midi(&format_2, &options),
+        Err(ImportError::Smf(SmfError::UnsupportedFormat(2)))
+    );
+
+    let mut smpte = file(Vec::new());
+    smpte[12] = 0xE7;
+    assert_eq!(
+        import_midi(&smpte, &options),
+        Err(ImportError::Smf(SmfError::SmpteTiming))
+    );
+
+    let no_steps = ImportOptions {
+        subdivision: Subdivision {
+            numerator: 0,
+            denominator: 16,
+        },
+        ..Default::default()
+    };
+    assert!(matches!(
+        import_midi(&file(Vec::new()), &no_steps),
+        Err(ImportError::InvalidSubdivision { .. })
+    ));
+}
+
+#[test]
+fn files_without_a_tempo_play_at_120() {
+    let track = [0x00, 0xFF, 0x2F, 0x00];
+    let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\x01\xe0MTrk".to_vec();
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&track);
+
+    let imported: Sequence = import(&bytes).sequence;
+    assert_eq!(imported.bpm, 120);
+    assert_eq!(imported.sequence_length, 1);
+    assert!(imported.trigs.is_empty());
+}
+
+/// A file with one track of nothing but `tempos`, each a delta time in ticks
+/// and microseconds per quarter note, at 480 ticks per quarter.
+fn file_with_tempos(tempos: &[(u8, u32)]) -> Vec<u8> {
+    let mut track = Vec::new();
+    for (delta, micros_per_quarter) in tempos {
+        track.extend_from_slice(&[*delta, 0xFF, 0x51, 0x03]);
+        track.extend_from_slice(&micros_per_quarter.to_be_bytes()[1..]);
+    }
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\x01\xe0MTrk".to_vec();
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&track);
+    bytes
+}
+
+#[test]
+fn later_tempo_changes_are_counted() {
+    // 120, 120 again, 100 and back to 120 BPM.
+    let imported = import(&file_with_tempos(&[
+        (0, 500_000),
+        (120, 500_000),
+        (120, 600_000),
+        (120, 500_000),
+    ]));
+
+    assert_eq!(imported.sequence.bpm, 120);
+    assert_eq!(imported.ignored_tempo_changes, 2);
+    assert!(!imported.tempo_clamped);
+}
+
+#[test]
+fn tempos_sequences_cant_play_are_clamped_and_reported() {
+    // 40 BPM.
+    let imported = import(&file_with_tempos(&[(0, 1_500_000)]));
+    assert_eq!(imported.sequence.bpm, 60);
+    assert!(imported.tempo_clamped);
+
+    // 400 BPM.
+    let imported = import(&file_with_tempos(&[(0, 150_000)]));
+    assert_eq!(imported.sequence.bpm, 300);
+    assert!(imported.tempo_clamped);
+    assert_eq!(imported.ignored_tempo_changes, 0);
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::FakePorts;
+use helloworld_tonic::ports::{PortError, PortSelector, SelectedOutput};
+use helloworld_tonic::sequencer::MidiSink;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn ports_are_chosen_by_name_part_of_one_or_regex() {
+    let ports = names(&[
+        "Midi Through:0",
+        "Digitakt MIDI 1",
+        "Digitakt MIDI 1 (2)",
+        "Bass",
+    ]);
+
+    assert_eq!(PortSelector::new("").choose(&ports), Ok("Bass"));
+    // An exact name wins over longer names that contain it.
+    assert_eq!(
+        PortSelector::new("Digitakt MIDI 1").choose(&ports),
+        Ok("Digitakt MIDI 1")
+    );
+    assert_eq!(
+        PortSelector::new("Digitakt").choose(&ports),
+        Ok("Digitakt MIDI 1 (2)")
+    );
+    assert_eq!(
+        PortSelector::new("^Midi.*:0$").choose(&ports),
+        Ok("Midi Through:0")
+    );
+    // Not a valid regex, but still a name.
+    assert_eq!(
+        PortSelector::new("1 (2").choose(&ports),
+        Ok("Digitakt MIDI 1 (2)")
+    );
+}
+
+#[test]
+fn missing_ports_are_errors_not_panics() {
+    assert_eq!(PortSelector::new("").choose(&[]), Err(PortError::NoPorts));
+    assert_eq!(
+        PortSelector::new("Synth").choose(&names(&["Bass"])),
+        Err(PortError::NoMatch {
+            pattern: "Synth".to_string(),
+            available: names(&["Bass"]),
+        })
+    );
+}
+
+#[test]
+fn output_reconnects_when_its_device_returns() {
+    let ports = FakePorts::with(&["Bass", "Synth"]);
+    let (mut output, connected) = SelectedOutput::open(ports.clone(), PortSelector::new("Synth"));
+    assert_eq!(connected, Ok("Synth".to_string()));
+    output.send(&[0x90, 60, 100]).unwrap();
+
+    // Unplugged: messages are dropped until it is back.
+    ports.set(&["Bass"]);
+    assert!(matches!(output.reconnect(), Err(PortError::NoMatch { .. })));
+    assert_eq!(output.port_name(), None);
+    output.send(&[0x80, 60, 0]).unwrap();
+
+    ports.set(&["Bass", "Synth"]);
+    assert_eq!(output.reconnect(), Ok("Synth".to_string()));
+    output.send(&[0x90, 62, 100]).unwrap();
+
+    assert_eq!(
+        *ports.sent.lock().unwrap(),
+        vec![
+            ("Synth".to_string(), vec![0x90, 60, 100]),
+            ("Synth".to_string(), vec![0x90, 62, 100]),
+        ]
+    );
+}
+
+#[test]
+fn output_waits_for_a_port_that_is_not_there_yet() {
+    let ports = FakePorts::default();
+    let (mut output, connected) = SelectedOutput::open(ports.clone(), PortSelector::new(""));
+    assert_eq!(connected, Err(PortError::NoPorts));
+    output.send(&[0x90, 60, 100]).unwrap();
+
+    ports.set(&["Synth"]);
+    assert_eq!(output.reconnect(), Ok("Synth".to_string()));
+    output.send(&[0x90, 62, 100]).unwrap();
+    assert_eq!(
+        *ports.sent.lock().unwrap(),
+        vec![("Synth".to_string(), vec![0x90, 62, 100])]
+    );
+}
+
+#[test]
+fn selecting_switches_every_clone_and_a_failed_selection_changes_nothing() {
+    let ports = FakePorts::with(&["Bass", "Synth"]);
+    let (output, _) = SelectedOutput::open(ports.clone(), PortSelector::new("Bass"));
+    let mut handler_side = output.clone();
+
+    assert_eq!(
+        output.select(PortSelector::new("syn|Syn")),
+        Ok("Synth".to_string())
+    );
+    handler_side.send(&[0x90, 60, 100]).unwrap();
+
+    assert!(matches!(
+        output.select(PortSelector::new("Drums")),
+        Err(PortError::NoMatch { .. })
+    ));
+    handler_side.send(&[0x80, 60, 0]).unwrap();
+
+    // The new selection is what reconnecting looks for.
+    ports.set(&["Bass"]);
+    assert!(output.reconnect().is_err());
+    ports.set(&["Bass", "Synth"]);
+    assert_eq!(output.reconnect(), Ok("Synth".to_string()));
+
+    let sent = ports.sent.lock().unwrap();
+    assert!(sent.iter().all(|(port, _)| port == "Synth"));
+    assert_eq!(sent.len(), 2);
+}
+
+#[test]
+fn notes_keep_flowing_while_a_port_connects() {
+    let ports = FakePorts::default();
+    let (output, _) = SelectedOutput::open(ports.clone(), PortSelector::new("Synth"));
+    let mut handler_side = output.clone();
+
+    ports.set(&["Synth"]);
+    let connecting = ports.connecting.lock().unwrap();
+    let reconnecting = thread::spawn(move || output.reconnect());
+    thread::sleep(Duration::from_millis(50));
+
+    let (sent, done) = mpsc::channel();
+    thread::spawn(move || sent.send(handler_side.send(&[0x90, 60, 100])));
+    assert_eq!(done.recv_timeout(Duration::from_secs(1)), Ok(Ok(())));
+
+    drop(connecting);
+    assert_eq!(reconnecting.join().unwrap(), Ok("Synth".to_string()));
+}
+
+#[test]
+fn messages_are_dropped_rather_than_wait_for_a_busy_connection() {
+    let ports = FakePorts::with(&["Synth"]);
+    let (output, _) = SelectedOutput::open(ports.clone(), PortSelector::new("Synth"));
+    let mut handler_side = output.clone();
+
+    let sending = ports.sending.lock().unwrap();
+    let busy = output.clone();
+    let first = thread::spawn(move || busy.send_message(&[0x90, 60, 100]));
+    thread::sleep(Duration::from_millis(50));
+
+    let (sent, done) = mpsc::channel();
+    thread::spawn(move || sent.send(handler_side.send(&[0x90, 64, 100])));
+    assert_eq!(done.recv_timeout(Duration::from_secs(1)), Ok(Ok(())));
+    assert_eq!(output.dropped_messages(), 1);
+
+    drop(sending);
+    assert_eq!(first.join().unwrap(), Ok(()));
+    assert_eq!(
+        *ports.sent.lock().unwrap(),
+        vec![("Synth".to_string(), vec![0x90, 60, 100])]
+    );
+}
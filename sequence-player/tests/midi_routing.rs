@@ -0,0 +1,202 @@
+mod common;
+
+use common::{trig, FakePorts};
+use helloworld_tonic::midi_clock::ClockMessage;
+use helloworld_tonic::ports::{PortSelector, SelectedOutput};
+use helloworld_tonic::routing::{format_routes, parse_routes, MidiRouter, RoutingError};
+use helloworld_tonic::sequencer::{MidiSink, MidiStepHandler, StepHandler};
+use helloworld_tonic::server::sequence::MidiRoute;
+use helloworld_tonic::Trig;
+use std::path::PathBuf;
+
+fn route(track: u32, port: &str, channel: u32) -> MidiRoute {
+    MidiRoute {
+        track,
+        port: port.to_string(),
+        channel,
+    }
+}
+
+fn on_track(track: u32) -> Trig {
+    Trig {
+        track,
+        ..trig(0, 0.0, 0.5)
+    }
+}
+
+fn router(ports: &FakePorts, config: Option<PathBuf>) -> MidiRouter<FakePorts> {
+    let (main, _) = SelectedOutput::open(ports.clone(), PortSelector::new("Main"));
+    MidiRouter::new(ports.clone(), main, config).unwrap()
+}
+
+fn sent(ports: &FakePorts) -> Vec<(String, u8)> {
+    let sent = ports.sent.lock().unwrap();
+    sent.iter()
+        .map(|(port, message)| (port.clone(), message[0]))
+        .collect()
+}
+
+#[test]
+fn routes_survive_a_round_trip_through_the_routes_file() {
+    let routes = vec![
+        route(0, "Synth A", 1),
+        route(17, "^USB MIDI \\(2\\)$", 16),
+        route(3, "", 4),
+    ];
+    assert_eq!(parse_routes(&format_routes(&routes)), Ok(routes));
+    assert_eq!(
+        parse_routes("# comment\n\n  5   2   Port with  spaces  \n"),
+        Ok(vec![route(5, "Port with  spaces", 2)])
+    );
+    assert!(matches!(
+        parse_routes("1 2 Main\nnot a route\n"),
+        Err(RoutingError::Malformed { line: 2, .. })
+    ));
+}
+
+#[test]
+fn channels_beyond_16_and_tracks_routed_twice_are_rejected() {
+    let ports = FakePorts::with(&["Main"]);
+    let router = router(&ports, None);
+    router.set_routes(vec![route(1, "", 2)]).unwrap();
+
+    assert_eq!(
+        router.set_routes(vec![route(1, "", 17)]),
+        Err(RoutingError::InvalidChannel {
+            track: 1,
+            channel: 17
+        })
+    );
+    assert_eq!(
+        router.set_routes(vec![route(4, "", 1), route(4, "Main", 2)]),
+        Err(RoutingError::DuplicateTrack(4))
+    );
+    assert_eq!(router.routes(), vec![route(1, "", 2)]);
+}
+
+#[test]
+fn tracks_go_to_their_own_port_and_channel() {
+    let ports = FakePorts::with(&["Main", "Synth A", "Synth B"]);
+    let router = router(&ports, None);
+    router
+        .set_routes(vec![
+            route(0, "Synth A", 1),
+            route(17, "Synth B", 3),
+            route(2, "", 5),
+            route(3, "Synth A", 0),
+        ])
+        .unwrap();
+    let handler = MidiStepHandler::new(router);
+
+    let trigs: Vec<Trig> = [0, 17, 2, 3, 5].into_iter().map(on_track).collect();
+    handler.handle_notes_on(&trigs.iter().collect::<Vec<_>>());
+    handler.handle_notes_off(&[&trigs[1]]);
+
+    assert_eq!(
+        sent(&ports),
+        vec![
+            ("Synth A".to_string(), 0x90),
+            ("Synth B".to_string(), 0x92),
+            // Routed without a port: the main output on another channel.
+            ("Main".to_string(), 0x94),
+            // Routed without a channel: the track's own.
+            ("Synth A".to_string(), 0x93),
+            // Not routed at all.
+            ("Main".to_string(), 0x95),
+            ("Synth B".to_string(), 0x82),
+        ]
+    );
+}
+
+#[test]
+fn clock_goes_to_every_port_once() {
+    let ports = FakePorts::with(&["Main", "Synth A", "Synth B"]);
+    let router = router(&ports, None);
+    router
+        .set_routes(vec![route(0, "Synth B", 1), route(1, "B$", 2)])
+        .unwrap();
+    let handler = MidiStepHandler::with_midi_clock(router);
+
+    handler.handle_clock(ClockMessage::Pulse);
+
+    let mut sent = sent(&ports);
+    sent.sort();
+    assert_eq!(
+        sent,
+        vec![("Main".to_string(), 0xF8), ("Synth B".to_string(), 0xF8)]
+    );
+}
+
+#[test]
+fn ports_that_turn_up_later_still_get_clock_once() {
+    let ports = FakePorts::with(&["Main"]);
+    let router = router(&ports, None);
+    router
+        .set_routes(vec![route(0, "Synth B", 1), route(1, "B$", 2)])
+        .unwrap();
+    ports.set(&["Main", "Synth B"]);
+    router.reconnect();
+    let handler = MidiStepHandler::with_midi_clock(router);
+
+    handler.handle_clock(ClockMessage::Pulse);
+
+    let mut sent = sent(&ports);
+    sent.sort();
+    assert_eq!(
+        sent,
+        vec![("Main".to_string(), 0xF8), ("Synth B".to_string(), 0xF8)]
+    );
+}
+
+#[test]
+fn a_failing_port_does_not_keep_messages_from_the_others() {
+    let ports = FakePorts::with(&["Main", "Synth A", "Synth B"]);
+    let mut router = router(&ports, None);
+    router
+        .set_routes(vec![route(0, "Synth A", 1), route(1, "Synth B", 2)])
+        .unwrap();
+    ports.fail(&["Main", "Synth A"]);
+
+    assert_eq!(router.send(&[0xFA]), Err("Main is gone".to_string()));
+    assert_eq!(sent(&ports), vec![("Synth B".to_string(), 0xFA)]);
+}
+
+#[test]
+fn all_notes_off_reaches_every_port_past_one_that_fails() {
+    let ports = FakePorts::with(&["Main", "Synth A", "Synth B"]);
+    let router = router(&ports, None);
+    router
+        .set_routes(vec![route(0, "Synth A", 1), route(1, "Synth B", 2)])
+        .unwrap();
+    ports.fail(&["Synth A"]);
+    let handler = MidiStepHandler::new(router);
+
+    handler.handle_all_notes_off();
+
+    let sent = ports.sent.lock().unwrap();
+    for port in ["Main", "Synth B"] {
+        let messages: Vec<&Vec<u8>> = sent
+            .iter()
+            .filter(|(to, _)| to == port)
+            .map(|(_, message)| message)
+            .collect();
+        let expected: Vec<Vec<u8>> = (0..16)
+            .flat_map(|channel| [vec![0xB0 | channel, 123, 0], vec![0xB0 | channel, 120, 0]])
+            .collect();
+        assert_eq!(messages, expected.iter().collect::<Vec<_>>(), "{}", port);
+    }
+}
+
+#[test]
+fn routes_are_saved_and_loaded_again() {
+    let config = std::env::temp_dir().join(format!("midi-routes-{}.conf", std::process::id()));
+    let ports = FakePorts::with(&["Main", "Synth A"]);
+    let routes = vec![route(0, "Synth A", 1), route(20, "Synth A", 2)];
+    router(&ports, Some(config.clone()))
+        .set_routes(routes.clone())
+        .unwrap();
+
+    let reloaded = router(&ports, Some(config.clone()));
+    std::fs::remove_file(&config).unwrap();
+    assert_eq!(reloaded.routes(), routes);
+}
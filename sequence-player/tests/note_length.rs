@@ -0,0 +1,98 @@
+mod common;
+
+use common::{millis, note_off_times, note_on_times, play_until, sequence, trig};
+use helloworld_tonic::recording::Recorded;
+use helloworld_tonic::timing::VirtualClock;
+use helloworld_tonic::Trig;
+use std::sync::Arc;
+
+// 120 BPM sixteenths: every step is 125 ms.
+
+#[test]
+fn fractional_lengths_end_mid_step() {
+    let clock = Arc::new(VirtualClock::new());
+    let trigs = vec![trig(0, 0.0, 0.25), trig(1, 0.0, 1.5)];
+    let (start, events) = play_until(clock, sequence(4, 120, trigs), |events| {
+        !note_off_times(events, 1).is_empty()
+    });
+
+    assert_eq!(note_off_times(&events, 0)[0], start + millis(31.25));
+    assert_eq!(note_off_times(&events, 1)[0], start + millis(312.5));
+}
+
+#[test]
+fn note_offs_go_out_in_time_order() {
+    let clock = Arc::new(VirtualClock::new());
+    // Distinct pitches, so none of them retriggers another.
+    let trigs: Vec<Trig> = [(0, 3.0), (1, 0.5), (2, 0.75)]
+        .into_iter()
+        .map(|(step, length)| {
+            let mut trig = trig(step, 0.0, length);
+            if let Some(note) = trig.note.as_mut() {
+                note.value += step as i32;
+            }
+            trig
+        })
+        .collect();
+    let (_, events) = play_until(clock, sequence(8, 120, trigs), |events| {
+        !note_off_times(events, 0).is_empty()
+    });
+
+    let released: Vec<u32> = events
+        .iter()
+        .filter_map(|recorded| match recorded.event {
+            Recorded::NoteOff { step, .. } => Some(step),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(released[..3], [1, 2, 0]);
+}
+
+#[test]
+fn legato_note_off_precedes_the_next_note_on() {
+    let clock = Arc::new(VirtualClock::new());
+    let trigs = vec![trig(0, 0.0, 1.0), trig(1, 0.0, 1.0)];
+    let (_, events) = play_until(clock, sequence(4, 120, trigs), |events| {
+        !note_on_times(events, 1).is_empty()
+    });
+
+    let step_one = events
+        .iter()
+        .position(|recorded| matches!(recorded.event, Recorded::NoteOn { step: 1, .. }))
+        .unwrap();
+    assert!(matches!(
+        events[step_one - 1].event,
+        Recorded::NoteOff { step: 0, .. }
+    ));
+    assert_eq!(events[step_one - 1].time, events[step_one].time);
+}
+
+#[test]
+fn tied_note_holds_until_the_next_trig_on_its_track() {
+    let clock = Arc::new(VirtualClock::new());
+    let mut other_track = trig(1, 0.0, 1.0);
+    other_track.track = 1;
+    let trigs = vec![trig(0, 0.0, f32::INFINITY), other_track, trig(3, 0.0, 1.0)];
+    let (start, events) = play_until(clock, sequence(4, 120, trigs), |events| {
+        !note_off_times(events, 0).is_empty()
+    });
+
+    // The trig on another track doesn't cut it; the step 3 trig on the same
+    // track does.
+    assert_eq!(note_off_times(&events, 0)[0], start + millis(375.0));
+}
+
+#[test]
+fn tied_note_is_released_by_its_own_retrigger() {
+    let clock = Arc::new(VirtualClock::new());
+    let trigs = vec![trig(2, 0.0, f32::INFINITY)];
+    let (start, events) = play_until(clock, sequence(4, 120, trigs), |events| {
+        !note_off_times(events, 2).is_empty()
+    });
+
+    assert_eq!(note_off_times(&events, 2)[0], start + millis(750.0));
+    assert_eq!(
+        note_on_times(&events, 2)[..2],
+        [start + millis(250.0), start + millis(750.0)]
+    );
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use common::{sequence, trig, FakeConnection, Sent};
+use helloworld_tonic::sequencer::{MidiStepHandler, Sequencer, StepHandler};
+use helloworld_tonic::Trig;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Track whose notes bring the playback thread down.
+const CRASHING_TRACK: u32 = 13;
+
+/// Plays through a MIDI handler, but panics on the crashing track the way a
+/// bug in a handler would.
+struct CrashingHandler(MidiStepHandler<FakeConnection>);
+
+impl StepHandler for CrashingHandler {
+    fn handle_notes_on(&self, trigs: &[&Trig]) {
+        if trigs.iter().any(|trig| trig.track == CRASHING_TRACK) {
+            panic!("handler bug");
+        }
+        self.0.handle_notes_on(trigs);
+    }
+
+    fn handle_notes_off(&self, trigs: &[&Trig]) {
+        self.0.handle_notes_off(trigs);
+    }
+
+    fn handle_all_notes_off(&self) {
+        self.0.handle_all_notes_off();
+    }
+
+    fn handle_panic(&self) {
+        self.0.handle_panic();
+    }
+}
+
+fn midi_sequencer() -> (Sequencer, Sent) {
+    let sent = Sent::default();
+    let handler = MidiStepHandler::new(FakeConnection {
+        port: String::new(),
+        sent: sent.clone(),
+        ..Default::default()
+    });
+    (Sequencer::new(CrashingHandler(handler)), sent)
+}
+
+fn messages(sent: &Sent) -> Vec<Vec<u8>> {
+    let sent = sent.lock().unwrap();
+    sent.iter().map(|(_, message)| message.clone()).collect()
+}
+
+fn wait_for(sent: &Sent, done: impl Fn(&[Vec<u8>]) -> bool) {
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while !done(&messages(sent)) {
+        assert!(Instant::now() < give_up, "nothing came through");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// Whether All Sound Off has reached the last channel.
+fn silenced(messages: &[Vec<u8>]) -> bool {
+    messages.iter().any(|message| *message == [0xBF, 120, 0])
+}
+
+// A note held for the whole bar, so only the sequencer can end it early.
+fn held(track: u32) -> Trig {
+    Trig {
+        track,
+        ..trig(0, 0.0, 16.0)
+    }
+}
+
+/// The note-off for the held note, then All Notes Off and All Sound Off on
+/// each of the 16 channels.
+fn assert_silenced_after_note_on(messages: &[Vec<u8>]) {
+    let note_on = messages
+        .iter()
+        .position(|message| message[0] == 0x90)
+        .unwrap();
+    let mut expected = vec![vec![0x80, messages[note_on][1], 0]];
+    for channel in 0..16 {
+        expected.push(vec![0xB0 | channel, 123, 0]);
+        expected.push(vec![0xB0 | channel, 120, 0]);
+    }
+    assert_eq!(messages[note_on + 1..note_on + 34], expected);
+}
+
+#[test]
+fn stopping_ends_held_notes_and_silences_every_channel() {
+    let (sequencer, sent) = midi_sequencer();
+    sequencer
+        .cue_sequence(sequence(16, 120, vec![held(0)]))
+        .unwrap();
+    sequencer.start_sequence().unwrap();
+    wait_for(&sent, |messages| !messages.is_empty());
+
+    sequencer.stop_sequence().unwrap();
+    wait_for(&sent, silenced);
+
+    assert_silenced_after_note_on(&messages(&sent));
+}
+
+#[test]
+fn swapping_ends_the_notes_of_the_outgoing_sequence() {
+    let (sequencer, sent) = midi_sequencer();
+    sequencer
+        .cue_sequence(sequence(16, 120, vec![held(0)]))
+        .unwrap();
+    sequencer.start_sequence().unwrap();
+    wait_for(&sent, |messages| !messages.is_empty());
+
+    sequencer
+        .swap_sequence(sequence(16, 120, vec![held(1)]))
+        .unwrap();
+    wait_for(&sent, silenced);
+    sequencer.stop_sequence().unwrap();
+
+    assert_silenced_after_note_on(&messages(&sent));
+}
+
+#[test]
+fn a_crashed_playback_thread_is_silenced_and_restarted() {
+    let (sequencer, sent) = midi_sequencer();
+    let crashing = Trig {
+        track: CRASHING_TRACK,
+        ..trig(1, 0.0, 0.5)
+    };
+    sequencer
+        .cue_sequence(sequence(16, 240, vec![held(0), crashing]))
+        .unwrap();
+    sequencer.start_sequence().unwrap();
+    wait_for(&sent, silenced);
+    assert_silenced_after_note_on(&messages(&sent));
+
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while sequencer.is_playing() {
+        assert!(Instant::now() < give_up, "transport still playing");
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    // Commands reach the new playback thread.
+    sent.lock().unwrap().clear();
+    sequencer
+        .cue_sequence(sequence(16, 240, vec![held(2)]))
+        .unwrap();
+    sequencer.start_sequence().unwrap();
+    wait_for(&sent, |messages| {
+        messages.iter().any(|message| message[0] == 0x92)
+    });
+}
+
+#[test]
+fn panic_silences_everything_at_once() {
+    let (sequencer, sent) = midi_sequencer();
+    sequencer
+        .cue_sequence(sequence(16, 120, vec![held(0)]))
+        .unwrap();
+    sequencer.start_sequence().unwrap();
+    wait_for(&sent, |messages| !messages.is_empty());
+
+    sequencer.panic().unwrap();
+
+    // Sound stops before the call returns, not when playback gets to it.
+    assert_silenced_after_note_on(&messages(&sent));
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while sequencer.is_playing() {
+        assert!(Instant::now() < give_up, "transport still playing");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn the_playback_thread_leaves_silencing_to_a_panic_under_way() {
+    let sent = Sent::default();
+    let sending = Arc::new(Mutex::new(()));
+    let handler = Arc::new(MidiStepHandler::new(FakeConnection {
+        port: String::new(),
+        sent: sent.clone(),
+        sending: Arc::clone(&sending),
+        ..Default::default()
+    }));
+    let held_up = sending.lock().unwrap();
+    let panicking = {
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || handler.handle_panic())
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    let (done_tx, done) = mpsc::channel();
+    {
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            handler.handle_all_notes_off();
+            done_tx.send(())
+        });
+    }
+    assert_eq!(done.recv_timeout(Duration::from_secs(1)), Ok(()));
+
+    drop(held_up);
+    panicking.join().unwrap();
+    // All Notes Off and All Sound Off on each channel, from the panic alone.
+    assert_eq!(messages(&sent).len(), 32);
+}
@@ -0,0 +1,211 @@
+mod common;
+
+use common::{play_until, sequence, trig};
+use helloworld_tonic::recording::{Recorded, RecordedEvent};
+use helloworld_tonic::sequencer::{Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::server::sequence::{ControlChange, ParameterLocks};
+use helloworld_tonic::timing::VirtualClock;
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{TrackConfig, Trig};
+use std::sync::Arc;
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn control_changes(pairs: &[(u32, u32)]) -> ParameterLocks {
+    ParameterLocks {
+        control_changes: pairs
+            .iter()
+            .map(|(controller, value)| ControlChange {
+                controller: *controller,
+                value: *value,
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+fn locked(step: u32, length: f32, locks: ParameterLocks) -> Trig {
+    Trig {
+        locks: Some(locks),
+        ..trig(step, 0.0, length)
+    }
+}
+
+// Same trig a third higher, so it can overlap `locked` without retriggering.
+fn locked_third(step: u32, length: f32, locks: ParameterLocks) -> Trig {
+    let mut trig = locked(step, length, locks);
+    if let Some(note) = trig.note.as_mut() {
+        note.value += 4;
+    }
+    trig
+}
+
+fn with_defaults(trigs: Vec<Trig>, defaults: ParameterLocks) -> Sequence {
+    let mut sequence = sequence(4, 120, trigs);
+    sequence.tracks = vec![TrackConfig {
+        track: 0,
+        defaults: Some(defaults),
+        ..Default::default()
+    }];
+    sequence
+}
+
+fn play(sequence: Sequence, events_wanted: usize) -> Vec<RecordedEvent> {
+    let clock = Arc::new(VirtualClock::new());
+    let (_, events) = play_until(clock, sequence, |events| events.len() >= events_wanted);
+    events
+}
+
+fn parameters(event: &RecordedEvent) -> &ParameterLocks {
+    match &event.event {
+        Recorded::Parameters { parameters, .. } => parameters,
+        other => panic!("expected parameters, got {:?}", other),
+    }
+}
+
+fn is_note_on(event: &RecordedEvent) -> bool {
+    matches!(event.event, Recorded::NoteOn { .. })
+}
+
+fn is_note_off(event: &RecordedEvent) -> bool {
+    matches!(event.event, Recorded::NoteOff { .. })
+}
+
+#[test]
+fn locks_go_out_just_before_the_note_on() {
+    let locks = ParameterLocks {
+        pitch_bend: Some(2048),
+        channel_pressure: Some(90),
+        ..control_changes(&[(74, 100), (71, 20)])
+    };
+    let events = play(sequence(4, 120, vec![locked(1, 0.5, locks.clone())]), 3);
+
+    assert_eq!(parameters(&events[0]), &locks);
+    assert!(matches!(
+        events[0].event,
+        Recorded::Parameters { track: 0, .. }
+    ));
+    assert!(is_note_on(&events[1]));
+    assert_eq!(events[0].time, events[1].time);
+}
+
+#[test]
+fn track_defaults_return_after_the_note_off() {
+    let defaults = ParameterLocks {
+        channel_pressure: Some(10),
+        ..control_changes(&[(74, 64), (10, 0)])
+    };
+    let locks = ParameterLocks {
+        pitch_bend: Some(-4000),
+        channel_pressure: Some(100),
+        ..control_changes(&[(74, 127), (20, 5)])
+    };
+    let events = play(
+        with_defaults(vec![locked(0, 0.5, locks)], defaults.clone()),
+        5,
+    );
+
+    // Defaults go out when the sequence starts, then the locks, the note and
+    // finally the restored values once the note has ended.
+    assert_eq!(parameters(&events[0]), &defaults);
+    assert!(is_note_on(&events[2]));
+    assert!(is_note_off(&events[3]));
+    assert_eq!(
+        parameters(&events[4]),
+        &ParameterLocks {
+            pitch_bend: Some(0),
+            channel_pressure: Some(10),
+            // CC 20 has no default to return to.
+            ..control_changes(&[(74, 64)])
+        }
+    );
+    assert_eq!(events[3].time, events[4].time);
+}
+
+#[test]
+fn overlapping_locked_notes_restore_once_the_last_one_ends() {
+    let events = play(
+        with_defaults(
+            vec![
+                locked(0, 1.5, control_changes(&[(74, 10)])),
+                locked_third(1, 1.5, control_changes(&[(74, 20)])),
+            ],
+            control_changes(&[(74, 64)]),
+        ),
+        8,
+    );
+
+    let kinds: Vec<&str> = events[..8]
+        .iter()
+        .map(|event| match event.event {
+            Recorded::Parameters { .. } => "parameters",
+            Recorded::NoteOn { .. } => "on",
+            Recorded::NoteOff { .. } => "off",
+            _ => "other",
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            "parameters",
+            "parameters",
+            "on",
+            "parameters",
+            "on",
+            "off",
+            "off",
+            "parameters"
+        ]
+    );
+    assert_eq!(parameters(&events[7]), &control_changes(&[(74, 64)]));
+}
+
+#[test]
+fn unlocked_trigs_send_no_parameters() {
+    let events = play(sequence(4, 120, vec![trig(0, 0.0, 0.5)]), 2);
+    assert!(is_note_on(&events[0]));
+    assert!(is_note_off(&events[1]));
+}
+
+#[test]
+fn values_midi_cannot_carry_are_rejected() {
+    let sequencer = Sequencer::new(NullHandler);
+    let invalid = [
+        control_changes(&[(120, 0)]),
+        control_changes(&[(74, 128)]),
+        ParameterLocks {
+            pitch_bend: Some(8192),
+            ..Default::default()
+        },
+        ParameterLocks {
+            channel_pressure: Some(128),
+            ..Default::default()
+        },
+    ];
+    for locks in invalid {
+        let result = sequencer.cue_sequence(sequence(4, 120, vec![locked(2, 1.0, locks.clone())]));
+        assert!(
+            matches!(
+                result,
+                Err(SequencerError::InvalidParameterLock {
+                    track: 0,
+                    step: Some(2),
+                    ..
+                })
+            ),
+            "{} was accepted",
+            locks
+        );
+
+        let result = sequencer.cue_sequence(with_defaults(vec![], locks));
+        assert!(matches!(
+            result,
+            Err(SequencerError::InvalidParameterLock { step: None, .. })
+        ));
+    }
+}
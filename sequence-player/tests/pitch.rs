@@ -0,0 +1,135 @@
+mod common;
+
+use common::{compiled, sequence, trig, FakeConnection, Sent};
+use helloworld_tonic::sequencer::{MidiStepHandler, Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{MiddleC, Note, NoteValue, Pitch, Trig};
+
+const CONVENTIONS: [MiddleC; 3] = [MiddleC::C5, MiddleC::C4, MiddleC::C3];
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn note(octave: i32, value: NoteValue) -> Note {
+    Note {
+        octave,
+        value: value as i32,
+        velocity: 100,
+    }
+}
+
+fn midi_note(octave: i32, value: NoteValue, middle_c: MiddleC) -> Option<u8> {
+    Pitch::from_note(&note(octave, value), middle_c).map(Pitch::midi_note)
+}
+
+fn playing(note: Note, middle_c: MiddleC) -> Sequence {
+    let mut sequence = sequence(
+        4,
+        120,
+        vec![Trig {
+            note: Some(note),
+            ..trig(1, 0.0, 0.5)
+        }],
+    );
+    sequence.set_middle_c(middle_c);
+    sequence
+}
+
+#[test]
+fn every_midi_note_survives_a_round_trip() {
+    for middle_c in CONVENTIONS {
+        for midi_note in 0..=Pitch::MAX {
+            let pitch = Pitch::new(midi_note).unwrap();
+            let note = pitch.to_note(middle_c, 100);
+            assert_eq!(
+                Pitch::from_note(&note, middle_c),
+                Some(pitch),
+                "{} with middle C as {:?}",
+                note,
+                middle_c
+            );
+        }
+    }
+    assert_eq!(Pitch::new(128), None);
+}
+
+#[test]
+fn middle_c_is_note_60_in_every_convention() {
+    for middle_c in CONVENTIONS {
+        assert_eq!(
+            midi_note(middle_c.octave(), NoteValue::C, middle_c),
+            Some(60)
+        );
+        assert_eq!(
+            midi_note(middle_c.octave(), NoteValue::A, middle_c),
+            Some(69)
+        );
+    }
+    // C is the bottom of its octave, not a semitone above it.
+    assert_eq!(midi_note(4, NoteValue::C, MiddleC::C5), Some(48));
+    assert_eq!(midi_note(4, NoteValue::B, MiddleC::C5), Some(59));
+}
+
+#[test]
+fn notes_outside_the_midi_range_have_no_pitch() {
+    assert_eq!(midi_note(-1, NoteValue::C, MiddleC::C4), Some(0));
+    assert_eq!(midi_note(-2, NoteValue::B, MiddleC::C4), None);
+    assert_eq!(midi_note(9, NoteValue::G, MiddleC::C4), Some(127));
+    assert_eq!(midi_note(9, NoteValue::GSharp, MiddleC::C4), None);
+    assert_eq!(midi_note(i32::MAX, NoteValue::C, MiddleC::C3), None);
+    assert_eq!(midi_note(i32::MIN, NoteValue::C, MiddleC::C3), None);
+    assert_eq!(midi_note(4, NoteValue::UnknownNote, MiddleC::C4), None);
+}
+
+#[test]
+fn unrepresentable_notes_are_rejected_on_cue_and_swap() {
+    let sequencer = Sequencer::new(NullHandler);
+    let too_high = playing(note(10, NoteValue::C), MiddleC::C4);
+    let expected = SequencerError::InvalidNote {
+        track: 0,
+        step: 1,
+        note: "C10".to_string(),
+    };
+    assert_eq!(
+        sequencer.cue_sequence(too_high.clone()).err(),
+        Some(expected.clone())
+    );
+    assert_eq!(sequencer.swap_sequence(too_high).err(), Some(expected));
+
+    // The same octave is fine where middle C is written higher.
+    assert!(sequencer
+        .cue_sequence(playing(note(10, NoteValue::C), MiddleC::C5))
+        .is_ok());
+    assert!(matches!(
+        sequencer.cue_sequence(playing(note(4, NoteValue::UnknownNote), MiddleC::C4)),
+        Err(SequencerError::InvalidNote { .. })
+    ));
+}
+
+#[test]
+fn the_handler_sends_notes_in_the_sequence_convention() {
+    let sent = Sent::default();
+    let handler = MidiStepHandler::new(FakeConnection {
+        port: String::new(),
+        sent: sent.clone(),
+        ..Default::default()
+    });
+    let middle_c = Trig {
+        note: Some(note(3, NoteValue::C)),
+        ..trig(0, 0.0, 0.5)
+    };
+
+    handler.handle_sequence_change(&compiled(&playing(note(3, NoteValue::C), MiddleC::C3)));
+    handler.handle_notes_on(&[&middle_c]);
+    // A sequence numbering octaves differently doesn't change the note-off.
+    handler.handle_sequence_change(&compiled(&playing(note(3, NoteValue::C), MiddleC::C4)));
+    handler.handle_notes_off(&[&middle_c]);
+
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent[0].1, vec![0x90, 60, 100]);
+    assert_eq!(sent[1].1, vec![0x80, 60, 0]);
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::{play_until, play_with, sequence, trig};
+use helloworld_tonic::recording::{Recorded, RecordedEvent};
+use helloworld_tonic::timing::{StepDuration, VirtualClock};
+use helloworld_tonic::tracks::{master_length, track_settings, tracks};
+use helloworld_tonic::{Subdivision, TrackConfig, Trig};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn on_track(track: u32, step: u32) -> Trig {
+    Trig {
+        track,
+        ..trig(step, 0.0, 0.5)
+    }
+}
+
+fn track_config(track: u32, length: u32, subdivision: Option<(i64, i64)>) -> TrackConfig {
+    TrackConfig {
+        track,
+        length,
+        subdivision: subdivision.map(|(numerator, denominator)| Subdivision {
+            numerator,
+            denominator,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Times at which any trig on `track` started playing, as offsets from `start`.
+fn track_note_ons(events: &[RecordedEvent], start: Instant, track: u32) -> Vec<Duration> {
+    events
+        .iter()
+        .filter(|recorded| plays_on(recorded, track))
+        .map(|recorded| recorded.time - start)
+        .collect()
+}
+
+fn note_on_count(events: &[RecordedEvent], track: u32) -> usize {
+    events
+        .iter()
+        .filter(|recorded| plays_on(recorded, track))
+        .count()
+}
+
+fn plays_on(recorded: &RecordedEvent, track: u32) -> bool {
+    matches!(recorded.event, Recorded::NoteOn { track: played, .. } if played == track)
+}
+
+fn sixteenths(steps: &[u64]) -> Vec<Duration> {
+    let step = StepDuration::new(120, 1, 16);
+    steps.iter().map(|steps| step.steps(*steps)).collect()
+}
+
+#[test]
+fn tracks_loop_over_their_own_lengths() {
+    let mut polymeter = sequence(16, 120, vec![on_track(0, 0), on_track(1, 0)]);
+    polymeter.tracks = vec![track_config(0, 3, None), track_config(1, 4, None)];
+
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, polymeter, |events| {
+        note_on_count(events, 0) >= 4 && note_on_count(events, 1) >= 3
+    });
+
+    assert_eq!(
+        track_note_ons(&events, start, 0)[..4],
+        sixteenths(&[0, 3, 6, 9])[..]
+    );
+    assert_eq!(
+        track_note_ons(&events, start, 1)[..3],
+        sixteenths(&[0, 4, 8])[..]
+    );
+}
+
+#[test]
+fn tracks_run_at_their_own_speed() {
+    let mut polymeter = sequence(4, 120, vec![on_track(0, 0), on_track(1, 0)]);
+    // Track 1 at 2x: four of its steps pass in two master steps.
+    polymeter.tracks = vec![track_config(1, 2, Some((1, 32)))];
+
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, polymeter, |events| note_on_count(events, 1) >= 4);
+
+    let double_speed = StepDuration::new(120, 1, 32);
+    assert_eq!(
+        track_note_ons(&events, start, 1)[..4],
+        [0, 2, 4, 6].map(|steps| double_speed.steps(steps))[..]
+    );
+    assert_eq!(track_note_ons(&events, start, 0)[..1], sixteenths(&[0])[..]);
+}
+
+#[test]
+fn master_length_decides_when_a_cue_takes_over() {
+    let mut first = sequence(16, 120, vec![on_track(0, 0)]);
+    first.master_length = 6;
+    first.tracks = vec![track_config(0, 4, None)];
+    let next = sequence(16, 120, vec![on_track(5, 0)]);
+
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_with(
+        clock,
+        first,
+        |sequencer| {
+            sequencer.cue_sequence(next).unwrap();
+        },
+        |events| note_on_count(events, 5) > 0,
+    );
+
+    // Track 0 loops every four steps until the master length hands over, even
+    // though it is in the middle of its second loop.
+    assert_eq!(track_note_ons(&events, start, 0), sixteenths(&[0, 4]));
+    assert_eq!(track_note_ons(&events, start, 5)[..1], sixteenths(&[6])[..]);
+}
+
+#[test]
+fn unconfigured_tracks_follow_the_sequence() {
+    let mut sequence = sequence(12, 120, vec![on_track(2, 0), on_track(7, 3)]);
+    sequence.tracks = vec![TrackConfig {
+        midi_channel: 10,
+        ..track_config(2, 0, None)
+    }];
+
+    assert_eq!(master_length(&sequence), 12);
+    assert_eq!(
+        tracks(&sequence)
+            .iter()
+            .map(|settings| settings.track)
+            .collect::<Vec<_>>(),
+        vec![2, 7]
+    );
+
+    let configured = track_settings(&sequence, 2);
+    assert_eq!(configured.length, 12);
+    assert_eq!(configured.midi_channel, 9);
+    assert_eq!(configured.step_duration, StepDuration::new(120, 1, 16));
+
+    let unconfigured = track_settings(&sequence, 7);
+    assert_eq!(unconfigured.length, 12);
+    assert_eq!(unconfigured.midi_channel, 7);
+}
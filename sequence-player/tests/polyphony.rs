@@ -0,0 +1,189 @@
+mod common;
+
+use common::{play_until, sequence, trig};
+use helloworld_tonic::recording::{Recorded, RecordedEvent};
+use helloworld_tonic::timing::{StepDuration, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{Note, TrackConfig, Trig, VoiceMode};
+use std::sync::Arc;
+use std::time::Instant;
+
+const BPM: u32 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    On,
+    Off,
+}
+
+fn note(octave: i32, value: i32, velocity: u32) -> Note {
+    Note {
+        octave,
+        value,
+        velocity,
+    }
+}
+
+fn chord(step: u32, length: f32, notes: Vec<Note>) -> Trig {
+    trig(step, 0.0, length).with_notes(notes)
+}
+
+fn with_voice_mode(trigs: Vec<Trig>, voice_mode: VoiceMode) -> Sequence {
+    let mut sequence = sequence(4, BPM, trigs);
+    sequence.tracks = vec![TrackConfig {
+        track: 0,
+        voice_mode: voice_mode as i32,
+        ..Default::default()
+    }];
+    sequence
+}
+
+/// Play the first loop of `sequence`, keeping only its notes.
+fn first_loop(sequence: Sequence) -> (Instant, Vec<RecordedEvent>) {
+    let loop_end = StepDuration::new(BPM, 1, 16).steps(4);
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, sequence, |events| {
+        events
+            .last()
+            .is_some_and(|event| event.time.duration_since(events[0].time) >= loop_end)
+    });
+    let events = events
+        .into_iter()
+        .filter(|event| event.time < start + loop_end)
+        .filter(|event| {
+            matches!(
+                event.event,
+                Recorded::NoteOn { .. } | Recorded::NoteOff { .. }
+            )
+        })
+        .collect();
+    (start, events)
+}
+
+/// (kind, step, notes) of the notes of a step that start or end together,
+/// for comparing against what was played.
+fn played(events: &[RecordedEvent]) -> Vec<(Kind, u32, Vec<Note>)> {
+    let mut played: Vec<(Instant, Kind, u32, Vec<Note>)> = Vec::new();
+    for event in events {
+        let (kind, step, note) = match &event.event {
+            Recorded::NoteOn { step, note, .. } => (Kind::On, *step, note),
+            Recorded::NoteOff { step, note, .. } => (Kind::Off, *step, note),
+            _ => continue,
+        };
+        match played.last_mut() {
+            Some((time, last_kind, last_step, notes))
+                if *time == event.time && *last_kind == kind && *last_step == step =>
+            {
+                notes.push(note.clone());
+            }
+            _ => played.push((event.time, kind, step, vec![note.clone()])),
+        }
+    }
+    played
+        .into_iter()
+        .map(|(_, kind, step, notes)| (kind, step, notes))
+        .collect()
+}
+
+#[test]
+fn chords_play_every_note_with_its_own_velocity() {
+    let notes = vec![note(4, 1, 100), note(4, 5, 80), note(4, 8, 60)];
+    let (_, events) = first_loop(sequence(4, BPM, vec![chord(0, 0.5, notes.clone())]));
+
+    assert_eq!(
+        played(&events),
+        vec![(Kind::On, 0, notes.clone()), (Kind::Off, 0, notes),]
+    );
+}
+
+#[test]
+fn mono_tracks_cut_the_previous_note() {
+    let (start, events) = first_loop(with_voice_mode(
+        vec![
+            trig(0, 0.0, 3.0),
+            chord(1, 0.5, vec![note(5, 3, 90), note(5, 7, 90)]),
+        ],
+        VoiceMode::Mono,
+    ));
+
+    // The long note ends as the next trig starts, and only the first note of
+    // the chord plays.
+    assert_eq!(
+        played(&events),
+        vec![
+            (Kind::On, 0, vec![note(4, 1, 100)]),
+            (Kind::Off, 0, vec![note(4, 1, 100)]),
+            (Kind::On, 1, vec![note(5, 3, 90)]),
+            (Kind::Off, 1, vec![note(5, 3, 90)]),
+        ]
+    );
+    let step = StepDuration::new(BPM, 1, 16);
+    assert_eq!(events[1].time, start + step.as_duration());
+    assert_eq!(events[1].time, events[2].time);
+}
+
+#[test]
+fn poly_tracks_let_different_pitches_ring_over_each_other() {
+    let (_, events) = first_loop(with_voice_mode(
+        vec![trig(0, 0.0, 2.0), chord(1, 0.5, vec![note(4, 5, 100)])],
+        VoiceMode::Poly,
+    ));
+
+    assert_eq!(
+        played(&events),
+        vec![
+            (Kind::On, 0, vec![note(4, 1, 100)]),
+            (Kind::On, 1, vec![note(4, 5, 100)]),
+            (Kind::Off, 1, vec![note(4, 5, 100)]),
+            (Kind::Off, 0, vec![note(4, 1, 100)]),
+        ]
+    );
+}
+
+#[test]
+fn repeated_pitches_retrigger_with_a_single_note_off_each() {
+    let (start, events) = first_loop(sequence(
+        4,
+        BPM,
+        vec![
+            chord(0, 3.0, vec![note(4, 1, 100), note(4, 5, 100)]),
+            trig(1, 0.0, 0.5),
+        ],
+    ));
+
+    // The repeated C ends before it sounds again, and its original note-off
+    // no longer cuts the new one short; the E keeps its full length.
+    assert_eq!(
+        played(&events),
+        vec![
+            (Kind::On, 0, vec![note(4, 1, 100), note(4, 5, 100)]),
+            (Kind::Off, 0, vec![note(4, 1, 100)]),
+            (Kind::On, 1, vec![note(4, 1, 100)]),
+            (Kind::Off, 1, vec![note(4, 1, 100)]),
+            (Kind::Off, 0, vec![note(4, 5, 100)]),
+        ]
+    );
+    let step = StepDuration::new(BPM, 1, 16);
+    assert_eq!(events.last().unwrap().time, start + step.steps(3));
+}
+
+#[test]
+fn the_same_pitch_twice_on_one_step_plays_once() {
+    let (_, events) = first_loop(sequence(
+        4,
+        BPM,
+        vec![
+            trig(2, 0.0, 0.5),
+            chord(2, 1.0, vec![note(4, 1, 70), note(4, 8, 70)]),
+        ],
+    ));
+
+    // The later trig wins the shared pitch.
+    assert_eq!(
+        played(&events),
+        vec![
+            (Kind::On, 2, vec![note(4, 1, 70), note(4, 8, 70)]),
+            (Kind::Off, 2, vec![note(4, 1, 70), note(4, 8, 70)]),
+        ]
+    );
+}
@@ -0,0 +1,377 @@
+mod common;
+
+use common::{advance_until, sequence, trig};
+use helloworld_tonic::event_table::EventTable;
+use helloworld_tonic::sampler::{Sample, SampleBank, SampleSettings, TrackSample};
+use helloworld_tonic::sequencer::{Sequencer, StepHandler};
+use helloworld_tonic::server::sequence::trig_condition::Condition;
+use helloworld_tonic::server::sequence::{ControlChange, ParameterLocks, TrigCondition};
+use helloworld_tonic::synth::{SynthEvent, SynthSink, SynthStepHandler};
+use helloworld_tonic::timing::VirtualClock;
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::{Note, TrackConfig, Trig, VoiceMode};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Step boundaries played before counting starts, so queues and maps have
+// grown to what the sequence needs.
+const WARMUP_STEPS: usize = 256;
+// Step boundaries counted after that.
+const MEASURED_STEPS: usize = 2048;
+
+/// Counts allocations made by threads that have switched counting on.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count_allocation() {
+    if COUNTING.try_with(Cell::get).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Switches counting on for the playback thread once it has warmed up, and
+/// off again after `MEASURED_STEPS` more boundaries.
+#[derive(Clone, Default)]
+struct CountingHandler {
+    steps: Arc<AtomicUsize>,
+}
+
+impl StepHandler for CountingHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {
+        self.count_step();
+    }
+
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+
+    fn handle_parameters(&self, _track: u32, _parameters: &ParameterLocks) {}
+}
+
+impl CountingHandler {
+    fn count_step(&self) {
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        if steps == WARMUP_STEPS {
+            COUNTING.with(|counting| counting.set(true));
+        } else if steps == WARMUP_STEPS + MEASURED_STEPS {
+            COUNTING.with(|counting| counting.set(false));
+        }
+    }
+
+    /// Play on until `steps` boundaries have gone through the handler.
+    fn play_to(&self, clock: &VirtualClock, steps: usize) {
+        clock.settle();
+        advance_until(clock, || self.steps.load(Ordering::Relaxed) >= steps);
+    }
+}
+
+/// Plays on the built-in synth, counting steps as `CountingHandler` does.
+struct CountingSynth {
+    counter: CountingHandler,
+    synth: SynthStepHandler<NullSink>,
+}
+
+impl StepHandler for CountingSynth {
+    fn handle_notes_on(&self, trigs: &[&Trig]) {
+        self.synth.handle_notes_on(trigs);
+        self.counter.count_step();
+    }
+
+    fn handle_notes_off(&self, trigs: &[&Trig]) {
+        self.synth.handle_notes_off(trigs);
+    }
+
+    fn handle_parameters(&self, track: u32, parameters: &ParameterLocks) {
+        self.synth.handle_parameters(track, parameters);
+    }
+
+    fn handle_sequence_change(&self, table: &Arc<EventTable>) {
+        self.synth.handle_sequence_change(table);
+    }
+}
+
+/// Takes every synth event and does nothing with it.
+struct NullSink;
+
+impl SynthSink for NullSink {
+    fn send(&mut self, _event: SynthEvent) {}
+}
+
+fn track_sample() -> TrackSample {
+    let sample = Sample {
+        sample_rate: 44_100,
+        frames: vec![1.0; 64],
+    };
+    TrackSample::new(sample, SampleSettings::default()).unwrap()
+}
+
+fn note(value: i32) -> Note {
+    Note {
+        octave: 4,
+        value,
+        velocity: 100,
+    }
+}
+
+fn cc(controller: u32, value: u32) -> ControlChange {
+    ControlChange { controller, value }
+}
+
+/// Four tracks of chords, locks, micro-timing, conditions and ties, at
+/// different lengths so the playheads drift against each other.
+fn busy_sequence() -> Sequence {
+    let mut trigs = Vec::new();
+    for step in 0..16 {
+        trigs.push(Trig {
+            note: Some(note(1)),
+            chord: vec![note(5), note(8)],
+            locks: Some(ParameterLocks {
+                control_changes: vec![cc(74, step * 8)],
+                pitch_bend: Some(step as i32 * 100),
+                ..Default::default()
+            }),
+            ..trig(step, 0.0, 0.5)
+        });
+    }
+    for step in 0..12 {
+        trigs.push(Trig {
+            track: 1,
+            offset: if step % 2 == 0 { 0.25 } else { -0.25 },
+            condition: Some(TrigCondition {
+                condition: Some(Condition::Probability(60)),
+            }),
+            ..trig(step, 0.0, 0.75)
+        });
+    }
+    for step in (0..10).step_by(2) {
+        trigs.push(Trig {
+            track: 2,
+            note: Some(note(step as i32 + 1)),
+            length: f32::INFINITY,
+            ..trig(step, 0.0, 1.0)
+        });
+    }
+    for step in 0..7 {
+        trigs.push(Trig {
+            track: 3,
+            note: Some(note(1)),
+            chord: vec![note(4)],
+            ..trig(step, 0.0, 2.5)
+        });
+    }
+
+    let mut sequence = sequence(16, 140, trigs);
+    sequence.tracks = vec![
+        TrackConfig {
+            track: 0,
+            defaults: Some(ParameterLocks {
+                control_changes: vec![cc(74, 64)],
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        TrackConfig {
+            track: 1,
+            length: 12,
+            ..Default::default()
+        },
+        TrackConfig {
+            track: 2,
+            length: 10,
+            voice_mode: VoiceMode::Mono as i32,
+            ..Default::default()
+        },
+        TrackConfig {
+            track: 3,
+            length: 7,
+            ..Default::default()
+        },
+    ];
+    sequence
+}
+
+// Allocation counts are shared by the whole test binary, so the tests take
+// turns.
+static SERIAL: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[test]
+fn playback_thread_does_not_allocate_once_warmed_up() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    let handler = CountingHandler::default();
+    let clock = Arc::new(VirtualClock::with_jitter(Duration::from_micros(500)));
+    let sequencer = Sequencer::with_clock(handler.clone(), Arc::clone(&clock));
+    sequencer.set_random_seed(7);
+    sequencer.cue_sequence(busy_sequence()).unwrap();
+    sequencer.start_sequence().unwrap();
+
+    handler.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS);
+    drop(sequencer);
+
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn cues_and_swaps_do_not_allocate_on_the_playback_thread() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    let handler = CountingHandler::default();
+    let clock = Arc::new(VirtualClock::new());
+    let sequencer = Sequencer::with_clock(handler.clone(), Arc::clone(&clock));
+    sequencer.cue_sequence(busy_sequence()).unwrap();
+    sequencer.start_sequence().unwrap();
+
+    // Compiled on this thread; the playback thread only takes them over.
+    handler.play_to(&clock, WARMUP_STEPS);
+    let mut faster = busy_sequence();
+    faster.bpm = 160;
+    sequencer.cue_sequence(faster).unwrap();
+    handler.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS / 2);
+    sequencer.swap_sequence(busy_sequence()).unwrap();
+
+    handler.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS);
+    drop(sequencer);
+
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn the_synth_picks_up_new_samples_without_allocating_on_the_playback_thread() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    let samples = SampleBank::default();
+    samples.assign(0, track_sample());
+    let counter = CountingHandler::default();
+    let handler = CountingSynth {
+        counter: counter.clone(),
+        synth: SynthStepHandler::with_samples(NullSink, samples.clone()),
+    };
+    let clock = Arc::new(VirtualClock::new());
+    let sequencer = Sequencer::with_clock(handler, Arc::clone(&clock));
+    sequencer.set_random_seed(7);
+    sequencer.cue_sequence(busy_sequence()).unwrap();
+    sequencer.start_sequence().unwrap();
+
+    // Loaded on this thread; the playback thread only looks them up.
+    counter.play_to(&clock, WARMUP_STEPS);
+    samples.assign(1, track_sample());
+    samples.clear(0);
+    counter.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS / 2);
+    samples.assign(3, track_sample());
+
+    counter.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS);
+    drop(sequencer);
+
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn restoring_every_controller_does_not_allocate_on_the_playback_thread() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    let handler = CountingHandler::default();
+    let clock = Arc::new(VirtualClock::new());
+    let sequencer = Sequencer::with_clock(handler.clone(), Arc::clone(&clock));
+    sequencer.cue_sequence(busy_sequence()).unwrap();
+    sequencer.start_sequence().unwrap();
+
+    // Only one controller has been restored by the time counting starts; this
+    // restores every one there is, each locked twice.
+    handler.play_to(&clock, WARMUP_STEPS);
+    let every_controller: Vec<ControlChange> =
+        (0..120).map(|controller| cc(controller, 1)).collect();
+    let mut locked = sequence(
+        16,
+        140,
+        vec![Trig {
+            locks: Some(ParameterLocks {
+                control_changes: [every_controller.clone(), every_controller].concat(),
+                ..Default::default()
+            }),
+            ..trig(0, 0.0, 0.5)
+        }],
+    );
+    locked.tracks = vec![TrackConfig {
+        track: 0,
+        defaults: Some(ParameterLocks {
+            control_changes: (0..120).map(|controller| cc(controller, 64)).collect(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }];
+    sequencer.cue_sequence(locked).unwrap();
+
+    handler.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS);
+    drop(sequencer);
+
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn the_synth_keeps_every_sounding_note_without_allocating_on_the_playback_thread() {
+    let _serial = SERIAL
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    let counter = CountingHandler::default();
+    let handler = CountingSynth {
+        counter: counter.clone(),
+        synth: SynthStepHandler::new(NullSink),
+    };
+    let clock = Arc::new(VirtualClock::new());
+    let sequencer = Sequencer::with_clock(handler, Arc::clone(&clock));
+    sequencer.set_random_seed(7);
+    sequencer.cue_sequence(busy_sequence()).unwrap();
+    sequencer.start_sequence().unwrap();
+
+    // Far more notes than voices, on every track the synth plays, all held
+    // at once.
+    counter.play_to(&clock, WARMUP_STEPS);
+    let chords = (0..16)
+        .map(|track| Trig {
+            track,
+            note: Some(note(1)),
+            chord: (2..=6).map(note).collect(),
+            ..trig(0, 0.0, 4.0)
+        })
+        .collect();
+    sequencer.cue_sequence(sequence(16, 140, chords)).unwrap();
+
+    counter.play_to(&clock, WARMUP_STEPS + MEASURED_STEPS);
+    drop(sequencer);
+
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,273 @@
+mod common;
+
+use common::{compiled, sequence, trig};
+use helloworld_tonic::render::{render_wav_with_samples, RenderOptions};
+use helloworld_tonic::sampler::{
+    Sample, SampleBank, SampleError, SampleSettings, TrackSample, DEFAULT_ROOT_KEY,
+};
+use helloworld_tonic::sequencer::StepHandler;
+use helloworld_tonic::synth::{Patch, Synth, SynthEvent, SynthSink, SynthStepHandler, SAMPLE_RATE};
+use helloworld_tonic::{MiddleC, Pitch, Trig};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+// Samples in a sixteenth at 120 BPM.
+const STEP: f64 = SAMPLE_RATE as f64 / 8.0;
+
+// What the synth plays a full-scale sample at, full velocity.
+const FULL: f32 = 0.25;
+
+fn wav_file<S: hound::Sample + Copy>(spec: hound::WavSpec, values: &[S]) -> Vec<u8> {
+    let mut bytes = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+    for value in values {
+        writer.write_sample(*value).unwrap();
+    }
+    writer.finalize().unwrap();
+    bytes.into_inner()
+}
+
+fn constant(frames: usize) -> Sample {
+    Sample {
+        sample_rate: SAMPLE_RATE,
+        frames: vec![1.0; frames],
+    }
+}
+
+fn track_sample(sample: Sample, settings: SampleSettings) -> Arc<TrackSample> {
+    Arc::new(TrackSample::new(sample, settings).unwrap())
+}
+
+fn note_on(key: u8, velocity: u8, sample: &Arc<TrackSample>) -> SynthEvent {
+    SynthEvent::NoteOn {
+        track: 0,
+        key,
+        velocity,
+        patch: Patch::Sample(Arc::clone(sample)),
+    }
+}
+
+fn render(synth: &mut Synth, frames: usize) -> Vec<f32> {
+    let mut samples = vec![0.0; frames];
+    synth.render(&mut samples);
+    samples
+}
+
+fn at(step: f64) -> usize {
+    (step * STEP).round() as usize
+}
+
+fn sounding(samples: &[f32]) -> usize {
+    samples.iter().filter(|sample| **sample != 0.0).count()
+}
+
+#[test]
+fn wav_files_of_any_format_are_mixed_down_to_mono() {
+    let stereo = hound::WavSpec {
+        channels: 2,
+        sample_rate: 22_050,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let sample = Sample::from_wav(&wav_file(stereo, &[16384i16, 0, -32768, -32768])).unwrap();
+    assert_eq!(sample.sample_rate, 22_050);
+    assert_eq!(sample.frames, vec![0.25, -1.0]);
+
+    let float = hound::WavSpec {
+        channels: 1,
+        sample_rate: 48_000,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let sample = Sample::from_wav(&wav_file(float, &[0.5f32, -0.125])).unwrap();
+    assert_eq!(sample.frames, vec![0.5, -0.125]);
+}
+
+#[test]
+fn only_wav_files_with_frames_load() {
+    assert!(matches!(
+        Sample::from_wav(b"RIFF, but not really"),
+        Err(SampleError::Wav(_))
+    ));
+
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    assert_eq!(
+        Sample::from_wav(&wav_file::<i16>(spec, &[])),
+        Err(SampleError::Empty)
+    );
+}
+
+#[test]
+fn settings_must_pick_a_key_and_part_of_the_sample() {
+    let new = |settings| TrackSample::new(constant(100), settings);
+
+    assert_eq!(
+        new(SampleSettings {
+            root_key: 128,
+            ..Default::default()
+        }),
+        Err(SampleError::InvalidRootKey(128))
+    );
+    for (start, length) in [(1.0, 0.5), (-0.1, 0.5), (0.5, 0.0), (0.0, 1.5)] {
+        assert_eq!(
+            new(SampleSettings {
+                start,
+                length,
+                ..Default::default()
+            }),
+            Err(SampleError::InvalidRegion { start, length })
+        );
+    }
+}
+
+#[test]
+fn a_sample_plays_through_once_whatever_the_note_length() {
+    let sample = track_sample(constant(1000), SampleSettings::default());
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(note_on(60, 127, &sample));
+    let mut samples = render(&mut synth, 10);
+    synth.handle(SynthEvent::NoteOff { track: 0, key: 60 });
+    samples.extend(render(&mut synth, 1990));
+
+    assert!(samples[..1000].iter().all(|sample| *sample == FULL));
+    assert_eq!(sounding(&samples[1000..]), 0);
+    assert_eq!(synth.voices(), 0);
+}
+
+#[test]
+fn notes_above_the_root_key_play_faster_and_velocity_sets_the_gain() {
+    let sample = track_sample(constant(1000), SampleSettings::default());
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(note_on(72, 127, &sample));
+    assert_eq!(sounding(&render(&mut synth, 2000)), 500);
+
+    synth.handle(note_on(48, 127, &sample));
+    assert_eq!(sounding(&render(&mut synth, 4000)), 2000);
+
+    synth.handle(note_on(60, 64, &sample));
+    let quieter = render(&mut synth, 10);
+    assert!(quieter.iter().all(|sample| *sample == FULL * 64.0 / 127.0));
+}
+
+#[test]
+fn start_and_length_pick_the_part_played() {
+    let ramp = Sample {
+        sample_rate: SAMPLE_RATE,
+        frames: (0..1000).map(|frame| frame as f32 / 1000.0).collect(),
+    };
+    let sample = track_sample(
+        ramp,
+        SampleSettings {
+            start: 0.5,
+            length: 0.25,
+            ..Default::default()
+        },
+    );
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(note_on(60, 127, &sample));
+    let samples = render(&mut synth, 1000);
+
+    assert_eq!(samples[0], 0.5 * FULL);
+    assert_eq!(samples[249], 0.749 * FULL);
+    assert_eq!(sounding(&samples), 250);
+}
+
+#[test]
+fn a_looping_sample_repeats_until_the_note_ends() {
+    let sample = track_sample(
+        constant(100),
+        SampleSettings {
+            looping: true,
+            ..Default::default()
+        },
+    );
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(note_on(60, 127, &sample));
+    assert!(render(&mut synth, 1000)
+        .iter()
+        .all(|sample| *sample == FULL));
+
+    synth.handle(SynthEvent::NoteOff { track: 0, key: 60 });
+    render(&mut synth, SAMPLE_RATE as usize / 100);
+    assert_eq!(synth.voices(), 0);
+}
+
+#[test]
+fn tracks_play_their_sample_from_their_steps() {
+    let samples = SampleBank::default();
+    samples.assign(
+        0,
+        TrackSample::new(constant(at(0.5)), SampleSettings::default()).unwrap(),
+    );
+    let file = render_wav_with_samples(
+        sequence(
+            16,
+            120,
+            vec![Trig {
+                note: Some(
+                    Pitch::new(DEFAULT_ROOT_KEY)
+                        .unwrap()
+                        .to_note(MiddleC::default(), 127),
+                ),
+                ..trig(4, 0.0, 8.0)
+            }],
+        ),
+        &RenderOptions::default(),
+        samples,
+    )
+    .unwrap();
+    let samples: Vec<i16> = hound::WavReader::new(file.as_slice())
+        .unwrap()
+        .into_samples()
+        .map(Result::unwrap)
+        .collect();
+
+    let sounding = |from: usize, to: usize| samples[from..to].iter().any(|sample| *sample != 0);
+    assert!(!sounding(0, at(4.0)));
+    assert!(sounding(at(4.0), at(4.5)));
+    assert!(!sounding(at(4.5) + 1, samples.len()));
+}
+
+#[derive(Clone, Default)]
+struct Events(Arc<Mutex<Vec<SynthEvent>>>);
+
+impl SynthSink for Events {
+    fn send(&mut self, event: SynthEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn a_cleared_track_goes_back_to_the_synth() {
+    let events = Events::default();
+    let samples = SampleBank::default();
+    let handler = SynthStepHandler::with_samples(events.clone(), samples.clone());
+    let played = sequence(16, 120, vec![trig(0, 0.0, 1.0)]);
+    handler.handle_sequence_change(&compiled(&played));
+
+    samples.assign(
+        0,
+        TrackSample::new(constant(10), SampleSettings::default()).unwrap(),
+    );
+    handler.handle_notes_on(&[&played.trigs[0]]);
+    assert!(samples.clear(0));
+    assert!(!samples.clear(0));
+    handler.handle_notes_on(&[&played.trigs[0]]);
+
+    let patches: Vec<bool> = events
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|event| match event {
+            SynthEvent::NoteOn { patch, .. } => Some(matches!(patch, Patch::Sample(_))),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(patches, vec![true, false]);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::{note_on_times, play_until, sequence, trig};
+use helloworld_tonic::timing::{StepDuration, StepTimeline, VirtualClock};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const STEPS: usize = 10_000;
+
+/// Play a single-step 128 BPM pattern and return the transport start time
+/// along with the time of each of the first `STEPS` steps.
+fn record_steps(clock: Arc<VirtualClock>) -> (Instant, Vec<Instant>) {
+    let every_step = sequence(1, 128, vec![trig(0, 0.0, 0.5)]);
+    let (start, events) = play_until(clock, every_step, |events| {
+        note_on_times(events, 0).len() >= STEPS
+    });
+
+    let step_times = note_on_times(&events, 0).into_iter().take(STEPS).collect();
+    (start, step_times)
+}
+
+#[test]
+fn step_duration_is_not_truncated_to_milliseconds() {
+    let step = StepDuration::new(128, 1, 16);
+    assert_eq!(step.as_duration(), Duration::from_nanos(117_187_500));
+    assert_eq!(
+        step.steps(STEPS as u64),
+        Duration::from_micros(1_171_875_000)
+    );
+}
+
+#[test]
+fn step_duration_rounds_the_total_not_each_step() {
+    // 127 BPM sixteenths are 118.110236... ms, which no whole number of
+    // nanoseconds represents.
+    let step = StepDuration::new(127, 1, 16);
+    assert_eq!(step.as_duration(), Duration::from_nanos(118_110_236));
+    assert_eq!(step.steps(2032), Duration::from_secs(240));
+}
+
+#[test]
+fn timeline_retime_keeps_elapsed_steps_in_place() {
+    let start = Instant::now();
+    let mut timeline = StepTimeline::new(start, StepDuration::new(120, 1, 16));
+    for _ in 0..4 {
+        timeline.advance();
+    }
+    timeline.retime(StepDuration::new(60, 1, 16));
+
+    assert_eq!(timeline.next_deadline(), start + Duration::from_millis(500));
+    timeline.advance();
+    assert_eq!(timeline.next_deadline(), start + Duration::from_millis(750));
+}
+
+#[test]
+fn steps_land_exactly_on_the_timeline() {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, step_times) = record_steps(clock);
+
+    let step = StepDuration::new(128, 1, 16);
+    for (index, time) in step_times.iter().enumerate() {
+        assert_eq!(*time, start + step.steps(index as u64), "step {}", index);
+    }
+    assert_eq!(
+        step_times[STEPS - 1] - start,
+        Duration::from_nanos(117_187_500 * (STEPS as u64 - 1))
+    );
+}
+
+#[test]
+fn late_wakeups_do_not_accumulate_drift() {
+    let max_jitter = Duration::from_millis(3);
+    let clock = Arc::new(VirtualClock::with_jitter(max_jitter));
+    let (start, step_times) = record_steps(clock);
+
+    let step = StepDuration::new(128, 1, 16);
+    for (index, time) in step_times.iter().enumerate() {
+        let ideal = start + step.steps(index as u64);
+        assert!(*time >= ideal, "step {} fired early", index);
+        assert!(
+            *time - ideal < max_jitter,
+            "step {} drifted by {:?}",
+            index,
+            *time - ideal
+        );
+    }
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::{note_on_times, play_until, sequence, trig};
+use helloworld_tonic::sequencer::{Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::timing::{StepDuration, VirtualClock};
+use helloworld_tonic::{Subdivision, Trig};
+use std::sync::Arc;
+use std::time::Duration;
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn with_subdivision(numerator: i64, denominator: i64) -> helloworld_tonic::types::Sequence {
+    let mut sequence = sequence(4, 120, vec![trig(0, 0.0, 0.5), trig(1, 0.0, 0.5)]);
+    sequence.trig_subdivision = Some(Subdivision {
+        numerator,
+        denominator,
+    });
+    sequence
+}
+
+#[test]
+fn numerator_is_part_of_the_step_length() {
+    // At 120 BPM a whole note lasts two seconds.
+    assert_eq!(
+        StepDuration::new(120, 1, 16).as_duration(),
+        Duration::from_millis(125)
+    );
+    assert_eq!(
+        StepDuration::new(120, 3, 32).as_duration(),
+        Duration::from_nanos(187_500_000)
+    );
+    assert_eq!(
+        StepDuration::new(120, 1, 12).steps(3),
+        Duration::from_millis(500)
+    );
+}
+
+#[test]
+fn triplet_steps_play_at_triplet_spacing() {
+    let clock = Arc::new(VirtualClock::new());
+    let (_, events) = play_until(clock, with_subdivision(1, 12), |events| {
+        !note_on_times(events, 1).is_empty()
+    });
+
+    let spacing = note_on_times(&events, 1)[0] - note_on_times(&events, 0)[0];
+    assert_eq!(spacing, StepDuration::new(120, 1, 12).as_duration());
+}
+
+#[test]
+fn dotted_steps_play_at_dotted_spacing() {
+    let clock = Arc::new(VirtualClock::new());
+    let (_, events) = play_until(clock, with_subdivision(3, 32), |events| {
+        !note_on_times(events, 1).is_empty()
+    });
+
+    let spacing = note_on_times(&events, 1)[0] - note_on_times(&events, 0)[0];
+    assert_eq!(spacing, Duration::from_nanos(187_500_000));
+}
+
+#[test]
+fn cue_reports_the_step_duration() {
+    let sequencer = Sequencer::new(NullHandler);
+    let metadata = sequencer.cue_sequence(with_subdivision(1, 12)).unwrap();
+    assert_eq!(metadata.step_duration, Duration::from_nanos(166_666_666));
+
+    let metadata = sequencer.start_sequence().unwrap();
+    assert_eq!(metadata.step_duration, Duration::from_nanos(166_666_666));
+}
+
+#[test]
+fn invalid_subdivisions_are_rejected() {
+    let sequencer = Sequencer::new(NullHandler);
+    for (numerator, denominator) in [(0, 16), (1, 0), (-1, 16), (1, -8), (1, 4096)] {
+        let result = sequencer.cue_sequence(with_subdivision(numerator, denominator));
+        assert!(
+            matches!(
+                result,
+                Err(SequencerError::InvalidSubdivision { numerator: n, denominator: d })
+                    if n == numerator && d == denominator
+            ),
+            "{}/{} was accepted",
+            numerator,
+            denominator
+        );
+        assert!(sequencer
+            .swap_sequence(with_subdivision(numerator, denominator))
+            .is_err());
+    }
+}
+
+#[test]
+fn missing_subdivision_defaults_to_sixteenths() {
+    let sequencer = Sequencer::new(NullHandler);
+    let mut sequence = with_subdivision(1, 16);
+    sequence.trig_subdivision = None;
+    let metadata = sequencer.cue_sequence(sequence).unwrap();
+    assert_eq!(metadata.step_duration, Duration::from_millis(125));
+}
+
+#[test]
+fn speed_multipliers_map_to_subdivisions() {
+    let cases = [
+        ((2, 1), (1, 32)),
+        ((3, 2), (1, 24)),
+        ((1, 1), (1, 16)),
+        ((3, 4), (1, 12)),
+        ((1, 2), (1, 8)),
+        ((1, 4), (1, 4)),
+        ((1, 8), (1, 2)),
+    ];
+    for ((speed_numerator, speed_denominator), (numerator, denominator)) in cases {
+        assert_eq!(
+            Subdivision::from_speed(speed_numerator, speed_denominator),
+            Subdivision {
+                numerator,
+                denominator
+            }
+        );
+    }
+}
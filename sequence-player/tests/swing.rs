@@ -0,0 +1,164 @@
+mod common;
+
+use common::{note_on_times, play_until, sequence, trig};
+use helloworld_tonic::groove::MAX_TRIG_OFFSET;
+use helloworld_tonic::recording::{Recorded, RecordedEvent};
+use helloworld_tonic::sequencer::{Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::server::sequence::GrooveTemplate;
+use helloworld_tonic::timing::{StepDuration, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::Trig;
+use std::sync::Arc;
+use std::time::Instant;
+
+const BPM: u32 = 120;
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn swung(swing: u32, trigs: Vec<Trig>) -> Sequence {
+    let mut sequence = sequence(8, BPM, trigs);
+    sequence.swing = swing;
+    sequence
+}
+
+/// Play `sequence` until every step in `0..steps` has a note-on and return
+/// how far each one landed from its grid position, in fractions of a step.
+fn step_offsets(sequence: Sequence, steps: u32) -> Vec<f64> {
+    let clock = Arc::new(VirtualClock::new());
+    let (start, events) = play_until(clock, sequence, |events| {
+        (0..steps).all(|step| !note_on_times(events, step).is_empty())
+    });
+
+    let step_duration = StepDuration::new(BPM, 1, 16);
+    (0..steps)
+        .map(|step| {
+            let grid = start + step_duration.steps(step as u64);
+            signed_millis(note_on_times(&events, step)[0], grid)
+                / step_duration.as_duration().as_secs_f64()
+                / 1000.0
+        })
+        .collect()
+}
+
+fn signed_millis(time: Instant, reference: Instant) -> f64 {
+    if time >= reference {
+        (time - reference).as_secs_f64() * 1000.0
+    } else {
+        -(reference - time).as_secs_f64() * 1000.0
+    }
+}
+
+fn assert_offsets(actual: &[f64], expected: &[f64]) {
+    assert_eq!(actual.len(), expected.len());
+    for (step, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+        assert!(
+            (actual - expected).abs() < 1e-5,
+            "step {} landed {} steps off the grid, expected {}",
+            step,
+            actual,
+            expected
+        );
+    }
+}
+
+fn first_velocity(events: &[RecordedEvent], step: u32) -> u32 {
+    events
+        .iter()
+        .find_map(|recorded| match &recorded.event {
+            Recorded::NoteOn {
+                step: played, note, ..
+            } if *played == step => Some(note.velocity),
+            _ => None,
+        })
+        .unwrap()
+}
+
+fn four_straight_trigs() -> Vec<Trig> {
+    (0..4).map(|step| trig(step, 0.0, 0.5)).collect()
+}
+
+#[test]
+fn straight_swing_leaves_steps_on_the_grid() {
+    for swing in [0, 50] {
+        let offsets = step_offsets(swung(swing, four_straight_trigs()), 4);
+        assert_offsets(&offsets, &[0.0, 0.0, 0.0, 0.0]);
+    }
+}
+
+#[test]
+fn swing_delays_every_second_step() {
+    // At 66% the first step of each pair takes 66% of the pair.
+    let offsets = step_offsets(swung(66, four_straight_trigs()), 4);
+    assert_offsets(&offsets, &[0.0, 0.32, 0.0, 0.32]);
+
+    let offsets = step_offsets(swung(80, four_straight_trigs()), 4);
+    assert_offsets(&offsets, &[0.0, 0.6, 0.0, 0.6]);
+}
+
+#[test]
+fn swing_adds_to_trig_offsets() {
+    let trigs = vec![
+        trig(0, 0.25, 0.5),
+        trig(1, -0.25, 0.5),
+        trig(2, 0.0, 0.5),
+        trig(3, 0.5, 0.5),
+    ];
+    let offsets = step_offsets(swung(75, trigs), 4);
+    // A whole step late is past the micro-timing range.
+    let limit = MAX_TRIG_OFFSET as f64;
+    assert_offsets(&offsets, &[0.25, 0.25, 0.0, limit]);
+}
+
+#[test]
+fn offset_swing_and_groove_together_stay_within_a_step() {
+    let mut sequence = swung(80, vec![trig(0, 0.0, 0.5), trig(1, 0.9, 0.5)]);
+    sequence.groove = Some(GrooveTemplate {
+        timing: vec![0.0, 0.9],
+        velocity: Vec::new(),
+    });
+    let offsets = step_offsets(sequence, 2);
+    assert_offsets(&offsets, &[0.0, MAX_TRIG_OFFSET as f64]);
+}
+
+#[test]
+fn groove_template_shifts_timing_and_velocity() {
+    let mut sequence = swung(0, four_straight_trigs());
+    sequence.groove = Some(GrooveTemplate {
+        timing: vec![0.0, 0.125, -0.25],
+        velocity: vec![0, 40, -20],
+    });
+
+    let offsets = step_offsets(sequence.clone(), 4);
+    // The three-step template wraps round to step 3.
+    assert_offsets(&offsets, &[0.0, 0.125, -0.25, 0.0]);
+
+    let clock = Arc::new(VirtualClock::new());
+    let (_, events) = play_until(clock, sequence, |events| {
+        !note_on_times(events, 3).is_empty()
+    });
+    assert_eq!(first_velocity(&events, 0), 100);
+    assert_eq!(first_velocity(&events, 1), 127);
+    assert_eq!(first_velocity(&events, 2), 80);
+    assert_eq!(first_velocity(&events, 3), 100);
+}
+
+#[test]
+fn swing_outside_the_hardware_range_is_rejected() {
+    let sequencer = Sequencer::new(NullHandler);
+    for swing in [1, 49, 81, 100] {
+        assert_eq!(
+            sequencer
+                .cue_sequence(swung(swing, four_straight_trigs()))
+                .unwrap_err(),
+            SequencerError::InvalidSwing(swing)
+        );
+    }
+    assert!(sequencer
+        .cue_sequence(swung(80, four_straight_trigs()))
+        .is_ok());
+}
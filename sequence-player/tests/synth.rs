@@ -0,0 +1,401 @@
+mod common;
+
+use common::{compiled, sequence, trig};
+use helloworld_tonic::render::{render_wav, RenderError, RenderOptions};
+use helloworld_tonic::sequencer::{SequencerError, StepHandler};
+use helloworld_tonic::server::sequence::ParameterLocks;
+use helloworld_tonic::synth::{
+    Envelope, Patch, Synth, SynthEvent, SynthSink, SynthStepHandler, Waveform, MAX_VOICES,
+    SAMPLE_RATE, SYNTH_TRACKS,
+};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::validation::{InvalidSequence, Violation};
+use helloworld_tonic::{MiddleC, Note, Pitch, TrackConfig, Trig};
+use std::collections::HashSet;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Samples in a sixteenth at 120 BPM.
+const STEP: f64 = SAMPLE_RATE as f64 / 8.0;
+
+// Held at full level from the first sample and cut the moment it is let go.
+const ORGAN: Patch = Patch::Tone {
+    waveform: Waveform::Square,
+    envelope: Envelope {
+        attack: 0.0,
+        decay: 0.0,
+        sustain: 1.0,
+        release: 0.0,
+    },
+};
+
+fn wav(sequence: Sequence, loops: u32) -> (hound::WavSpec, Vec<i16>) {
+    let options = RenderOptions {
+        loops,
+        ..Default::default()
+    };
+    let file = render_wav(sequence, &options).unwrap();
+    let reader = hound::WavReader::new(file.as_slice()).unwrap();
+    let spec = reader.spec();
+    let samples = reader.into_samples().map(Result::unwrap).collect();
+    (spec, samples)
+}
+
+fn peak(samples: &[i16]) -> i16 {
+    samples
+        .iter()
+        .map(|sample| sample.saturating_abs())
+        .max()
+        .unwrap_or(0)
+}
+
+fn at(step: f64) -> usize {
+    (step * STEP).round() as usize
+}
+
+fn note_on(key: u8, patch: Patch) -> SynthEvent {
+    SynthEvent::NoteOn {
+        track: 0,
+        key,
+        velocity: 127,
+        patch,
+    }
+}
+
+fn render(synth: &mut Synth, frames: usize) -> Vec<f32> {
+    let mut samples = vec![0.0; frames];
+    synth.render(&mut samples);
+    samples
+}
+
+#[test]
+fn files_are_mono_16_bit_and_as_long_as_the_loops() {
+    let (spec, samples) = wav(sequence(16, 120, vec![trig(0, 0.0, 1.0)]), 3);
+
+    assert_eq!(spec.channels, 1);
+    assert_eq!(spec.sample_rate, SAMPLE_RATE);
+    assert_eq!(spec.bits_per_sample, 16);
+    assert_eq!(samples.len(), at(48.0));
+}
+
+#[test]
+fn notes_sound_from_their_step_and_fade_after_release() {
+    let (_, samples) = wav(sequence(16, 120, vec![trig(4, 0.0, 1.0)]), 1);
+
+    assert_eq!(peak(&samples[..at(4.0)]), 0);
+    assert!(peak(&samples[at(4.0)..at(5.0)]) > 1000);
+    // The default release lasts 0.2 seconds, under two steps.
+    assert!(peak(&samples[at(5.0)..at(7.0)]) > 0);
+    assert_eq!(peak(&samples[at(7.0)..]), 0);
+}
+
+#[test]
+fn drums_on_channel_10_ring_out_past_the_note() {
+    let kick = Pitch::new(36).unwrap().to_note(MiddleC::default(), 100);
+    let mut drums = sequence(
+        16,
+        120,
+        vec![Trig {
+            note: Some(kick),
+            ..trig(0, 0.0, 0.1)
+        }],
+    );
+    drums.tracks = vec![TrackConfig {
+        track: 0,
+        midi_channel: 10,
+        ..Default::default()
+    }];
+    let (_, samples) = wav(drums, 1);
+
+    assert!(peak(&samples[at(2.0)..at(3.0)]) > 100);
+    assert_eq!(peak(&samples[at(12.0)..]), 0);
+}
+
+#[test]
+fn renders_are_identical_every_time() {
+    let mut hats = sequence(16, 120, Vec::new());
+    hats.trigs = (0..16)
+        .map(|step| Trig {
+            note: Some(Pitch::new(42).unwrap().to_note(MiddleC::default(), 90)),
+            ..trig(step, 0.0, 0.5)
+        })
+        .collect();
+    hats.tracks = vec![TrackConfig {
+        track: 0,
+        midi_channel: 10,
+        ..Default::default()
+    }];
+
+    assert_eq!(wav(hats.clone(), 2), wav(hats, 2));
+}
+
+#[test]
+fn nothing_renders_from_an_invalid_sequence() {
+    let error = render_wav(
+        sequence(16, 120, vec![trig(16, 0.0, 1.0)]),
+        &RenderOptions::default(),
+    )
+    .unwrap_err();
+    assert!(matches!(error, RenderError::InvalidSequence(_)));
+}
+
+#[test]
+fn velocity_and_track_volume_scale_the_level() {
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(note_on(69, ORGAN));
+    let full = render(&mut synth, 100);
+    assert!(full.iter().all(|sample| sample.abs() == 0.25));
+
+    synth.handle(SynthEvent::Volume {
+        track: 0,
+        level: 0.5,
+    });
+    assert!(render(&mut synth, 100)
+        .iter()
+        .all(|sample| sample.abs() == 0.125));
+}
+
+#[test]
+fn all_notes_off_silences_every_voice_within_a_few_milliseconds() {
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(note_on(60, ORGAN));
+    synth.handle(note_on(36, Patch::Drums));
+    render(&mut synth, 1000);
+
+    synth.handle(SynthEvent::AllNotesOff);
+    render(&mut synth, SAMPLE_RATE as usize / 100);
+    assert_eq!(synth.voices(), 0);
+    assert!(render(&mut synth, 100).iter().all(|sample| *sample == 0.0));
+}
+
+#[test]
+fn the_oldest_voice_gives_way_past_the_limit() {
+    let mut synth = Synth::new(SAMPLE_RATE);
+    for key in 0..MAX_VOICES as u8 + 8 {
+        synth.handle(note_on(key + 40, ORGAN));
+        render(&mut synth, 10);
+    }
+    assert_eq!(synth.voices(), MAX_VOICES);
+}
+
+#[derive(Clone, Default)]
+struct Events(Arc<Mutex<Vec<SynthEvent>>>);
+
+impl SynthSink for Events {
+    fn send(&mut self, event: SynthEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn a_track_program_picks_its_waveform() {
+    let events = Events::default();
+    let handler = SynthStepHandler::new(events.clone());
+    let mut played = sequence(16, 120, vec![trig(0, 0.0, 1.0)]);
+    played.tracks = vec![TrackConfig {
+        track: 0,
+        ..Default::default()
+    }];
+    handler.handle_sequence_change(&compiled(&played));
+    handler.handle_notes_on(&[&played.trigs[0]]);
+    handler.handle_parameters(
+        0,
+        &ParameterLocks {
+            program: Some(3),
+            ..Default::default()
+        },
+    );
+    handler.handle_notes_on(&[&played.trigs[0]]);
+
+    let waveforms: Vec<Waveform> = events
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|event| match event {
+            SynthEvent::NoteOn {
+                patch: Patch::Tone { waveform, .. },
+                ..
+            } => Some(*waveform),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(waveforms, vec![Waveform::Saw, Waveform::Sine]);
+}
+
+#[test]
+fn tracks_on_one_channel_share_its_program() {
+    let events = Events::default();
+    let handler = SynthStepHandler::new(events.clone());
+    let mut played = sequence(
+        16,
+        120,
+        vec![
+            trig(0, 0.0, 1.0),
+            Trig {
+                track: 1,
+                ..trig(0, 0.0, 1.0)
+            },
+        ],
+    );
+    played.tracks = [0, 1]
+        .into_iter()
+        .map(|track| TrackConfig {
+            track,
+            midi_channel: 2,
+            ..Default::default()
+        })
+        .collect();
+    handler.handle_sequence_change(&compiled(&played));
+    handler.handle_parameters(
+        0,
+        &ParameterLocks {
+            program: Some(2),
+            ..Default::default()
+        },
+    );
+    handler.handle_notes_on(&[&played.trigs[0], &played.trigs[1]]);
+
+    let waveforms: Vec<Waveform> = events
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|event| match event {
+            SynthEvent::NoteOn {
+                patch: Patch::Tone { waveform, .. },
+                ..
+            } => Some(*waveform),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(waveforms, vec![Waveform::Triangle, Waveform::Triangle]);
+}
+
+#[test]
+fn only_the_synth_tracks_play() {
+    let beyond = SYNTH_TRACKS as u32;
+    let mut synth = Synth::new(SAMPLE_RATE);
+    synth.handle(SynthEvent::Volume {
+        track: beyond,
+        level: 0.5,
+    });
+    synth.handle(SynthEvent::NoteOn {
+        track: beyond,
+        key: 69,
+        velocity: 127,
+        patch: ORGAN,
+    });
+    assert_eq!(synth.voices(), 0);
+    synth.handle(note_on(69, ORGAN));
+    assert!(render(&mut synth, 100)
+        .iter()
+        .all(|sample| sample.abs() == 0.25));
+
+    let events = Events::default();
+    let handler = SynthStepHandler::new(events.clone());
+    let played = Trig {
+        track: beyond,
+        ..trig(0, 0.0, 1.0)
+    };
+    handler.handle_notes_on(&[&played]);
+    handler.handle_notes_off(&[&played]);
+    assert!(events.0.lock().unwrap().is_empty());
+
+    let error = render_wav(sequence(16, 120, vec![played]), &RenderOptions::default());
+    assert_eq!(
+        error,
+        Err(RenderError::InvalidSequence(InvalidSequence(vec![
+            Violation {
+                field: "trigs[0].track".to_string(),
+                error: SequencerError::NoSynthTrack(beyond),
+            }
+        ])))
+    );
+}
+
+#[test]
+fn every_sounding_note_gets_its_note_off() {
+    let events = Events::default();
+    let handler = SynthStepHandler::new(events.clone());
+    let chords: Vec<Trig> = (0..SYNTH_TRACKS as u32)
+        .map(|track| Trig {
+            track,
+            chord: [5, 8]
+                .map(|value| Note {
+                    octave: 4,
+                    value,
+                    velocity: 100,
+                })
+                .to_vec(),
+            ..trig(0, 0.0, 1.0)
+        })
+        .collect();
+    let chords: Vec<&Trig> = chords.iter().collect();
+    handler.handle_notes_on(&chords);
+    handler.handle_notes_off(&chords);
+
+    let events = events.0.lock().unwrap();
+    let played: HashSet<(u32, u8)> = events
+        .iter()
+        .filter_map(|event| match event {
+            SynthEvent::NoteOn { track, key, .. } => Some((*track, *key)),
+            _ => None,
+        })
+        .collect();
+    let released: HashSet<(u32, u8)> = events
+        .iter()
+        .filter_map(|event| match event {
+            SynthEvent::NoteOff { track, key } => Some((*track, *key)),
+            _ => None,
+        })
+        .collect();
+    assert!(played.len() > MAX_VOICES);
+    assert_eq!(released, played);
+    assert_eq!(events.len(), played.len() * 2);
+}
+
+/// Records events once the test lets sending go ahead.
+#[derive(Clone, Default)]
+struct HeldUp {
+    events: Events,
+    sending: Arc<Mutex<()>>,
+}
+
+impl SynthSink for HeldUp {
+    fn send(&mut self, event: SynthEvent) {
+        drop(self.sending.lock().unwrap());
+        self.events.send(event);
+    }
+}
+
+#[test]
+fn the_playback_thread_leaves_silencing_to_a_panic_under_way() {
+    let sink = HeldUp::default();
+    let handler = Arc::new(SynthStepHandler::new(sink.clone()));
+    let held_up = sink.sending.lock().unwrap();
+    let panicking = {
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || handler.handle_panic())
+    };
+    thread::sleep(Duration::from_millis(50));
+
+    let (done_tx, done) = mpsc::channel();
+    {
+        let handler = Arc::clone(&handler);
+        thread::spawn(move || {
+            handler.handle_notes_on(&[&trig(0, 0.0, 1.0)]);
+            handler.handle_all_notes_off();
+            done_tx.send(())
+        });
+    }
+    assert_eq!(done.recv_timeout(Duration::from_secs(1)), Ok(()));
+
+    drop(held_up);
+    panicking.join().unwrap();
+    assert_eq!(
+        *sink.events.0.lock().unwrap(),
+        vec![SynthEvent::AllNotesOff]
+    );
+}
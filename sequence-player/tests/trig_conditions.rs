@@ -0,0 +1,189 @@
+mod common;
+
+use common::{play_seeded, play_until, play_with, sequence, trig};
+use helloworld_tonic::recording::{Recorded, RecordedEvent};
+use helloworld_tonic::sequencer::{Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::server::sequence::trig_condition::Condition;
+use helloworld_tonic::server::sequence::{IterationCondition, TrigCondition};
+use helloworld_tonic::timing::{Clock, StepDuration, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::Trig;
+use std::sync::Arc;
+use std::time::Instant;
+
+const BPM: u32 = 120;
+const LENGTH: u32 = 4;
+const LOOPS: u32 = 8;
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn conditional(track: u32, step: u32, condition: Condition) -> Trig {
+    Trig {
+        track,
+        condition: Some(TrigCondition {
+            condition: Some(condition),
+        }),
+        ..trig(step, 0.0, 0.5)
+    }
+}
+
+fn iteration(a: u32, b: u32) -> Condition {
+    Condition::Iteration(IterationCondition { a, b })
+}
+
+fn pattern(trigs: Vec<Trig>) -> Sequence {
+    sequence(LENGTH, BPM, trigs)
+}
+
+/// Whether `clock` has moved on past the first `LOOPS` loops from `start`.
+fn past_loops(clock: &VirtualClock, start: Instant) -> bool {
+    clock.now() - start > StepDuration::new(BPM, 1, 16).steps((LOOPS * LENGTH) as u64)
+}
+
+/// Loops, counted from zero, in which the trig on `track` and `step` played.
+fn loops_played(events: &[RecordedEvent], start: Instant, track: u32, step: u32) -> Vec<u32> {
+    let loop_duration = StepDuration::new(BPM, 1, 16).steps(LENGTH as u64);
+    events
+        .iter()
+        .filter(|recorded| {
+            matches!(
+                recorded.event,
+                Recorded::NoteOn { track: played_track, step: played_step, .. }
+                    if played_track == track && played_step == step
+            )
+        })
+        .map(|recorded| ((recorded.time - start).as_nanos() / loop_duration.as_nanos()) as u32)
+        .filter(|played_loop| *played_loop < LOOPS)
+        .collect()
+}
+
+fn play_loops(sequence: Sequence) -> (Instant, Vec<RecordedEvent>) {
+    let clock = Arc::new(VirtualClock::new());
+    let start = clock.now();
+    play_until(Arc::clone(&clock), sequence, |_| past_loops(&clock, start))
+}
+
+#[test]
+fn iteration_conditions_follow_the_loop_count() {
+    let (start, events) = play_loops(pattern(vec![
+        conditional(0, 0, iteration(2, 3)),
+        conditional(1, 0, iteration(1, 1)),
+        conditional(2, 3, iteration(4, 4)),
+    ]));
+
+    assert_eq!(loops_played(&events, start, 0, 0), vec![1, 4, 7]);
+    assert_eq!(
+        loops_played(&events, start, 1, 0),
+        (0..LOOPS).collect::<Vec<_>>()
+    );
+    assert_eq!(loops_played(&events, start, 2, 3), vec![3, 7]);
+}
+
+#[test]
+fn first_conditions_only_tell_the_first_loop_apart() {
+    let (start, events) = play_loops(pattern(vec![
+        conditional(0, 1, Condition::First(true)),
+        conditional(1, 1, Condition::First(false)),
+    ]));
+
+    assert_eq!(loops_played(&events, start, 0, 1), vec![0]);
+    assert_eq!(
+        loops_played(&events, start, 1, 1),
+        (1..LOOPS).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn fill_conditions_follow_fill_mode() {
+    let clock = Arc::new(VirtualClock::new());
+    let start = clock.now();
+    let (start, events) = play_with(
+        Arc::clone(&clock),
+        pattern(vec![
+            conditional(0, 2, Condition::Fill(true)),
+            conditional(1, 2, Condition::Fill(false)),
+        ]),
+        |sequencer| sequencer.set_fill(true).unwrap(),
+        |_| past_loops(&clock, start),
+    );
+
+    assert_eq!(
+        loops_played(&events, start, 0, 2),
+        (0..LOOPS).collect::<Vec<_>>()
+    );
+    assert!(loops_played(&events, start, 1, 2).is_empty());
+}
+
+#[test]
+fn previous_and_neighbor_conditions_follow_earlier_outcomes() {
+    let (start, events) = play_loops(pattern(vec![
+        conditional(0, 0, iteration(1, 2)),
+        conditional(0, 1, Condition::Previous(true)),
+        conditional(0, 2, Condition::Previous(false)),
+        conditional(1, 1, Condition::Neighbor(true)),
+        conditional(1, 2, Condition::Neighbor(false)),
+    ]));
+
+    let even = vec![0, 2, 4, 6];
+    let odd = vec![1, 3, 5, 7];
+    assert_eq!(loops_played(&events, start, 0, 0), even);
+    // PRE and NEI read outcomes without recording their own, so the 1:2
+    // result on step 0 stays the one they see.
+    assert_eq!(loops_played(&events, start, 0, 1), even);
+    assert_eq!(loops_played(&events, start, 0, 2), odd);
+    assert_eq!(loops_played(&events, start, 1, 1), even);
+    assert_eq!(loops_played(&events, start, 1, 2), odd);
+}
+
+#[test]
+fn seeded_probability_is_reproducible() {
+    let sequence = pattern(vec![
+        conditional(0, 0, Condition::Probability(50)),
+        conditional(1, 1, Condition::Probability(100)),
+    ]);
+    let play = |seed| {
+        let clock = Arc::new(VirtualClock::new());
+        let start = clock.now();
+        play_seeded(seed, Arc::clone(&clock), sequence.clone(), |_| {
+            past_loops(&clock, start)
+        })
+    };
+
+    let (start, events) = play(7);
+    let (repeat_start, repeat_events) = play(7);
+    let coin_flips = loops_played(&events, start, 0, 0);
+    assert_eq!(coin_flips, loops_played(&repeat_events, repeat_start, 0, 0));
+    assert!(!coin_flips.is_empty() && coin_flips.len() < LOOPS as usize);
+    assert_eq!(loops_played(&events, start, 1, 1).len(), LOOPS as usize);
+}
+
+#[test]
+fn impossible_conditions_are_rejected() {
+    let sequencer = Sequencer::new(NullHandler);
+    for condition in [
+        Condition::Probability(0),
+        Condition::Probability(101),
+        iteration(0, 2),
+        iteration(3, 2),
+        iteration(1, 9),
+    ] {
+        let result = sequencer.cue_sequence(pattern(vec![conditional(3, 2, condition.clone())]));
+        assert!(
+            matches!(
+                result,
+                Err(SequencerError::InvalidTrigCondition {
+                    track: 3,
+                    step: 2,
+                    ..
+                })
+            ),
+            "{} was accepted",
+            condition
+        );
+    }
+}
@@ -0,0 +1,207 @@
+mod common;
+
+use common::{sequence, trig};
+use helloworld_tonic::sampler::SampleBank;
+use helloworld_tonic::sequencer::{Sequencer, SequencerError, StepHandler};
+use helloworld_tonic::server::rpc;
+use helloworld_tonic::server::sequence::sequencer_service_server::SequencerService;
+use helloworld_tonic::server::sequence::{LoadSampleRequest, RenderWavRequest};
+use helloworld_tonic::server::SequencerServiceImpl;
+use helloworld_tonic::synth::SYNTH_TRACKS;
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::validation::violations;
+use helloworld_tonic::{Note, NoteValue, TrackConfig, Trig};
+use prost::Message;
+use tonic::{Code, Request};
+
+struct NullHandler;
+
+impl StepHandler for NullHandler {
+    fn handle_notes_on(&self, _trigs: &[&Trig]) {}
+    fn handle_notes_off(&self, _trigs: &[&Trig]) {}
+}
+
+fn loud(step: u32, velocity: u32) -> Trig {
+    Trig {
+        chord: vec![Note {
+            octave: 5,
+            value: NoteValue::E as i32,
+            velocity,
+        }],
+        ..trig(step, 0.0, 0.5)
+    }
+}
+
+// Wrong in three places: no steps, a trig past the end of its track and a
+// chord note too loud for MIDI.
+fn broken() -> Sequence {
+    let mut sequence = sequence(0, 120, vec![trig(0, 0.0, 0.5), loud(1, 128)]);
+    sequence.tracks = vec![TrackConfig {
+        track: 0,
+        length: 1,
+        ..Default::default()
+    }];
+    sequence
+}
+
+const BROKEN_FIELDS: [&str; 3] = [
+    "sequence_length",
+    "trigs[1].step",
+    "trigs[1].chord[0].velocity",
+];
+
+fn fields(sequence: &Sequence) -> Vec<String> {
+    violations(sequence)
+        .into_iter()
+        .map(|violation| violation.field)
+        .collect()
+}
+
+#[test]
+fn every_violation_is_reported_with_its_field() {
+    assert_eq!(fields(&broken()), BROKEN_FIELDS);
+    assert!(fields(&sequence(4, 120, vec![loud(3, 127)])).is_empty());
+}
+
+#[test]
+fn sequences_that_cant_play_as_written_are_rejected_on_cue_and_swap() {
+    let sequencer = Sequencer::new(NullHandler);
+    let empty = sequence(0, 120, vec![]);
+    assert_eq!(
+        sequencer.cue_sequence(empty.clone()).err(),
+        Some(SequencerError::EmptySequence)
+    );
+    assert_eq!(
+        sequencer.swap_sequence(empty).err(),
+        Some(SequencerError::EmptySequence)
+    );
+
+    // A trig has to fit its own track, which may be longer than the sequence.
+    let mut polymetric = sequence(4, 120, vec![trig(5, 0.0, 0.5)]);
+    assert_eq!(
+        sequencer.cue_sequence(polymetric.clone()).err(),
+        Some(SequencerError::StepOutOfRange {
+            track: 0,
+            step: 5,
+            length: 4
+        })
+    );
+    polymetric.tracks = vec![TrackConfig {
+        track: 0,
+        length: 6,
+        ..Default::default()
+    }];
+    assert!(sequencer.cue_sequence(polymetric).is_ok());
+
+    assert_eq!(
+        sequencer
+            .cue_sequence(sequence(4, 120, vec![loud(0, 200)]))
+            .err(),
+        Some(SequencerError::InvalidVelocity {
+            track: 0,
+            step: 0,
+            velocity: 200
+        })
+    );
+}
+
+#[test]
+fn a_track_can_only_be_configured_once() {
+    let mut twice = sequence(4, 120, vec![trig(0, 0.0, 0.5)]);
+    twice.tracks = [2, 0, 2, 2]
+        .into_iter()
+        .map(|track| TrackConfig {
+            track,
+            ..Default::default()
+        })
+        .collect();
+    assert_eq!(fields(&twice), ["tracks[2].track", "tracks[3].track"]);
+
+    let sequencer = Sequencer::new(NullHandler);
+    assert_eq!(
+        sequencer.cue_sequence(twice).err(),
+        Some(SequencerError::DuplicateTrack(2))
+    );
+}
+
+#[tokio::test]
+async fn the_status_details_list_each_field_violation() {
+    let service = SequencerServiceImpl::new(Sequencer::new(NullHandler));
+
+    let status = service
+        .cue_sequence(Request::new(broken()))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let details = rpc::Status::decode(status.details()).unwrap();
+    assert_eq!(details.code, Code::InvalidArgument as i32);
+    assert_eq!(details.details[0].type_url, rpc::BAD_REQUEST_TYPE_URL);
+    let bad_request = rpc::BadRequest::decode(details.details[0].value.as_slice()).unwrap();
+    let fields: Vec<&str> = bad_request
+        .field_violations
+        .iter()
+        .map(|violation| violation.field.as_str())
+        .collect();
+    assert_eq!(fields, BROKEN_FIELDS);
+    assert!(bad_request.field_violations[2]
+        .description
+        .contains("Velocity 128"));
+}
+
+#[tokio::test]
+async fn sequences_and_samples_for_the_synth_keep_to_its_tracks() {
+    let beyond = sequence(
+        4,
+        120,
+        vec![Trig {
+            track: SYNTH_TRACKS as u32,
+            ..trig(0, 0.0, 0.5)
+        }],
+    );
+    let on_midi = SequencerServiceImpl::new(Sequencer::new(NullHandler));
+    assert!(on_midi
+        .cue_sequence(Request::new(beyond.clone()))
+        .await
+        .is_ok());
+
+    let on_synth =
+        SequencerServiceImpl::with_sample_bank(Sequencer::new(NullHandler), SampleBank::default());
+    let cued = on_synth
+        .cue_sequence(Request::new(beyond.clone()))
+        .await
+        .unwrap_err();
+    let swapped = on_synth
+        .swap_sequence(Request::new(beyond.clone()))
+        .await
+        .unwrap_err();
+    let rendered = on_synth
+        .render_wav(Request::new(RenderWavRequest {
+            sequence: Some(beyond),
+            ..Default::default()
+        }))
+        .await
+        .unwrap_err();
+    for status in [&cued, &swapped, &rendered] {
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "Track 16 must be below 16 to play on the built-in synth"
+        );
+    }
+    let details = rpc::Status::decode(rendered.details()).unwrap();
+    let bad_request = rpc::BadRequest::decode(details.details[0].value.as_slice()).unwrap();
+    assert_eq!(
+        bad_request.field_violations[0].field,
+        "sequence.trigs[0].track"
+    );
+
+    let loaded = on_synth
+        .load_sample(Request::new(LoadSampleRequest {
+            track: SYNTH_TRACKS as u32,
+            ..Default::default()
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(loaded.code(), Code::InvalidArgument);
+}
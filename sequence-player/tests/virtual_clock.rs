@@ -0,0 +1,384 @@
+mod common;
+
+use common::{sequence, trig};
+use helloworld_tonic::recording::{Recorded, RecordedEvent, RecordingStepHandler};
+use helloworld_tonic::sequencer::Sequencer;
+use helloworld_tonic::server::sequence::{TransportEvent, TransportEventKind};
+use helloworld_tonic::timing::{Clock, VirtualClock};
+use helloworld_tonic::types::Sequence;
+use helloworld_tonic::Trig;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+// Sixteenths at 120 BPM.
+const STEP: Duration = Duration::from_millis(125);
+
+struct Rig {
+    clock: Arc<VirtualClock>,
+    recorder: RecordingStepHandler<Arc<VirtualClock>>,
+    sequencer: Sequencer,
+    start: Instant,
+}
+
+impl Rig {
+    fn new() -> Self {
+        let clock = Arc::new(VirtualClock::new());
+        let recorder = RecordingStepHandler::new(Arc::clone(&clock));
+        let sequencer = Sequencer::with_clock(recorder.clone(), Arc::clone(&clock));
+        clock.settle();
+        let start = clock.now();
+        Self {
+            clock,
+            recorder,
+            sequencer,
+            start,
+        }
+    }
+
+    /// Cue and start `sequence`, returning once its first step has played.
+    fn play(sequence: Sequence) -> Self {
+        let rig = Self::new();
+        rig.sequencer.cue_sequence(sequence).unwrap();
+        rig.sequencer.start_sequence().unwrap();
+        rig.clock.settle();
+        rig
+    }
+
+    fn advance_steps(&self, steps: f64) {
+        self.clock.advance(STEP.mul_f64(steps));
+    }
+
+    /// Steps of the notes started so far, each with its time in steps since
+    /// the start.
+    fn note_ons(&self) -> Vec<(u32, f64)> {
+        self.notes(|event| matches!(event, Recorded::NoteOn { .. }))
+    }
+
+    fn note_offs(&self) -> Vec<(u32, f64)> {
+        self.notes(|event| matches!(event, Recorded::NoteOff { .. }))
+    }
+
+    fn notes(&self, wanted: impl Fn(&Recorded) -> bool) -> Vec<(u32, f64)> {
+        self.recorder
+            .events()
+            .into_iter()
+            .filter(|recorded| wanted(&recorded.event))
+            .map(|RecordedEvent { time, event }| {
+                let step = match event {
+                    Recorded::NoteOn { step, .. } | Recorded::NoteOff { step, .. } => step,
+                    _ => unreachable!(),
+                };
+                (step, self.steps_since_start(time))
+            })
+            .collect()
+    }
+
+    fn steps_since_start(&self, time: Instant) -> f64 {
+        (time - self.start).as_secs_f64() / STEP.as_secs_f64()
+    }
+}
+
+// Kind, step, sequence id and cued steps left of a transport event.
+type Watched = (TransportEventKind, u32, u64, Option<u32>);
+
+/// The next `count` transport events, as the delivery thread passes them
+/// on, and then that nothing else came.
+fn watched(events: &mut broadcast::Receiver<TransportEvent>, count: usize) -> Vec<Watched> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut received: Vec<TransportEvent> = Vec::new();
+    while received.len() < count && Instant::now() < deadline {
+        match events.try_recv() {
+            Ok(event) => received.push(event),
+            Err(broadcast::error::TryRecvError::Empty) => thread::sleep(Duration::from_millis(1)),
+            Err(error) => panic!("Transport events lost: {}", error),
+        }
+    }
+    thread::sleep(Duration::from_millis(20));
+    assert!(matches!(
+        events.try_recv(),
+        Err(broadcast::error::TryRecvError::Empty)
+    ));
+    assert!(received
+        .windows(2)
+        .all(|pair| pair[0].timestamp_micros <= pair[1].timestamp_micros));
+    received
+        .into_iter()
+        .map(|event| {
+            (
+                event.kind(),
+                event.step,
+                event.sequence_id,
+                event.cued_remaining_steps,
+            )
+        })
+        .collect()
+}
+
+fn quarter_notes(sequence_length: u32, bpm: u32) -> Sequence {
+    let trigs = (0..sequence_length)
+        .step_by(4)
+        .map(|step| trig(step, 0.0, 1.0))
+        .collect();
+    sequence(sequence_length, bpm, trigs)
+}
+
+fn with_track(track: u32, trigs: impl IntoIterator<Item = Trig>) -> Vec<Trig> {
+    trigs
+        .into_iter()
+        .map(|trig| Trig { track, ..trig })
+        .collect()
+}
+
+#[test]
+fn nothing_plays_until_started() {
+    let rig = Rig::new();
+    rig.sequencer.cue_sequence(quarter_notes(16, 120)).unwrap();
+    rig.advance_steps(32.0);
+
+    assert!(rig.recorder.events().is_empty());
+    assert!(!rig.sequencer.is_playing());
+}
+
+#[test]
+fn start_plays_the_first_step_at_once_and_the_rest_on_the_grid() {
+    let rig = Rig::play(quarter_notes(16, 120));
+    assert!(rig.sequencer.is_playing());
+    assert_eq!(rig.note_ons(), vec![(0, 0.0)]);
+
+    rig.advance_steps(15.5);
+    assert_eq!(
+        rig.note_ons(),
+        vec![(0, 0.0), (4, 4.0), (8, 8.0), (12, 12.0)]
+    );
+    assert_eq!(rig.sequencer.current_step(), 0);
+
+    rig.advance_steps(0.5);
+    assert_eq!(rig.note_ons().last(), Some(&(0, 16.0)));
+}
+
+#[test]
+fn advancing_in_small_increments_plays_the_same_as_one_jump() {
+    let jump = Rig::play(quarter_notes(16, 120));
+    jump.advance_steps(40.0);
+
+    let crawl = Rig::play(quarter_notes(16, 120));
+    for _ in 0..400 {
+        crawl.clock.advance(STEP / 10);
+    }
+
+    assert_eq!(jump.note_ons(), crawl.note_ons());
+    assert_eq!(jump.note_offs(), crawl.note_offs());
+}
+
+#[test]
+fn stop_releases_sounding_notes_and_halts_the_transport() {
+    let rig = Rig::play(sequence(16, 120, vec![trig(0, 0.0, 8.0)]));
+    rig.advance_steps(2.5);
+    rig.recorder.take_events();
+
+    rig.sequencer.stop_sequence().unwrap();
+    rig.clock.settle();
+    let stopped = rig.recorder.take_events();
+    assert!(!rig.sequencer.is_playing());
+    assert_eq!(
+        stopped
+            .iter()
+            .map(|recorded| (rig.steps_since_start(recorded.time), &recorded.event))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                2.5,
+                &Recorded::NoteOff {
+                    track: 0,
+                    step: 0,
+                    note: trig(0, 0.0, 0.0).note.unwrap()
+                }
+            ),
+            (2.5, &Recorded::AllNotesOff),
+        ]
+    );
+
+    rig.advance_steps(32.0);
+    assert!(rig.recorder.events().is_empty());
+    assert_eq!(rig.clock.next_deadline(), None);
+}
+
+#[test]
+fn start_after_stop_plays_from_the_top() {
+    let rig = Rig::play(quarter_notes(16, 120));
+    rig.advance_steps(5.5);
+    rig.sequencer.stop_sequence().unwrap();
+    rig.advance_steps(10.0);
+    rig.recorder.take_events();
+
+    rig.sequencer.start_sequence().unwrap();
+    rig.clock.settle();
+    rig.advance_steps(4.0);
+    assert_eq!(rig.note_ons(), vec![(0, 15.5), (4, 19.5)]);
+}
+
+#[test]
+fn resume_after_stop_continues_where_playback_stopped() {
+    let rig = Rig::play(quarter_notes(16, 120));
+    rig.advance_steps(5.5);
+    rig.sequencer.stop_sequence().unwrap();
+    rig.advance_steps(10.0);
+    rig.recorder.take_events();
+
+    rig.sequencer.resume_sequence().unwrap();
+    rig.clock.settle();
+    rig.advance_steps(2.0);
+    // Steps 6 and 7 play from the restart, then step 8 has its note.
+    assert_eq!(rig.note_ons(), vec![(8, 17.5)]);
+}
+
+#[test]
+fn swap_replaces_the_sequence_without_moving_the_playhead() {
+    let rig = Rig::play(quarter_notes(16, 120));
+    rig.advance_steps(5.5);
+
+    let every_step = sequence(
+        16,
+        120,
+        with_track(1, (0..16).map(|step| trig(step, 0.0, 0.5))),
+    );
+    rig.sequencer.swap_sequence(every_step).unwrap();
+    rig.clock.settle();
+    rig.advance_steps(2.0);
+
+    let ons = rig.note_ons();
+    assert_eq!(&ons[ons.len() - 2..], &[(6, 6.0), (7, 7.0)]);
+    assert_eq!(rig.sequencer.current_step(), 8);
+}
+
+#[test]
+fn cued_sequence_takes_over_on_step_zero() {
+    let rig = Rig::play(quarter_notes(16, 120));
+    rig.advance_steps(6.5);
+
+    let offbeats = sequence(
+        8,
+        120,
+        with_track(1, [trig(2, 0.0, 1.0), trig(6, 0.0, 1.0)]),
+    );
+    let cued = rig.sequencer.cue_sequence(offbeats).unwrap();
+    assert_eq!(cued.remaining_steps, 9);
+    rig.advance_steps(9.0);
+    assert_eq!(rig.note_ons().last(), Some(&(12, 12.0)));
+    assert_eq!(rig.sequencer.current_step(), 0);
+
+    // The cued sequence replaces the old one from step 0, so the old
+    // sequence's downbeat doesn't play again.
+    rig.advance_steps(16.0);
+    assert_eq!(
+        rig.note_ons(),
+        vec![
+            (0, 0.0),
+            (4, 4.0),
+            (8, 8.0),
+            (12, 12.0),
+            (2, 18.0),
+            (6, 22.0),
+            (2, 26.0),
+            (6, 30.0),
+        ]
+    );
+}
+
+#[test]
+fn note_offs_follow_trig_length_from_when_the_note_started() {
+    let rig = Rig::play(sequence(
+        16,
+        120,
+        vec![
+            trig(0, 0.0, 0.5),
+            trig(4, 0.5, 2.0),
+            with_track(1, [trig(2, -0.25, 1.25)]).remove(0),
+        ],
+    ));
+    rig.advance_steps(8.0);
+
+    assert_eq!(rig.note_ons(), vec![(0, 0.0), (2, 1.75), (4, 4.5)]);
+    assert_eq!(rig.note_offs(), vec![(0, 0.5), (2, 3.0), (4, 6.5)]);
+}
+
+#[test]
+fn tied_notes_last_until_the_next_trig_on_their_track() {
+    let rig = Rig::play(sequence(
+        16,
+        120,
+        vec![trig(0, 0.0, f32::INFINITY), trig(6, 0.0, 1.0)],
+    ));
+    rig.advance_steps(5.5);
+    assert!(rig.note_offs().is_empty());
+
+    rig.advance_steps(1.0);
+    assert_eq!(rig.note_offs(), vec![(0, 6.0)]);
+}
+
+#[test]
+fn watchers_see_the_start_every_step_and_the_stop() {
+    let rig = Rig::new();
+    let mut events = rig.sequencer.subscribe_transport();
+    let cued = rig.sequencer.cue_sequence(quarter_notes(4, 120)).unwrap();
+    rig.sequencer.start_sequence().unwrap();
+    rig.clock.settle();
+    rig.advance_steps(2.5);
+    rig.sequencer.stop_sequence().unwrap();
+    rig.clock.settle();
+
+    let id = cued.sequence_id;
+    assert_eq!(
+        watched(&mut events, 5),
+        vec![
+            (TransportEventKind::Started, 0, id, None),
+            (TransportEventKind::Step, 0, id, None),
+            (TransportEventKind::Step, 1, id, None),
+            (TransportEventKind::Step, 2, id, None),
+            (TransportEventKind::Stopped, 3, id, None),
+        ]
+    );
+}
+
+#[test]
+fn watchers_see_a_swap_where_the_playhead_is() {
+    let rig = Rig::play(quarter_notes(16, 120));
+    rig.advance_steps(5.5);
+    let mut events = rig.sequencer.subscribe_transport();
+
+    rig.sequencer.swap_sequence(quarter_notes(16, 120)).unwrap();
+    rig.clock.settle();
+    rig.advance_steps(1.0);
+
+    assert_eq!(
+        watched(&mut events, 2),
+        vec![
+            (TransportEventKind::Swapped, 6, 2, None),
+            (TransportEventKind::Step, 6, 2, None),
+        ]
+    );
+}
+
+#[test]
+fn watchers_count_down_to_a_cued_sequence_taking_over() {
+    let rig = Rig::play(quarter_notes(4, 120));
+    rig.advance_steps(1.5);
+    let mut events = rig.sequencer.subscribe_transport();
+
+    let cued = rig.sequencer.cue_sequence(quarter_notes(8, 120)).unwrap();
+    assert_eq!(cued.remaining_steps, 2);
+    rig.advance_steps(3.0);
+
+    let id = cued.sequence_id;
+    assert_eq!(
+        watched(&mut events, 4),
+        vec![
+            (TransportEventKind::Step, 2, 1, Some(1)),
+            (TransportEventKind::Step, 3, 1, Some(0)),
+            (TransportEventKind::CuePromoted, 0, id, None),
+            (TransportEventKind::Step, 0, id, None),
+        ]
+    );
+}
@@ -0,0 +1,56 @@
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use common::{sequence, trig};
+use helloworld_tonic::ports::{create_virtual_output, select_input, PortSelector};
+use helloworld_tonic::sequencer::MidiStepHandler;
+use helloworld_tonic::Sequencer;
+use midir::MidiInput;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Loops the virtual output back into an input of our own, so it runs on a
+// headless box as long as the ALSA sequencer is there.
+#[test]
+fn notes_reach_software_subscribed_to_the_virtual_port() {
+    let output = match create_virtual_output("vtakt-loopback") {
+        Ok(output) => output,
+        Err(error) => {
+            println!("Skipping, no ALSA sequencer: {}", error);
+            return;
+        }
+    };
+    let midi_in = MidiInput::new("vtakt-loopback-in").unwrap();
+    let (_, port) = select_input(&midi_in, &PortSelector::new("vtakt-loopback")).unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let _input = midi_in
+        .connect(
+            &port,
+            "loopback",
+            |_, message, received: &mut Arc<Mutex<Vec<Vec<u8>>>>| {
+                received.lock().unwrap().push(message.to_vec())
+            },
+            Arc::clone(&received),
+        )
+        .unwrap();
+
+    let sequencer = Sequencer::new(MidiStepHandler::new(output));
+    sequencer
+        .cue_sequence(sequence(4, 240, vec![trig(0, 0.0, 0.5), trig(2, 0.0, 0.5)]))
+        .unwrap();
+    sequencer.start_sequence().unwrap();
+
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while received.lock().unwrap().len() < 4 {
+        assert!(Instant::now() < give_up, "nothing came through the port");
+        thread::sleep(Duration::from_millis(5));
+    }
+    drop(sequencer);
+
+    let received = received.lock().unwrap();
+    let statuses: Vec<u8> = received[..4].iter().map(|message| message[0]).collect();
+    assert_eq!(statuses, vec![0x90, 0x80, 0x90, 0x80]);
+    assert_eq!(received[0][2], 100);
+}